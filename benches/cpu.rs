@@ -0,0 +1,69 @@
+//! Measures how fast the emulator runs a CPU-bound loop, in emulated seconds per second. The
+//! program never touches the PPU or APU, but they still run alongside the CPU, as they do in
+//! games. Run with `cargo bench`; there's no harness, since the standard one is unstable.
+
+//
+// Author: Patrick Walton
+//
+
+extern crate nes;
+
+use nes::rom::Rom;
+use nes::Headless;
+
+use std::time::Instant;
+
+/// 60 emulated seconds
+const FRAMES: usize = 3600;
+const FRAMES_PER_SECOND: f64 = 60.0988;
+
+/// The program, assembled at $C000: a loop of loads, stores, arithmetic and branches.
+#[rustfmt::skip]
+const PROGRAM: [u8; 0x16] = [
+    0xa2, 0x00,             // C000: LDX #0
+    0xa0, 0x00,             // C002: LDY #0
+    // loop:
+    0xb5, 0x00,             // C004: LDA $00,X
+    0x18,                   // C006: CLC
+    0x69, 0x03,             // C007: ADC #3
+    0x95, 0x00,             // C009: STA $00,X
+    0x99, 0x00, 0x02,       // C00B: STA $0200,Y
+    0xe8,                   // C00E: INX
+    0xc8,                   // C00F: INY
+    0xd0, 0xf2,             // C010: BNE loop
+    0x4c, 0x04, 0xc0,       // C012: JMP loop
+    0x00,                   // C015: padding
+];
+
+fn rom() -> Rom {
+    let mut image = b"NES\x1a\x01\x01".to_vec();
+    image.resize(16, 0);
+    let mut prg = vec![0; 0x4000];
+    prg[..PROGRAM.len()].copy_from_slice(&PROGRAM);
+    // The reset vector points at $C000.
+    prg[0x3ffc] = 0x00;
+    prg[0x3ffd] = 0xc0;
+    image.extend_from_slice(&prg);
+    image.resize(16 + 0x4000 + 0x2000, 0);
+    Rom::from_bytes(&image).unwrap()
+}
+
+fn main() {
+    let mut console = Headless::new(rom()).unwrap();
+    let mut samples = vec![];
+    let start = Instant::now();
+    for _ in 0..FRAMES {
+        console.run_frame(&mut samples);
+        samples.clear();
+    }
+    let elapsed = start.elapsed();
+    let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+    let emulated = FRAMES as f64 / FRAMES_PER_SECOND;
+    println!(
+        "{} frames in {:.2} s: {:.1} times real time, {:.1} MHz",
+        FRAMES,
+        seconds,
+        emulated / seconds,
+        console.cpu.cy as f64 / seconds / 1e6
+    );
+}
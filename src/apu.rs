@@ -4,21 +4,25 @@
 // Author: Patrick Walton
 //
 
-use audio::{self, OutputBuffer};
-use mem::Mem;
-use speex::Resampler;
-use util::{Save, Xorshift};
-
+use audio::{AudioSink, AudioStats};
+use mapper::Mapper;
+use mem::{IrqLine, IrqSource, Mem};
+use rom::Region;
+use util::Save;
+use wav::WavWriter;
+
+use std::cell::RefCell;
+use std::cmp;
+use std::f32;
 use std::fs::File;
+use std::mem;
 use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
 
-const CYCLES_PER_EVEN_TICK: u64 = 7438;
-const CYCLES_PER_ODD_TICK: u64 = 7439;
-
-const NES_SAMPLE_RATE: u32 = 1789920; // Actual is 1789800, but this is divisible by 240.
-const OUTPUT_SAMPLE_RATE: u32 = 44100;
-const TICK_FREQUENCY: u32 = 240;
-const NES_SAMPLES_PER_TICK: u32 = NES_SAMPLE_RATE / TICK_FREQUENCY;
+/// The rate the mixed output is played, and recorded, at.
+pub const OUTPUT_SAMPLE_RATE: u32 = 44100;
 
 const PULSE_WAVEFORMS: [u8; 4] = [0b01000000, 0b01100000, 0b01111000, 0b10011111];
 
@@ -32,11 +36,130 @@ const TRIANGLE_WAVEFORM: [u8; 32] = [
     13, 14, 15,
 ];
 
-// TODO: PAL
-const NOISE_PERIODS: [u16; 16] = [
+const NTSC_NOISE_PERIODS: [u16; 16] = [
     4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
 ];
 
+const PAL_NOISE_PERIODS: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
+const NOTE_NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+//
+// Timing
+//
+
+/// The parts of the APU's behavior that depend on the TV system the console was made for.
+struct Timing {
+    /// CPU cycles per second, the rate the channels' timers run at.
+    cpu_rate: u32,
+    noise_periods: &'static [u16; 16],
+    /// The CPU cycles, counted from the start of a frame counter sequence, on which its steps
+    /// fall. The four-step sequence ends a cycle after the fourth step, and the five-step one a
+    /// cycle after the fifth.
+    frame_steps: [u64; 5],
+}
+
+static NTSC_TIMING: Timing = Timing {
+    cpu_rate: 1789773,
+    noise_periods: &NTSC_NOISE_PERIODS,
+    frame_steps: [7457, 14913, 22371, 29829, 37281],
+};
+
+static PAL_TIMING: Timing = Timing {
+    cpu_rate: 1662607,
+    noise_periods: &PAL_NOISE_PERIODS,
+    frame_steps: [8313, 16627, 24939, 33253, 41565],
+};
+
+/// The Dendy has an NTSC APU driven by a slower clock.
+static DENDY_TIMING: Timing = Timing {
+    cpu_rate: 1773448,
+    noise_periods: &NTSC_NOISE_PERIODS,
+    frame_steps: [7457, 14913, 22371, 29829, 37281],
+};
+
+impl Timing {
+    fn for_region(region: Region) -> &'static Timing {
+        match region.timing() {
+            Region::Pal => &PAL_TIMING,
+            Region::Dendy => &DENDY_TIMING,
+            _ => &NTSC_TIMING,
+        }
+    }
+}
+
+//
+// Channels
+//
+
+/// A sound channel that can be muted, soloed or shown in the frequency readout.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    /// The delta modulation channel. It isn't emulated yet, so it is always silent.
+    Dmc,
+    /// Sound generated by the cartridge, if any.
+    Expansion,
+}
+
+pub const CHANNELS: [Channel; 6] = [
+    Channel::Pulse1,
+    Channel::Pulse2,
+    Channel::Triangle,
+    Channel::Noise,
+    Channel::Dmc,
+    Channel::Expansion,
+];
+
+impl Channel {
+    pub fn name(self) -> &'static str {
+        match self {
+            Channel::Pulse1 => "Pulse 1",
+            Channel::Pulse2 => "Pulse 2",
+            Channel::Triangle => "Triangle",
+            Channel::Noise => "Noise",
+            Channel::Dmc => "DMC",
+            Channel::Expansion => "Expansion",
+        }
+    }
+
+    /// Returns true if the channel plays notes, as opposed to noise or samples.
+    pub fn pitched(self) -> bool {
+        self != Channel::Noise && self != Channel::Dmc
+    }
+
+    /// The index of the channel's sample buffer.
+    fn sample_buffer(self) -> usize {
+        match self {
+            Channel::Pulse1 => 0,
+            Channel::Pulse2 => 1,
+            Channel::Triangle => 2,
+            Channel::Noise => 3,
+            Channel::Dmc => 4,
+            Channel::Expansion => 5,
+        }
+    }
+}
+
+/// Returns the name of the equal-tempered note closest to `frequency`, such as "A4", along with
+/// how far off it is in cents.
+pub fn note_name(frequency: f64) -> (String, i32) {
+    // MIDI note numbering: A4 (440 Hz) is note 69 and C-1 is note 0.
+    let note = 69.0 + 12.0 * (frequency / 440.0).log2();
+    let nearest = note.round();
+    let cents = ((note - nearest) * 100.0).round() as i32;
+    let nearest = nearest as i32;
+    let name = NOTE_NAMES[nearest.rem_euclid(12) as usize];
+    (format!("{}{}", name, nearest.div_euclid(12) - 1), cents)
+}
+
 //
 // Channel lengths
 //
@@ -63,12 +186,15 @@ struct ApuLength {
     disable: bool,
     id: u8,
     remaining: u8,
+    /// Set through APUSTATUS. A disabled counter stays at 0, even when the length is written.
+    enabled: bool,
 }
 
 save_struct!(ApuLength {
     disable,
     id,
-    remaining
+    remaining,
+    enabled
 });
 
 impl ApuLength {
@@ -77,6 +203,7 @@ impl ApuLength {
             disable: false,
             id: 0,
             remaining: 0,
+            enabled: false,
         }
     }
 
@@ -87,9 +214,10 @@ impl ApuLength {
             0 => self.disable = ((val >> db.bit_number() as usize) & 1) != 0,
             1 | 2 => {}
             3 => {
-                // FIXME: Only set `remaining` if APUSTATUS has enabled this channel.
                 self.id = val >> 3;
-                self.remaining = LENGTH_COUNTERS[self.id as usize];
+                if self.enabled {
+                    self.remaining = LENGTH_COUNTERS[self.id as usize];
+                }
             }
             _ => panic!("can't happen"),
         }
@@ -100,33 +228,52 @@ impl ApuLength {
             self.remaining -= 1;
         }
     }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.remaining = 0;
+        }
+    }
 }
 
 /// Volume envelope
 #[derive(Copy, Clone)]
 struct ApuEnvelope {
-    enabled: bool,
+    /// If set, the channel plays at `volume` and the decay level is ignored.
+    constant_volume: bool,
+    /// Restarts the decay from 15 when it reaches 0. This is the same register bit as the length
+    /// counter halt.
+    looping: bool,
+    /// The constant volume, or the divider's period.
     volume: u8,
-    period: u8,
-    counter: u8,
+    /// Set by a write to the length register, so that the next quarter frame restarts the
+    /// envelope instead of clocking it.
+    start: bool,
+    divider: u8,
+    decay: u8,
     length: ApuLength,
 }
 
 save_struct!(ApuEnvelope {
-    enabled,
+    constant_volume,
+    looping,
     volume,
-    period,
-    counter,
+    start,
+    divider,
+    decay,
     length
 });
 
 impl ApuEnvelope {
     fn new() -> ApuEnvelope {
         ApuEnvelope {
-            enabled: false,
+            constant_volume: false,
+            looping: false,
             volume: 0,
-            period: 0,
-            counter: 0,
+            start: false,
+            divider: 0,
+            decay: 0,
             length: ApuLength::new(),
         }
     }
@@ -135,48 +282,53 @@ impl ApuEnvelope {
     fn storeb(&mut self, addr: u16, val: u8) {
         self.length.storeb(addr, val, DisableBit5);
 
-        if (addr & 0x3) == 0 {
-            self.enabled = ((val >> 4) & 1) == 0;
-            if self.enabled {
-                self.volume = 15;
-                self.period = val & 0xf;
-                self.counter = 0;
-            } else {
+        match addr & 0x3 {
+            0 => {
+                self.looping = ((val >> 5) & 1) != 0;
+                self.constant_volume = ((val >> 4) & 1) != 0;
                 self.volume = val & 0xf;
             }
+            3 => self.start = true,
+            _ => {}
         }
     }
 
-    // This routine executes at 240 Hz and adjusts the volume and counter appropriately.
+    // Clocked every quarter frame. The divider counts down from `volume`, and each time it
+    // wraps around the decay level drops by one.
     fn tick(&mut self) {
-        if self.enabled {
-            self.counter += 1;
-            if self.counter >= self.period {
-                self.counter = 0;
-                if self.volume == 0 {
-                    if self.loops() {
-                        self.volume = 15;
-                    }
-                } else {
-                    self.volume -= 1;
-                    if self.volume == 0 && !self.loops() {
-                        self.length.remaining = 0;
-                    }
-                }
-            }
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+            return;
+        }
+
+        if self.divider > 0 {
+            self.divider -= 1;
+            return;
+        }
+        self.divider = self.volume;
+        if self.decay > 0 {
+            self.decay -= 1;
+        } else if self.looping {
+            self.decay = 15;
         }
     }
 
-    fn loops(self) -> bool {
-        self.length.disable
+    fn output(&self) -> u8 {
+        if self.constant_volume {
+            self.volume
+        } else {
+            self.decay
+        }
     }
 
     fn audible(&self) -> bool {
-        self.volume > 0 && self.length.remaining > 0
+        self.output() > 0 && self.length.remaining > 0
     }
 
     fn sample_volume(&self) -> i16 {
-        (self.volume as i16 * 4) << 8
+        (self.output() as i16) << LEVEL_SHIFT
     }
 }
 
@@ -227,19 +379,63 @@ struct ApuPulse {
     sweep: ApuPulseSweep,
     timer: ApuTimer,
     duty: u8,
-    sweep_cycle: u8,
+    /// Counts down the half frames until the sweep next adjusts the period.
+    sweep_divider: u8,
+    /// Set by a write to the sweep register, so that the divider restarts on the next half frame.
+    sweep_reload: bool,
     waveform_index: u8,
+    /// Pulse 1 negates its sweep in ones' complement, subtracting one more than pulse 2 does.
+    ones_complement: bool,
 }
 
 impl ApuPulse {
-    fn new() -> ApuPulse {
+    fn new(ones_complement: bool) -> ApuPulse {
         ApuPulse {
             envelope: ApuEnvelope::new(),
             sweep: ApuPulseSweep(0),
             timer: ApuTimer::new(),
             duty: 0,
-            sweep_cycle: 0,
+            sweep_divider: 0,
+            sweep_reload: false,
             waveform_index: 0,
+            ones_complement: ones_complement,
+        }
+    }
+
+    // The period the sweep would change to. This is worked out continuously, whether or not the
+    // sweep is enabled, since it decides whether the channel is muted.
+    fn sweep_target(&self) -> u16 {
+        let period = self.timer.value;
+        let delta = period >> self.sweep.shift_count() as usize;
+        if !self.sweep.negate() {
+            period + delta
+        } else if self.ones_complement {
+            period.saturating_sub(delta + 1)
+        } else {
+            period - delta
+        }
+    }
+
+    // Periods under 8 and sweep targets past the 11-bit timer silence the channel.
+    fn muted(&self) -> bool {
+        self.timer.value < 8 || self.sweep_target() > 0x7ff
+    }
+
+    fn audible(&self) -> bool {
+        self.envelope.audible() && !self.muted()
+    }
+
+    // Clocked every half frame.
+    fn clock_sweep(&mut self) {
+        let adjusts = self.sweep.enabled() && self.sweep.shift_count() != 0 && !self.muted();
+        if self.sweep_divider == 0 && adjusts {
+            self.timer.value = self.sweep_target();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep.period() - 1;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
         }
     }
 }
@@ -249,8 +445,10 @@ save_struct!(ApuPulse {
     sweep,
     timer,
     duty,
-    sweep_cycle,
-    waveform_index
+    sweep_divider,
+    sweep_reload,
+    waveform_index,
+    ones_complement
 });
 
 /// APU pulse sweep
@@ -276,6 +474,7 @@ impl ApuPulseSweep {
         self.0 >> 7 != 0
     }
 
+    /// The number of half frames between adjustments.
     fn period(self) -> u8 {
         ((self.0 >> 4) & 0x7) + 1
     }
@@ -355,14 +554,19 @@ struct ApuNoise {
     timer: u16,
     /// The number of ticks since the last timer.
     timer_count: u16,
-    /// The xorshift RNG.
-    rng: Xorshift, // FIXME: This is inaccurate.
+    /// The 15-bit linear feedback shift register. The channel is silent while bit 0 is set.
+    shift: u16,
+    /// Bit 7 of $400E. Set, the feedback comes from bit 6 instead of bit 1, which repeats the
+    /// sequence every 93 steps (or 31, depending on the register's contents) for a metallic tone.
+    short_mode: bool,
 }
 
 save_struct!(ApuNoise {
     envelope,
     timer,
-    timer_count
+    timer_count,
+    shift,
+    short_mode
 });
 
 impl ApuNoise {
@@ -371,9 +575,17 @@ impl ApuNoise {
             envelope: ApuEnvelope::new(),
             timer: 0,
             timer_count: 0,
-            rng: Xorshift::new(),
+            shift: 1,
+            short_mode: false,
         }
     }
+
+    // Clocked each time the timer runs out.
+    fn clock_shift(&mut self) {
+        let tap = if self.short_mode { 6 } else { 1 };
+        let feedback = (self.shift ^ (self.shift >> tap)) & 1;
+        self.shift = (self.shift >> 1) | (feedback << 14);
+    }
 }
 
 /// APUSTATUS: 0x4015
@@ -438,33 +650,227 @@ impl Save for Regs {
 // Sample buffers
 //
 
-const SAMPLE_COUNT: usize = 178992;
+/// A tenth of a second of output samples, the size of the output device's buffer.
+const SAMPLE_COUNT: usize = OUTPUT_SAMPLE_RATE as usize / 10;
 
 struct SampleBuffer {
     samples: [i16; SAMPLE_COUNT],
 }
 
+//
+// Mixing
+//
+
+/// Channel sample buffers hold the channel's 4-bit output level shifted left by this much. The
+/// bits below are the fraction left when a sample averages several levels.
+const LEVEL_SHIFT: usize = 10;
+
+/// The APU's non-linear mixer, as lookup tables of the formulas on the NESdev wiki. The pulses
+/// share one resistor network and the triangle, noise and DMC another, so a channel's loudness
+/// depends on what the others in its group are playing.
+struct Mixer {
+    /// Indexed by the sum of the pulse levels.
+    pulse: [i16; 31],
+    /// Indexed by 3 times the triangle level, plus 2 times the noise level, plus the DMC level.
+    tnd: [i16; 203],
+}
+
+impl Mixer {
+    fn new() -> Mixer {
+        let mut mixer = Mixer {
+            pulse: [0; 31],
+            tnd: [0; 203],
+        };
+        // Level 0 is silence; the formulas divide by the level.
+        for n in 1..mixer.pulse.len() {
+            mixer.pulse[n] = Mixer::to_sample(95.52 / (8128.0 / n as f64 + 100.0));
+        }
+        for n in 1..mixer.tnd.len() {
+            mixer.tnd[n] = Mixer::to_sample(163.67 / (24329.0 / n as f64 + 100.0));
+        }
+        mixer
+    }
+
+    // The two groups together reach just under 1.0 at full volume.
+    fn to_sample(output: f64) -> i16 {
+        (output * 32767.0) as i16
+    }
+
+    fn mix(&self, pulse1: i16, pulse2: i16, triangle: i16, noise: i16, dmc: i16) -> i32 {
+        let pulse = Mixer::lookup(&self.pulse, pulse1 as i32 + pulse2 as i32);
+        let tnd = 3 * triangle as i32 + 2 * noise as i32 + dmc as i32;
+        pulse + Mixer::lookup(&self.tnd, tnd)
+    }
+
+    // Interpolates between the two entries a fractional level falls between.
+    fn lookup(table: &[i16], level: i32) -> i32 {
+        let index = (level >> LEVEL_SHIFT) as usize;
+        let low = table[index] as i32;
+        if index + 1 == table.len() {
+            return low;
+        }
+        let fraction = level & ((1 << LEVEL_SHIFT) - 1);
+        low + (((table[index + 1] as i32 - low) * fraction) >> LEVEL_SHIFT)
+    }
+}
+
+//
+// Output filters
+//
+
+/// A first-order filter, run on the mixed output. The console's output stage has two high-pass
+/// filters, which take out the DC offset of the mix, and a low-pass filter.
+struct Filter {
+    high_pass: bool,
+    /// The weight of each new input: RC / (RC + dt) for high-pass filters and dt / (RC + dt) for
+    /// low-pass ones, where dt is the time between samples.
+    alpha: f32,
+    prev_input: f32,
+    prev_output: f32,
+}
+
+impl Filter {
+    fn new(high_pass: bool, cutoff: f32) -> Filter {
+        let rc = 1.0 / (2.0 * f32::consts::PI * cutoff);
+        let dt = 1.0 / OUTPUT_SAMPLE_RATE as f32;
+        Filter {
+            high_pass: high_pass,
+            alpha: if high_pass {
+                rc / (rc + dt)
+            } else {
+                dt / (rc + dt)
+            },
+            prev_input: 0.0,
+            prev_output: 0.0,
+        }
+    }
+
+    fn run(&mut self, input: f32) -> f32 {
+        let output = if self.high_pass {
+            self.alpha * (self.prev_output + input - self.prev_input)
+        } else {
+            self.prev_output + self.alpha * (input - self.prev_output)
+        };
+        self.prev_input = input;
+        self.prev_output = output;
+        output
+    }
+}
+
+save_struct!(Filter {
+    prev_input,
+    prev_output
+});
+
 /// APU state
 pub struct Apu {
     regs: Regs,
 
-    sample_buffers: Box<[SampleBuffer; 5]>,
+    timing: &'static Timing,
+    sample_buffers: Vec<SampleBuffer>,
     sample_buffer_offset: usize,
-    output_buffer: Option<*mut OutputBuffer>,
-    resampler: Resampler,
+    /// Where mixed samples go to be played, if there's an audio device.
+    output: Option<Box<AudioSink>>,
+    /// A copy of the mixed samples goes here while the audio is being recorded.
+    recorder: Option<WavWriter>,
+    mixer: Mixer,
+    /// The 90 Hz high-pass, 440 Hz high-pass and 14 kHz low-pass filters, in the order they
+    /// apply, and whether they're applied at all.
+    filters: [Filter; 3],
+    filtered: bool,
+    /// If true, the output device is left out, since it can't play samples faster than real time
+    /// and waiting for it would hold the emulator back.
+    fast_forward: bool,
+
+    /// The cartridge, which may produce expansion audio.
+    mapper: Rc<RefCell<Box<Mapper + Send>>>,
+    /// The expansion audio for one output sample, which cartridges generate a CPU cycle at a time.
+    expansion_samples: Vec<i16>,
+
+    /// If set, only this channel is mixed into the output.
+    solo: Option<Channel>,
+    /// Channels left out of the mix, indexed by sample buffer. Soloing a channel plays it even if
+    /// it's muted.
+    muted: [bool; 6],
+
+    /// The last value written to $4017. Bit 7 selects the five-step sequence, which never raises
+    /// an IRQ, and bit 6 inhibits the IRQ.
+    frame_counter: u8,
+    /// The CPU cycle the frame counter has run to, and how far into its sequence that is.
+    frame_counter_cy: u64,
+    sequence_cycle: u64,
+    /// Whether the sequence running is the five-step one. A write to $4017 only changes this
+    /// when it restarts the sequence.
+    five_step: bool,
+    /// A write to $4017 restarts the sequence 3 or 4 cycles later, on `restart_cy`.
+    restart_pending: bool,
+    restart_cy: u64,
+    irq: IrqLine,
+
+    /// CPU cycles stolen by DMA that the CPU hasn't stalled for yet.
+    stall: u64,
 
     pub cy: u64,
-    pub ticks: u64,
+    /// How far the next output sample is from being due. Each CPU cycle adds
+    /// `OUTPUT_SAMPLE_RATE`, and each sample takes away the CPU's clock rate.
+    sample_phase: u64,
 }
 
-save_struct!(Apu { regs, cy, ticks });
+impl Save for Apu {
+    fn save(&mut self, fd: &mut File) {
+        self.regs.save(fd);
+        self.cy.save(fd);
+        self.sample_phase.save(fd);
+        self.frame_counter.save(fd);
+        self.frame_counter_cy.save(fd);
+        self.sequence_cycle.save(fd);
+        self.five_step.save(fd);
+        self.restart_pending.save(fd);
+        self.restart_cy.save(fd);
+        for filter in self.filters.iter_mut() {
+            filter.save(fd);
+        }
+        self.filtered.save(fd);
+        // The soloed channel is saved as its sample buffer plus one, or 0 for none.
+        let mut solo = self
+            .solo
+            .map_or(0, |channel| channel.sample_buffer() as u8 + 1);
+        solo.save(fd);
+        for muted in self.muted.iter_mut() {
+            muted.save(fd);
+        }
+    }
+    fn load(&mut self, fd: &mut File) {
+        self.regs.load(fd);
+        self.cy.load(fd);
+        self.sample_phase.load(fd);
+        self.frame_counter.load(fd);
+        self.frame_counter_cy.load(fd);
+        self.sequence_cycle.load(fd);
+        self.five_step.load(fd);
+        self.restart_pending.load(fd);
+        self.restart_cy.load(fd);
+        for filter in self.filters.iter_mut() {
+            filter.load(fd);
+        }
+        self.filtered.load(fd);
+        let mut solo = 0u8;
+        solo.load(fd);
+        self.solo = CHANNELS.get((solo as usize).wrapping_sub(1)).cloned();
+        for muted in self.muted.iter_mut() {
+            muted.load(fd);
+        }
+    }
+}
 
 impl Mem for Apu {
     fn loadb(&mut self, addr: u16) -> u8 {
-        match addr {
-            0x4015 => *self.regs.status,
-            _ => 0,
+        let val = self.peekb(addr);
+        if addr == 0x4015 {
+            // Reading the status acknowledges the frame IRQ.
+            self.irq.acknowledge(IrqSource::FrameCounter);
         }
+        val
     }
     fn storeb(&mut self, addr: u16, val: u8) {
         match addr {
@@ -473,62 +879,216 @@ impl Mem for Apu {
             0x4008...0x400b => self.regs.triangle.storeb(addr, val),
             0x400c...0x400f => self.update_noise(addr, val),
             0x4015 => self.update_status(val),
+            0x4017 => self.update_frame_counter(val),
             _ => {} // TODO
         }
     }
+    fn peekb(&self, addr: u16) -> u8 {
+        match addr {
+            0x4015 => self.read_status(),
+            _ => 0,
+        }
+    }
 }
 
 impl Apu {
-    pub fn new(output_buffer: Option<*mut OutputBuffer>) -> Apu {
+    pub fn new(
+        output: Option<Box<AudioSink>>,
+        mapper: Rc<RefCell<Box<Mapper + Send>>>,
+        region: Region,
+    ) -> Apu {
+        let timing = Timing::for_region(region);
         Apu {
             regs: Regs {
-                pulses: [ApuPulse::new(), ApuPulse::new()],
+                pulses: [ApuPulse::new(true), ApuPulse::new(false)],
                 triangle: ApuTriangle::new(),
                 noise: ApuNoise::new(),
                 status: ApuStatus(0),
             },
 
-            sample_buffers: Box::new([
-                SampleBuffer {
-                    samples: [0; SAMPLE_COUNT],
-                },
-                SampleBuffer {
-                    samples: [0; SAMPLE_COUNT],
-                },
-                SampleBuffer {
-                    samples: [0; SAMPLE_COUNT],
-                },
-                SampleBuffer {
-                    samples: [0; SAMPLE_COUNT],
-                },
-                SampleBuffer {
+            timing: timing,
+
+            // Built one at a time; all six at once would overflow smaller thread stacks.
+            sample_buffers: (0..6)
+                .map(|_| SampleBuffer {
                     samples: [0; SAMPLE_COUNT],
-                },
-            ]),
+                })
+                .collect(),
 
             sample_buffer_offset: 0,
-            output_buffer: output_buffer,
-            resampler: Resampler::new(1, NES_SAMPLE_RATE, OUTPUT_SAMPLE_RATE, 0).unwrap(),
+            output: output,
+            recorder: None,
+            mixer: Mixer::new(),
+            filters: [
+                Filter::new(true, 90.0),
+                Filter::new(true, 440.0),
+                Filter::new(false, 14000.0),
+            ],
+            filtered: true,
+            fast_forward: false,
+
+            mapper: mapper,
+            expansion_samples: vec![0; (timing.cpu_rate / OUTPUT_SAMPLE_RATE) as usize + 1],
+
+            solo: None,
+            muted: [false; 6],
+
+            frame_counter: 0,
+            frame_counter_cy: 0,
+            sequence_cycle: 0,
+            five_step: false,
+            restart_pending: false,
+            restart_cy: 0,
+            irq: IrqLine::new(),
+
+            stall: 0,
 
             cy: 0,
-            ticks: 0,
+            sample_phase: 0,
         }
     }
 
-    fn update_status(&mut self, val: u8) {
-        self.regs.status = ApuStatus(val);
+    /// Gives the APU the CPU's IRQ line, for the frame IRQ.
+    pub fn connect_irq(&mut self, irq: IrqLine) {
+        self.irq = irq;
+    }
 
-        for i in 0..2 {
-            if !self.regs.status.pulse_enabled(i as u8) {
-                self.regs.pulses[i].envelope.length.remaining = 0;
+    /// Steals cycles from the CPU. The DMC does this each time it fetches a sample byte, which
+    /// stalls the CPU for up to 4 cycles depending on what the CPU is doing.
+    pub fn request_stall(&mut self, cycles: u64) {
+        self.stall += cycles;
+    }
+
+    /// Returns the cycles requested by `request_stall` since this was last called.
+    pub fn take_stall(&mut self) -> u64 {
+        let stall = self.stall;
+        self.stall = 0;
+        stall
+    }
+
+    /// Returns the channel that is currently soloed, if any.
+    pub fn solo(&self) -> Option<Channel> {
+        self.solo
+    }
+
+    /// Silences every channel but the given one, or restores them all if `None`.
+    pub fn set_solo(&mut self, channel: Option<Channel>) {
+        self.solo = channel;
+    }
+
+    /// Returns true if the channel is left out of the mix.
+    pub fn muted(&self, channel: Channel) -> bool {
+        self.muted[channel.sample_buffer()]
+    }
+
+    /// Leaves the channel out of the mix, or puts it back.
+    pub fn set_muted(&mut self, channel: Channel, muted: bool) {
+        self.muted[channel.sample_buffer()] = muted;
+    }
+
+    /// Starts recording the mixed output, or stops if it's `None`. Returns the recording that was
+    /// in progress, which has to be finished to be playable.
+    pub fn set_recorder(&mut self, recorder: Option<WavWriter>) -> Option<WavWriter> {
+        mem::replace(&mut self.recorder, recorder)
+    }
+
+    /// How the audio device is keeping up, if there is one.
+    pub fn audio_stats(&self) -> Option<AudioStats> {
+        self.output.as_ref().map(|output| output.stats())
+    }
+
+    /// Returns true if the mixed output goes through the console's output filters.
+    pub fn filtered(&self) -> bool {
+        self.filtered
+    }
+
+    /// Enables or disables the output filters. Without them the output keeps the DC offset and
+    /// the harsher high end of the raw mix.
+    pub fn set_filtered(&mut self, filtered: bool) {
+        self.filtered = filtered;
+    }
+
+    /// Starts or stops fast-forwarding. Meanwhile the mixed samples are still returned by
+    /// `play_channels` and recorded, but not played.
+    pub fn set_fast_forward(&mut self, fast_forward: bool) {
+        self.fast_forward = fast_forward;
+    }
+
+    /// Returns the frequency the channel is currently playing at, in Hz, or `None` if it is
+    /// silent. For the noise channel, this is the rate at which the noise changes. Expansion audio
+    /// is cartridge-specific and the DMC plays samples, so their frequencies are never known.
+    pub fn channel_frequency(&self, channel: Channel) -> Option<f64> {
+        let rate = self.timing.cpu_rate as f64;
+        match channel {
+            Channel::Pulse1 | Channel::Pulse2 => {
+                let pulse = &self.regs.pulses[channel.sample_buffer()];
+                if !pulse.audible() {
+                    return None;
+                }
+                // Each of the eight steps of the waveform lasts one wavelength.
+                Some(rate / (pulse.timer.wavelen() * 8) as f64)
+            }
+            Channel::Triangle => {
+                let triangle = &self.regs.triangle;
+                if !triangle.audible() || !triangle.timer.audible() {
+                    return None;
+                }
+                // 32 steps, each half a wavelength long.
+                Some(rate / (triangle.timer.wavelen() * 16) as f64)
             }
+            Channel::Noise => {
+                let noise = &self.regs.noise;
+                if !noise.envelope.audible() || noise.timer == 0 {
+                    return None;
+                }
+                Some(rate / noise.timer as f64)
+            }
+            Channel::Dmc | Channel::Expansion => None,
         }
-        if !self.regs.status.triangle_enabled() {
-            self.regs.triangle.length.remaining = 0;
+    }
+
+    // Bits 0-3 report which length counters are still running, and bits 6 and 7 the frame and
+    // DMC IRQs. Bit 4 would report a DMC sample in progress, but the DMC isn't emulated yet.
+    fn read_status(&self) -> u8 {
+        let regs = &self.regs;
+        let lengths = [
+            regs.pulses[0].envelope.length,
+            regs.pulses[1].envelope.length,
+            regs.triangle.length,
+            regs.noise.envelope.length,
+        ];
+        let mut status = 0;
+        for (i, length) in lengths.iter().enumerate() {
+            if length.remaining > 0 {
+                status |= 1 << i;
+            }
         }
-        if !self.regs.status.noise_enabled() {
-            self.regs.noise.envelope.length.remaining = 0;
+        if self.irq.asserted_by(IrqSource::FrameCounter) {
+            status |= 0x40;
         }
+        if self.irq.asserted_by(IrqSource::Dmc) {
+            status |= 0x80;
+        }
+        status
+    }
+
+    fn update_status(&mut self, val: u8) {
+        self.regs.status = ApuStatus(val & 0x1f);
+
+        let status = self.regs.status;
+        for i in 0..2 {
+            let enabled = status.pulse_enabled(i as u8);
+            self.regs.pulses[i].envelope.length.set_enabled(enabled);
+        }
+        self.regs
+            .triangle
+            .length
+            .set_enabled(status.triangle_enabled());
+        self.regs
+            .noise
+            .envelope
+            .length
+            .set_enabled(status.noise_enabled());
     }
 
     // FIXME: Refactor into a method on ApuPulse itself.
@@ -537,24 +1097,39 @@ impl Apu {
         pulse.envelope.storeb(addr, val); // Write to the envelope.
         pulse.timer.storeb(addr, val); // Write to the timer.
         match addr & 0x3 {
+            // A new duty cycle picks up at the same point in the sequence.
             0 => pulse.duty = val >> 6,
             1 => {
-                // TODO: Set reload flag.
                 pulse.sweep = ApuPulseSweep(val);
-                pulse.sweep_cycle = 0;
+                pulse.sweep_reload = true;
             }
-            2 | 3 => {}
+            2 => {}
+            // Writing the high period bits restarts the sequence, as well as the envelope, so that
+            // each note attacks from the start of its waveform.
+            3 => pulse.waveform_index = 0,
             _ => panic!("can't happen"),
         }
     }
 
+    // The IRQ inhibit takes effect at once, but the sequence only restarts 3 CPU cycles later if
+    // the write lands on an APU cycle, or 4 if it lands between two.
+    fn update_frame_counter(&mut self, val: u8) {
+        self.frame_counter = val;
+        if (val & 0x40) != 0 {
+            self.irq.acknowledge(IrqSource::FrameCounter);
+        }
+        let delay = if self.frame_counter_cy % 2 == 0 { 3 } else { 4 };
+        self.restart_pending = true;
+        self.restart_cy = self.frame_counter_cy + delay;
+    }
+
     // FIXME: Refactor into a method on ApuNoise itself.
     fn update_noise(&mut self, addr: u16, val: u8) {
         self.regs.noise.envelope.storeb(addr, val);
 
         if (addr & 3) == 2 {
-            // TODO: Mode bit.
-            self.regs.noise.timer = NOISE_PERIODS[val as usize & 0xf];
+            self.regs.noise.short_mode = (val & 0x80) != 0;
+            self.regs.noise.timer = self.timing.noise_periods[val as usize & 0xf];
         }
     }
 
@@ -563,245 +1138,451 @@ impl Apu {
     //
 
     pub fn step(&mut self, run_to_cycle: u64) {
-        loop {
-            let mut next_tick_cycle = self.cy;
-            if self.ticks % 2 == 0 {
-                next_tick_cycle += CYCLES_PER_EVEN_TICK;
-            } else {
-                next_tick_cycle += CYCLES_PER_ODD_TICK;
-            }
-
-            if next_tick_cycle > run_to_cycle {
+        // If the sample buffers are full, the next sample has to wait until they're flushed.
+        while self.sample_buffer_offset < SAMPLE_COUNT {
+            let cycles = self.cycles_until_sample();
+            let sample_cycle = self.cy + cycles;
+            if sample_cycle > run_to_cycle {
                 break;
             }
 
-            self.tick();
+            self.run_frame_counter(sample_cycle);
+            self.synthesize(cycles);
 
-            self.cy = next_tick_cycle;
+            self.cy = sample_cycle;
+            self.sample_phase += cycles * OUTPUT_SAMPLE_RATE as u64;
+            self.sample_phase -= self.timing.cpu_rate as u64;
         }
+        self.run_frame_counter(run_to_cycle);
     }
 
-    fn tick(&mut self) {
-        // 120 Hz operations: length counter and sweep.
-        if self.ticks % 2 == 0 {
-            // TODO: Remember that triangle wave has a different length disable bit.
-            for i in 0..2 {
-                let pulse = &mut self.regs.pulses[i];
-
-                // Length counter.
-                pulse.envelope.length.decrement();
-
-                // Sweep.
-                pulse.sweep_cycle += 1;
-                if pulse.sweep_cycle >= pulse.sweep.period() {
-                    pulse.sweep_cycle = 0;
-
-                    if pulse.sweep.enabled() {
-                        let delta = pulse.timer.value >> pulse.sweep.shift_count() as usize;
-                        if !pulse.sweep.negate() {
-                            pulse.timer.value += delta;
-                        } else {
-                            pulse.timer.value -= delta;
-                        }
-                    }
-                }
+    // Samples don't divide evenly into CPU cycles, so they cover 40 or 41 cycles apiece on NTSC.
+    fn cycles_until_sample(&self) -> u64 {
+        let rate = OUTPUT_SAMPLE_RATE as u64;
+        (self.timing.cpu_rate as u64 - self.sample_phase + rate - 1) / rate
+    }
+
+    //
+    // The frame counter
+    //
+
+    /// Runs the frame counter up to the given CPU cycle, clocking the envelopes, the triangle's
+    /// linear counter, the length counters and the sweeps on its steps.
+    fn run_frame_counter(&mut self, run_to_cycle: u64) {
+        while self.frame_counter_cy < run_to_cycle {
+            self.frame_counter_cy += 1;
+            if self.restart_pending && self.frame_counter_cy == self.restart_cy {
+                self.restart_pending = false;
+                self.restart_sequence();
+            } else {
+                self.clock_frame_counter();
             }
+        }
+    }
 
-            // Length counter for triangle and noise.
-            self.regs.triangle.length.decrement();
-            self.regs.noise.envelope.length.decrement();
+    // Starts the sequence $4017 selects. The five-step sequence clocks everything straight away.
+    fn restart_sequence(&mut self) {
+        self.sequence_cycle = 0;
+        self.five_step = (self.frame_counter & 0x80) != 0;
+        if self.five_step {
+            self.clock_quarter_frame();
+            self.clock_half_frame();
         }
+    }
 
-        // 240 Hz operations: envelope and linear counter.
+    fn clock_frame_counter(&mut self) {
+        let steps = self.timing.frame_steps;
+        let last_step = if self.five_step { steps[4] } else { steps[3] };
+        self.sequence_cycle += 1;
+        if self.sequence_cycle > last_step {
+            self.sequence_cycle = 0;
+        }
+
+        let cycle = self.sequence_cycle;
+        if cycle == steps[0] || cycle == steps[2] {
+            self.clock_quarter_frame();
+        } else if cycle == steps[1] || cycle == last_step {
+            self.clock_quarter_frame();
+            self.clock_half_frame();
+        }
+
+        // The four-step sequence raises the IRQ on the cycle before its last step and on it.
+        let irq_cycle = cycle == steps[3] - 1 || cycle == steps[3];
+        if !self.five_step && irq_cycle && (self.frame_counter & 0x40) == 0 {
+            self.irq.assert(IrqSource::FrameCounter);
+        }
+    }
+
+    // Quarter frames: the envelopes and the triangle's linear counter.
+    fn clock_quarter_frame(&mut self) {
         self.regs.pulses[0].envelope.tick();
         self.regs.pulses[1].envelope.tick();
         self.regs.triangle.tick();
         self.regs.noise.envelope.tick();
+    }
 
-        // Fill the sample buffers.
-        self.play_pulse(0, 0);
-        self.play_pulse(1, 1);
-        self.play_triangle(2);
-        self.play_noise(3);
-        self.sample_buffer_offset += NES_SAMPLES_PER_TICK as usize;
+    // Half frames: the length counters and the sweeps.
+    fn clock_half_frame(&mut self) {
+        // TODO: Remember that triangle wave has a different length disable bit.
+        for i in 0..2 {
+            let pulse = &mut self.regs.pulses[i];
+
+            // Length counter.
+            pulse.envelope.length.decrement();
+
+            // Sweep.
+            pulse.clock_sweep();
+        }
 
-        // TODO: 60 Hz IRQ.
+        // Length counter for triangle and noise.
+        self.regs.triangle.length.decrement();
+        self.regs.noise.envelope.length.decrement();
+    }
 
-        self.ticks += 1;
+    // Adds one sample to each channel's buffer: the channel's average level over the given
+    // number of CPU cycles. Averaging is a boxcar filter, which attenuates waveforms above half
+    // the output rate but doesn't remove them, so some aliasing is still heard.
+    fn synthesize(&mut self, cycles: u64) {
+        let offset = self.sample_buffer_offset;
+        self.sample_buffers[0].samples[offset] = self.play_pulse(0, cycles);
+        self.sample_buffers[1].samples[offset] = self.play_pulse(1, cycles);
+        self.sample_buffers[2].samples[offset] = self.play_triangle(cycles);
+        self.sample_buffers[3].samples[offset] = self.play_noise(cycles);
+        self.sample_buffers[5].samples[offset] = self.play_expansion(cycles);
+        self.sample_buffer_offset += 1;
     }
 
     //
     // Channel playback
     //
 
-    fn get_or_zero_sample_buffer(
-        buffer: &mut [i16],
-        offset: usize,
-        audible: bool,
-    ) -> Option<&mut [i16]> {
-        let buffer = &mut buffer[offset..offset + NES_SAMPLES_PER_TICK as usize];
-        if audible {
-            return Some(buffer);
-        }
-
-        for dest in buffer.iter_mut() {
-            *dest = 0;
-        }
-        None
+    // How many of the remaining cycles a timer with the given period runs before it expires.
+    // Writes can leave the count past a shortened period, which expires on the next cycle.
+    fn timer_run(period: u64, count: u64, remaining: u64) -> u64 {
+        cmp::min(cmp::max(period.saturating_sub(count), 1), remaining)
     }
 
-    fn play_pulse(&mut self, pulse_number: usize, channel: usize) {
+    fn play_pulse(&mut self, pulse_number: usize, cycles: u64) -> i16 {
         let pulse = &mut self.regs.pulses[pulse_number];
-        let audible = pulse.envelope.audible() && pulse.timer.audible();
-        let buffer_opt = Apu::get_or_zero_sample_buffer(
-            &mut self.sample_buffers[channel].samples,
-            self.sample_buffer_offset,
-            audible,
-        );
-        match buffer_opt {
-            None => {}
-            Some(buffer) => {
-                // Process sound.
-                // TODO: Vectorize this for speed.
-                let volume = pulse.envelope.sample_volume();
-                let wavelen = pulse.timer.wavelen();
-                let waveform = PULSE_WAVEFORMS[pulse.duty as usize];
-                let mut waveform_index = pulse.waveform_index;
-                let mut wavelen_count = pulse.timer.wavelen_count;
-
-                for dest in buffer.iter_mut() {
-                    wavelen_count += 1;
-                    if wavelen_count >= wavelen {
-                        wavelen_count = 0;
-                        waveform_index = (waveform_index + 1) % 8;
-                    }
-
-                    *dest = if ((waveform >> (7 - waveform_index) as usize) & 1) != 0 {
-                        volume
-                    } else {
-                        0
-                    };
-                }
+        if !pulse.audible() {
+            return 0;
+        }
+
+        let volume = pulse.envelope.sample_volume() as u64;
+        let wavelen = pulse.timer.wavelen();
+        let waveform = PULSE_WAVEFORMS[pulse.duty as usize];
+        let mut high_cycles = 0;
+        let mut remaining = cycles;
+        while remaining > 0 {
+            let run = Apu::timer_run(wavelen, pulse.timer.wavelen_count, remaining);
+            if ((waveform >> (7 - pulse.waveform_index) as usize) & 1) != 0 {
+                high_cycles += run;
+            }
+            remaining -= run;
 
-                pulse.waveform_index = waveform_index;
-                pulse.timer.wavelen_count = wavelen_count;
+            pulse.timer.wavelen_count += run;
+            if pulse.timer.wavelen_count >= wavelen {
+                pulse.timer.wavelen_count = 0;
+                pulse.waveform_index = (pulse.waveform_index + 1) % 8;
             }
         }
+        (volume * high_cycles / cycles) as i16
     }
 
-    fn play_triangle(&mut self, channel: usize) {
+    fn play_triangle(&mut self, cycles: u64) -> i16 {
         let triangle = &mut self.regs.triangle;
-        let buffer_opt = Apu::get_or_zero_sample_buffer(
-            &mut self.sample_buffers[channel].samples,
-            self.sample_buffer_offset,
-            triangle.audible(),
-        );
-        match buffer_opt {
-            None => {}
-            Some(buffer) => {
-                let wavelen = triangle.timer.wavelen() / 2;
-                let mut waveform_index = triangle.waveform_index;
-                let mut wavelen_count = triangle.timer.wavelen_count;
-
-                for dest in buffer.iter_mut() {
-                    wavelen_count += 1;
-                    if wavelen_count >= wavelen {
-                        wavelen_count = 0;
-                        waveform_index = (waveform_index + 1) % 32;
-                    }
-
-                    // FIXME: Factor out this calculation.
-                    *dest = (TRIANGLE_WAVEFORM[waveform_index as usize] as i16 * 4) << 8;
-                }
+        if !triangle.audible() {
+            return 0;
+        }
 
-                triangle.waveform_index = waveform_index;
-                triangle.timer.wavelen_count = wavelen_count;
+        let wavelen = triangle.timer.wavelen() / 2;
+        let mut level_cycles = 0;
+        let mut remaining = cycles;
+        while remaining > 0 {
+            let run = Apu::timer_run(wavelen, triangle.timer.wavelen_count, remaining);
+            level_cycles += TRIANGLE_WAVEFORM[triangle.waveform_index as usize] as u64 * run;
+            remaining -= run;
+
+            triangle.timer.wavelen_count += run;
+            if triangle.timer.wavelen_count >= wavelen {
+                triangle.timer.wavelen_count = 0;
+                triangle.waveform_index = (triangle.waveform_index + 1) % 32;
             }
         }
+        ((level_cycles << LEVEL_SHIFT) / cycles) as i16
     }
 
-    fn play_noise(&mut self, channel: usize) {
+    fn play_noise(&mut self, cycles: u64) -> i16 {
         let noise = &mut self.regs.noise;
-        let buffer_opt = Apu::get_or_zero_sample_buffer(
-            &mut self.sample_buffers[channel].samples,
-            self.sample_buffer_offset,
-            noise.envelope.audible(),
-        );
-        match buffer_opt {
-            None => {}
-            Some(buffer) => {
-                let volume = noise.envelope.sample_volume();
-                let timer = noise.timer;
-                let mut timer_count = noise.timer_count;
-                let mut rng = noise.rng;
-                let mut on = 1;
-
-                for dest in buffer.iter_mut() {
-                    timer_count += 1;
-                    if timer_count >= timer {
-                        timer_count = 0;
-                        on = rng.next() & 1;
-                    }
-
-                    *dest = if on == 0 { 0 } else { volume };
-                }
+        if !noise.envelope.audible() {
+            return 0;
+        }
+
+        let volume = noise.envelope.sample_volume() as u64;
+        let timer = noise.timer as u64;
+        let mut timer_count = noise.timer_count as u64;
+        let mut on_cycles = 0;
+        let mut remaining = cycles;
+        while remaining > 0 {
+            let run = Apu::timer_run(timer, timer_count, remaining);
+            if (noise.shift & 1) == 0 {
+                on_cycles += run;
+            }
+            remaining -= run;
 
-                noise.timer_count = timer_count;
-                noise.rng = rng;
+            timer_count += run;
+            if timer_count >= timer {
+                timer_count = 0;
+                noise.clock_shift();
             }
         }
+        noise.timer_count = timer_count as u16;
+        (volume * on_cycles / cycles) as i16
+    }
+
+    fn play_expansion(&mut self, cycles: u64) -> i16 {
+        let samples = &mut self.expansion_samples[..cycles as usize];
+        if !self.mapper.borrow_mut().expansion_audio(samples) {
+            return 0;
+        }
+        let sum: i64 = samples.iter().map(|&sample| sample as i64).sum();
+        (sum / cycles as i64) as i16
     }
 
-    // Resamples and flushes channel buffers to the audio output device if necessary.
-    pub fn play_channels(&mut self) {
-        let sample_buffer_length = self.sample_buffers[0].samples.len();
+    // Mixes and queues channel buffers for the audio output device once they fill up. Returns
+    // the mixed samples if it did, whether or not there is an output device.
+    pub fn play_channels(&mut self) -> Option<&[i16]> {
+        let sample_buffer_length = SAMPLE_COUNT;
         if self.sample_buffer_offset < sample_buffer_length {
-            return;
+            return None;
         }
         self.sample_buffer_offset = 0;
 
-        // First, mix all sample buffers into the first one.
-        //
-        // FIXME: This should not be a linear mix, for accuracy.
-        for i in 0..self.sample_buffers[0].samples.len() {
-            let mut val = 0;
-            for j in 0..5 {
-                val += self.sample_buffers[j].samples[i] as i32;
+        // First, mix all sample buffers into the first one. The console's own channels go through
+        // its mixer, and the expansion audio, which cartridges mix in outside it, is added after.
+        let mut audible = [false; 6];
+        for (j, audible) in audible.iter_mut().enumerate() {
+            *audible = match self.solo {
+                Some(solo) => j == solo.sample_buffer(),
+                None => !self.muted[j],
+            };
+        }
+        for i in 0..sample_buffer_length {
+            let mut samples = [0; 6];
+            for j in 0..6 {
+                if audible[j] {
+                    samples[j] = self.sample_buffers[j].samples[i];
+                }
             }
+            let mut val = self
+                .mixer
+                .mix(samples[0], samples[1], samples[2], samples[3], samples[4]);
+            val += samples[5] as i32;
+
+            if self.filtered {
+                let mut filtered = val as f32;
+                for filter in self.filters.iter_mut() {
+                    filtered = filter.run(filtered);
+                }
+                val = filtered as i32;
+            }
+
+            self.sample_buffers[0].samples[i] = val.clamp(-32768, 32767) as i16;
+        }
 
-            if val > 32767 {
-                val = 32767;
-            } else if val < -32768 {
-                val = -32768;
+        let result = match self.recorder {
+            Some(ref mut recorder) => {
+                recorder.write(&self.sample_buffers[0].samples[..sample_buffer_length])
             }
+            None => Ok(()),
+        };
+        if let Err(err) = result {
+            println!("Stopped recording: {}", err);
+            // Finish what was recorded, so that it isn't lost.
+            if let Some(recorder) = self.recorder.take() {
+                if let Err(err) = recorder.finish() {
+                    println!("Couldn't finish the recording: {}", err);
+                }
+            }
+        }
 
-            self.sample_buffers[0].samples[i] = val as i16;
+        // Wait for the device to make room for the samples. This is what keeps the emulator
+        // running at the speed the audio plays.
+        match self.output {
+            Some(ref output) if !self.fast_forward => {
+                let mixed = &self.sample_buffers[0].samples[..sample_buffer_length];
+                let mut pushed = output.push(mixed);
+                while pushed < mixed.len() {
+                    thread::sleep(Duration::from_millis(1));
+                    pushed += output.push(&mixed[pushed..]);
+                }
+            }
+            _ => {}
         }
+        Some(&self.sample_buffers[0].samples[..sample_buffer_length])
+    }
+}
 
-        if self.output_buffer.is_none() {
-            return;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mapper::Nrom;
+    use rom::Rom;
+
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::{Seek, SeekFrom};
+
+    // An NTSC APU with an NROM cartridge and no audio output.
+    fn apu() -> Apu {
+        let mut image = b"NES\x1a\x01\x01".to_vec();
+        image.resize(16 + 0x4000 + 0x2000, 0);
+        let rom = Box::new(Rom::from_bytes(&image).unwrap());
+        let mapper: Box<Mapper + Send> = Box::new(Nrom::new(rom));
+        Apu::new(None, Rc::new(RefCell::new(mapper)), Region::Ntsc)
+    }
+
+    #[test]
+    fn disabled_channels_ignore_length_writes() {
+        let mut apu = apu();
+        apu.storeb(0x4015, 0x01);
+        apu.storeb(0x4003, 0x08);
+        apu.storeb(0x4007, 0x08);
+        apu.storeb(0x400b, 0x08);
+        apu.storeb(0x400f, 0x08);
+        assert_eq!(apu.loadb(0x4015) & 0x0f, 0x01);
+
+        // Disabling a channel also clears its counter.
+        apu.storeb(0x4015, 0x00);
+        assert_eq!(apu.loadb(0x4015) & 0x0f, 0x00);
+    }
+
+    #[test]
+    fn mixer_tables_match_formulas_at_ends() {
+        let mixer = Mixer::new();
+        assert_eq!(mixer.pulse[0], 0);
+        assert_eq!(mixer.tnd[0], 0);
+        // 95.52 / (8128 / 30 + 100) and 163.67 / (24329 / 202 + 100), scaled to 16 bits
+        assert_eq!(mixer.pulse[30], 8437);
+        assert_eq!(mixer.tnd[202], 24328);
+
+        let full = 15 << LEVEL_SHIFT;
+        assert_eq!(mixer.mix(0, 0, 0, 0, 0), 0);
+        // Full levels land exactly on the last pulse entry, without interpolating past it.
+        assert_eq!(
+            mixer.mix(full, full, full, full, 0),
+            8437 + mixer.tnd[75] as i32
+        );
+    }
+
+    #[test]
+    fn pulse_is_muted_below_period_8() {
+        let mut pulse = ApuPulse::new(false);
+        pulse.timer.value = 7;
+        assert!(pulse.muted());
+        pulse.timer.value = 8;
+        assert!(!pulse.muted());
+
+        // A sweep target past the 11-bit timer mutes the channel even with the sweep disabled.
+        pulse.timer.value = 0x400;
+        *pulse.sweep = 0x00;
+        assert!(pulse.muted());
+        *pulse.sweep = 0x08;
+        assert!(!pulse.muted());
+    }
+
+    #[test]
+    fn noise_short_mode_repeats_every_93_steps() {
+        let mut noise = ApuNoise::new();
+        noise.short_mode = true;
+        for _ in 0..93 {
+            noise.clock_shift();
         }
-        let output_buffer = self.output_buffer.unwrap();
+        assert_eq!(noise.shift, 1);
 
-        // Wait for the audio callback to catch up if necessary.
+        let mut steps = 0;
         loop {
-            unsafe {
-                let lock = audio::AUDIO_MUTEX.lock().unwrap();
-                let _lock = audio::AUDIO_CONDVAR.wait(lock).unwrap();
-                if (*output_buffer).play_offset == (*output_buffer).samples.len() {
-                    break;
-                }
+            noise.clock_shift();
+            steps += 1;
+            if noise.shift == 1 {
+                break;
             }
         }
-        let _lock = audio::lock();
-        unsafe {
-            // Resample and output the audio.
-            let _ = self.resampler.process(
-                0,
-                &mut self.sample_buffers[0].samples,
-                &mut (*output_buffer).samples,
-            );
-            (*output_buffer).play_offset = 0;
+        assert_eq!(steps, 93);
+
+        noise.short_mode = false;
+        steps = 0;
+        loop {
+            noise.clock_shift();
+            steps += 1;
+            if noise.shift == 1 {
+                break;
+            }
         }
+        assert_eq!(steps, 32767);
+    }
+
+    #[test]
+    fn envelope_decays_and_loops() {
+        let mut envelope = ApuEnvelope::new();
+        // Divider period 1, so the decay level drops every other quarter frame.
+        envelope.storeb(0x400c, 0x01);
+        envelope.storeb(0x400f, 0x00);
+        envelope.tick();
+        assert_eq!(envelope.output(), 15);
+        for _ in 0..30 {
+            envelope.tick();
+        }
+        assert_eq!(envelope.output(), 0);
+        envelope.tick();
+        envelope.tick();
+        assert_eq!(envelope.output(), 0);
+
+        envelope.storeb(0x400c, 0x21);
+        envelope.tick();
+        envelope.tick();
+        assert_eq!(envelope.output(), 15);
+
+        // Constant volume ignores the decay.
+        envelope.storeb(0x400c, 0x1a);
+        assert_eq!(envelope.output(), 10);
+    }
+
+    #[test]
+    fn mixer_settings_and_filters_survive_a_savestate() {
+        let path = env::temp_dir().join("sprocketnes-apu-state-test");
+        let mut fd = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        let mut saved = apu();
+        saved.set_solo(Some(Channel::Triangle));
+        saved.set_muted(Channel::Noise, true);
+        saved.set_filtered(false);
+        saved.filters[0].run(1000.0);
+        saved.save(&mut fd);
+
+        let mut apu = apu();
+        fd.seek(SeekFrom::Start(0)).unwrap();
+        apu.load(&mut fd);
+        let _ = fs::remove_file(&path);
+        assert_eq!(apu.solo(), Some(Channel::Triangle));
+        assert!(apu.muted(Channel::Noise));
+        assert!(!apu.muted(Channel::Pulse1));
+        assert!(!apu.filtered());
+        assert_eq!(apu.filters[0].prev_input, 1000.0);
+        assert_eq!(apu.filters[0].prev_output, saved.filters[0].prev_output);
+    }
+
+    #[test]
+    fn stalls_accumulate_until_taken() {
+        let mut apu = apu();
+        apu.request_stall(4);
+        apu.request_stall(3);
+        assert_eq!(apu.take_stall(), 7);
+        assert_eq!(apu.take_stall(), 0);
     }
 }
@@ -4,115 +4,445 @@
 // Author: Patrick Walton
 //
 
-// TODO: This module is very unsafe. Adding a reader-writer audio lock to SDL would help make it
-// safe.
-
-use sdl2::audio::{AudioCallback, AudioDevice, AudioDeviceLockGuard, AudioSpecDesired};
-use sdl2::Sdl;
+use apu::OUTPUT_SAMPLE_RATE;
+use sdl2::audio::{AudioCallback, AudioDevice, AudioQueue, AudioSpecDesired};
+use sdl2::{get_error, Sdl};
+use std::cell::Cell;
 use std::cmp;
-use std::mem;
-use std::slice::from_raw_parts_mut;
-use std::sync::{Condvar, Mutex};
+use std::sync::atomic::{AtomicI16, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// How many samples the device asks for at once: a tenth of a second.
+const DEVICE_SAMPLES: u16 = 4410;
+
+/// How many samples can be queued for the device: enough for the callback to always find a full
+/// request waiting while the APU mixes the next one.
+const QUEUE_LEN: usize = DEVICE_SAMPLES as usize * 2;
 
 //
-// The audio callback
+// The sample queue
 //
 
-const SAMPLE_COUNT: usize = 4410 * 2;
+/// A bounded ring buffer of samples with one producer, the APU on the emulator thread, and one
+/// consumer, the audio callback on SDL's thread. Neither side ever blocks the other.
+pub struct SampleQueue {
+    samples: Vec<AtomicI16>,
+    /// How many samples have ever been popped and pushed. The difference is how many are queued.
+    read: AtomicUsize,
+    write: AtomicUsize,
+    /// How many pops found fewer samples than they wanted, once anything had been pushed.
+    underruns: AtomicUsize,
+}
 
-static mut G_AUDIO_DEVICE: Option<*mut AudioDevice<NesAudioCallback>> = None;
+impl SampleQueue {
+    pub fn new(capacity: usize) -> SampleQueue {
+        SampleQueue {
+            samples: (0..capacity).map(|_| AtomicI16::new(0)).collect(),
+            read: AtomicUsize::new(0),
+            write: AtomicUsize::new(0),
+            underruns: AtomicUsize::new(0),
+        }
+    }
 
-static mut G_OUTPUT_BUFFER: Option<*mut OutputBuffer> = None;
+    /// How many samples are queued.
+    pub fn len(&self) -> usize {
+        let write = self.write.load(Ordering::Acquire);
+        let read = self.read.load(Ordering::Acquire);
+        write.wrapping_sub(read)
+    }
 
-lazy_static! {
-    pub static ref AUDIO_MUTEX: Mutex<()> = Mutex::new(());
-    pub static ref AUDIO_CONDVAR: Condvar = Condvar::new();
-}
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn stats(&self) -> AudioStats {
+        AudioStats {
+            queued: self.len(),
+            capacity: self.samples.len(),
+            underruns: self.underruns.load(Ordering::Relaxed),
+        }
+    }
 
-pub struct OutputBuffer {
-    pub samples: [u8; SAMPLE_COUNT],
-    pub play_offset: usize,
+    /// Queues as many of the samples as there is room for, and returns how many that was.
+    pub fn push(&self, samples: &[i16]) -> usize {
+        let read = self.read.load(Ordering::Acquire);
+        let write = self.write.load(Ordering::Relaxed);
+        let room = self.samples.len() - write.wrapping_sub(read);
+        let count = cmp::min(samples.len(), room);
+        for (i, &sample) in samples[..count].iter().enumerate() {
+            let index = write.wrapping_add(i) % self.samples.len();
+            self.samples[index].store(sample, Ordering::Relaxed);
+        }
+        self.write
+            .store(write.wrapping_add(count), Ordering::Release);
+        count
+    }
+
+    /// Fills the start of `samples` with as many queued samples as there are, and returns how
+    /// many that was.
+    pub fn pop(&self, samples: &mut [i16]) -> usize {
+        let write = self.write.load(Ordering::Acquire);
+        let read = self.read.load(Ordering::Relaxed);
+        let count = cmp::min(samples.len(), write.wrapping_sub(read));
+        if count < samples.len() && write != 0 {
+            self.underruns.fetch_add(1, Ordering::Relaxed);
+        }
+        for (i, dest) in samples[..count].iter_mut().enumerate() {
+            let index = read.wrapping_add(i) % self.samples.len();
+            *dest = self.samples[index].load(Ordering::Relaxed);
+        }
+        self.read.store(read.wrapping_add(count), Ordering::Release);
+        count
+    }
 }
 
-pub struct NesAudioCallback;
+//
+// The audio callback
+//
+
+pub struct NesAudioCallback {
+    queue: Arc<SampleQueue>,
+    /// The last sample played. If the queue runs dry, this is held rather than dropping to zero,
+    /// which would click.
+    last_sample: i16,
+}
 
 impl AudioCallback for NesAudioCallback {
     type Channel = i16;
 
     fn callback(&mut self, buf: &mut [Self::Channel]) {
-        unsafe {
-            let samples: &mut [u8] =
-                from_raw_parts_mut(&mut buf[0] as *mut i16 as *mut u8, buf.len() * 2);
-            let output_buffer: &mut OutputBuffer = mem::transmute(G_OUTPUT_BUFFER.unwrap());
-            let play_offset = output_buffer.play_offset;
-            let output_buffer_len = output_buffer.samples.len();
-
-            for i in 0..samples.len() {
-                if i + play_offset >= output_buffer_len {
-                    break;
-                }
-                samples[i] = output_buffer.samples[i + play_offset];
-            }
+        let count = self.queue.pop(buf);
+        if count > 0 {
+            self.last_sample = buf[count - 1];
+        }
+        for dest in buf[count..].iter_mut() {
+            *dest = self.last_sample;
+        }
+    }
+}
 
-            let _ = AUDIO_MUTEX.lock();
-            output_buffer.play_offset = cmp::min(play_offset + samples.len(), output_buffer_len);
-            AUDIO_CONDVAR.notify_one();
+//
+// Telemetry
+//
+
+/// How well the output is keeping up with the device. Underruns are heard as crackling; a queue
+/// that stays full is heard as lag.
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct AudioStats {
+    /// How many samples are waiting to be played.
+    pub queued: usize,
+    /// How many samples can wait at most.
+    pub capacity: usize,
+    /// How many times the device wanted samples and found too few since audio started.
+    pub underruns: usize,
+}
+
+impl AudioStats {
+    /// How full the queue is, from 0 to 1.
+    pub fn fill(&self) -> f64 {
+        if self.capacity == 0 {
+            0.0
+        } else {
+            self.queued as f64 / self.capacity as f64
         }
     }
+
+    /// How long a sample pushed now waits before the device takes it, in milliseconds. The
+    /// device's own buffer adds to this.
+    pub fn latency_ms(&self) -> f64 {
+        self.queued as f64 * 1000.0 / OUTPUT_SAMPLE_RATE as f64
+    }
 }
 
-/// Audio initialization. If successful, returns a pointer to an allocated `OutputBuffer` that can
-/// be filled with raw audio data.
-pub fn open(sdl: &Sdl) -> Option<*mut OutputBuffer> {
-    let output_buffer = Box::new(OutputBuffer {
-        samples: [0; SAMPLE_COUNT],
-        play_offset: 0,
-    });
-    let output_buffer_ptr: *mut OutputBuffer = unsafe { mem::transmute(&*output_buffer) };
+/// Somewhere to play the mixed output.
+pub trait AudioSink {
+    /// Queues as many of the samples as the device has room for, and returns how many that was.
+    fn push(&self, samples: &[i16]) -> usize;
 
-    unsafe {
-        G_OUTPUT_BUFFER = Some(output_buffer_ptr);
-        mem::forget(output_buffer);
+    fn stats(&self) -> AudioStats;
+}
+
+/// How samples get to the device.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum AudioBackend {
+    /// SDL calls back on its own thread for samples, which come from a `SampleQueue`.
+    Callback,
+    /// Samples are pushed onto SDL's own queue with `SDL_QueueAudio`, and there's no callback.
+    Queue,
+    /// cpal's default output device, for systems where SDL audio is missing or unwanted.
+    #[cfg(feature = "cpal")]
+    Cpal,
+    /// No device at all: the audio is mixed and thrown away, for CI and benchmarks.
+    Null,
+}
+
+//
+// Null
+//
+
+/// Takes every sample and plays none, so the APU never waits for room.
+pub struct NullAudio;
+
+impl AudioSink for NullAudio {
+    fn push(&self, samples: &[i16]) -> usize {
+        samples.len()
+    }
+
+    fn stats(&self) -> AudioStats {
+        AudioStats::default()
     }
+}
 
+//
+// SDL
+//
+
+/// An open SDL audio device, which plays until this is dropped.
+pub enum SdlOutput {
+    Callback(AudioDevice<NesAudioCallback>, Arc<SampleQueue>),
+    Queue {
+        queue: AudioQueue<i16>,
+        /// SDL doesn't report underruns, so they're counted when a push finds the queue empty
+        /// after the first.
+        started: Cell<bool>,
+        underruns: Cell<usize>,
+    },
+}
+
+impl AudioSink for SdlOutput {
+    fn push(&self, samples: &[i16]) -> usize {
+        match *self {
+            SdlOutput::Callback(_, ref queue) => queue.push(samples),
+            SdlOutput::Queue {
+                ref queue,
+                ref started,
+                ref underruns,
+            } => {
+                let queued = queue.size() as usize / 2;
+                if queued == 0 && started.get() {
+                    underruns.set(underruns.get() + 1);
+                }
+                started.set(true);
+                let count = cmp::min(samples.len(), QUEUE_LEN.saturating_sub(queued));
+                // If SDL won't take the samples they're dropped, rather than retried forever.
+                if count > 0 && !queue.queue(&samples[..count]) {
+                    println!("Error queueing audio: {}", get_error());
+                }
+                count
+            }
+        }
+    }
+
+    fn stats(&self) -> AudioStats {
+        match *self {
+            SdlOutput::Callback(_, ref queue) => queue.stats(),
+            SdlOutput::Queue {
+                ref queue,
+                ref underruns,
+                ..
+            } => AudioStats {
+                queued: queue.size() as usize / 2,
+                capacity: QUEUE_LEN,
+                underruns: underruns.get(),
+            },
+        }
+    }
+}
+
+fn open_sdl(sdl: &Sdl, backend: AudioBackend) -> Result<SdlOutput, String> {
     let spec = AudioSpecDesired {
         freq: Some(44100),
         channels: Some(1),
-        samples: Some(4410),
+        samples: Some(DEVICE_SAMPLES),
     };
 
-    let audio_subsystem = sdl.audio().unwrap();
-    unsafe {
-        match audio_subsystem.open_playback(None, &spec, |_| NesAudioCallback) {
-            Ok(device) => {
-                device.resume();
-                G_AUDIO_DEVICE = Some(mem::transmute(Box::new(device)));
-                return Some(output_buffer_ptr);
-            }
-            Err(e) => {
-                println!("Error initializing AudioDevice: {}", e);
-                return None;
-            }
-        }
+    let audio_subsystem = sdl.audio()?;
+    if backend == AudioBackend::Queue {
+        let queue = audio_subsystem.open_queue(None, &spec)?;
+        queue.resume();
+        return Ok(SdlOutput::Queue {
+            queue: queue,
+            started: Cell::new(false),
+            underruns: Cell::new(0),
+        });
     }
+
+    let queue = Arc::new(SampleQueue::new(QUEUE_LEN));
+    let callback = NesAudioCallback {
+        queue: queue.clone(),
+        last_sample: 0,
+    };
+    let device = audio_subsystem.open_playback(None, &spec, |_| callback)?;
+    device.resume();
+    Ok(SdlOutput::Callback(device, queue))
 }
 
 //
-// Audio tear-down
+// cpal
 //
 
-pub fn close() {
-    unsafe {
-        match G_AUDIO_DEVICE {
-            None => {}
-            Some(ptr) => {
-                let _: Box<AudioDevice<NesAudioCallback>> = mem::transmute(ptr);
-                G_AUDIO_DEVICE = None;
+/// A cpal output stream, which plays until this is dropped.
+#[cfg(feature = "cpal")]
+pub struct CpalOutput {
+    _stream: cpal::Stream,
+    queue: Arc<SampleQueue>,
+}
+
+#[cfg(feature = "cpal")]
+impl AudioSink for CpalOutput {
+    fn push(&self, samples: &[i16]) -> usize {
+        self.queue.push(samples)
+    }
+
+    fn stats(&self) -> AudioStats {
+        self.queue.stats()
+    }
+}
+
+/// Converts the output rate to a device's own rate by interpolating between samples, for devices
+/// that can't play at the output rate.
+#[cfg(feature = "cpal")]
+struct Resampler {
+    /// How far through the output samples each device sample moves.
+    step: f64,
+    /// How far the next device sample is between `prev` and `next`, from 0 to 1.
+    position: f64,
+    prev: i16,
+    next: i16,
+    source: Vec<i16>,
+}
+
+#[cfg(feature = "cpal")]
+impl Resampler {
+    fn new(device_rate: u32) -> Resampler {
+        Resampler {
+            step: OUTPUT_SAMPLE_RATE as f64 / device_rate as f64,
+            position: 0.0,
+            prev: 0,
+            next: 0,
+            source: vec![],
+        }
+    }
+
+    // Fills `samples` at the device rate, taking exactly as many samples from the callback as
+    // the interpolation moves past.
+    fn fill(&mut self, callback: &mut NesAudioCallback, samples: &mut [i16]) {
+        if samples.is_empty() {
+            return;
+        }
+        let taken = (self.position + (samples.len() - 1) as f64 * self.step) as usize;
+        self.source.resize(taken, 0);
+        callback.callback(&mut self.source);
+
+        let mut source = self.source.iter();
+        for dest in samples.iter_mut() {
+            while self.position >= 1.0 {
+                self.prev = self.next;
+                self.next = source.next().cloned().unwrap_or(self.next);
+                self.position -= 1.0;
             }
+            let (prev, next) = (self.prev as f64, self.next as f64);
+            *dest = (prev + (next - prev) * self.position) as i16;
+            self.position += self.step;
         }
     }
 }
 
-pub fn lock<'a>() -> Option<AudioDeviceLockGuard<'a, NesAudioCallback>> {
-    unsafe { G_AUDIO_DEVICE.map(|dev| (*dev).lock()) }
+// Builds a stream of the device's own sample format, channel count and sample rate. Each output
+// sample goes to every channel.
+#[cfg(feature = "cpal")]
+fn build_cpal_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    queue: Arc<SampleQueue>,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: cpal::SizedSample + cpal::FromSample<i16>,
+{
+    use cpal::traits::DeviceTrait;
+
+    let channels = config.channels as usize;
+    let mut callback = NesAudioCallback {
+        queue: queue,
+        last_sample: 0,
+    };
+    let mut resampler = Resampler::new(config.sample_rate.0);
+    let mut samples = vec![];
+    device.build_output_stream(
+        config,
+        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            samples.resize(data.len() / channels, 0);
+            resampler.fill(&mut callback, &mut samples);
+            for (frame, &sample) in data.chunks_mut(channels).zip(samples.iter()) {
+                for dest in frame.iter_mut() {
+                    *dest = T::from_sample(sample);
+                }
+            }
+        },
+        |e| println!("Error playing audio: {}", e),
+        None,
+    )
+}
+
+#[cfg(feature = "cpal")]
+fn open_cpal() -> Result<CpalOutput, String> {
+    use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+    use cpal::SampleFormat;
+
+    let device = match cpal::default_host().default_output_device() {
+        Some(device) => device,
+        None => return Err("no output device".to_string()),
+    };
+    // Play at the output rate if the device can, in its default sample format. Otherwise the
+    // samples are resampled to the device's default rate.
+    let default = device.default_output_config().map_err(|e| e.to_string())?;
+    let rate = cpal::SampleRate(OUTPUT_SAMPLE_RATE);
+    let at_output_rate = device
+        .supported_output_configs()
+        .ok()
+        .and_then(|mut configs| {
+            configs.find(|range| {
+                range.sample_format() == default.sample_format()
+                    && range.min_sample_rate() <= rate
+                    && range.max_sample_rate() >= rate
+            })
+        });
+    let supported = match at_output_rate {
+        Some(range) => range.with_sample_rate(rate),
+        None => default,
+    };
+    let config = supported.config();
+
+    let queue = Arc::new(SampleQueue::new(QUEUE_LEN));
+    let stream = match supported.sample_format() {
+        SampleFormat::I16 => build_cpal_stream::<i16>(&device, &config, queue.clone()),
+        SampleFormat::U16 => build_cpal_stream::<u16>(&device, &config, queue.clone()),
+        SampleFormat::F32 => build_cpal_stream::<f32>(&device, &config, queue.clone()),
+        format => return Err(format!("unsupported sample format {}", format)),
+    };
+    let stream = stream.map_err(|e| e.to_string())?;
+    stream.play().map_err(|e| e.to_string())?;
+    Ok(CpalOutput {
+        _stream: stream,
+        queue: queue,
+    })
+}
+
+/// Audio initialization. Returns `None`, after reporting why, if there's no device to play on.
+pub fn open(sdl: &Sdl, backend: AudioBackend) -> Option<Box<AudioSink>> {
+    let output = match backend {
+        AudioBackend::Callback | AudioBackend::Queue => {
+            open_sdl(sdl, backend).map(|output| Box::new(output) as Box<AudioSink>)
+        }
+        #[cfg(feature = "cpal")]
+        AudioBackend::Cpal => open_cpal().map(|output| Box::new(output) as Box<AudioSink>),
+        AudioBackend::Null => Ok(Box::new(NullAudio) as Box<AudioSink>),
+    };
+    match output {
+        Ok(output) => Some(output),
+        Err(e) => {
+            println!("Error initializing audio output: {}", e);
+            None
+        }
+    }
 }
@@ -4,42 +4,143 @@
 
 extern crate nes;
 
-use nes::gfx::Scale;
+use nes::config::{self, Config};
+use nes::mapper::MapperRegistry;
+use nes::patch;
 use nes::rom::Rom;
+use nes::EmulatorOptions;
 
 use std::env;
-use std::fs::File;
 use std::path::Path;
+use std::process;
 
 struct Options {
     rom_path: String,
-    scale: Scale,
+    /// The settings given on the command line, which override all others.
+    settings: Config,
 }
 
 fn usage() {
     println!("usage: sprocketnes [options] <path-to-rom>");
     println!("options:");
-    println!("    -1 scale by 1x (default)");
-    println!("    -2 scale by 2x");
-    println!("    -3 scale by 3x");
+    println!("    --scale <n> open the window n times the picture's size (default 1)");
+    println!("    --aspect <square|8:7> the shape of the pixels (default square)");
+    println!(
+        "    --integer-scaling <on|off> only scale the picture by whole numbers (default off)"
+    );
+    println!("    -1, -2, -3 same as --scale 1, 2 or 3");
+    println!("    --upscaler <none|scale2x|scale3x|hq2x|2xbr> smooth the screen as it's scaled up");
+    println!("    --crt <on|off> draw the screen like a CRT television (default off)");
+    println!("    --scanlines <on|off> darken the gaps between scanlines (default off)");
+    println!("    --scanline-intensity <percent> how much the scanlines darken (default 50)");
+    println!("    --vsync <on|off> wait for the monitor's refresh to show frames (default on)");
+    println!("    --no-vsync same as --vsync off");
+    println!("    --palette <path> draw with the colors in a .pal file");
+    println!("    --region <ntsc|pal|dendy> run as this console (default from the header)");
+    println!("    --audio-filters <on|off> filter the audio like the console does (default on)");
+    println!("    --no-audio-filters same as --audio-filters off");
+    println!(
+        "    --audio-backend <callback|queue|cpal|null> how audio is played (default callback)"
+    );
+    println!("        cpal only works in builds with the cpal feature");
+    println!("    --no-audio same as --audio-backend null, for running without an audio device");
+    println!("    --sprite-limit <on|off> limit sprites to 8 per scanline (default on)");
+    println!("    --no-sprite-limit same as --sprite-limit off");
+    println!("    --fast-boot run the PPU warm-up before showing the window");
+    println!("    --import-fceux <path> start from an FCEUX savestate (.fc0-.fc9)");
+    println!("    --save-dir <path> where to keep savestates (default .) and battery saves");
+    println!("    --no-softpatch don't apply the .ips or .bps patch next to the ROM");
+    println!("    --cycle-stepped run the CPU a cycle at a time (slower, more accurate)");
+    println!("    --dot-stepped-ppu run the PPU a dot at a time (slower, more accurate)");
+    println!("    --break <addr,...> pause in the debugger when the CPU reaches these addresses");
+    println!("    --trace <path> log every instruction to a file, nestest.log style");
+    println!("    --trace-range <start-end> only log instructions in this address range");
+    println!("    --record <path> record the audio to a .wav file");
+    println!("    --start-pc <addr> start at this address instead of resetting");
+    println!("    --rumble <triggers> rumble controllers on RAM writes (see the README)");
+    println!();
+    println!("Each setting can also be given in the ROM's .cfg file or, with lower");
+    println!("precedence, in an environment variable such as SPROCKETNES_SCALE or");
+    println!("SPROCKETNES_SAVE_DIR.");
 }
 
 fn parse_args() -> Option<Options> {
     let mut options = Options {
         rom_path: String::new(),
-        scale: Scale::Scale1x,
+        settings: Config::new(),
     };
 
-    for arg in env::args().skip(1) {
-        match &*arg {
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        // Flags that take a value, and the setting they map to.
+        let key = match &*arg {
+            "--scale" => "scale",
+            "--aspect" => "aspect",
+            "--integer-scaling" => "integer_scaling",
+            "--upscaler" => "upscaler",
+            "--crt" => "crt",
+            "--scanlines" => "scanlines",
+            "--scanline-intensity" => "scanline_intensity",
+            "--vsync" => "vsync",
+            "--palette" => "palette",
+            "--region" => "region",
+            "--audio-filters" => "audio_filters",
+            "--audio-backend" => "audio_backend",
+            "--sprite-limit" => "sprite_limit",
+            "--import-fceux" => "fceux_state",
+            "--save-dir" => "save_dir",
+            "--break" => "breakpoints",
+            "--trace" => "trace",
+            "--trace-range" => "trace_range",
+            "--record" => "record",
+            "--start-pc" => "start_pc",
+            "--rumble" => "rumble",
+
+            // Older spellings, kept for compatibility.
             "-1" => {
-                options.scale = Scale::Scale1x;
+                options.settings.set("scale", "1");
+                continue;
             }
             "-2" => {
-                options.scale = Scale::Scale2x;
+                options.settings.set("scale", "2");
+                continue;
             }
             "-3" => {
-                options.scale = Scale::Scale3x;
+                options.settings.set("scale", "3");
+                continue;
+            }
+            "--no-sprite-limit" => {
+                options.settings.set("sprite_limit", "off");
+                continue;
+            }
+            "--no-audio-filters" => {
+                options.settings.set("audio_filters", "off");
+                continue;
+            }
+            "--no-vsync" => {
+                options.settings.set("vsync", "off");
+                continue;
+            }
+            "--no-audio" => {
+                options.settings.set("audio_backend", "null");
+                continue;
+            }
+
+            "--fast-boot" => {
+                options.settings.set("fast_boot", "on");
+                continue;
+            }
+            "--no-softpatch" => {
+                options.settings.set("softpatch", "off");
+                continue;
+            }
+            "--cycle-stepped" => {
+                options.settings.set("cycle_stepped", "on");
+                continue;
+            }
+            "--dot-stepped-ppu" => {
+                options.settings.set("dot_stepped_ppu", "on");
+                continue;
             }
             _ if arg.starts_with('-') => {
                 usage();
@@ -47,11 +148,20 @@ fn parse_args() -> Option<Options> {
             }
             _ => {
                 options.rom_path = arg;
+                continue;
+            }
+        };
+
+        match args.next() {
+            Some(value) => options.settings.set(key, &value),
+            None => {
+                usage();
+                return None;
             }
         }
     }
 
-    if options.rom_path.len() == 0 {
+    if options.rom_path.is_empty() {
         usage();
         return None;
     }
@@ -59,14 +169,71 @@ fn parse_args() -> Option<Options> {
     Some(options)
 }
 
+/// Combines the environment, the per-ROM configuration file and the command line, in increasing
+/// order of precedence.
+fn load_settings(options: &Options) -> Config {
+    let mut settings = Config::from_env(&nes::CONFIG_KEYS);
+
+    let path = config::rom_config_path(Path::new(&options.rom_path));
+    if path.exists() {
+        match Config::load(&path) {
+            Ok(rom_config) => settings.merge(&rom_config),
+            Err(err) => println!("Ignoring {}: {}", path.display(), err),
+        }
+    }
+
+    settings.merge(&options.settings);
+    settings
+}
+
 fn main() {
     let options = match parse_args() {
         Some(options) => options,
         None => return,
     };
+    let settings = load_settings(&options);
+    let mut emulator_options = match EmulatorOptions::from_config(&settings) {
+        Ok(emulator_options) => emulator_options,
+        Err(err) => {
+            println!("error: {}", err);
+            return;
+        }
+    };
 
     let rom_path = &options.rom_path;
-    let rom = Rom::load(&mut File::open(&Path::new(rom_path)).unwrap()).unwrap();
+    // Battery saves go next to the ROM, unless there's a directory for saves.
+    let battery_path = Path::new(rom_path).with_extension("sav");
+    emulator_options.battery_path =
+        Some(match (settings.get("save_dir"), battery_path.file_name()) {
+            (Some(dir), Some(name)) => Path::new(dir).join(name),
+            _ => battery_path.clone(),
+        });
+    emulator_options.screenshot_base = Some(Path::new(rom_path).with_extension(""));
+    let mut rom = match Rom::from_path(Path::new(rom_path)) {
+        Ok(rom) => rom,
+        Err(err) => {
+            eprintln!("error: couldn't load {}: {}", rom_path, err);
+            process::exit(1);
+        }
+    };
+    if emulator_options.softpatch {
+        if let Some(path) = patch::find_patch(Path::new(rom_path)) {
+            match patch::load(&rom, &path) {
+                Ok(patched) => {
+                    println!("Applied {}", path.display());
+                    rom = patched;
+                }
+                Err(err) => println!("Ignoring {}: {}", path.display(), err),
+            }
+        }
+    }
 
-    nes::start_emulator(rom, options.scale);
+    if let Err(err) = nes::start_emulator(rom, emulator_options) {
+        eprintln!("Sorry, this ROM can't be played: {}.", err);
+        eprintln!("Supported mappers:");
+        for (number, boards) in MapperRegistry::new().supported() {
+            eprintln!("    {:3} {}", number, boards);
+        }
+        process::exit(1);
+    }
 }
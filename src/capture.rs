@@ -0,0 +1,319 @@
+//! Clips of the screen, as animated `.gif` files.
+
+//
+// Author: Patrick Walton
+//
+
+use ppu::{PixelFormat, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Only every this many frames is kept, since GIF delays are too coarse for 60 fps.
+const FRAME_STEP: u64 = 2;
+
+/// LZW codes are at most this many bits, after which the dictionary starts over.
+const MAX_CODE_BITS: u32 = 12;
+
+//
+// Palette quantization
+//
+
+// Finds the colors of a picture, in the order they first appear, and each pixel's index among
+// them. If there are more than a GIF can hold, the low bits of each channel are dropped until
+// there aren't. The screen rarely has more than the PPU's 64 colors in any one frame.
+fn quantize(pixels: &[u8], format: PixelFormat) -> (Vec<[u8; 3]>, Vec<u8>) {
+    let bytes_per_pixel = format.bytes_per_pixel();
+    let mut mask = 0xff;
+    loop {
+        let mut colors = vec![];
+        let mut indices = HashMap::new();
+        let mut image = Vec::with_capacity(pixels.len() / bytes_per_pixel);
+        for pixel in pixels.chunks(bytes_per_pixel) {
+            let color = match format {
+                PixelFormat::Bgr24 => [pixel[2] & mask, pixel[1] & mask, pixel[0] & mask],
+                PixelFormat::Rgba32 => [pixel[0] & mask, pixel[1] & mask, pixel[2] & mask],
+            };
+            let index = *indices.entry(color).or_insert_with(|| {
+                colors.push(color);
+                colors.len() - 1
+            });
+            if index > 255 {
+                break;
+            }
+            image.push(index as u8);
+        }
+        if colors.len() <= 256 {
+            return (colors, image);
+        }
+        mask <<= 1;
+    }
+}
+
+//
+// LZW compression
+//
+
+// Packs codes of varying widths into bytes, least significant bit first.
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, code: u16, width: u32) {
+        self.bits |= (code as u32) << self.count;
+        self.count += width;
+        while self.count >= 8 {
+            self.bytes.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.bytes.push(self.bits as u8);
+        }
+        self.bytes
+    }
+}
+
+// Compresses color indices the way GIF's variant of LZW does, with codes starting one bit wider
+// than `min_code_bits`.
+fn compress(image: &[u8], min_code_bits: u32) -> Vec<u8> {
+    let clear = 1u16 << min_code_bits;
+    let end = clear + 1;
+    let mut out = BitWriter {
+        bytes: vec![],
+        bits: 0,
+        count: 0,
+    };
+    let mut dictionary: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut next_code = end + 1;
+    let mut width = min_code_bits + 1;
+
+    out.write(clear, width);
+    let mut prefix = match image.first() {
+        Some(&index) => index as u16,
+        None => {
+            out.write(end, width);
+            return out.finish();
+        }
+    };
+    for &index in image[1..].iter() {
+        if let Some(&code) = dictionary.get(&(prefix, index)) {
+            prefix = code;
+            continue;
+        }
+        out.write(prefix, width);
+        if next_code == 1 << MAX_CODE_BITS {
+            out.write(clear, width);
+            dictionary.clear();
+            next_code = end + 1;
+            width = min_code_bits + 1;
+        } else {
+            dictionary.insert((prefix, index), next_code);
+            // The decoder widens its codes as soon as it adds the code that needs it.
+            if next_code == 1 << width && width < MAX_CODE_BITS {
+                width += 1;
+            }
+            next_code += 1;
+        }
+        prefix = index as u16;
+    }
+    out.write(prefix, width);
+    out.write(end, width);
+    out.finish()
+}
+
+//
+// The writer
+//
+
+/// Where a clip is written: a file, or a `Vec` for tests.
+pub struct GifWriter {
+    out: Box<Write>,
+    /// How many frames have been offered to `frame`, kept or not.
+    frames: u64,
+    /// How long each kept frame is shown for, in hundredths of a second, and how much of that
+    /// hasn't been given to a frame yet. Delays are whole hundredths, so they alternate to keep
+    /// the clip at the right speed.
+    frame_delay: f64,
+    pending_delay: f64,
+}
+
+impl GifWriter {
+    /// Starts a clip of the screen running at `frame_rate`, writing the header straight away.
+    /// The clip loops forever.
+    pub fn new(mut out: Box<Write>, frame_rate: f64) -> io::Result<GifWriter> {
+        out.write_all(b"GIF89a")?;
+        out.write_all(&(SCREEN_WIDTH as u16).to_le_bytes())?;
+        out.write_all(&(SCREEN_HEIGHT as u16).to_le_bytes())?;
+        // No global color table; every frame has its own.
+        out.write_all(&[0, 0, 0])?;
+        out.write_all(&[0x21, 0xff, 11])?;
+        out.write_all(b"NETSCAPE2.0")?;
+        out.write_all(&[3, 1, 0, 0, 0])?; // Loop forever
+        Ok(GifWriter {
+            out: out,
+            frames: 0,
+            frame_delay: 100.0 * FRAME_STEP as f64 / frame_rate,
+            pending_delay: 0.0,
+        })
+    }
+
+    /// Starts a clip in a new file, replacing any that's there.
+    pub fn create(path: &Path, frame_rate: f64) -> io::Result<GifWriter> {
+        let file = File::create(path)?;
+        GifWriter::new(Box::new(BufWriter::new(file)), frame_rate)
+    }
+
+    /// Offers the next frame of the screen. Only every other one is kept.
+    pub fn frame(&mut self, pixels: &[u8], format: PixelFormat) -> io::Result<()> {
+        self.frames += 1;
+        if (self.frames - 1) % FRAME_STEP != 0 {
+            return Ok(());
+        }
+
+        self.pending_delay += self.frame_delay;
+        let delay = self.pending_delay.round();
+        self.pending_delay -= delay;
+        self.out.write_all(&[0x21, 0xf9, 4, 0x04])?;
+        self.out.write_all(&(delay as u16).to_le_bytes())?;
+        self.out.write_all(&[0, 0])?;
+
+        let (colors, image) = quantize(pixels, format);
+        // The color table's size is a power of two, at least 4.
+        let mut table_bits = 2;
+        while 1 << table_bits < colors.len() {
+            table_bits += 1;
+        }
+        self.out.write_all(&[0x2c, 0, 0, 0, 0])?;
+        self.out.write_all(&(SCREEN_WIDTH as u16).to_le_bytes())?;
+        self.out.write_all(&(SCREEN_HEIGHT as u16).to_le_bytes())?;
+        self.out.write_all(&[0x80 | (table_bits - 1) as u8])?;
+        for i in 0..1 << table_bits {
+            let color = colors.get(i).cloned().unwrap_or([0, 0, 0]);
+            self.out.write_all(&color)?;
+        }
+
+        self.out.write_all(&[table_bits as u8])?;
+        for block in compress(&image, table_bits).chunks(255) {
+            self.out.write_all(&[block.len() as u8])?;
+            self.out.write_all(block)?;
+        }
+        self.out.write_all(&[0])
+    }
+
+    /// Ends the clip. Until this is called, it may be missing frames.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.out.write_all(&[0x3b])?;
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reads codes back out of a `BitWriter`'s bytes.
+    struct BitReader<'a> {
+        bytes: &'a [u8],
+        position: usize,
+    }
+
+    impl<'a> BitReader<'a> {
+        fn read(&mut self, width: u32) -> u16 {
+            let mut code = 0;
+            for bit in 0..width as usize {
+                let position = self.position + bit;
+                code |= ((self.bytes[position / 8] >> (position % 8)) as u16 & 1) << bit;
+            }
+            self.position += width as usize;
+            code
+        }
+    }
+
+    // A GIF decoder's LZW decompression, returning the image and the widths codes were read at
+    // after each clear code.
+    fn decompress(bytes: &[u8], min_code_bits: u32) -> (Vec<u8>, Vec<u32>) {
+        let (clear, end) = (1u16 << min_code_bits, (1u16 << min_code_bits) + 1);
+        let mut reader = BitReader {
+            bytes: bytes,
+            position: 0,
+        };
+        let mut image = vec![];
+        let mut widths_at_clear = vec![];
+        let mut dictionary: Vec<Vec<u8>> = vec![];
+        let mut width = min_code_bits + 1;
+        let mut prev: Option<u16> = None;
+        loop {
+            let code = reader.read(width);
+            if code == clear {
+                widths_at_clear.push(width);
+                dictionary = (0..clear).map(|index| vec![index as u8]).collect();
+                dictionary.push(vec![]);
+                dictionary.push(vec![]);
+                width = min_code_bits + 1;
+                prev = None;
+                continue;
+            }
+            if code == end {
+                return (image, widths_at_clear);
+            }
+            let entry = match prev {
+                None => dictionary[code as usize].clone(),
+                Some(prev) => {
+                    let mut entry = dictionary[prev as usize].clone();
+                    let first = if (code as usize) < dictionary.len() {
+                        dictionary[code as usize][0]
+                    } else {
+                        entry[0]
+                    };
+                    entry.push(first);
+                    if dictionary.len() < 1 << MAX_CODE_BITS {
+                        dictionary.push(entry);
+                    }
+                    dictionary[code as usize].clone()
+                }
+            };
+            image.extend_from_slice(&entry);
+            if dictionary.len() == 1 << width && width < MAX_CODE_BITS {
+                width += 1;
+            }
+            prev = Some(code);
+        }
+    }
+
+    #[test]
+    fn lzw_round_trips_through_width_changes_and_clears() {
+        // A pseudorandom picture in 16 colors fills the dictionary several times over.
+        let mut seed = 1u32;
+        let image: Vec<u8> = (0..SCREEN_WIDTH * SCREEN_HEIGHT)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                (seed >> 16) as u8 & 0x0f
+            })
+            .collect();
+        let (decompressed, widths_at_clear) = decompress(&compress(&image, 4), 4);
+        assert!(decompressed == image);
+        // The first clear is at the start, and the dictionary fills up at 12 bits each time.
+        assert_eq!(widths_at_clear[0], 5);
+        assert!(widths_at_clear.len() > 2);
+        assert!(widths_at_clear[1..]
+            .iter()
+            .all(|&width| width == MAX_CODE_BITS));
+    }
+
+    #[test]
+    fn lzw_round_trips_short_images() {
+        for image in [vec![], vec![3], vec![1, 1, 1, 1, 1, 1, 1, 1, 2, 1, 1]].iter() {
+            let (decompressed, _) = decompress(&compress(image, 2), 2);
+            assert_eq!(&decompressed, image);
+        }
+    }
+}
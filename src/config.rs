@@ -0,0 +1,193 @@
+//! Configuration files.
+//!
+//! The format is deliberately tiny: one `key = value` pair per line. Blank lines and lines
+//! starting with `#` are ignored.
+//!
+//! Settings can come from several places. From lowest to highest precedence:
+//!
+//! 1. The built-in defaults.
+//! 2. `SPROCKETNES_*` environment variables (see `env_var_name`).
+//! 3. The per-ROM configuration file (see `rom_config_path`).
+//! 4. Command-line flags.
+
+//
+// Author: Patrick Walton
+//
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// IO error while reading the configuration file
+    IoError(io::Error),
+    /// The given line (starting at 1) isn't a `key = value` pair
+    SyntaxError(usize),
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(err: io::Error) -> Self {
+        ConfigError::IoError(err)
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            ConfigError::IoError(ref err) => write!(f, "{}", err),
+            ConfigError::SyntaxError(line) => write!(f, "line {}: expected `key = value`", line),
+        }
+    }
+}
+
+/// A set of configuration values
+pub struct Config {
+    values: HashMap<String, String>,
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            values: HashMap::new(),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Config, ConfigError> {
+        let mut config = Config::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.find('=') {
+                Some(pos) => config.set(line[..pos].trim(), line[pos + 1..].trim()),
+                None => return Err(ConfigError::SyntaxError(index + 1)),
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let mut text = String::new();
+        File::open(path)?.read_to_string(&mut text)?;
+        Config::parse(&text)
+    }
+
+    /// Reads the environment variable for each of `keys`, skipping unset ones.
+    pub fn from_env(keys: &[&str]) -> Config {
+        let mut config = Config::new();
+        for &key in keys.iter() {
+            if let Ok(value) = env::var(env_var_name(key)) {
+                config.set(key, value.trim());
+            }
+        }
+        config
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(|value| &**value)
+    }
+
+    /// Returns the value of a boolean setting. `on`, `true`, `yes` and `1` are true; `off`,
+    /// `false`, `no` and `0` are false.
+    pub fn get_bool(&self, key: &str) -> Result<Option<bool>, String> {
+        match self.get(key) {
+            None => Ok(None),
+            Some("on") | Some("true") | Some("yes") | Some("1") => Ok(Some(true)),
+            Some("off") | Some("false") | Some("no") | Some("0") => Ok(Some(false)),
+            Some(value) => Err(format!("expected on or off for {}: {}", key, value)),
+        }
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) {
+        self.values.insert(key.to_string(), value.to_string());
+    }
+
+    /// Returns all keys starting with `prefix`, along with their values.
+    pub fn with_prefix<'a>(&'a self, prefix: &'a str) -> Vec<(&'a str, &'a str)> {
+        self.values
+            .iter()
+            .filter(|&(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (&**key, &**value))
+            .collect()
+    }
+
+    /// Copies every value in `other` into this configuration, replacing existing values.
+    pub fn merge(&mut self, other: &Config) {
+        for (key, value) in other.values.iter() {
+            self.values.insert(key.clone(), value.clone());
+        }
+    }
+}
+
+/// Returns the environment variable that sets `key`: `save_dir` is `SPROCKETNES_SAVE_DIR` and
+/// `input.a` is `SPROCKETNES_INPUT_A`.
+pub fn env_var_name(key: &str) -> String {
+    format!("SPROCKETNES_{}", key.replace('.', "_").to_uppercase())
+}
+
+/// Returns the path of the per-ROM configuration file that overrides the global settings for the
+/// ROM at `rom_path`: `game.nes` uses `game.cfg`.
+pub fn rom_config_path(rom_path: &Path) -> PathBuf {
+    rom_path.with_extension("cfg")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_pairs_and_skips_comments() {
+        let config = Config::parse("# Settings\n\nscale = 3\n  input.a=x  \n").unwrap();
+        assert_eq!(config.get("scale"), Some("3"));
+        assert_eq!(config.get("input.a"), Some("x"));
+        assert_eq!(config.get("# Settings"), None);
+    }
+
+    #[test]
+    fn reports_the_line_of_a_syntax_error() {
+        match Config::parse("scale = 3\n\nfullscreen\n") {
+            Err(ConfigError::SyntaxError(line)) => assert_eq!(line, 3),
+            _ => panic!("expected a syntax error"),
+        }
+    }
+
+    #[test]
+    fn reads_booleans() {
+        let config = Config::parse("vsync = yes\nmute = 0\nfullscreen = maybe").unwrap();
+        assert_eq!(config.get_bool("vsync"), Ok(Some(true)));
+        assert_eq!(config.get_bool("mute"), Ok(Some(false)));
+        assert_eq!(config.get_bool("scale"), Ok(None));
+        assert!(config.get_bool("fullscreen").is_err());
+    }
+
+    #[test]
+    fn merging_overrides_existing_values() {
+        let mut config = Config::parse("scale = 2\nvsync = on").unwrap();
+        config.merge(&Config::parse("scale = 4").unwrap());
+        assert_eq!(config.get("scale"), Some("4"));
+        assert_eq!(config.get("vsync"), Some("on"));
+    }
+
+    #[test]
+    fn finds_keys_by_prefix() {
+        let config = Config::parse("input.a = x\ninput.b = z\nscale = 2").unwrap();
+        let mut keys = config.with_prefix("input.");
+        keys.sort();
+        assert_eq!(keys, vec![("input.a", "x"), ("input.b", "z")]);
+    }
+
+    #[test]
+    fn names_environment_variables_and_rom_configs() {
+        assert_eq!(env_var_name("save_dir"), "SPROCKETNES_SAVE_DIR");
+        assert_eq!(env_var_name("input.a"), "SPROCKETNES_INPUT_A");
+        assert_eq!(
+            rom_config_path(Path::new("roms/game.nes")),
+            Path::new("roms/game.cfg")
+        );
+    }
+}
@@ -2,53 +2,42 @@
 // Author: Patrick Walton
 //
 
-use mem::Mem;
+use disasm::Disassembler;
+use fceux::{self, FceuxSection};
+use mem::{IrqLine, Mem};
+use trace::Tracer;
 use util::Save;
 
+use std::fmt;
 use std::fs::File;
-use std::ops::Deref;
-
-#[cfg(cpuspew)]
-use disasm::Disassembler;
+use std::mem;
 use std::num::Wrapping;
+use std::ops::Deref;
 
-const CARRY_FLAG: u8 = 1 << 0;
-const ZERO_FLAG: u8 = 1 << 1;
-const IRQ_FLAG: u8 = 1 << 2;
-const DECIMAL_FLAG: u8 = 1 << 3;
-const BREAK_FLAG: u8 = 1 << 4;
-const OVERFLOW_FLAG: u8 = 1 << 6;
-const NEGATIVE_FLAG: u8 = 1 << 7;
+// The bits of the flags register
+pub const CARRY_FLAG: u8 = 1 << 0;
+pub const ZERO_FLAG: u8 = 1 << 1;
+pub const IRQ_FLAG: u8 = 1 << 2;
+pub const DECIMAL_FLAG: u8 = 1 << 3;
+pub const BREAK_FLAG: u8 = 1 << 4;
+pub const OVERFLOW_FLAG: u8 = 1 << 6;
+pub const NEGATIVE_FLAG: u8 = 1 << 7;
 
 const NMI_VECTOR: u16 = 0xfffa;
 const RESET_VECTOR: u16 = 0xfffc;
 const BRK_VECTOR: u16 = 0xfffe;
 
-/// The number of cycles that each machine operation takes. Indexed by opcode number.
-///
-/// FIXME: This is copied from FCEU.
-static CYCLE_TABLE: [u8; 256] = [
-    /*0x00*/ 7, 6, 2, 8, 3, 3, 5, 5, 3, 2, 2, 2, 4, 4, 6, 6, /*0x10*/ 2, 5, 2, 8, 4, 4,
-    6, 6, 2, 4, 2, 7, 4, 4, 7, 7, /*0x20*/ 6, 6, 2, 8, 3, 3, 5, 5, 4, 2, 2, 2, 4, 4, 6, 6,
-    /*0x30*/ 2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, /*0x40*/ 6, 6, 2, 8, 3, 3,
-    5, 5, 3, 2, 2, 2, 3, 4, 6, 6, /*0x50*/ 2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
-    /*0x60*/ 6, 6, 2, 8, 3, 3, 5, 5, 4, 2, 2, 2, 5, 4, 6, 6, /*0x70*/ 2, 5, 2, 8, 4, 4,
-    6, 6, 2, 4, 2, 7, 4, 4, 7, 7, /*0x80*/ 2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4,
-    /*0x90*/ 2, 6, 2, 6, 4, 4, 4, 4, 2, 5, 2, 5, 5, 5, 5, 5, /*0xA0*/ 2, 6, 2, 6, 3, 3,
-    3, 3, 2, 2, 2, 2, 4, 4, 4, 4, /*0xB0*/ 2, 5, 2, 5, 4, 4, 4, 4, 2, 4, 2, 4, 4, 4, 4, 4,
-    /*0xC0*/ 2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6, /*0xD0*/ 2, 5, 2, 8, 4, 4,
-    6, 6, 2, 4, 2, 7, 4, 4, 7, 7, /*0xE0*/ 2, 6, 3, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6,
-    /*0xF0*/ 2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
-];
-
-/// CPU Registers
-struct Regs {
-    a: u8,
-    x: u8,
-    y: u8,
-    s: u8,
-    flags: u8,
-    pc: u16,
+/// CPU Registers. `Cpu::regs` returns a copy of them, and `Cpu::set_regs` replaces them.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Regs {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    /// The stack pointer, an offset into page 1
+    pub s: u8,
+    /// The processor status, made of the `*_FLAG` bits
+    pub flags: u8,
+    pub pc: u16,
 }
 
 save_struct!(Regs {
@@ -124,556 +113,582 @@ impl<M: Mem> AddressingMode<M> for MemoryAddressingMode {
     }
 }
 
-/// Opcode decoding
-///
-/// This is implemented as a macro so that both the disassembler and the emulator can use it.
-macro_rules! decode_op {
-    ($op:expr, $this:ident) => {
-        // We try to keep this in the same order as the implementations above.
-        // TODO: Use arm macros to fix some of this duplication.
-        match $op {
-            // Loads
-            0xa1 => {
-                let v = $this.indexed_indirect_x();
-                $this.lda(v)
-            }
-            0xa5 => {
-                let v = $this.zero_page();
-                $this.lda(v)
-            }
-            0xa9 => {
-                let v = $this.immediate();
-                $this.lda(v)
-            }
-            0xad => {
-                let v = $this.absolute();
-                $this.lda(v)
-            }
-            0xb1 => {
-                let v = $this.indirect_indexed_y();
-                $this.lda(v)
-            }
-            0xb5 => {
-                let v = $this.zero_page_x();
-                $this.lda(v)
-            }
-            0xb9 => {
-                let v = $this.absolute_y();
-                $this.lda(v)
-            }
-            0xbd => {
-                let v = $this.absolute_x();
-                $this.lda(v)
-            }
-
-            0xa2 => {
-                let v = $this.immediate();
-                $this.ldx(v)
-            }
-            0xa6 => {
-                let v = $this.zero_page();
-                $this.ldx(v)
-            }
-            0xb6 => {
-                let v = $this.zero_page_y();
-                $this.ldx(v)
-            }
-            0xae => {
-                let v = $this.absolute();
-                $this.ldx(v)
-            }
-            0xbe => {
-                let v = $this.absolute_y();
-                $this.ldx(v)
-            }
-
-            0xa0 => {
-                let v = $this.immediate();
-                $this.ldy(v)
-            }
-            0xa4 => {
-                let v = $this.zero_page();
-                $this.ldy(v)
-            }
-            0xb4 => {
-                let v = $this.zero_page_x();
-                $this.ldy(v)
-            }
-            0xac => {
-                let v = $this.absolute();
-                $this.ldy(v)
-            }
-            0xbc => {
-                let v = $this.absolute_x();
-                $this.ldy(v)
-            }
-
-            // Stores
-            0x85 => {
-                let v = $this.zero_page();
-                $this.sta(v)
-            }
-            0x95 => {
-                let v = $this.zero_page_x();
-                $this.sta(v)
-            }
-            0x8d => {
-                let v = $this.absolute();
-                $this.sta(v)
-            }
-            0x9d => {
-                let v = $this.absolute_x();
-                $this.sta(v)
-            }
-            0x99 => {
-                let v = $this.absolute_y();
-                $this.sta(v)
-            }
-            0x81 => {
-                let v = $this.indexed_indirect_x();
-                $this.sta(v)
-            }
-            0x91 => {
-                let v = $this.indirect_indexed_y();
-                $this.sta(v)
-            }
-
-            0x86 => {
-                let v = $this.zero_page();
-                $this.stx(v)
-            }
-            0x96 => {
-                let v = $this.zero_page_y();
-                $this.stx(v)
-            }
-            0x8e => {
-                let v = $this.absolute();
-                $this.stx(v)
-            }
-
-            0x84 => {
-                let v = $this.zero_page();
-                $this.sty(v)
-            }
-            0x94 => {
-                let v = $this.zero_page_x();
-                $this.sty(v)
-            }
-            0x8c => {
-                let v = $this.absolute();
-                $this.sty(v)
-            }
-
-            // Arithmetic
-            0x69 => {
-                let v = $this.immediate();
-                $this.adc(v)
-            }
-            0x65 => {
-                let v = $this.zero_page();
-                $this.adc(v)
-            }
-            0x75 => {
-                let v = $this.zero_page_x();
-                $this.adc(v)
-            }
-            0x6d => {
-                let v = $this.absolute();
-                $this.adc(v)
-            }
-            0x7d => {
-                let v = $this.absolute_x();
-                $this.adc(v)
-            }
-            0x79 => {
-                let v = $this.absolute_y();
-                $this.adc(v)
-            }
-            0x61 => {
-                let v = $this.indexed_indirect_x();
-                $this.adc(v)
-            }
-            0x71 => {
-                let v = $this.indirect_indexed_y();
-                $this.adc(v)
-            }
+//
+// Opcodes
+//
 
-            0xe9 => {
-                let v = $this.immediate();
-                $this.sbc(v)
-            }
-            0xe5 => {
-                let v = $this.zero_page();
-                $this.sbc(v)
-            }
-            0xf5 => {
-                let v = $this.zero_page_x();
-                $this.sbc(v)
-            }
-            0xed => {
-                let v = $this.absolute();
-                $this.sbc(v)
-            }
-            0xfd => {
-                let v = $this.absolute_x();
-                $this.sbc(v)
-            }
-            0xf9 => {
-                let v = $this.absolute_y();
-                $this.sbc(v)
-            }
-            0xe1 => {
-                let v = $this.indexed_indirect_x();
-                $this.sbc(v)
-            }
-            0xf1 => {
-                let v = $this.indirect_indexed_y();
-                $this.sbc(v)
-            }
+/// How an instruction finds its operand.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Mode {
+    /// No operand, or one the instruction implies, such as the stack
+    Implied,
+    /// The accumulator
+    Accumulator,
+    /// The byte after the opcode
+    Immediate,
+    /// An address in page zero
+    ZeroPage,
+    /// An address in page zero, plus X, wrapping within page zero
+    ZeroPageX,
+    /// An address in page zero, plus Y, wrapping within page zero
+    ZeroPageY,
+    /// A full address
+    Absolute,
+    /// A full address plus X
+    AbsoluteX,
+    /// A full address plus Y
+    AbsoluteY,
+    /// An address stored at a full address, for JMP
+    Indirect,
+    /// An address stored in page zero, at the operand plus X
+    IndexedIndirectX,
+    /// An address stored in page zero, plus Y
+    IndirectIndexedY,
+    /// A signed displacement from the next instruction, for branches
+    Relative,
+}
 
-            // Comparisons
-            0xc9 => {
-                let v = $this.immediate();
-                $this.cmp(v)
-            }
-            0xc5 => {
-                let v = $this.zero_page();
-                $this.cmp(v)
-            }
-            0xd5 => {
-                let v = $this.zero_page_x();
-                $this.cmp(v)
-            }
-            0xcd => {
-                let v = $this.absolute();
-                $this.cmp(v)
-            }
-            0xdd => {
-                let v = $this.absolute_x();
-                $this.cmp(v)
-            }
-            0xd9 => {
-                let v = $this.absolute_y();
-                $this.cmp(v)
-            }
-            0xc1 => {
-                let v = $this.indexed_indirect_x();
-                $this.cmp(v)
-            }
-            0xd1 => {
-                let v = $this.indirect_indexed_y();
-                $this.cmp(v)
-            }
+impl Mode {
+    /// The number of operand bytes that follow the opcode.
+    pub fn operand_len(self) -> u16 {
+        match self {
+            Mode::Implied | Mode::Accumulator => 0,
+            Mode::Absolute | Mode::AbsoluteX | Mode::AbsoluteY | Mode::Indirect => 2,
+            _ => 1,
+        }
+    }
+}
 
-            0xe0 => {
-                let v = $this.immediate();
-                $this.cpx(v)
-            }
-            0xe4 => {
-                let v = $this.zero_page();
-                $this.cpx(v)
-            }
-            0xec => {
-                let v = $this.absolute();
-                $this.cpx(v)
-            }
+/// What the CPU and the disassembler know about an opcode.
+#[derive(Clone, Copy, Debug)]
+pub struct Opcode {
+    pub mnemonic: &'static str,
+    pub mode: Mode,
+    /// The cycles it takes, not counting the extra ones a taken branch or a read whose indexing
+    /// crosses a page takes.
+    pub cycles: u8,
+    /// Unofficial opcodes are side effects of how the 6502 decodes instructions, and aren't in
+    /// its documentation.
+    pub unofficial: bool,
+}
 
-            0xc0 => {
-                let v = $this.immediate();
-                $this.cpy(v)
-            }
-            0xc4 => {
-                let v = $this.zero_page();
-                $this.cpy(v)
-            }
-            0xcc => {
-                let v = $this.absolute();
-                $this.cpy(v)
-            }
+impl Opcode {
+    /// Whether the instruction takes another cycle when indexing crosses a page, to fix up the
+    /// high byte of the address before reading from it. Only reads do: stores and
+    /// read-modify-write instructions always spend that cycle, so their counts already include it.
+    pub fn page_penalty(&self) -> bool {
+        match self.mode {
+            Mode::AbsoluteX | Mode::AbsoluteY => self.cycles == 4,
+            Mode::IndirectIndexedY => self.cycles == 5,
+            _ => false,
+        }
+    }
+}
 
-            // Bitwise operations
-            0x29 => {
-                let v = $this.immediate();
-                $this.and(v)
-            }
-            0x25 => {
-                let v = $this.zero_page();
-                $this.and(v)
-            }
-            0x35 => {
-                let v = $this.zero_page_x();
-                $this.and(v)
-            }
-            0x2d => {
-                let v = $this.absolute();
-                $this.and(v)
-            }
-            0x3d => {
-                let v = $this.absolute_x();
-                $this.and(v)
-            }
-            0x39 => {
-                let v = $this.absolute_y();
-                $this.and(v)
-            }
-            0x21 => {
-                let v = $this.indexed_indirect_x();
-                $this.and(v)
-            }
-            0x31 => {
-                let v = $this.indirect_indexed_y();
-                $this.and(v)
-            }
+/// Calls the `Cpu` method that runs an instruction, with the operand its addressing mode selects.
+/// Branches and instructions without operands fetch whatever they need themselves.
+macro_rules! dispatch {
+    ($cpu:ident, $handler:ident, Implied) => {
+        $cpu.$handler()
+    };
+    ($cpu:ident, $handler:ident, Relative) => {
+        $cpu.$handler()
+    };
+    ($cpu:ident, $handler:ident, Accumulator) => {{
+        let v = $cpu.accumulator();
+        $cpu.$handler(v)
+    }};
+    ($cpu:ident, $handler:ident, Immediate) => {{
+        let v = $cpu.immediate();
+        $cpu.$handler(v)
+    }};
+    ($cpu:ident, $handler:ident, ZeroPage) => {{
+        let v = $cpu.zero_page();
+        $cpu.$handler(v)
+    }};
+    ($cpu:ident, $handler:ident, ZeroPageX) => {{
+        let v = $cpu.zero_page_x();
+        $cpu.$handler(v)
+    }};
+    ($cpu:ident, $handler:ident, ZeroPageY) => {{
+        let v = $cpu.zero_page_y();
+        $cpu.$handler(v)
+    }};
+    ($cpu:ident, $handler:ident, Absolute) => {{
+        let v = $cpu.absolute();
+        $cpu.$handler(v)
+    }};
+    ($cpu:ident, $handler:ident, AbsoluteX) => {{
+        let v = $cpu.absolute_x();
+        $cpu.$handler(v)
+    }};
+    ($cpu:ident, $handler:ident, AbsoluteY) => {{
+        let v = $cpu.absolute_y();
+        $cpu.$handler(v)
+    }};
+    ($cpu:ident, $handler:ident, Indirect) => {{
+        let v = $cpu.indirect();
+        $cpu.$handler(v)
+    }};
+    ($cpu:ident, $handler:ident, IndexedIndirectX) => {{
+        let v = $cpu.indexed_indirect_x();
+        $cpu.$handler(v)
+    }};
+    ($cpu:ident, $handler:ident, IndirectIndexedY) => {{
+        let v = $cpu.indirect_indexed_y();
+        $cpu.$handler(v)
+    }};
+}
 
-            0x09 => {
-                let v = $this.immediate();
-                $this.ora(v)
-            }
-            0x05 => {
-                let v = $this.zero_page();
-                $this.ora(v)
-            }
-            0x15 => {
-                let v = $this.zero_page_x();
-                $this.ora(v)
-            }
-            0x0d => {
-                let v = $this.absolute();
-                $this.ora(v)
-            }
-            0x1d => {
-                let v = $this.absolute_x();
-                $this.ora(v)
-            }
-            0x19 => {
-                let v = $this.absolute_y();
-                $this.ora(v)
-            }
-            0x01 => {
-                let v = $this.indexed_indirect_x();
-                $this.ora(v)
-            }
-            0x11 => {
-                let v = $this.indirect_indexed_y();
-                $this.ora(v)
-            }
+/// Builds both the opcode table, `OPCODES`, and the CPU's dispatch table, `Cpu::HANDLERS`, from
+/// one line per opcode: its mnemonic, addressing mode, cycle count and the method that runs it.
+macro_rules! opcodes {
+    (
+        official {
+            $($op:expr => $mnemonic:ident, $mode:ident, $cycles:expr, $handler:ident;)*
+        }
+        unofficial {
+            $($uop:expr => $umnemonic:ident, $umode:ident, $ucycles:expr, $uhandler:ident;)*
+        }
+    ) => {
+        /// Every opcode, indexed by opcode number.
+        pub static OPCODES: [Opcode; 256] = {
+            let mut table = [Opcode {
+                mnemonic: "JAM",
+                mode: Mode::Implied,
+                cycles: 2,
+                unofficial: true,
+            }; 256];
+            $(
+                table[$op] = Opcode {
+                    mnemonic: stringify!($mnemonic),
+                    mode: Mode::$mode,
+                    cycles: $cycles,
+                    unofficial: false,
+                };
+            )*
+            $(
+                table[$uop] = Opcode {
+                    mnemonic: stringify!($umnemonic),
+                    mode: Mode::$umode,
+                    cycles: $ucycles,
+                    unofficial: true,
+                };
+            )*
+            table
+        };
 
-            0x49 => {
-                let v = $this.immediate();
-                $this.eor(v)
-            }
-            0x45 => {
-                let v = $this.zero_page();
-                $this.eor(v)
-            }
-            0x55 => {
-                let v = $this.zero_page_x();
-                $this.eor(v)
-            }
-            0x4d => {
-                let v = $this.absolute();
-                $this.eor(v)
-            }
-            0x5d => {
-                let v = $this.absolute_x();
-                $this.eor(v)
-            }
-            0x59 => {
-                let v = $this.absolute_y();
-                $this.eor(v)
-            }
-            0x41 => {
-                let v = $this.indexed_indirect_x();
-                $this.eor(v)
-            }
-            0x51 => {
-                let v = $this.indirect_indexed_y();
-                $this.eor(v)
-            }
+        impl<M: Mem> Cpu<M> {
+            /// The function that runs each opcode, indexed by opcode number.
+            const HANDLERS: [fn(&mut Cpu<M>); 256] = {
+                let mut table = [Cpu::<M>::jam as fn(&mut Cpu<M>); 256];
+                $(table[$op] = |cpu: &mut Cpu<M>| dispatch!(cpu, $handler, $mode);)*
+                $(table[$uop] = |cpu: &mut Cpu<M>| dispatch!(cpu, $uhandler, $umode);)*
+                table
+            };
+        }
+    };
+}
 
-            0x24 => {
-                let v = $this.zero_page();
-                $this.bit(v)
-            }
-            0x2c => {
-                let v = $this.absolute();
-                $this.bit(v)
-            }
+// The cycle counts are copied from FCEU.
+#[rustfmt::skip]
+opcodes! {
+    official {
+        // Loads
+        0xa1 => LDA, IndexedIndirectX, 6, lda;
+        0xa5 => LDA, ZeroPage, 3, lda;
+        0xa9 => LDA, Immediate, 2, lda;
+        0xad => LDA, Absolute, 4, lda;
+        0xb1 => LDA, IndirectIndexedY, 5, lda;
+        0xb5 => LDA, ZeroPageX, 4, lda;
+        0xb9 => LDA, AbsoluteY, 4, lda;
+        0xbd => LDA, AbsoluteX, 4, lda;
+
+        0xa2 => LDX, Immediate, 2, ldx;
+        0xa6 => LDX, ZeroPage, 3, ldx;
+        0xb6 => LDX, ZeroPageY, 4, ldx;
+        0xae => LDX, Absolute, 4, ldx;
+        0xbe => LDX, AbsoluteY, 4, ldx;
+
+        0xa0 => LDY, Immediate, 2, ldy;
+        0xa4 => LDY, ZeroPage, 3, ldy;
+        0xb4 => LDY, ZeroPageX, 4, ldy;
+        0xac => LDY, Absolute, 4, ldy;
+        0xbc => LDY, AbsoluteX, 4, ldy;
+
+        // Stores
+        0x85 => STA, ZeroPage, 3, sta;
+        0x95 => STA, ZeroPageX, 4, sta;
+        0x8d => STA, Absolute, 4, sta;
+        0x9d => STA, AbsoluteX, 5, sta;
+        0x99 => STA, AbsoluteY, 5, sta;
+        0x81 => STA, IndexedIndirectX, 6, sta;
+        0x91 => STA, IndirectIndexedY, 6, sta;
+
+        0x86 => STX, ZeroPage, 3, stx;
+        0x96 => STX, ZeroPageY, 4, stx;
+        0x8e => STX, Absolute, 4, stx;
+
+        0x84 => STY, ZeroPage, 3, sty;
+        0x94 => STY, ZeroPageX, 4, sty;
+        0x8c => STY, Absolute, 4, sty;
+
+        // Arithmetic
+        0x69 => ADC, Immediate, 2, adc;
+        0x65 => ADC, ZeroPage, 3, adc;
+        0x75 => ADC, ZeroPageX, 4, adc;
+        0x6d => ADC, Absolute, 4, adc;
+        0x7d => ADC, AbsoluteX, 4, adc;
+        0x79 => ADC, AbsoluteY, 4, adc;
+        0x61 => ADC, IndexedIndirectX, 6, adc;
+        0x71 => ADC, IndirectIndexedY, 5, adc;
+
+        0xe9 => SBC, Immediate, 2, sbc;
+        0xe5 => SBC, ZeroPage, 3, sbc;
+        0xf5 => SBC, ZeroPageX, 4, sbc;
+        0xed => SBC, Absolute, 4, sbc;
+        0xfd => SBC, AbsoluteX, 4, sbc;
+        0xf9 => SBC, AbsoluteY, 4, sbc;
+        0xe1 => SBC, IndexedIndirectX, 6, sbc;
+        0xf1 => SBC, IndirectIndexedY, 5, sbc;
+
+        // Comparisons
+        0xc9 => CMP, Immediate, 2, cmp;
+        0xc5 => CMP, ZeroPage, 3, cmp;
+        0xd5 => CMP, ZeroPageX, 4, cmp;
+        0xcd => CMP, Absolute, 4, cmp;
+        0xdd => CMP, AbsoluteX, 4, cmp;
+        0xd9 => CMP, AbsoluteY, 4, cmp;
+        0xc1 => CMP, IndexedIndirectX, 6, cmp;
+        0xd1 => CMP, IndirectIndexedY, 5, cmp;
+
+        0xe0 => CPX, Immediate, 2, cpx;
+        0xe4 => CPX, ZeroPage, 3, cpx;
+        0xec => CPX, Absolute, 4, cpx;
+
+        0xc0 => CPY, Immediate, 2, cpy;
+        0xc4 => CPY, ZeroPage, 3, cpy;
+        0xcc => CPY, Absolute, 4, cpy;
+
+        // Bitwise operations
+        0x29 => AND, Immediate, 2, and;
+        0x25 => AND, ZeroPage, 3, and;
+        0x35 => AND, ZeroPageX, 4, and;
+        0x2d => AND, Absolute, 4, and;
+        0x3d => AND, AbsoluteX, 4, and;
+        0x39 => AND, AbsoluteY, 4, and;
+        0x21 => AND, IndexedIndirectX, 6, and;
+        0x31 => AND, IndirectIndexedY, 5, and;
+
+        0x09 => ORA, Immediate, 2, ora;
+        0x05 => ORA, ZeroPage, 3, ora;
+        0x15 => ORA, ZeroPageX, 4, ora;
+        0x0d => ORA, Absolute, 4, ora;
+        0x1d => ORA, AbsoluteX, 4, ora;
+        0x19 => ORA, AbsoluteY, 4, ora;
+        0x01 => ORA, IndexedIndirectX, 6, ora;
+        0x11 => ORA, IndirectIndexedY, 5, ora;
+
+        0x49 => EOR, Immediate, 2, eor;
+        0x45 => EOR, ZeroPage, 3, eor;
+        0x55 => EOR, ZeroPageX, 4, eor;
+        0x4d => EOR, Absolute, 4, eor;
+        0x5d => EOR, AbsoluteX, 4, eor;
+        0x59 => EOR, AbsoluteY, 4, eor;
+        0x41 => EOR, IndexedIndirectX, 6, eor;
+        0x51 => EOR, IndirectIndexedY, 5, eor;
+
+        0x24 => BIT, ZeroPage, 3, bit;
+        0x2c => BIT, Absolute, 4, bit;
+
+        // Shifts and rotates
+        0x2a => ROL, Accumulator, 2, rol;
+        0x26 => ROL, ZeroPage, 5, rol;
+        0x36 => ROL, ZeroPageX, 6, rol;
+        0x2e => ROL, Absolute, 6, rol;
+        0x3e => ROL, AbsoluteX, 7, rol;
+
+        0x6a => ROR, Accumulator, 2, ror;
+        0x66 => ROR, ZeroPage, 5, ror;
+        0x76 => ROR, ZeroPageX, 6, ror;
+        0x6e => ROR, Absolute, 6, ror;
+        0x7e => ROR, AbsoluteX, 7, ror;
+
+        0x0a => ASL, Accumulator, 2, asl;
+        0x06 => ASL, ZeroPage, 5, asl;
+        0x16 => ASL, ZeroPageX, 6, asl;
+        0x0e => ASL, Absolute, 6, asl;
+        0x1e => ASL, AbsoluteX, 7, asl;
+
+        0x4a => LSR, Accumulator, 2, lsr;
+        0x46 => LSR, ZeroPage, 5, lsr;
+        0x56 => LSR, ZeroPageX, 6, lsr;
+        0x4e => LSR, Absolute, 6, lsr;
+        0x5e => LSR, AbsoluteX, 7, lsr;
+
+        // Increments and decrements
+        0xe6 => INC, ZeroPage, 5, inc;
+        0xf6 => INC, ZeroPageX, 6, inc;
+        0xee => INC, Absolute, 6, inc;
+        0xfe => INC, AbsoluteX, 7, inc;
+
+        0xc6 => DEC, ZeroPage, 5, dec;
+        0xd6 => DEC, ZeroPageX, 6, dec;
+        0xce => DEC, Absolute, 6, dec;
+        0xde => DEC, AbsoluteX, 7, dec;
+
+        0xe8 => INX, Implied, 2, inx;
+        0xca => DEX, Implied, 2, dex;
+        0xc8 => INY, Implied, 2, iny;
+        0x88 => DEY, Implied, 2, dey;
+
+        // Register moves
+        0xaa => TAX, Implied, 2, tax;
+        0xa8 => TAY, Implied, 2, tay;
+        0x8a => TXA, Implied, 2, txa;
+        0x98 => TYA, Implied, 2, tya;
+        0x9a => TXS, Implied, 2, txs;
+        0xba => TSX, Implied, 2, tsx;
+
+        // Flag operations
+        0x18 => CLC, Implied, 2, clc;
+        0x38 => SEC, Implied, 2, sec;
+        0x58 => CLI, Implied, 2, cli;
+        0x78 => SEI, Implied, 2, sei;
+        0xb8 => CLV, Implied, 2, clv;
+        0xd8 => CLD, Implied, 2, cld;
+        0xf8 => SED, Implied, 2, sed;
+
+        // Branches
+        0x10 => BPL, Relative, 2, bpl;
+        0x30 => BMI, Relative, 2, bmi;
+        0x50 => BVC, Relative, 2, bvc;
+        0x70 => BVS, Relative, 2, bvs;
+        0x90 => BCC, Relative, 2, bcc;
+        0xb0 => BCS, Relative, 2, bcs;
+        0xd0 => BNE, Relative, 2, bne;
+        0xf0 => BEQ, Relative, 2, beq;
+
+        // Jumps
+        0x4c => JMP, Absolute, 3, jmp;
+        0x6c => JMP, Indirect, 5, jmp;
+
+        // Procedure calls
+        0x20 => JSR, Absolute, 6, jsr;
+
+        0x60 => RTS, Implied, 6, rts;
+        0x00 => BRK, Implied, 7, brk;
+        0x40 => RTI, Implied, 6, rti;
+
+        // Stack operations
+        0x48 => PHA, Implied, 3, pha;
+        0x68 => PLA, Implied, 4, pla;
+        0x08 => PHP, Implied, 3, php;
+        0x28 => PLP, Implied, 4, plp;
+
+        // No operation
+        0xea => NOP, Implied, 2, nop;
+    }
+    unofficial {
+        // Loads and stores
+        0xa7 => LAX, ZeroPage, 3, lax;
+        0xb7 => LAX, ZeroPageY, 4, lax;
+        0xaf => LAX, Absolute, 4, lax;
+        0xbf => LAX, AbsoluteY, 4, lax;
+        0xa3 => LAX, IndexedIndirectX, 6, lax;
+        0xb3 => LAX, IndirectIndexedY, 5, lax;
+
+        0xab => LXA, Immediate, 2, lxa;
+
+        0xbb => LAS, AbsoluteY, 4, las;
+
+        0x87 => SAX, ZeroPage, 3, sax;
+        0x97 => SAX, ZeroPageY, 4, sax;
+        0x8f => SAX, Absolute, 4, sax;
+        0x83 => SAX, IndexedIndirectX, 6, sax;
+
+        0x9f => SHA, AbsoluteY, 5, sha;
+        0x93 => SHA, IndirectIndexedY, 6, sha;
+
+        0x9e => SHX, AbsoluteY, 5, shx;
+
+        0x9c => SHY, AbsoluteX, 5, shy;
+
+        0x9b => TAS, AbsoluteY, 5, tas;
+
+        // Read-modify-write instructions
+        0x07 => SLO, ZeroPage, 5, slo;
+        0x17 => SLO, ZeroPageX, 6, slo;
+        0x0f => SLO, Absolute, 6, slo;
+        0x1f => SLO, AbsoluteX, 7, slo;
+        0x1b => SLO, AbsoluteY, 7, slo;
+        0x03 => SLO, IndexedIndirectX, 8, slo;
+        0x13 => SLO, IndirectIndexedY, 8, slo;
+
+        0x27 => RLA, ZeroPage, 5, rla;
+        0x37 => RLA, ZeroPageX, 6, rla;
+        0x2f => RLA, Absolute, 6, rla;
+        0x3f => RLA, AbsoluteX, 7, rla;
+        0x3b => RLA, AbsoluteY, 7, rla;
+        0x23 => RLA, IndexedIndirectX, 8, rla;
+        0x33 => RLA, IndirectIndexedY, 8, rla;
+
+        0x47 => SRE, ZeroPage, 5, sre;
+        0x57 => SRE, ZeroPageX, 6, sre;
+        0x4f => SRE, Absolute, 6, sre;
+        0x5f => SRE, AbsoluteX, 7, sre;
+        0x5b => SRE, AbsoluteY, 7, sre;
+        0x43 => SRE, IndexedIndirectX, 8, sre;
+        0x53 => SRE, IndirectIndexedY, 8, sre;
+
+        0x67 => RRA, ZeroPage, 5, rra;
+        0x77 => RRA, ZeroPageX, 6, rra;
+        0x6f => RRA, Absolute, 6, rra;
+        0x7f => RRA, AbsoluteX, 7, rra;
+        0x7b => RRA, AbsoluteY, 7, rra;
+        0x63 => RRA, IndexedIndirectX, 8, rra;
+        0x73 => RRA, IndirectIndexedY, 8, rra;
+
+        0xc7 => DCP, ZeroPage, 5, dcp;
+        0xd7 => DCP, ZeroPageX, 6, dcp;
+        0xcf => DCP, Absolute, 6, dcp;
+        0xdf => DCP, AbsoluteX, 7, dcp;
+        0xdb => DCP, AbsoluteY, 7, dcp;
+        0xc3 => DCP, IndexedIndirectX, 8, dcp;
+        0xd3 => DCP, IndirectIndexedY, 8, dcp;
+
+        0xe7 => ISB, ZeroPage, 5, isb;
+        0xf7 => ISB, ZeroPageX, 6, isb;
+        0xef => ISB, Absolute, 6, isb;
+        0xff => ISB, AbsoluteX, 7, isb;
+        0xfb => ISB, AbsoluteY, 7, isb;
+        0xe3 => ISB, IndexedIndirectX, 8, isb;
+        0xf3 => ISB, IndirectIndexedY, 8, isb;
+
+        // Immediate instructions
+        0x0b => ANC, Immediate, 2, anc;
+        0x2b => ANC, Immediate, 2, anc;
+
+        0x4b => ALR, Immediate, 2, alr;
+
+        0x6b => ARR, Immediate, 2, arr;
+
+        0xcb => AXS, Immediate, 2, axs;
+
+        0x8b => XAA, Immediate, 2, xaa;
+
+        0xeb => SBC, Immediate, 2, sbc;
+
+        // No-ops, which still read their operands
+        0x80 => NOP, Immediate, 2, nop_read;
+        0x82 => NOP, Immediate, 2, nop_read;
+        0x89 => NOP, Immediate, 2, nop_read;
+        0xc2 => NOP, Immediate, 2, nop_read;
+        0xe2 => NOP, Immediate, 3, nop_read;
+        0x04 => NOP, ZeroPage, 3, nop_read;
+        0x44 => NOP, ZeroPage, 3, nop_read;
+        0x64 => NOP, ZeroPage, 3, nop_read;
+        0x14 => NOP, ZeroPageX, 4, nop_read;
+        0x34 => NOP, ZeroPageX, 4, nop_read;
+        0x54 => NOP, ZeroPageX, 4, nop_read;
+        0x74 => NOP, ZeroPageX, 4, nop_read;
+        0xd4 => NOP, ZeroPageX, 4, nop_read;
+        0xf4 => NOP, ZeroPageX, 4, nop_read;
+        0x0c => NOP, Absolute, 4, nop_read;
+        0x1c => NOP, AbsoluteX, 4, nop_read;
+        0x3c => NOP, AbsoluteX, 4, nop_read;
+        0x5c => NOP, AbsoluteX, 4, nop_read;
+        0x7c => NOP, AbsoluteX, 4, nop_read;
+        0xdc => NOP, AbsoluteX, 4, nop_read;
+        0xfc => NOP, AbsoluteX, 4, nop_read;
+
+        0x1a => NOP, Implied, 2, nop;
+        0x3a => NOP, Implied, 2, nop;
+        0x5a => NOP, Implied, 2, nop;
+        0x7a => NOP, Implied, 2, nop;
+        0xda => NOP, Implied, 2, nop;
+        0xfa => NOP, Implied, 2, nop;
+
+        // Opcodes that lock up the CPU
+        0x02 => JAM, Implied, 2, jam;
+        0x12 => JAM, Implied, 2, jam;
+        0x22 => JAM, Implied, 2, jam;
+        0x32 => JAM, Implied, 2, jam;
+        0x42 => JAM, Implied, 2, jam;
+        0x52 => JAM, Implied, 2, jam;
+        0x62 => JAM, Implied, 2, jam;
+        0x72 => JAM, Implied, 2, jam;
+        0x92 => JAM, Implied, 2, jam;
+        0xb2 => JAM, Implied, 2, jam;
+        0xd2 => JAM, Implied, 2, jam;
+        0xf2 => JAM, Implied, 2, jam;
+    }
+}
 
-            // Shifts and rotates
-            0x2a => {
-                let v = $this.accumulator();
-                $this.rol(v)
-            }
-            0x26 => {
-                let v = $this.zero_page();
-                $this.rol(v)
-            }
-            0x36 => {
-                let v = $this.zero_page_x();
-                $this.rol(v)
-            }
-            0x2e => {
-                let v = $this.absolute();
-                $this.rol(v)
-            }
-            0x3e => {
-                let v = $this.absolute_x();
-                $this.rol(v)
-            }
+//
+// Breakpoints and watchpoints
+//
 
-            0x6a => {
-                let v = $this.accumulator();
-                $this.ror(v)
-            }
-            0x66 => {
-                let v = $this.zero_page();
-                $this.ror(v)
-            }
-            0x76 => {
-                let v = $this.zero_page_x();
-                $this.ror(v)
-            }
-            0x6e => {
-                let v = $this.absolute();
-                $this.ror(v)
-            }
-            0x7e => {
-                let v = $this.absolute_x();
-                $this.ror(v)
-            }
+/// The kinds of memory access a watchpoint stops on.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Access {
+    /// Loads only
+    Read,
+    /// Stores only
+    Write,
+    /// Loads and stores
+    ReadWrite,
+}
 
-            0x0a => {
-                let v = $this.accumulator();
-                $this.asl(v)
-            }
-            0x06 => {
-                let v = $this.zero_page();
-                $this.asl(v)
-            }
-            0x16 => {
-                let v = $this.zero_page_x();
-                $this.asl(v)
-            }
-            0x0e => {
-                let v = $this.absolute();
-                $this.asl(v)
-            }
-            0x1e => {
-                let v = $this.absolute_x();
-                $this.asl(v)
-            }
+/// A range of addresses that stops the CPU when an instruction accesses it. Accesses made on
+/// the CPU's behalf, such as OAM DMA, count too; the disassembler's and debugger's don't.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Watchpoint {
+    pub start: u16,
+    /// The last address watched, inclusive
+    pub end: u16,
+    pub access: Access,
+}
 
-            0x4a => {
-                let v = $this.accumulator();
-                $this.lsr(v)
-            }
-            0x46 => {
-                let v = $this.zero_page();
-                $this.lsr(v)
-            }
-            0x56 => {
-                let v = $this.zero_page_x();
-                $this.lsr(v)
-            }
-            0x4e => {
-                let v = $this.absolute();
-                $this.lsr(v)
-            }
-            0x5e => {
-                let v = $this.absolute_x();
-                $this.lsr(v)
-            }
+impl Watchpoint {
+    /// Watches a single address.
+    pub fn at(addr: u16, access: Access) -> Watchpoint {
+        Watchpoint {
+            start: addr,
+            end: addr,
+            access: access,
+        }
+    }
 
-            // Increments and decrements
-            0xe6 => {
-                let v = $this.zero_page();
-                $this.inc(v)
-            }
-            0xf6 => {
-                let v = $this.zero_page_x();
-                $this.inc(v)
-            }
-            0xee => {
-                let v = $this.absolute();
-                $this.inc(v)
-            }
-            0xfe => {
-                let v = $this.absolute_x();
-                $this.inc(v)
-            }
+    fn matches(&self, addr: u16, write: bool) -> bool {
+        let access = match self.access {
+            Access::Read => !write,
+            Access::Write => write,
+            Access::ReadWrite => true,
+        };
+        access && addr >= self.start && addr <= self.end
+    }
+}
 
-            0xc6 => {
-                let v = $this.zero_page();
-                $this.dec(v)
-            }
-            0xd6 => {
-                let v = $this.zero_page_x();
-                $this.dec(v)
-            }
-            0xce => {
-                let v = $this.absolute();
-                $this.dec(v)
-            }
-            0xde => {
-                let v = $this.absolute_x();
-                $this.dec(v)
-            }
+/// Why the CPU stopped.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Break {
+    /// The CPU reached a breakpoint. The instruction there hasn't run yet.
+    Breakpoint(u16),
+    /// An instruction read a watched address. The instruction has finished.
+    Read(u16),
+    /// An instruction wrote the value to a watched address. The instruction has finished.
+    Write(u16, u8),
+}
 
-            0xe8 => $this.inx(),
-            0xca => $this.dex(),
-            0xc8 => $this.iny(),
-            0x88 => $this.dey(),
-
-            // Register moves
-            0xaa => $this.tax(),
-            0xa8 => $this.tay(),
-            0x8a => $this.txa(),
-            0x98 => $this.tya(),
-            0x9a => $this.txs(),
-            0xba => $this.tsx(),
-
-            // Flag operations
-            0x18 => $this.clc(),
-            0x38 => $this.sec(),
-            0x58 => $this.cli(),
-            0x78 => $this.sei(),
-            0xb8 => $this.clv(),
-            0xd8 => $this.cld(),
-            0xf8 => $this.sed(),
-
-            // Branches
-            0x10 => $this.bpl(),
-            0x30 => $this.bmi(),
-            0x50 => $this.bvc(),
-            0x70 => $this.bvs(),
-            0x90 => $this.bcc(),
-            0xb0 => $this.bcs(),
-            0xd0 => $this.bne(),
-            0xf0 => $this.beq(),
-
-            // Jumps
-            0x4c => $this.jmp(),
-            0x6c => $this.jmpi(),
-
-            // Procedure calls
-            0x20 => $this.jsr(),
-            0x60 => $this.rts(),
-            0x00 => $this.brk(),
-            0x40 => $this.rti(),
-
-            // Stack operations
-            0x48 => $this.pha(),
-            0x68 => $this.pla(),
-            0x08 => $this.php(),
-            0x28 => $this.plp(),
-
-            // No operation
-            0xea => $this.nop(),
-
-            _ => panic!("unimplemented or illegal instruction: {}", $op),
+impl fmt::Display for Break {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Break::Breakpoint(pc) => write!(f, "Breakpoint at ${:04X}", pc),
+            Break::Read(addr) => write!(f, "Read from ${:04X}", addr),
+            Break::Write(addr, val) => write!(f, "Wrote ${:02X} to ${:04X}", val, addr),
         }
-    };
+    }
 }
 
 //
@@ -687,15 +702,55 @@ pub struct Cpu<M: Mem> {
     pub cy: Cycles,
     regs: Regs,
     pub mem: M,
+
+    /// Set when NMI is raised. NMI is edge-triggered, so it stays pending until it's serviced.
+    nmi_pending: bool,
+    /// The IRQ line. IRQ is level-triggered: it's serviced whenever it's polled while a device
+    /// holds the line and the I flag is clear.
+    irq: IrqLine,
+    /// The I flag as of the last interrupt poll. CLI, SEI and PLP change the flag after the poll
+    /// at the end of the instruction, so their effect on IRQs is delayed by one instruction.
+    irq_masked: bool,
+    /// True if the last step was BRK or an IRQ, whose vector an NMI can take over.
+    hijackable: bool,
+    /// Set by the indexed addressing modes if indexing carried into the high byte of the address.
+    page_crossed: bool,
+    /// Set by the JAM opcodes. The CPU does nothing, not even service interrupts, until it's reset.
+    halted: bool,
+    /// If true, the CPU ticks the memory map once per cycle, before each access, instead of
+    /// leaving it to catch up after every instruction. See `set_cycle_stepped`.
+    cycle_stepped: bool,
+
+    breakpoints: Vec<u16>,
+    watchpoints: Vec<Watchpoint>,
+    /// The first breakpoint or watchpoint hit since `take_break` was last called.
+    pending_break: Option<Break>,
+    /// The breakpoint the CPU last stopped at, which the next step runs past rather than
+    /// stopping at again.
+    resume_pc: Option<u16>,
+    /// Where instructions are logged, if tracing is on.
+    tracer: Option<Tracer>,
 }
 
 /// The CPU implements Mem so that it can handle writes to the DMA register.
 impl<M: Mem> Mem for Cpu<M> {
     fn loadb(&mut self, addr: u16) -> u8 {
+        if self.cycle_stepped {
+            self.tick();
+        }
+        if !self.watchpoints.is_empty() {
+            self.watch(addr, None);
+        }
         self.mem.loadb(addr)
     }
 
     fn storeb(&mut self, addr: u16, val: u8) {
+        if self.cycle_stepped {
+            self.tick();
+        }
+        if !self.watchpoints.is_empty() {
+            self.watch(addr, Some(val));
+        }
         // Handle OAM_DMA.
         if addr == 0x4014 {
             self.dma(val)
@@ -703,56 +758,155 @@ impl<M: Mem> Mem for Cpu<M> {
             self.mem.storeb(addr, val)
         }
     }
+
+    fn peekb(&self, addr: u16) -> u8 {
+        self.mem.peekb(addr)
+    }
 }
 
 impl<M: Mem + Save> Save for Cpu<M> {
     fn save(&mut self, fd: &mut File) {
         self.cy.save(fd);
         self.regs.save(fd);
+        self.irq.save(fd);
         self.mem.save(fd);
     }
 
     fn load(&mut self, fd: &mut File) {
         self.cy.load(fd);
         self.regs.load(fd);
+        self.irq.load(fd);
         self.mem.load(fd);
+        self.halted = false;
     }
 }
 
 impl<M: Mem> Cpu<M> {
     // Debugging
-    #[cfg(cpuspew)]
+    /// Logs the instruction about to run, if tracing is on and its address is in range.
     fn trace(&mut self) {
-        let mut disassembler = Disassembler {
-            pc: self.regs.pc,
-            mem: &mut self.mem,
+        let pc = self.regs.pc;
+        match self.tracer {
+            Some(ref tracer) if tracer.wants(pc) => {}
+            _ => return,
+        }
+
+        let (disassembly, len) = {
+            let mut disassembler = Disassembler {
+                pc: pc,
+                mem: &self.mem,
+                regs: Some(self.regs),
+            };
+            let disassembly = disassembler.disassemble();
+            (disassembly, disassembler.pc.wrapping_sub(pc))
         };
-        println!(
-            "{:04X} {:20s} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
-            self.regs.pc as usize,
-            disassembler.disassemble(),
-            self.regs.a as usize,
-            self.regs.x as usize,
-            self.regs.y as usize,
-            self.regs.flags as usize,
-            self.regs.s as usize,
-            self.cy as usize
-        );
+        let bytes: Vec<u8> = (0..len)
+            .map(|offset| self.mem.peekb(pc.wrapping_add(offset)))
+            .collect();
+        let registers = self.registers_string();
+
+        let result = match self.tracer {
+            Some(ref mut tracer) => tracer.log(pc, &bytes, &disassembly, &registers, self.cy),
+            None => Ok(()),
+        };
+        if let Err(err) = result {
+            println!("Stopped tracing: {}", err);
+            self.tracer = None;
+        }
     }
-    #[cfg(not(cpuspew))]
-    fn trace(&mut self) {}
 
-    // Performs DMA to the OAMDATA ($2004) register.
+    // FCEUX savestates
+    /// Restores the registers and RAM from an FCEUX CPU section. Returns a description of each
+    /// entry that was missing.
+    pub fn import_fceux(&mut self, section: &FceuxSection) -> Vec<String> {
+        let mut warnings = vec![];
+        match section.get_u16("PC") {
+            Some(pc) => self.regs.pc = pc,
+            None => warnings.push("CPU entry PC is missing".to_string()),
+        }
+        for &name in ["A", "P", "X", "Y", "S"].iter() {
+            let val = match section.get_u8(name) {
+                Some(val) => val,
+                None => {
+                    warnings.push(format!("CPU entry {} is missing", name));
+                    continue;
+                }
+            };
+            match name {
+                "A" => self.regs.a = val,
+                "P" => self.regs.flags = val,
+                "X" => self.regs.x = val,
+                "Y" => self.regs.y = val,
+                _ => self.regs.s = val,
+            }
+        }
+        match section.get_exact("RAM", 0x800) {
+            Some(ram) => {
+                for (addr, &val) in ram.iter().enumerate() {
+                    self.mem.storeb(addr as u16, val);
+                }
+            }
+            None => warnings.push("CPU entry RAM is missing".to_string()),
+        }
+        warnings
+    }
+
+    /// Returns the registers and RAM as an FCEUX CPU section.
+    pub fn export_fceux(&mut self) -> FceuxSection {
+        let mut section = FceuxSection::new(fceux::SECTION_CPU);
+        section.put_u16("PC", self.regs.pc);
+        section.put("A", &[self.regs.a]);
+        section.put("P", &[self.regs.flags]);
+        section.put("X", &[self.regs.x]);
+        section.put("Y", &[self.regs.y]);
+        section.put("S", &[self.regs.s]);
+        let ram: Vec<u8> = (0..0x800).map(|addr| self.mem.loadb(addr)).collect();
+        section.put("RAM", &ram);
+        section
+    }
+
+    // Performs DMA to the OAMDATA ($2004) register. The CPU is halted for a cycle, plus another
+    // if the DMA starts on an odd cycle so that reads line up with even ones, and then for a read
+    // and a write per byte: 513 or 514 cycles in all. `self.cy` already counts the write to
+    // $4014, so the stall is simply added on, and the PPU and APU catch up with it along with the
+    // rest of the instruction. In cycle-stepped mode the reads and writes tick for themselves.
     fn dma(&mut self, hi_addr: u8) {
         let start = (hi_addr as u16) << 8;
 
+        let alignment = if self.cy % 2 == 1 { 2 } else { 1 };
+        self.idle(alignment);
         for addr in start..start + 256 {
             let val = self.loadb(addr);
             self.storeb(0x2004, val);
+        }
+        if !self.cycle_stepped {
+            self.cy += 512;
+        }
+    }
 
-            // FIXME: The last address sometimes takes 1 cycle, sometimes 2 -- NESdev isn't very
-            // clear on this.
-            self.cy += 2;
+    // Cycle accounting
+    /// Runs the rest of the system for one cycle and latches any NMI it raises.
+    fn tick(&mut self) {
+        self.cy += 1;
+        if self.mem.tick(self.cy) {
+            self.nmi();
+        }
+    }
+    /// Spends cycles that have no memory access of their own.
+    fn idle(&mut self, cycles: Cycles) {
+        if self.cycle_stepped {
+            for _ in 0..cycles {
+                self.tick();
+            }
+        } else {
+            self.cy += cycles;
+        }
+    }
+    /// Idles until the cycle count reaches `cy`, if it hasn't already.
+    fn idle_until(&mut self, cy: Cycles) {
+        if self.cy < cy {
+            let cycles = cy - self.cy;
+            self.idle(cycles);
         }
     }
 
@@ -773,31 +927,26 @@ impl<M: Mem> Cpu<M> {
         val
     }
 
-    // Stack helpers
+    // Stack helpers. The stack is page 1, and the stack pointer wraps around within it.
     fn pushb(&mut self, val: u8) {
         let s = self.regs.s;
-        self.storeb(0x100 + s as u16, val);
-        self.regs.s -= 1;
+        self.storeb(0x100 | s as u16, val);
+        self.regs.s = s.wrapping_sub(1);
     }
+    /// Pushes the high byte first, so that the word is little-endian on the stack.
     fn pushw(&mut self, val: u16) {
-        // FIXME: Is this correct? FCEU has two self.storeb()s here. Might have different
-        // semantics...
-        let s = self.regs.s;
-        self.storew(0x100 + (s - 1) as u16, val);
-        self.regs.s -= 2;
+        self.pushb((val >> 8) as u8);
+        self.pushb((val & 0xff) as u8);
     }
     fn popb(&mut self) -> u8 {
-        let s = self.regs.s;
-        let val = self.loadb(0x100 + s as u16 + 1);
-        self.regs.s += 1;
-        val
+        let s = self.regs.s.wrapping_add(1);
+        self.regs.s = s;
+        self.loadb(0x100 | s as u16)
     }
     fn popw(&mut self) -> u16 {
-        // FIXME: See comment in pushw().
-        let s = self.regs.s;
-        let val = self.loadw(0x100 + s as u16 + 1);
-        self.regs.s += 2;
-        val
+        let lo = self.popb() as u16;
+        let hi = self.popb() as u16;
+        lo | hi << 8
     }
 
     // Flag helpers
@@ -835,12 +984,12 @@ impl<M: Mem> Cpu<M> {
     }
     fn zero_page_x(&mut self) -> MemoryAddressingMode {
         MemoryAddressingMode {
-            val: (self.loadb_bump_pc() + self.regs.x) as u16,
+            val: self.loadb_bump_pc().wrapping_add(self.regs.x) as u16,
         }
     }
     fn zero_page_y(&mut self) -> MemoryAddressingMode {
         MemoryAddressingMode {
-            val: (self.loadb_bump_pc() + self.regs.y) as u16,
+            val: self.loadb_bump_pc().wrapping_add(self.regs.y) as u16,
         }
     }
     fn absolute(&mut self) -> MemoryAddressingMode {
@@ -849,25 +998,42 @@ impl<M: Mem> Cpu<M> {
         }
     }
     fn absolute_x(&mut self) -> MemoryAddressingMode {
-        MemoryAddressingMode {
-            val: self.loadw_bump_pc() + self.regs.x as u16,
-        }
+        let base = self.loadw_bump_pc();
+        let x = self.regs.x;
+        self.index(base, x)
     }
     fn absolute_y(&mut self) -> MemoryAddressingMode {
+        let base = self.loadw_bump_pc();
+        let y = self.regs.y;
+        self.index(base, y)
+    }
+    fn indirect(&mut self) -> MemoryAddressingMode {
+        let addr = self.loadw_bump_pc();
+
+        // Replicate the famous CPU bug...
+        let lo = self.loadb(addr);
+        let hi = self.loadb((addr & 0xff00) | (addr.wrapping_add(1) & 0x00ff));
+
         MemoryAddressingMode {
-            val: self.loadw_bump_pc() + self.regs.y as u16,
+            val: (hi as u16) << 8 | lo as u16,
         }
     }
     fn indexed_indirect_x(&mut self) -> MemoryAddressingMode {
         let val = self.loadb_bump_pc();
         let x = self.regs.x;
-        let addr = self.loadw_zp(val + x);
+        let addr = self.loadw_zp(val.wrapping_add(x));
         MemoryAddressingMode { val: addr }
     }
     fn indirect_indexed_y(&mut self) -> MemoryAddressingMode {
         let val = self.loadb_bump_pc();
         let y = self.regs.y;
-        let addr = self.loadw_zp(val) + y as u16;
+        let base = self.loadw_zp(val);
+        self.index(base, y)
+    }
+    /// Adds an index register to an address, noting whether that crossed a page.
+    fn index(&mut self, base: u16, index: u8) -> MemoryAddressingMode {
+        let addr = base.wrapping_add(index as u16);
+        self.page_crossed = (base ^ addr) & 0xff00 != 0;
         MemoryAddressingMode { val: addr }
     }
 
@@ -907,6 +1073,10 @@ impl<M: Mem> Cpu<M> {
     #[inline(always)]
     fn adc<AM: AddressingMode<M>>(&mut self, am: AM) {
         let val = am.load(self);
+        self.adc_val(val)
+    }
+    #[inline(always)]
+    fn adc_val(&mut self, val: u8) {
         let mut result = self.regs.a as u32 + val as u32;
         if self.get_flag(CARRY_FLAG) {
             result += 1;
@@ -925,6 +1095,10 @@ impl<M: Mem> Cpu<M> {
     #[inline(always)]
     fn sbc<AM: AddressingMode<M>>(&mut self, am: AM) {
         let val = am.load(self);
+        self.sbc_val(val)
+    }
+    #[inline(always)]
+    fn sbc_val(&mut self, val: u8) {
         let a = self.regs.a;
         let mut result = (Wrapping(a as u32) - Wrapping(val as u32)).0;
         if !self.get_flag(CARRY_FLAG) {
@@ -945,6 +1119,9 @@ impl<M: Mem> Cpu<M> {
     // Comparisons
     fn cmp_base<AM: AddressingMode<M>>(&mut self, x: u8, am: AM) {
         let y = am.load(self);
+        self.compare(x, y)
+    }
+    fn compare(&mut self, x: u8, y: u8) {
         let result = (Wrapping(x as u32) - Wrapping(y as u32)).0;
         self.set_flag(CARRY_FLAG, (result & 0x100) == 0);
         let _ = self.set_zn(result as u8);
@@ -984,7 +1161,7 @@ impl<M: Mem> Cpu<M> {
     }
 
     // Shifts and rotates
-    fn shl_base<AM: AddressingMode<M>>(&mut self, lsb: bool, am: AM) {
+    fn shl_base<AM: AddressingMode<M>>(&mut self, lsb: bool, am: AM) -> u8 {
         let val = am.load(self);
         let new_carry = (val & 0x80) != 0;
         let mut result = val << 1;
@@ -992,10 +1169,11 @@ impl<M: Mem> Cpu<M> {
             result |= 1;
         }
         self.set_flag(CARRY_FLAG, new_carry);
-        let val = self.set_zn(result as u8);
-        am.store(self, val)
+        let val = self.set_zn(result);
+        am.store(self, val);
+        val
     }
-    fn shr_base<AM: AddressingMode<M>>(&mut self, msb: bool, am: AM) {
+    fn shr_base<AM: AddressingMode<M>>(&mut self, msb: bool, am: AM) -> u8 {
         let val = am.load(self);
         let new_carry = (val & 0x1) != 0;
         let mut result = val >> 1;
@@ -1003,22 +1181,23 @@ impl<M: Mem> Cpu<M> {
             result |= 0x80;
         }
         self.set_flag(CARRY_FLAG, new_carry);
-        let val = self.set_zn(result as u8);
-        am.store(self, val)
+        let val = self.set_zn(result);
+        am.store(self, val);
+        val
     }
     fn rol<AM: AddressingMode<M>>(&mut self, am: AM) {
         let val = self.get_flag(CARRY_FLAG);
-        self.shl_base(val, am)
+        self.shl_base(val, am);
     }
     fn ror<AM: AddressingMode<M>>(&mut self, am: AM) {
         let val = self.get_flag(CARRY_FLAG);
-        self.shr_base(val, am)
+        self.shr_base(val, am);
     }
     fn asl<AM: AddressingMode<M>>(&mut self, am: AM) {
-        self.shl_base(false, am)
+        self.shl_base(false, am);
     }
     fn lsr<AM: AddressingMode<M>>(&mut self, am: AM) {
-        self.shr_base(false, am)
+        self.shr_base(false, am);
     }
 
     // Increments and decrements
@@ -1098,65 +1277,72 @@ impl<M: Mem> Cpu<M> {
     }
 
     // Branches
-    fn bra_base(&mut self, cond: bool) {
+    /// Branches if `cond` holds. Returns the cycles the branch took beyond the two in the cycle
+    /// table: one if it was taken, and another if it went to a different page.
+    fn bra_base(&mut self, cond: bool) -> Cycles {
         let disp = self.loadb_bump_pc() as i8;
-        if cond {
-            self.regs.pc = (self.regs.pc as i32 + disp as i32) as u16;
+        if !cond {
+            return 0;
+        }
+        let pc = self.regs.pc;
+        self.regs.pc = (pc as i32 + disp as i32) as u16;
+        if (pc ^ self.regs.pc) & 0xff00 != 0 {
+            2
+        } else {
+            1
         }
     }
     fn bpl(&mut self) {
         let flag = !self.get_flag(NEGATIVE_FLAG);
-        self.bra_base(flag)
+        let cycles = self.bra_base(flag);
+        self.idle(cycles)
     }
     fn bmi(&mut self) {
         let flag = self.get_flag(NEGATIVE_FLAG);
-        self.bra_base(flag)
+        let cycles = self.bra_base(flag);
+        self.idle(cycles)
     }
     fn bvc(&mut self) {
         let flag = !self.get_flag(OVERFLOW_FLAG);
-        self.bra_base(flag)
+        let cycles = self.bra_base(flag);
+        self.idle(cycles)
     }
     fn bvs(&mut self) {
         let flag = self.get_flag(OVERFLOW_FLAG);
-        self.bra_base(flag)
+        let cycles = self.bra_base(flag);
+        self.idle(cycles)
     }
     fn bcc(&mut self) {
         let flag = !self.get_flag(CARRY_FLAG);
-        self.bra_base(flag)
+        let cycles = self.bra_base(flag);
+        self.idle(cycles)
     }
     fn bcs(&mut self) {
         let flag = self.get_flag(CARRY_FLAG);
-        self.bra_base(flag)
+        let cycles = self.bra_base(flag);
+        self.idle(cycles)
     }
     fn bne(&mut self) {
         let flag = !self.get_flag(ZERO_FLAG);
-        self.bra_base(flag)
+        let cycles = self.bra_base(flag);
+        self.idle(cycles)
     }
     fn beq(&mut self) {
         let flag = self.get_flag(ZERO_FLAG);
-        self.bra_base(flag)
+        let cycles = self.bra_base(flag);
+        self.idle(cycles)
     }
 
     // Jumps
-    fn jmp(&mut self) {
-        self.regs.pc = self.loadw_bump_pc()
-    }
-    fn jmpi(&mut self) {
-        let addr = self.loadw_bump_pc();
-
-        // Replicate the famous CPU bug...
-        let lo = self.loadb(addr);
-        let hi = self.loadb((addr & 0xff00) | ((addr + 1) & 0x00ff));
-
-        self.regs.pc = (hi as u16) << 8 | lo as u16;
+    fn jmp(&mut self, am: MemoryAddressingMode) {
+        self.regs.pc = *am
     }
 
     // Procedure calls
-    fn jsr(&mut self) {
-        let addr = self.loadw_bump_pc();
+    fn jsr(&mut self, am: MemoryAddressingMode) {
         let pc = self.regs.pc;
         self.pushw(pc - 1);
-        self.regs.pc = addr;
+        self.regs.pc = *am;
     }
     fn rts(&mut self) {
         self.regs.pc = self.popw() + 1
@@ -1164,8 +1350,9 @@ impl<M: Mem> Cpu<M> {
     fn brk(&mut self) {
         let pc = self.regs.pc;
         self.pushw(pc + 1);
+        // The pushed B flag is how handlers tell BRK apart from IRQ.
         let flags = self.regs.flags;
-        self.pushb(flags); // FIXME: FCEU sets BREAK_FLAG and U_FLAG here, why?
+        self.pushb(flags | BREAK_FLAG);
         self.set_flag(IRQ_FLAG, true);
         self.regs.pc = self.loadw(BRK_VECTOR);
     }
@@ -1196,37 +1383,403 @@ impl<M: Mem> Cpu<M> {
     // No operation
     fn nop(&mut self) {}
 
-    // The main fetch-and-decode routine
+    //
+    // Unofficial instructions
+    //
+    // These are side effects of how the 6502 decodes opcodes. Several games and most test ROMs
+    // rely on them. See http://wiki.nesdev.com/w/index.php/Programming_with_unofficial_opcodes
+    //
+
+    // Loads and stores
+    fn lax<AM: AddressingMode<M>>(&mut self, am: AM) {
+        let val = am.load(self);
+        self.regs.a = val;
+        self.regs.x = self.set_zn(val)
+    }
+    fn lxa<AM: AddressingMode<M>>(&mut self, am: AM) {
+        // Unstable: the value ORed into A varies between chips. $EE is the usual one.
+        let val = (self.regs.a | 0xee) & am.load(self);
+        self.regs.a = val;
+        self.regs.x = self.set_zn(val)
+    }
+    fn las<AM: AddressingMode<M>>(&mut self, am: AM) {
+        let val = am.load(self) & self.regs.s;
+        self.regs.a = val;
+        self.regs.s = val;
+        self.regs.x = self.set_zn(val)
+    }
+    fn sax<AM: AddressingMode<M>>(&mut self, am: AM) {
+        let val = self.regs.a & self.regs.x;
+        am.store(self, val)
+    }
+    /// Stores `val` ANDed with one more than the high byte of the address before it was indexed.
+    /// If indexing crossed a page, that value replaces the high byte of the address, too.
+    fn store_and_high(&mut self, am: MemoryAddressingMode, index: u8, val: u8) {
+        let addr = *am;
+        let base = addr.wrapping_sub(index as u16);
+        let val = val & ((base >> 8) as u8).wrapping_add(1);
+        let addr = if (base ^ addr) & 0xff00 != 0 {
+            (val as u16) << 8 | (addr & 0xff)
+        } else {
+            addr
+        };
+        self.storeb(addr, val)
+    }
+    fn sha(&mut self, am: MemoryAddressingMode) {
+        let (val, y) = (self.regs.a & self.regs.x, self.regs.y);
+        self.store_and_high(am, y, val)
+    }
+    fn shx(&mut self, am: MemoryAddressingMode) {
+        let (val, y) = (self.regs.x, self.regs.y);
+        self.store_and_high(am, y, val)
+    }
+    fn shy(&mut self, am: MemoryAddressingMode) {
+        let (val, x) = (self.regs.y, self.regs.x);
+        self.store_and_high(am, x, val)
+    }
+    fn tas(&mut self, am: MemoryAddressingMode) {
+        self.regs.s = self.regs.a & self.regs.x;
+        let (val, y) = (self.regs.s, self.regs.y);
+        self.store_and_high(am, y, val)
+    }
+
+    // Read-modify-write instructions, which combine a shift, rotate, increment or decrement in
+    // memory with an operation on the accumulator
+    fn slo<AM: AddressingMode<M>>(&mut self, am: AM) {
+        let val = self.shl_base(false, am) | self.regs.a;
+        self.regs.a = self.set_zn(val)
+    }
+    fn rla<AM: AddressingMode<M>>(&mut self, am: AM) {
+        let carry = self.get_flag(CARRY_FLAG);
+        let val = self.shl_base(carry, am) & self.regs.a;
+        self.regs.a = self.set_zn(val)
+    }
+    fn sre<AM: AddressingMode<M>>(&mut self, am: AM) {
+        let val = self.shr_base(false, am) ^ self.regs.a;
+        self.regs.a = self.set_zn(val)
+    }
+    fn rra<AM: AddressingMode<M>>(&mut self, am: AM) {
+        let carry = self.get_flag(CARRY_FLAG);
+        let val = self.shr_base(carry, am);
+        self.adc_val(val)
+    }
+    fn dcp<AM: AddressingMode<M>>(&mut self, am: AM) {
+        let val = am.load(self).wrapping_sub(1);
+        am.store(self, val);
+        let a = self.regs.a;
+        self.compare(a, val)
+    }
+    fn isb<AM: AddressingMode<M>>(&mut self, am: AM) {
+        let val = am.load(self).wrapping_add(1);
+        am.store(self, val);
+        self.sbc_val(val)
+    }
+
+    // Immediate instructions
+    fn anc<AM: AddressingMode<M>>(&mut self, am: AM) {
+        self.and(am);
+        let negative = self.get_flag(NEGATIVE_FLAG);
+        self.set_flag(CARRY_FLAG, negative)
+    }
+    fn alr<AM: AddressingMode<M>>(&mut self, am: AM) {
+        self.and(am);
+        self.lsr(AccumulatorAddressingMode)
+    }
+    fn arr<AM: AddressingMode<M>>(&mut self, am: AM) {
+        self.and(am);
+        self.ror(AccumulatorAddressingMode);
+        let a = self.regs.a;
+        self.set_flag(CARRY_FLAG, (a & 0x40) != 0);
+        self.set_flag(OVERFLOW_FLAG, ((a >> 6) ^ (a >> 5)) & 1 != 0)
+    }
+    fn axs<AM: AddressingMode<M>>(&mut self, am: AM) {
+        let val = am.load(self);
+        let a_and_x = self.regs.a & self.regs.x;
+        self.compare(a_and_x, val);
+        self.regs.x = a_and_x.wrapping_sub(val)
+    }
+    fn xaa<AM: AddressingMode<M>>(&mut self, am: AM) {
+        // Unstable, like LXA.
+        let val = (self.regs.a | 0xee) & self.regs.x & am.load(self);
+        self.regs.a = self.set_zn(val)
+    }
+
+    // No-ops
+    fn nop_read<AM: AddressingMode<M>>(&mut self, am: AM) {
+        am.load(self);
+    }
+
+    // Halts the CPU, leaving the program counter on the offending opcode.
+    fn jam(&mut self) {
+        self.regs.pc -= 1;
+        self.halted = true;
+    }
+
+    // The main fetch-and-decode routine. Interrupts polled at the end of the last instruction are
+    // serviced instead of running an instruction.
     pub fn step(&mut self) {
+        // Only NMIs raised between this step and the next can take over BRK's or an IRQ's vector.
+        self.hijackable = false;
+
+        // Pay for any cycles the APU stole with DMA while the last instruction ran. The rest of the
+        // system runs on meanwhile, so the main loop catches it up over them like any others.
+        let stall = self.mem.take_stall();
+        self.idle(stall);
+
+        if self.halted {
+            // Let the rest of the system run on regardless.
+            self.idle(1);
+            return;
+        }
+
+        if self.nmi_pending {
+            self.nmi_pending = false;
+            self.interrupt(NMI_VECTOR);
+            self.hijackable = false;
+            return;
+        }
+        if self.irq.asserted() && !self.irq_masked {
+            self.interrupt(BRK_VECTOR);
+            self.hijackable = true;
+            return;
+        }
+
         self.trace();
 
+        let pc = self.regs.pc;
+        if self.resume_pc.take() != Some(pc) && self.breakpoints.contains(&pc) {
+            self.stop(Break::Breakpoint(pc));
+            self.resume_pc = Some(pc);
+            return;
+        }
+
+        let irq_flag = self.get_flag(IRQ_FLAG);
+        let start = self.cy;
         let op = self.loadb_bump_pc();
-        decode_op!(op, self);
 
-        self.cy += CYCLE_TABLE[op as usize] as Cycles;
+        // Counted up front, so that anything the instruction adds (such as a DMA stall) starts at
+        // the end of the instruction. In cycle-stepped mode, the cycles the instruction spends
+        // without touching memory are made up at the end instead.
+        let cycles = OPCODES[op as usize].cycles as Cycles;
+        if !self.cycle_stepped {
+            self.cy += cycles;
+        }
+        self.page_crossed = false;
+        (Self::HANDLERS[op as usize])(self);
+        self.idle_until(start + cycles);
+        if self.page_crossed && OPCODES[op as usize].page_penalty() {
+            self.idle(1);
+        }
+
+        self.irq_masked = match op {
+            // PLP, CLI and SEI
+            0x28 | 0x58 | 0x78 => irq_flag,
+            _ => self.get_flag(IRQ_FLAG),
+        };
+        self.hijackable = op == 0x00;
+    }
+
+    /// Pushes the program counter and flags and jumps through the given vector, taking 7 cycles.
+    fn interrupt(&mut self, vector: u16) {
+        let start = self.cy;
+        let (pc, flags) = (self.regs.pc, self.regs.flags);
+        self.pushw(pc);
+        self.pushb(flags);
+        self.set_flag(IRQ_FLAG, true);
+        self.irq_masked = true;
+        self.regs.pc = self.loadw(vector);
+        self.idle_until(start + 7);
     }
 
     /// External interfaces
+    /// Turns the console on: the registers start out clear, and then the reset sequence runs.
+    pub fn power_on(&mut self) {
+        self.regs = Regs {
+            a: 0,
+            x: 0,
+            y: 0,
+            s: 0,
+            flags: 0x20,
+            pc: 0,
+        };
+        self.reset();
+    }
+
+    /// Presses the reset button. The reset sequence is an interrupt whose pushes don't write, so
+    /// it moves S down by 3 without touching the stack. It also sets I, and the reset line
+    /// silences the APU. A, X and Y are left alone.
     pub fn reset(&mut self) {
-        self.regs.pc = self.loadw(RESET_VECTOR);
+        self.halted = false;
+        self.nmi_pending = false;
+        self.regs.s = self.regs.s.wrapping_sub(3);
+        self.set_flag(IRQ_FLAG, true);
+        self.irq_masked = true;
+        self.mem.storeb(0x4015, 0);
+        self.regs.pc = self.mem.loadw(RESET_VECTOR);
+    }
+
+    /// Starts executing at `pc` instead of going through the reset vector, as test ROMs such as
+    /// nestest allow.
+    pub fn start_at(&mut self, pc: u16) {
+        self.halted = false;
+        self.regs.pc = pc;
+    }
+
+    /// The address of the next instruction.
+    pub fn pc(&self) -> u16 {
+        self.regs.pc
+    }
+
+    /// Returns a copy of the registers.
+    pub fn regs(&self) -> Regs {
+        self.regs
     }
 
+    /// Replaces the registers, for debuggers and test harnesses. The new program counter takes
+    /// effect from the next step.
+    pub fn set_regs(&mut self, regs: Regs) {
+        self.regs = regs;
+    }
+
+    /// The number of cycles the CPU has run since it was created.
+    pub fn cycles(&self) -> Cycles {
+        self.cy
+    }
+
+    /// Formats the other registers the way traces do: `A:00 X:00 Y:00 P:24 SP:FD`.
+    pub fn registers_string(&self) -> String {
+        format!(
+            "A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
+            self.regs.a, self.regs.x, self.regs.y, self.regs.flags, self.regs.s
+        )
+    }
+
+    /// If a JAM opcode has halted the CPU, returns its address.
+    pub fn halted(&self) -> Option<u16> {
+        if self.halted {
+            Some(self.regs.pc)
+        } else {
+            None
+        }
+    }
+
+    /// Signals an NMI, which is serviced after the current instruction. If the CPU has just
+    /// started servicing BRK or an IRQ, the NMI takes over its vector instead, as on hardware.
     pub fn nmi(&mut self) {
-        let (pc, flags) = (self.regs.pc, self.regs.flags);
-        self.pushw(pc);
-        self.pushb(flags);
-        self.regs.pc = self.loadw(NMI_VECTOR);
+        if self.hijackable {
+            self.hijackable = false;
+            self.regs.pc = self.mem.loadw(NMI_VECTOR);
+        } else {
+            self.nmi_pending = true;
+        }
+    }
+
+    /// The IRQ line. Devices assert and acknowledge IRQs on a clone of it.
+    pub fn irq_line(&self) -> &IrqLine {
+        &self.irq
+    }
+
+    /// Replaces the IRQ line with one shared with the devices that raise IRQs.
+    pub fn connect_irq(&mut self, irq: IrqLine) {
+        self.irq = irq;
+    }
+
+    /// Stops the CPU whenever it's about to run the instruction at `pc`.
+    pub fn add_breakpoint(&mut self, pc: u16) {
+        if !self.breakpoints.contains(&pc) {
+            self.breakpoints.push(pc);
+        }
+    }
+
+    /// Removes a breakpoint. Returns false if there wasn't one at `pc`.
+    pub fn remove_breakpoint(&mut self, pc: u16) -> bool {
+        let len = self.breakpoints.len();
+        self.breakpoints.retain(|&breakpoint| breakpoint != pc);
+        self.breakpoints.len() != len
+    }
+
+    pub fn breakpoints(&self) -> &[u16] {
+        &self.breakpoints
     }
 
-    pub fn irq(&mut self) {
-        if self.get_flag(IRQ_FLAG) {
+    /// Stops the CPU after any instruction that accesses the watched addresses.
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+    }
+
+    /// Removes a watchpoint. Returns false if there wasn't one like it.
+    pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|&other| other != watchpoint);
+        self.watchpoints.len() != len
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Removes every breakpoint and watchpoint.
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+        self.watchpoints.clear();
+    }
+
+    /// Returns the breakpoint or watchpoint the CPU has stopped at, if any, without clearing it.
+    /// Callers that step the CPU in a loop should check this after every step.
+    pub fn pending_break(&self) -> Option<Break> {
+        self.pending_break
+    }
+
+    /// Returns and clears the breakpoint or watchpoint the CPU has stopped at. Stepping again
+    /// carries on from where the CPU stopped, running the instruction at a breakpoint.
+    pub fn take_break(&mut self) -> Option<Break> {
+        self.pending_break.take()
+    }
+
+    /// Records a break, unless an earlier one hasn't been taken yet.
+    fn stop(&mut self, reason: Break) {
+        if self.pending_break.is_none() {
+            self.pending_break = Some(reason);
+        }
+    }
+
+    /// Stops the CPU if a watchpoint covers the access. `val` is the value stored, for stores.
+    fn watch(&mut self, addr: u16, val: Option<u8>) {
+        let write = val.is_some();
+        if !self.watchpoints.iter().any(|w| w.matches(addr, write)) {
             return;
         }
+        self.stop(match val {
+            Some(val) => Break::Write(addr, val),
+            None => Break::Read(addr),
+        });
+    }
 
-        let (pc, flags) = (self.regs.pc, self.regs.flags);
-        self.pushw(pc);
-        self.pushb(flags);
-        self.regs.pc = self.loadw(BRK_VECTOR);
+    /// Starts logging instructions to the given tracer, or stops if it's `None`. Returns the
+    /// tracer that was in use, so that it can be flushed or resumed later.
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) -> Option<Tracer> {
+        mem::replace(&mut self.tracer, tracer)
+    }
+
+    pub fn tracing(&self) -> bool {
+        self.tracer.is_some()
+    }
+
+    /// Whether the CPU is cycle-stepped.
+    pub fn cycle_stepped(&self) -> bool {
+        self.cycle_stepped
+    }
+
+    /// Switches between stepping a whole instruction at a time, after which the caller brings the
+    /// rest of the system up to date, and stepping a cycle at a time, where the CPU calls
+    /// `Mem::tick` before every memory access so that each access happens on its real cycle and
+    /// sees the PPU and APU as they are then. Cycles without a memory access, such as the
+    /// internal operations of implied instructions, are run at the end of the instruction.
+    /// Cycle-stepping is slower, and off by default.
+    pub fn set_cycle_stepped(&mut self, cycle_stepped: bool) {
+        self.cycle_stepped = cycle_stepped;
     }
 
     pub fn new(mem: M) -> Cpu<M> {
@@ -1234,6 +1787,334 @@ impl<M: Mem> Cpu<M> {
             cy: 0,
             regs: Regs::new(),
             mem: mem,
+
+            nmi_pending: false,
+            irq: IrqLine::new(),
+            irq_masked: true,
+            hijackable: false,
+            page_crossed: false,
+            halted: false,
+            cycle_stepped: false,
+
+            breakpoints: vec![],
+            watchpoints: vec![],
+            pending_break: None,
+            resume_pc: None,
+            tracer: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mem::{IrqSource, Ram};
+
+    use std::env;
+    use std::fs;
+
+    const START: u16 = 0x0400;
+
+    /// A CPU with nothing but RAM, about to run the program at $0400.
+    fn with_program(program: &[u8]) -> Cpu<Ram> {
+        let mut ram = Ram { val: [0; 0x800] };
+        ram.val[START as usize..START as usize + program.len()].copy_from_slice(program);
+        let mut cpu = Cpu::new(ram);
+        cpu.start_at(START);
+        cpu
+    }
+
+    #[test]
+    fn breakpoint_stops_before_the_instruction() {
+        // LDA #$42
+        let mut cpu = with_program(&[0xa9, 0x42]);
+        cpu.add_breakpoint(START);
+
+        cpu.step();
+        assert_eq!(cpu.pending_break(), Some(Break::Breakpoint(START)));
+        assert_eq!(cpu.pc(), START);
+        assert_eq!(cpu.regs().a, 0);
+        assert_eq!(cpu.cy, 0);
+
+        assert_eq!(cpu.take_break(), Some(Break::Breakpoint(START)));
+        cpu.step();
+        assert_eq!(cpu.pending_break(), None);
+        assert_eq!(cpu.pc(), START + 2);
+        assert_eq!(cpu.regs().a, 0x42);
+    }
+
+    #[test]
+    fn read_watchpoint_fires_on_loads_only() {
+        // STA $0200; LDA $0200
+        let mut cpu = with_program(&[0x8d, 0x00, 0x02, 0xad, 0x00, 0x02]);
+        cpu.add_watchpoint(Watchpoint::at(0x0200, Access::Read));
+
+        cpu.step();
+        assert_eq!(cpu.pending_break(), None);
+        cpu.step();
+        assert_eq!(cpu.take_break(), Some(Break::Read(0x0200)));
+        // The load has finished.
+        assert_eq!(cpu.pc(), START + 6);
+    }
+
+    #[test]
+    fn write_watchpoint_fires_on_stores_in_its_range() {
+        // LDA $0300; LDA #$99; STA $0302; STA $0300
+        let mut cpu = with_program(&[
+            0xad, 0x00, 0x03, 0xa9, 0x99, 0x8d, 0x02, 0x03, 0x8d, 0x00, 0x03,
+        ]);
+        cpu.add_watchpoint(Watchpoint {
+            start: 0x02ff,
+            end: 0x0301,
+            access: Access::Write,
+        });
+
+        for _ in 0..3 {
+            cpu.step();
+            assert_eq!(cpu.pending_break(), None);
+        }
+        cpu.step();
+        assert_eq!(cpu.take_break(), Some(Break::Write(0x0300, 0x99)));
+        assert_eq!(cpu.mem.val[0x0300], 0x99);
+    }
+
+    /// Runs the program one instruction at a time, returning the cycles each took.
+    fn run(cpu: &mut Cpu<Ram>, instructions: usize) -> Vec<Cycles> {
+        (0..instructions)
+            .map(|_| {
+                let start = cpu.cy;
+                cpu.step();
+                cpu.cy - start
+            })
+            .collect()
+    }
+
+    fn flags(cpu: &Cpu<Ram>) -> u8 {
+        cpu.regs().flags & (CARRY_FLAG | ZERO_FLAG | OVERFLOW_FLAG | NEGATIVE_FLAG)
+    }
+
+    #[test]
+    fn savestate_keeps_the_irq_sources() {
+        let mut saved = with_program(&[]);
+        saved.irq_line().assert(IrqSource::Mapper);
+        saved.irq_line().assert(IrqSource::Dmc);
+        let path = env::temp_dir().join("sprocketnes-cpu-irq-test");
+        saved.save(&mut File::create(&path).unwrap());
+
+        let mut restored = with_program(&[]);
+        let irq = IrqLine::new();
+        restored.connect_irq(irq.clone());
+        irq.assert(IrqSource::FrameCounter);
+        restored.load(&mut File::open(&path).unwrap());
+        let _ = fs::remove_file(&path);
+
+        assert!(irq.asserted_by(IrqSource::Mapper));
+        assert!(irq.asserted_by(IrqSource::Dmc));
+        assert!(!irq.asserted_by(IrqSource::FrameCounter));
+    }
+
+    #[test]
+    fn adc_sets_carry_and_overflow() {
+        let cases = [
+            (0x50, 0x50, false, 0xa0, OVERFLOW_FLAG | NEGATIVE_FLAG),
+            (0xff, 0x01, false, 0x00, CARRY_FLAG | ZERO_FLAG),
+            (
+                0x80,
+                0x80,
+                false,
+                0x00,
+                CARRY_FLAG | ZERO_FLAG | OVERFLOW_FLAG,
+            ),
+            (0x3f, 0x40, true, 0x80, OVERFLOW_FLAG | NEGATIVE_FLAG),
+            (0x01, 0x01, true, 0x03, 0),
+        ];
+        for &(a, val, carry, result, expected) in &cases {
+            // LDA #a; ADC #val
+            let mut cpu = with_program(&[0xa9, a, 0x69, val]);
+            let mut regs = cpu.regs();
+            regs.flags = if carry { CARRY_FLAG } else { 0 };
+            cpu.set_regs(regs);
+            run(&mut cpu, 2);
+            assert_eq!(cpu.regs().a, result, "{:02X} + {:02X}", a, val);
+            assert_eq!(flags(&cpu), expected, "{:02X} + {:02X}", a, val);
+        }
+    }
+
+    #[test]
+    fn sbc_and_cmp_set_borrow_as_clear_carry() {
+        // SEC; LDA #$50; SBC #$B0
+        let mut cpu = with_program(&[0x38, 0xa9, 0x50, 0xe9, 0xb0]);
+        run(&mut cpu, 3);
+        assert_eq!(cpu.regs().a, 0xa0);
+        assert_eq!(flags(&cpu), OVERFLOW_FLAG | NEGATIVE_FLAG);
+
+        // LDA #$40; CMP #$40; CMP #$41
+        let mut cpu = with_program(&[0xa9, 0x40, 0xc9, 0x40, 0xc9, 0x41]);
+        run(&mut cpu, 2);
+        assert_eq!(flags(&cpu), CARRY_FLAG | ZERO_FLAG);
+        run(&mut cpu, 1);
+        assert_eq!(flags(&cpu), NEGATIVE_FLAG);
+        assert_eq!(cpu.regs().a, 0x40);
+    }
+
+    #[test]
+    fn unofficial_opcodes_combine_their_halves() {
+        // LAX $10; DCP $11; ISB $12
+        let mut cpu = with_program(&[0xa7, 0x10, 0xc7, 0x11, 0xe7, 0x12]);
+        cpu.mem.val[0x10] = 0x80;
+        cpu.mem.val[0x11] = 0x81;
+        cpu.mem.val[0x12] = 0x0f;
+
+        run(&mut cpu, 1);
+        assert_eq!((cpu.regs().a, cpu.regs().x), (0x80, 0x80));
+        assert_eq!(flags(&cpu), NEGATIVE_FLAG);
+
+        run(&mut cpu, 1);
+        assert_eq!(cpu.mem.val[0x11], 0x80);
+        assert_eq!(flags(&cpu), CARRY_FLAG | ZERO_FLAG);
+
+        // $80 - $10, with the carry from DCP meaning no borrow
+        run(&mut cpu, 1);
+        assert_eq!(cpu.mem.val[0x12], 0x10);
+        assert_eq!(cpu.regs().a, 0x70);
+        assert_eq!(flags(&cpu), CARRY_FLAG | OVERFLOW_FLAG);
+    }
+
+    #[test]
+    fn reads_take_a_cycle_more_across_pages() {
+        #[rustfmt::skip]
+        let program = [
+            0xbd, 0xf0, 0x04,   // LDA $04F0,X
+            0x9d, 0xf0, 0x04,   // STA $04F0,X
+            0xbf, 0xf0, 0x04,   // LAX $04F0,Y
+            0xb1, 0x10,         // LDA ($10),Y
+            0x1e, 0xf0, 0x04,   // ASL $04F0,X
+        ];
+        for &cycle_stepped in &[false, true] {
+            for &(index, expected) in &[(0x0f, [4, 5, 4, 5, 7]), (0x10, [5, 5, 5, 6, 7])] {
+                let mut cpu = with_program(&program);
+                cpu.set_cycle_stepped(cycle_stepped);
+                cpu.mem.val[0x10] = 0xf0;
+                cpu.mem.val[0x11] = 0x04;
+                let mut regs = cpu.regs();
+                regs.x = index;
+                regs.y = index;
+                cpu.set_regs(regs);
+                assert_eq!(run(&mut cpu, 5), expected, "index {:02X}", index);
+            }
+        }
+    }
+
+    #[test]
+    fn branches_take_a_cycle_more_across_pages() {
+        // At $04F0: BNE +2; BNE +$0C, which lands on $0500
+        let mut program = vec![0xea; 0xf0];
+        program.extend_from_slice(&[0xd0, 0x00, 0xd0, 0x0c]);
+        let mut cpu = with_program(&program);
+        cpu.start_at(0x04f0);
+        assert_eq!(run(&mut cpu, 2), [3, 4]);
+        assert_eq!(cpu.pc(), 0x0500);
+
+        // BEQ, not taken
+        let mut cpu = with_program(&[0xf0, 0x10]);
+        assert_eq!(run(&mut cpu, 1), [2]);
+    }
+
+    #[test]
+    fn indirect_jmp_wraps_within_the_page() {
+        // JMP ($02FF)
+        let mut cpu = with_program(&[0x6c, 0xff, 0x02]);
+        cpu.mem.val[0x02ff] = 0x34;
+        cpu.mem.val[0x0200] = 0x12;
+        cpu.mem.val[0x0300] = 0x56;
+        assert_eq!(run(&mut cpu, 1), [5]);
+        assert_eq!(cpu.pc(), 0x1234);
+
+        // JMP ($FFFF) takes the high byte from $FF00, which RAM mirrors at $0700, not $0000.
+        let mut cpu = with_program(&[0x6c, 0xff, 0xff]);
+        cpu.mem.val[0x07ff] = 0x78;
+        cpu.mem.val[0x0700] = 0x06;
+        cpu.mem.val[0x0000] = 0x99;
+        run(&mut cpu, 1);
+        assert_eq!(cpu.pc(), 0x0678);
+    }
+
+    #[test]
+    fn zero_page_indexing_wraps_within_page_zero() {
+        // LDA $F0,X; LDA ($FF,X)
+        let mut cpu = with_program(&[0xb5, 0xf0, 0xa1, 0xff]);
+        let mut regs = cpu.regs();
+        regs.x = 0x20;
+        cpu.set_regs(regs);
+        cpu.mem.val[0x10] = 0x42;
+        // ($FF + $20) = $1F, which points at $0300.
+        cpu.mem.val[0x1f] = 0x00;
+        cpu.mem.val[0x20] = 0x03;
+        cpu.mem.val[0x0300] = 0x24;
+        run(&mut cpu, 1);
+        assert_eq!(cpu.regs().a, 0x42);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.regs().a, 0x24);
+
+        // LDA ($FF),Y reads the pointer's high byte from $00.
+        let mut cpu = with_program(&[0xb1, 0xff]);
+        cpu.mem.val[0xff] = 0x00;
+        cpu.mem.val[0x00] = 0x03;
+        cpu.mem.val[0x0300] = 0x5a;
+        run(&mut cpu, 1);
+        assert_eq!(cpu.regs().a, 0x5a);
+    }
+
+    /// RAM on a bus that steals `stall` cycles once, and notes the cycle each byte is read on.
+    struct StallingRam {
+        ram: Ram,
+        stall: u64,
+        cy: u64,
+        reads: Vec<(u16, u64)>,
+    }
+
+    impl Mem for StallingRam {
+        fn loadb(&mut self, addr: u16) -> u8 {
+            self.reads.push((addr, self.cy));
+            self.ram.loadb(addr)
+        }
+        fn storeb(&mut self, addr: u16, val: u8) {
+            self.ram.storeb(addr, val)
+        }
+        fn tick(&mut self, cy: u64) -> bool {
+            self.cy = cy;
+            false
+        }
+        fn take_stall(&mut self) -> u64 {
+            mem::replace(&mut self.stall, 0)
+        }
+    }
+
+    #[test]
+    fn stalls_are_paid_before_the_next_instruction() {
+        for &cycle_stepped in &[false, true] {
+            // NOP; NOP
+            let mut ram = Ram { val: [0; 0x800] };
+            ram.val[START as usize] = 0xea;
+            ram.val[START as usize + 1] = 0xea;
+            let mut cpu = Cpu::new(StallingRam {
+                ram: ram,
+                stall: 3,
+                cy: 0,
+                reads: vec![],
+            });
+            cpu.start_at(START);
+            cpu.set_cycle_stepped(cycle_stepped);
+
+            cpu.step();
+            assert_eq!(cpu.cy, 3 + 2);
+            cpu.step();
+            assert_eq!(cpu.cy, 3 + 2 + 2);
+            if cycle_stepped {
+                // Each opcode is fetched on the cycle after the stall or the last instruction.
+                assert_eq!(cpu.mem.reads[0], (START, 4));
+                assert!(cpu.mem.reads.contains(&(START + 1, 6)));
+            }
         }
     }
 }
@@ -0,0 +1,211 @@
+//! An interactive debugger for the main loop. It pauses emulation when the CPU hits a breakpoint
+//! or watchpoint, or when asked to, and then runs the console an instruction or a frame at a time
+//! while showing the registers and the code around the program counter.
+
+//
+// Author: Patrick Walton
+//
+
+use cpu::{Break, Cpu};
+use disasm::Disassembler;
+use mem::Mem;
+
+/// How many instructions the view disassembles, starting at the program counter.
+const DISASSEMBLY_LINES: usize = 8;
+
+const JSR: u8 = 0x20;
+
+/// A debugger hotkey.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DebugCommand {
+    /// Pause, or resume if paused.
+    TogglePause,
+    /// Run one instruction.
+    Step,
+    /// Run one instruction, or a whole subroutine if the instruction is a JSR.
+    StepOver,
+    /// Run until the PPU finishes a frame.
+    FrameAdvance,
+}
+
+/// What the main loop should run after a debugger command.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Run {
+    /// Nothing; stay as we are.
+    Nothing,
+    /// A single instruction.
+    Instruction,
+    /// Instructions until the PPU finishes a frame.
+    Frame,
+    /// Everything: the debugger has resumed.
+    Continue,
+}
+
+pub struct Debugger {
+    paused: bool,
+    /// The breakpoint that stepping over a JSR set on the instruction after it. It's removed the
+    /// next time the CPU stops, whether or not it was the reason.
+    step_over: Option<u16>,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            paused: false,
+            step_over: None,
+        }
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Carries out a hotkey. Any command pauses a running console.
+    pub fn command<M: Mem>(&mut self, cpu: &mut Cpu<M>, command: DebugCommand) -> Run {
+        if !self.paused {
+            self.paused = true;
+            return Run::Nothing;
+        }
+
+        match command {
+            DebugCommand::TogglePause => {
+                self.paused = false;
+                Run::Continue
+            }
+            DebugCommand::Step => Run::Instruction,
+            DebugCommand::StepOver => {
+                let pc = cpu.pc();
+                if cpu.mem.peekb(pc) != JSR {
+                    return Run::Instruction;
+                }
+                let return_pc = pc.wrapping_add(3);
+                if !cpu.breakpoints().contains(&return_pc) {
+                    cpu.add_breakpoint(return_pc);
+                    self.step_over = Some(return_pc);
+                }
+                self.paused = false;
+                Run::Continue
+            }
+            DebugCommand::FrameAdvance => Run::Frame,
+        }
+    }
+
+    /// Pauses because the CPU stopped, and returns a description of why for the status line.
+    pub fn stopped<M: Mem>(&mut self, cpu: &mut Cpu<M>, reason: Break) -> String {
+        self.paused = true;
+        if let Some(pc) = self.step_over.take() {
+            cpu.remove_breakpoint(pc);
+            if reason == Break::Breakpoint(pc) {
+                return format!("Paused at ${:04X}", pc);
+            }
+        }
+        reason.to_string()
+    }
+
+    /// Returns the lines to draw over the paused screen: the registers, the cycle count and the
+    /// next few instructions, the first with its operand resolved. Breakpoints are marked with `*`.
+    pub fn view<M: Mem>(&self, cpu: &Cpu<M>) -> Vec<String> {
+        let mut lines = vec![
+            format!("PC:{:04X} {}", cpu.pc(), cpu.registers_string()),
+            format!("CYC:{}", cpu.cy),
+        ];
+
+        let mut disassembler = Disassembler {
+            pc: cpu.pc(),
+            mem: &cpu.mem,
+            regs: Some(cpu.regs()),
+        };
+        for _ in 0..DISASSEMBLY_LINES {
+            let pc = disassembler.pc;
+            let marker = if cpu.breakpoints().contains(&pc) {
+                "*"
+            } else {
+                " "
+            };
+            let instruction = disassembler.disassemble();
+            lines.push(format!("{}{:04X} {}", marker, pc, instruction));
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mem::Ram;
+
+    /// A CPU with nothing but RAM and a paused debugger, about to run the program at $0400.
+    fn paused(program: &[u8]) -> (Debugger, Cpu<Ram>) {
+        let mut ram = Ram { val: [0; 0x800] };
+        ram.val[0x0400..0x0400 + program.len()].copy_from_slice(program);
+        let mut cpu = Cpu::new(ram);
+        cpu.start_at(0x0400);
+        let mut debugger = Debugger::new();
+        assert_eq!(debugger.command(&mut cpu, DebugCommand::Step), Run::Nothing);
+        assert!(debugger.paused());
+        (debugger, cpu)
+    }
+
+    #[test]
+    fn commands_once_paused() {
+        // LDA #$01
+        let (mut debugger, mut cpu) = paused(&[0xa9, 0x01]);
+        assert_eq!(
+            debugger.command(&mut cpu, DebugCommand::Step),
+            Run::Instruction
+        );
+        assert_eq!(
+            debugger.command(&mut cpu, DebugCommand::StepOver),
+            Run::Instruction
+        );
+        assert_eq!(
+            debugger.command(&mut cpu, DebugCommand::FrameAdvance),
+            Run::Frame
+        );
+        assert!(debugger.paused());
+        assert_eq!(
+            debugger.command(&mut cpu, DebugCommand::TogglePause),
+            Run::Continue
+        );
+        assert!(!debugger.paused());
+    }
+
+    #[test]
+    fn step_over_runs_the_whole_subroutine() {
+        // JSR $0410, with an RTS at $0410
+        let mut program = vec![0x20, 0x10, 0x04];
+        program.resize(0x10, 0xea);
+        program.push(0x60);
+        let (mut debugger, mut cpu) = paused(&program);
+
+        assert_eq!(
+            debugger.command(&mut cpu, DebugCommand::StepOver),
+            Run::Continue
+        );
+        assert_eq!(cpu.breakpoints(), &[0x0403]);
+
+        let mut steps = 0;
+        while cpu.pending_break().is_none() {
+            cpu.step();
+            steps += 1;
+        }
+        // JSR, RTS, and then the stop at the breakpoint
+        assert_eq!(steps, 3);
+        let reason = cpu.take_break().unwrap();
+        assert_eq!(debugger.stopped(&mut cpu, reason), "Paused at $0403");
+        assert!(debugger.paused());
+        assert!(cpu.breakpoints().is_empty());
+    }
+
+    #[test]
+    fn view_marks_breakpoints() {
+        // LDA #$01; LDA #$02
+        let (debugger, mut cpu) = paused(&[0xa9, 0x01, 0xa9, 0x02]);
+        cpu.add_breakpoint(0x0402);
+        let lines = debugger.view(&cpu);
+        assert_eq!(lines.len(), 2 + DISASSEMBLY_LINES);
+        assert!(lines[0].starts_with("PC:0400 "));
+        assert!(lines[2].starts_with(" 0400 "));
+        assert!(lines[3].starts_with("*0402 "));
+    }
+}
@@ -2,11 +2,15 @@
 // Author: Patrick Walton
 //
 
+use cpu::{Mode, Opcode, Regs, OPCODES};
 use mem::Mem;
 
 pub struct Disassembler<'a, M: Mem + 'a> {
     pub pc: u16,
-    pub mem: &'a mut M,
+    pub mem: &'a M,
+    /// The registers the next instruction will run with, if they're known. With them, operands
+    /// are annotated with the addresses they resolve to and the values there, as in nestest.log.
+    pub regs: Option<Regs>,
 }
 
 impl<'a, M: Mem> Disassembler<'a, M> {
@@ -15,8 +19,8 @@ impl<'a, M: Mem> Disassembler<'a, M> {
     //
 
     fn loadb_bump_pc(&mut self) -> u8 {
-        let val = (&mut *self.mem).loadb(self.pc);
-        self.pc += 1;
+        let val = self.mem.peekb(self.pc);
+        self.pc = self.pc.wrapping_add(1);
         val
     }
     fn loadw_bump_pc(&mut self) -> u16 {
@@ -28,262 +32,229 @@ impl<'a, M: Mem> Disassembler<'a, M> {
     fn disb_bump_pc(&mut self) -> String {
         format!("${:02X}", self.loadb_bump_pc() as usize)
     }
-    fn disw_bump_pc(&mut self) -> String {
-        format!("${:04X}", self.loadw_bump_pc() as usize)
+
+    /// Like loadw_zp on the CPU: the high byte of $FF comes from $00.
+    fn peekw_zp(&self, addr: u8) -> u16 {
+        self.mem.peekb(addr as u16) as u16
+            | (self.mem.peekb(addr.wrapping_add(1) as u16) as u16) << 8
     }
 
     //
-    // Mnemonics
+    // Annotations
     //
 
-    // TODO: When we get method macros some of this ugly duplication can go away.
-
-    // Loads
-    fn lda(&mut self, am: String) -> String {
-        (format!("LDA {}", am)).to_string()
-    }
-    fn ldx(&mut self, am: String) -> String {
-        (format!("LDX {}", am)).to_string()
-    }
-    fn ldy(&mut self, am: String) -> String {
-        (format!("LDY {}", am)).to_string()
+    /// ` = 5A`: the value at an address the operand names directly.
+    fn value_at(&self, addr: u16) -> String {
+        match self.regs {
+            Some(_) => format!(" = {:02X}", self.mem.peekb(addr)),
+            None => String::new(),
+        }
+    }
+    /// ` @ 0300 = 5A`: the address an indexed operand resolves to, and the value there.
+    fn indexed(&self, base: u16, index: fn(&Regs) -> u8, zero_page: bool) -> String {
+        match self.regs {
+            Some(ref regs) if zero_page => {
+                let addr = (base as u8).wrapping_add(index(regs)) as u16;
+                format!(" @ {:02X} = {:02X}", addr, self.mem.peekb(addr))
+            }
+            Some(ref regs) => {
+                let addr = base.wrapping_add(index(regs) as u16);
+                format!(" @ {:04X} = {:02X}", addr, self.mem.peekb(addr))
+            }
+            None => String::new(),
+        }
     }
 
-    // Stores
-    fn sta(&mut self, am: String) -> String {
-        (format!("STA {}", am)).to_string()
-    }
-    fn stx(&mut self, am: String) -> String {
-        (format!("STX {}", am)).to_string()
-    }
-    fn sty(&mut self, am: String) -> String {
-        (format!("STY {}", am)).to_string()
-    }
+    //
+    // Addressing modes
+    //
 
-    // Arithmetic
-    fn adc(&mut self, am: String) -> String {
-        (format!("ADC {}", am)).to_string()
-    }
-    fn sbc(&mut self, am: String) -> String {
-        (format!("SBC {}", am)).to_string()
+    fn operand(&mut self, opcode: &Opcode) -> String {
+        match opcode.mode {
+            Mode::Implied => String::new(),
+            Mode::Accumulator => "A".to_string(),
+            Mode::Immediate => format!("#{}", self.disb_bump_pc()),
+            Mode::Relative => {
+                let disp = self.loadb_bump_pc() as i8;
+                format!("${:04X}", self.pc.wrapping_add(disp as u16))
+            }
+            Mode::ZeroPage => {
+                let addr = self.loadb_bump_pc() as u16;
+                format!("${:02X}{}", addr, self.value_at(addr))
+            }
+            Mode::ZeroPageX => {
+                let base = self.loadb_bump_pc() as u16;
+                format!("${:02X},X{}", base, self.indexed(base, |regs| regs.x, true))
+            }
+            Mode::ZeroPageY => {
+                let base = self.loadb_bump_pc() as u16;
+                format!("${:02X},Y{}", base, self.indexed(base, |regs| regs.y, true))
+            }
+            // JMP and JSR go to the address rather than reading it.
+            Mode::Absolute if opcode.mnemonic == "JMP" || opcode.mnemonic == "JSR" => {
+                format!("${:04X}", self.loadw_bump_pc())
+            }
+            Mode::Absolute => {
+                let addr = self.loadw_bump_pc();
+                format!("${:04X}{}", addr, self.value_at(addr))
+            }
+            Mode::AbsoluteX => {
+                let base = self.loadw_bump_pc();
+                format!(
+                    "${:04X},X{}",
+                    base,
+                    self.indexed(base, |regs| regs.x, false)
+                )
+            }
+            Mode::AbsoluteY => {
+                let base = self.loadw_bump_pc();
+                format!(
+                    "${:04X},Y{}",
+                    base,
+                    self.indexed(base, |regs| regs.y, false)
+                )
+            }
+            Mode::Indirect => {
+                let addr = self.loadw_bump_pc();
+                let note = match self.regs {
+                    Some(_) => {
+                        // The CPU doesn't carry into the high byte of the pointer's address.
+                        let lo = self.mem.peekb(addr) as u16;
+                        let hi = self
+                            .mem
+                            .peekb((addr & 0xff00) | (addr.wrapping_add(1) & 0xff));
+                        format!(" = {:04X}", (hi as u16) << 8 | lo)
+                    }
+                    None => String::new(),
+                };
+                format!("(${:04X}){}", addr, note)
+            }
+            Mode::IndexedIndirectX => {
+                let base = self.loadb_bump_pc();
+                let note = match self.regs {
+                    Some(ref regs) => {
+                        let pointer = base.wrapping_add(regs.x);
+                        let addr = self.peekw_zp(pointer);
+                        format!(
+                            " @ {:02X} = {:04X} = {:02X}",
+                            pointer,
+                            addr,
+                            self.mem.peekb(addr)
+                        )
+                    }
+                    None => String::new(),
+                };
+                format!("(${:02X},X){}", base, note)
+            }
+            Mode::IndirectIndexedY => {
+                let pointer = self.loadb_bump_pc();
+                let note = match self.regs {
+                    Some(ref regs) => {
+                        let base = self.peekw_zp(pointer);
+                        let addr = base.wrapping_add(regs.y as u16);
+                        format!(
+                            " = {:04X} @ {:04X} = {:02X}",
+                            base,
+                            addr,
+                            self.mem.peekb(addr)
+                        )
+                    }
+                    None => String::new(),
+                };
+                format!("(${:02X}),Y{}", pointer, note)
+            }
+        }
     }
 
-    // Comparisons
-    fn cmp(&mut self, am: String) -> String {
-        (format!("CMP {}", am)).to_string()
-    }
-    fn cpx(&mut self, am: String) -> String {
-        (format!("CPX {}", am)).to_string()
-    }
-    fn cpy(&mut self, am: String) -> String {
-        (format!("CPY {}", am)).to_string()
+    // The main disassembly routine. Unofficial instructions are marked with an asterisk, as in
+    // nestest.log. Branch targets are resolved, and if the registers are known, so are the
+    // operands of the instruction they're for.
+    pub fn disassemble(&mut self) -> String {
+        let opcode = &OPCODES[self.loadb_bump_pc() as usize];
+        let marker = if opcode.unofficial { "*" } else { "" };
+        let operand = self.operand(opcode);
+        // The registers are unknown after this instruction runs.
+        self.regs = None;
+        if operand.is_empty() {
+            format!("{}{}", marker, opcode.mnemonic)
+        } else {
+            format!("{}{} {}", marker, opcode.mnemonic, operand)
+        }
     }
+}
 
-    // Bitwise operations
-    fn and(&mut self, am: String) -> String {
-        (format!("AND {}", am)).to_string()
-    }
-    fn ora(&mut self, am: String) -> String {
-        (format!("ORA {}", am)).to_string()
-    }
-    fn eor(&mut self, am: String) -> String {
-        (format!("EOR {}", am)).to_string()
-    }
-    fn bit(&mut self, am: String) -> String {
-        (format!("BIT {}", am)).to_string()
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    // Shifts and rotates
-    fn rol(&mut self, am: String) -> String {
-        (format!("ROL {}", am)).to_string()
-    }
-    fn ror(&mut self, am: String) -> String {
-        (format!("ROR {}", am)).to_string()
-    }
-    fn asl(&mut self, am: String) -> String {
-        (format!("ASL {}", am)).to_string()
-    }
-    fn lsr(&mut self, am: String) -> String {
-        (format!("LSR {}", am)).to_string()
-    }
+    use mem::Ram;
 
-    // Increments and decrements
-    fn inc(&mut self, am: String) -> String {
-        (format!("INC {}", am)).to_string()
-    }
-    fn dec(&mut self, am: String) -> String {
-        (format!("DEC {}", am)).to_string()
-    }
-    fn inx(&mut self) -> String {
-        "INX".to_string()
-    }
-    fn dex(&mut self) -> String {
-        "DEX".to_string()
-    }
-    fn iny(&mut self) -> String {
-        "INY".to_string()
-    }
-    fn dey(&mut self) -> String {
-        "DEY".to_string()
+    // Disassembles the instruction at $0000, with `regs` if given.
+    fn disassemble(ram: &Ram, regs: Option<Regs>) -> (String, u16) {
+        let mut disassembler = Disassembler {
+            pc: 0,
+            mem: ram,
+            regs: regs,
+        };
+        let text = disassembler.disassemble();
+        (text, disassembler.pc)
     }
 
-    // Register moves
-    fn tax(&mut self) -> String {
-        "TAX".to_string()
-    }
-    fn tay(&mut self) -> String {
-        "TAY".to_string()
-    }
-    fn txa(&mut self) -> String {
-        "TXA".to_string()
-    }
-    fn tya(&mut self) -> String {
-        "TYA".to_string()
-    }
-    fn txs(&mut self) -> String {
-        "TXS".to_string()
-    }
-    fn tsx(&mut self) -> String {
-        "TSX".to_string()
+    fn ram(bytes: &[u8]) -> Ram {
+        let mut ram = Ram { val: [0; 0x800] };
+        ram[..bytes.len()].copy_from_slice(bytes);
+        ram
     }
 
-    // Flag operations
-    fn clc(&mut self) -> String {
-        "CLC".to_string()
-    }
-    fn sec(&mut self) -> String {
-        "SEC".to_string()
-    }
-    fn cli(&mut self) -> String {
-        "CLI".to_string()
-    }
-    fn sei(&mut self) -> String {
-        "SEI".to_string()
-    }
-    fn clv(&mut self) -> String {
-        "CLV".to_string()
-    }
-    fn cld(&mut self) -> String {
-        "CLD".to_string()
-    }
-    fn sed(&mut self) -> String {
-        "SED".to_string()
+    fn regs(x: u8, y: u8) -> Regs {
+        Regs {
+            a: 0,
+            x: x,
+            y: y,
+            s: 0xfd,
+            flags: 0x24,
+            pc: 0,
+        }
     }
 
-    // Branches
-    // FIXME: Should disassemble the displacement!
-    fn bpl(&mut self) -> String {
-        "BPL xx".to_string()
-    }
-    fn bmi(&mut self) -> String {
-        "BMI xx".to_string()
-    }
-    fn bvc(&mut self) -> String {
-        "BVC xx".to_string()
-    }
-    fn bvs(&mut self) -> String {
-        "BVS xx".to_string()
-    }
-    fn bcc(&mut self) -> String {
-        "BCC xx".to_string()
-    }
-    fn bcs(&mut self) -> String {
-        "BCS xx".to_string()
-    }
-    fn bne(&mut self) -> String {
-        "BNE xx".to_string()
-    }
-    fn beq(&mut self) -> String {
-        "BEQ xx".to_string()
-    }
-
-    // Jumps
-    // FIXME: Should disassemble the address!
-    fn jmp(&mut self) -> String {
-        "JMP xx".to_string()
-    }
-    fn jmpi(&mut self) -> String {
-        "JMP (xx)".to_string()
-    }
-
-    // Procedure calls
-    // FIXME: Should disassemble the address!
-    fn jsr(&mut self) -> String {
-        "JSR xx".to_string()
-    }
-    fn rts(&mut self) -> String {
-        "RTS".to_string()
-    }
-    fn brk(&mut self) -> String {
-        "BRK".to_string()
-    }
-    fn rti(&mut self) -> String {
-        "RTI".to_string()
+    #[test]
+    fn disassembles_each_operand_size() {
+        assert_eq!(disassemble(&ram(&[0xea]), None), ("NOP".to_string(), 1));
+        assert_eq!(
+            disassemble(&ram(&[0xa9, 0x42]), None),
+            ("LDA #$42".to_string(), 2)
+        );
+        assert_eq!(
+            disassemble(&ram(&[0x8d, 0x00, 0x02]), None),
+            ("STA $0200".to_string(), 3)
+        );
     }
 
-    // Stack operations
-    fn pha(&mut self) -> String {
-        "PHA".to_string()
-    }
-    fn pla(&mut self) -> String {
-        "PLA".to_string()
-    }
-    fn php(&mut self) -> String {
-        "PHP".to_string()
-    }
-    fn plp(&mut self) -> String {
-        "PLP".to_string()
+    #[test]
+    fn resolves_branch_targets() {
+        // BNE -2 branches back onto itself.
+        assert_eq!(disassemble(&ram(&[0xd0, 0xfe]), None).0, "BNE $0000");
     }
 
-    // No operation
-    fn nop(&mut self) -> String {
-        "NOP".to_string()
+    #[test]
+    fn marks_unofficial_opcodes() {
+        assert_eq!(disassemble(&ram(&[0xa7, 0x10]), None).0, "*LAX $10");
     }
 
-    // Addressing modes
-    fn immediate(&mut self) -> String {
-        (format!("{}{}", "#", self.disb_bump_pc())).to_string()
-    }
-    fn accumulator(&mut self) -> String {
-        String::new()
-    }
-    fn zero_page(&mut self) -> String {
-        self.disb_bump_pc()
-    }
-    fn zero_page_x(&mut self) -> String {
-        let mut buf = self.disb_bump_pc();
-        buf.push_str(",X");
-        buf
-    }
-    fn zero_page_y(&mut self) -> String {
-        let mut buf = self.disb_bump_pc();
-        buf.push_str(",Y");
-        buf
-    }
-    fn absolute(&mut self) -> String {
-        self.disw_bump_pc()
-    }
-    fn absolute_x(&mut self) -> String {
-        let mut buf = self.disw_bump_pc();
-        buf.push_str(",X");
-        buf
-    }
-    fn absolute_y(&mut self) -> String {
-        let mut buf = self.disw_bump_pc();
-        buf.push_str(",Y");
-        buf
-    }
-    fn indexed_indirect_x(&mut self) -> String {
-        (format!("({},X)", self.disb_bump_pc())).to_string()
-    }
-    fn indirect_indexed_y(&mut self) -> String {
-        (format!("({}),Y", self.disb_bump_pc())).to_string()
-    }
+    #[test]
+    fn annotates_operands_when_the_registers_are_known() {
+        let mut mem = ram(&[0xb5, 0xff]);
+        mem[0x02] = 0x5a;
+        // Zero page indexing wraps within the zero page.
+        assert_eq!(disassemble(&mem, Some(regs(3, 0))).0, "LDA $FF,X @ 02 = 5A");
 
-    // The main disassembly routine.
-    #[inline(never)]
-    pub fn disassemble(&mut self) -> String {
-        let op = self.loadb_bump_pc();
-        decode_op!(op, self)
+        let mut mem = ram(&[0xb1, 0x10]);
+        mem[0x10] = 0x00;
+        mem[0x11] = 0x03;
+        mem[0x305] = 0x77;
+        assert_eq!(
+            disassemble(&mem, Some(regs(0, 5))).0,
+            "LDA ($10),Y = 0300 @ 0305 = 77"
+        );
     }
 }
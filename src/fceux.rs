@@ -0,0 +1,402 @@
+//! Best-effort import and export of FCEUX savestates (`.fc0` through `.fc9`).
+//!
+//! An FCEUX savestate is a 16-byte header followed by a (usually zlib-compressed) list of
+//! sections. Each section is a list of named entries, and each component of the emulator restores
+//! whichever entries it understands. Anything we can't restore is reported back to the caller
+//! rather than silently dropped.
+
+//
+// Author: Patrick Walton
+//
+
+use cpu::Cpu;
+use mem::MemMap;
+use util;
+
+use flate2::read::ZlibDecoder;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+const MAGIC: [u8; 4] = *b"FCSX";
+
+/// The FCEUX version we claim to be when exporting: 2.2.3.
+const EXPORT_VERSION: u32 = 22020;
+
+/// The marker in the header's compressed-length field for uncompressed data.
+const UNCOMPRESSED: u32 = 0xffffffff;
+
+/// The largest state we accept. Real ones are a few hundred kilobytes at most, so a header
+/// claiming more is corrupt, and believing it could mean allocating gigabytes.
+const MAX_STATE_LEN: u32 = 16 * 1024 * 1024;
+
+pub const SECTION_CPU: u8 = 1;
+pub const SECTION_CPU_COUNTERS: u8 = 2;
+pub const SECTION_PPU: u8 = 3;
+pub const SECTION_NEW_PPU: u8 = 31;
+pub const SECTION_CONTROLLERS: u8 = 4;
+pub const SECTION_SOUND: u8 = 5;
+pub const SECTION_MAPPER: u8 = 0x10;
+
+#[derive(Debug)]
+pub enum FceuxError {
+    /// IO error while reading or writing the savestate
+    IoError(io::Error),
+    /// Not an FCEUX savestate, or one from a version too old to support
+    FormatError,
+    /// The savestate ended in the middle of a section
+    Truncated,
+    /// The savestate lacks a section we can't do without
+    MissingSection(&'static str),
+}
+
+impl From<io::Error> for FceuxError {
+    fn from(err: io::Error) -> Self {
+        FceuxError::IoError(err)
+    }
+}
+
+impl fmt::Display for FceuxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            FceuxError::IoError(ref err) => write!(f, "{}", err),
+            FceuxError::FormatError => write!(f, "not an FCEUX savestate"),
+            FceuxError::Truncated => write!(f, "savestate is truncated"),
+            FceuxError::MissingSection(name) => write!(f, "savestate has no {} section", name),
+        }
+    }
+}
+
+/// One section of an FCEUX savestate: a list of entries with 4-character names.
+pub struct FceuxSection {
+    pub kind: u8,
+    entries: Vec<([u8; 4], Vec<u8>)>,
+}
+
+impl FceuxSection {
+    pub fn new(kind: u8) -> FceuxSection {
+        FceuxSection {
+            kind: kind,
+            entries: vec![],
+        }
+    }
+
+    fn name_bytes(name: &str) -> [u8; 4] {
+        let mut bytes = [0; 4];
+        for (dest, src) in bytes.iter_mut().zip(name.bytes()) {
+            *dest = src;
+        }
+        bytes
+    }
+
+    /// Returns the data of the entry with the given name, if present.
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        let name = FceuxSection::name_bytes(name);
+        self.entries
+            .iter()
+            .find(|entry| entry.0 == name)
+            .map(|entry| &*entry.1)
+    }
+
+    /// Returns the entry with the given name, if present and exactly `len` bytes long.
+    pub fn get_exact(&self, name: &str, len: usize) -> Option<&[u8]> {
+        self.get(name)
+            .and_then(|data| if data.len() == len { Some(data) } else { None })
+    }
+
+    /// Returns the byte entry with the given name.
+    pub fn get_u8(&self, name: &str) -> Option<u8> {
+        self.get_exact(name, 1).map(|data| data[0])
+    }
+
+    /// Returns the little-endian 16-bit entry with the given name.
+    pub fn get_u16(&self, name: &str) -> Option<u16> {
+        self.get_exact(name, 2)
+            .map(|data| data[0] as u16 | (data[1] as u16) << 8)
+    }
+
+    /// Copies the entry with the given name into `dest` if the sizes match. Returns true if it
+    /// did.
+    pub fn copy_to(&self, name: &str, dest: &mut [u8]) -> bool {
+        match self.get_exact(name, dest.len()) {
+            Some(data) => {
+                dest.copy_from_slice(data);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn put(&mut self, name: &str, data: &[u8]) {
+        self.entries
+            .push((FceuxSection::name_bytes(name), data.to_vec()));
+    }
+
+    pub fn put_u16(&mut self, name: &str, val: u16) {
+        self.put(name, &[val as u8, (val >> 8) as u8]);
+    }
+
+    fn parse(kind: u8, mut data: &[u8]) -> Result<FceuxSection, FceuxError> {
+        let mut section = FceuxSection::new(kind);
+        while !data.is_empty() {
+            if data.len() < 8 {
+                return Err(FceuxError::Truncated);
+            }
+            let name = [data[0], data[1], data[2], data[3]];
+            let len = read_u32(&data[4..8]) as usize;
+            if data.len() - 8 < len {
+                return Err(FceuxError::Truncated);
+            }
+            section.entries.push((name, data[8..8 + len].to_vec()));
+            data = &data[8 + len..];
+        }
+        Ok(section)
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        let len: usize = self.entries.iter().map(|entry| 8 + entry.1.len()).sum();
+        out.push(self.kind);
+        write_u32(out, len as u32);
+        for (name, data) in self.entries.iter() {
+            out.extend_from_slice(name);
+            write_u32(out, data.len() as u32);
+            out.extend_from_slice(data);
+        }
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+}
+
+fn write_u32(out: &mut Vec<u8>, val: u32) {
+    out.extend_from_slice(&[
+        val as u8,
+        (val >> 8) as u8,
+        (val >> 16) as u8,
+        (val >> 24) as u8,
+    ]);
+}
+
+fn read_sections(r: &mut Read) -> Result<Vec<FceuxSection>, FceuxError> {
+    let mut header = [0; 16];
+    util::read_to_buf(&mut header, r)?;
+    if header[0..4] != MAGIC {
+        return Err(FceuxError::FormatError);
+    }
+    let total_len = read_u32(&header[4..8]);
+    let compressed_len = read_u32(&header[12..16]);
+    if total_len > MAX_STATE_LEN
+        || (compressed_len != UNCOMPRESSED && compressed_len > MAX_STATE_LEN)
+    {
+        return Err(FceuxError::FormatError);
+    }
+
+    // Read no more than the header promises, and only as much as is really there.
+    let mut data = vec![];
+    if compressed_len == UNCOMPRESSED {
+        Read::take(r, total_len as u64).read_to_end(&mut data)?;
+    } else {
+        let mut compressed = vec![];
+        Read::take(r, compressed_len as u64).read_to_end(&mut compressed)?;
+        if compressed.len() < compressed_len as usize {
+            return Err(FceuxError::Truncated);
+        }
+        let mut decoder = ZlibDecoder::new(&*compressed);
+        if Read::take(&mut decoder, total_len as u64)
+            .read_to_end(&mut data)
+            .is_err()
+        {
+            return Err(FceuxError::FormatError);
+        }
+    }
+    if data.len() < total_len as usize {
+        return Err(FceuxError::Truncated);
+    }
+
+    let mut sections = vec![];
+    let mut rest = &*data;
+    while !rest.is_empty() {
+        if rest.len() < 5 {
+            return Err(FceuxError::Truncated);
+        }
+        let kind = rest[0];
+        let len = read_u32(&rest[1..5]) as usize;
+        if rest.len() - 5 < len {
+            return Err(FceuxError::Truncated);
+        }
+        sections.push(FceuxSection::parse(kind, &rest[5..5 + len])?);
+        rest = &rest[5 + len..];
+    }
+    Ok(sections)
+}
+
+/// Restores as much of an FCEUX savestate as possible. On success, returns a description of each
+/// part of the state that couldn't be restored.
+pub fn import(cpu: &mut Cpu<MemMap>, r: &mut Read) -> Result<Vec<String>, FceuxError> {
+    let sections = read_sections(r)?;
+    let find = |kind| sections.iter().find(|section| section.kind == kind);
+
+    // Check for the essential sections before modifying anything.
+    let cpu_section = find(SECTION_CPU).ok_or(FceuxError::MissingSection("CPU"))?;
+    let ppu_section = find(SECTION_PPU).ok_or(FceuxError::MissingSection("PPU"))?;
+
+    let mut warnings = vec![];
+    warnings.extend(cpu.import_fceux(cpu_section));
+    warnings.extend(cpu.mem.ppu.import_fceux(ppu_section));
+
+    match find(SECTION_MAPPER) {
+        Some(section) => {
+            if !cpu.mem.mapper.borrow_mut().import_fceux(section) {
+                warnings.push("mapper state is not supported for this mapper".to_string());
+            }
+        }
+        None => warnings.push("savestate has no mapper section".to_string()),
+    }
+
+    for section in sections.iter() {
+        match section.kind {
+            SECTION_CPU | SECTION_PPU | SECTION_MAPPER => {}
+            // Interrupt timing and the new PPU's internals have no equivalent here, and the
+            // controllers are re-read every frame anyway.
+            SECTION_CPU_COUNTERS | SECTION_NEW_PPU | SECTION_CONTROLLERS => {}
+            SECTION_SOUND => warnings.push("sound state is not supported".to_string()),
+            kind => warnings.push(format!("unknown section {} was skipped", kind)),
+        }
+    }
+
+    Ok(warnings)
+}
+
+/// Writes the CPU, RAM and PPU state as an uncompressed FCEUX savestate. FCEUX leaves anything
+/// the state doesn't mention (such as the mapper and sound) as it was.
+pub fn export(cpu: &mut Cpu<MemMap>, w: &mut Write) -> Result<(), FceuxError> {
+    let mut data = vec![];
+    cpu.export_fceux().write(&mut data);
+    cpu.mem.ppu.export_fceux().write(&mut data);
+
+    let mut header = MAGIC.to_vec();
+    write_u32(&mut header, data.len() as u32);
+    write_u32(&mut header, EXPORT_VERSION);
+    write_u32(&mut header, UNCOMPRESSED);
+    w.write_all(&header)?;
+    w.write_all(&data)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mem::Mem;
+    use rom::Rom;
+    use Headless;
+
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+
+    // A console running a blank NROM cartridge.
+    fn console() -> Headless {
+        let mut image = b"NES\x1a\x01\x01".to_vec();
+        image.resize(16 + 0x4000 + 0x2000, 0);
+        Headless::new(Rom::from_bytes(&image).unwrap()).unwrap()
+    }
+
+    // Writes the fourth palette byte through PPUADDR and PPUDATA.
+    fn write_palette(console: &mut Headless, val: u8) {
+        console.cpu.mem.storeb(0x2006, 0x3f);
+        console.cpu.mem.storeb(0x2006, 0x03);
+        console.cpu.mem.storeb(0x2007, val);
+    }
+
+    fn read_palette(console: &mut Headless) -> u8 {
+        console.cpu.mem.storeb(0x2006, 0x3f);
+        console.cpu.mem.storeb(0x2006, 0x03);
+        console.cpu.mem.loadb(0x2007) & 0x3f
+    }
+
+    // A header for `data`, stored compressed if `compressed_len` is given.
+    fn header(data_len: u32, compressed_len: u32) -> Vec<u8> {
+        let mut header = MAGIC.to_vec();
+        write_u32(&mut header, data_len);
+        write_u32(&mut header, EXPORT_VERSION);
+        write_u32(&mut header, compressed_len);
+        header
+    }
+
+    #[test]
+    fn exported_states_import() {
+        let mut source = console();
+        let mut regs = source.cpu.regs();
+        regs.a = 0x12;
+        regs.x = 0x34;
+        regs.pc = 0xc123;
+        source.cpu.set_regs(regs);
+        source.cpu.mem.storeb(0x0300, 0x56);
+        write_palette(&mut source, 0x21);
+        let mut state = vec![];
+        export(&mut source.cpu, &mut state).unwrap();
+
+        let mut dest = console();
+        let warnings = import(&mut dest.cpu, &mut &*state).unwrap();
+        assert_eq!(
+            warnings,
+            vec!["savestate has no mapper section".to_string()]
+        );
+        assert_eq!(dest.cpu.regs().a, 0x12);
+        assert_eq!(dest.cpu.regs().x, 0x34);
+        assert_eq!(dest.cpu.regs().pc, 0xc123);
+        assert_eq!(dest.cpu.mem.loadb(0x0300), 0x56);
+        assert_eq!(read_palette(&mut dest), 0x21);
+    }
+
+    #[test]
+    fn compressed_sections_are_read() {
+        let mut section = FceuxSection::new(SECTION_SOUND);
+        section.put_u16("FREQ", 0x1234);
+        let mut data = vec![];
+        section.write(&mut data);
+        let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+        encoder.write_all(&data).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut state = header(data.len() as u32, compressed.len() as u32);
+        state.extend_from_slice(&compressed);
+        let sections = read_sections(&mut &*state).unwrap();
+        assert_eq!(sections.len(), 1);
+        assert_eq!(sections[0].get_u16("FREQ"), Some(0x1234));
+    }
+
+    #[test]
+    fn truncated_states_are_refused() {
+        let mut state = vec![];
+        export(&mut console().cpu, &mut state).unwrap();
+        for &len in [10, 16, 100, state.len() - 1].iter() {
+            match read_sections(&mut &state[..len]) {
+                Err(FceuxError::Truncated) | Err(FceuxError::IoError(_)) => {}
+                _ => panic!("a savestate cut to {} bytes was read", len),
+            }
+        }
+    }
+
+    #[test]
+    fn garbage_is_refused() {
+        match read_sections(&mut &b"not a savestate at all"[..]) {
+            Err(FceuxError::FormatError) => {}
+            _ => panic!("garbage was read as a savestate"),
+        }
+
+        // A 4 GiB state isn't allocated, whether stored or compressed.
+        let state = header(0xfffffff0, UNCOMPRESSED);
+        assert!(read_sections(&mut &*state).is_err());
+        let mut state = header(0xfffffff0, 16);
+        state.extend_from_slice(&[0; 16]);
+        assert!(read_sections(&mut &*state).is_err());
+
+        // Neither is a compressed state that isn't zlib data.
+        let mut state = header(16, 16);
+        state.extend_from_slice(&[0xff; 16]);
+        match read_sections(&mut &*state) {
+            Err(FceuxError::FormatError) => {}
+            _ => panic!("bad zlib data was read"),
+        }
+    }
+}
@@ -0,0 +1,609 @@
+//! Filters that each frame goes through between the PPU and the window: upscalers that smooth
+//! the edges of pixel art, in place of the blocky nearest-neighbor scaling the window does on its
+//! own, and an imitation of a CRT television.
+
+//
+// Author: Patrick Walton
+//
+
+use ppu::PixelFormat;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Upscaler {
+    /// Each pixel is left as it is, and the window scales them up as blocks.
+    None,
+    /// AdvMAME2x: each pixel becomes four, and the corners of diagonal edges are filled in.
+    Scale2x,
+    /// AdvMAME3x: each pixel becomes nine, the same way as Scale2x.
+    Scale3x,
+    /// HQ2x: each pixel becomes four, and the corners are blended with the neighbors that differ
+    /// from the pixel, which smooths edges without blurring flat areas.
+    Hq2x,
+    /// Hyllian's 2xBR: each pixel becomes four, and the corners on edges are blended along them,
+    /// which rounds curves off where Scale2x leaves steps.
+    Xbr2x,
+}
+
+/// The upscalers in the order they're cycled through at runtime.
+pub const UPSCALERS: [Upscaler; 5] = [
+    Upscaler::None,
+    Upscaler::Scale2x,
+    Upscaler::Scale3x,
+    Upscaler::Hq2x,
+    Upscaler::Xbr2x,
+];
+
+impl Upscaler {
+    /// How many times wider and taller the upscaled picture is.
+    pub fn factor(self) -> usize {
+        match self {
+            Upscaler::None => 1,
+            Upscaler::Scale2x | Upscaler::Hq2x | Upscaler::Xbr2x => 2,
+            Upscaler::Scale3x => 3,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Upscaler::None => "No upscaling",
+            Upscaler::Scale2x => "Scale2x",
+            Upscaler::Scale3x => "Scale3x",
+            Upscaler::Hq2x => "HQ2x",
+            Upscaler::Xbr2x => "2xBR",
+        }
+    }
+
+    /// The upscaler after this one in `UPSCALERS`, wrapping around.
+    pub fn next(self) -> Upscaler {
+        let index = UPSCALERS
+            .iter()
+            .position(|&upscaler| upscaler == self)
+            .unwrap();
+        UPSCALERS[(index + 1) % UPSCALERS.len()]
+    }
+
+    /// Scales a `width` by `height` picture up into `dest`, which must have room for `factor()`
+    /// times as many pixels in each direction, in the same format. `picture` is where the
+    /// picture is unpacked, which is kept from frame to frame to save allocating it each time.
+    pub fn run(
+        self,
+        picture: &mut Picture,
+        src: &[u8],
+        width: usize,
+        height: usize,
+        format: PixelFormat,
+        dest: &mut [u8],
+    ) {
+        let compares_colors = self == Upscaler::Hq2x || self == Upscaler::Xbr2x;
+        picture.load(src, width, height, format, compares_colors);
+        let picture = &*picture;
+        let factor = self.factor();
+        let mut block = [0; 9];
+        for y in 0..height {
+            for x in 0..width {
+                match self {
+                    Upscaler::None => block[0] = picture.get(x, y, 0, 0),
+                    Upscaler::Scale2x => scale2x(picture, x, y, &mut block),
+                    Upscaler::Scale3x => scale3x(picture, x, y, &mut block),
+                    Upscaler::Hq2x => hq2x(picture, x, y, &mut block),
+                    Upscaler::Xbr2x => xbr2x(picture, x, y, &mut block),
+                }
+                for row in 0..factor {
+                    for column in 0..factor {
+                        let (dest_x, dest_y) = (x * factor + column, y * factor + row);
+                        let offset = (dest_y * width * factor + dest_x) * format.bytes_per_pixel();
+                        store(dest, offset, format, block[row * factor + column]);
+                    }
+                }
+            }
+        }
+    }
+}
+
+//
+// Pixels
+//
+// The upscalers work on colors packed as 0xRRGGBB, whichever format the PPU draws in.
+//
+
+fn load(src: &[u8], offset: usize, format: PixelFormat) -> u32 {
+    let (r, g, b) = match format {
+        PixelFormat::Bgr24 => (src[offset + 2], src[offset + 1], src[offset]),
+        PixelFormat::Rgba32 => (src[offset], src[offset + 1], src[offset + 2]),
+    };
+    (r as u32) << 16 | (g as u32) << 8 | b as u32
+}
+
+fn store(dest: &mut [u8], offset: usize, format: PixelFormat, color: u32) {
+    let (r, g, b) = ((color >> 16) as u8, (color >> 8) as u8, color as u8);
+    match format {
+        PixelFormat::Bgr24 => {
+            dest[offset] = b;
+            dest[offset + 1] = g;
+            dest[offset + 2] = r;
+        }
+        PixelFormat::Rgba32 => {
+            dest[offset] = r;
+            dest[offset + 1] = g;
+            dest[offset + 2] = b;
+            dest[offset + 3] = 0xff;
+        }
+    }
+}
+
+// The color halfway between two others.
+fn blend(a: u32, b: u32) -> u32 {
+    // Halve each channel before adding, so that none carries into the next.
+    ((a >> 1) & 0x7f7f7f) + ((b >> 1) & 0x7f7f7f) + (a & b & 0x010101)
+}
+
+/// A frame unpacked for filtering, along with each pixel's color in YUV for the upscalers that
+/// compare colors by it.
+pub struct Picture {
+    pixels: Vec<u32>,
+    yuv: Vec<[i32; 3]>,
+    width: usize,
+    height: usize,
+}
+
+impl Picture {
+    pub fn new() -> Picture {
+        Picture {
+            pixels: vec![],
+            yuv: vec![],
+            width: 0,
+            height: 0,
+        }
+    }
+
+    // Unpacks a frame into the buffers left from the last one, working out YUV colors too if
+    // `with_yuv` is set.
+    fn load(
+        &mut self,
+        src: &[u8],
+        width: usize,
+        height: usize,
+        format: PixelFormat,
+        with_yuv: bool,
+    ) {
+        self.pixels.clear();
+        self.pixels
+            .extend((0..width * height).map(|i| load(src, i * format.bytes_per_pixel(), format)));
+        self.yuv.clear();
+        if with_yuv {
+            self.yuv.extend(self.pixels.iter().map(|&color| yuv(color)));
+        }
+        self.width = width;
+        self.height = height;
+    }
+
+    // The index of the pixel `dx` and `dy` away from (`x`, `y`). Pixels off the edge repeat the
+    // nearest one on it.
+    fn index(&self, x: usize, y: usize, dx: isize, dy: isize) -> usize {
+        let clamp = |value: isize, len: usize| value.max(0).min(len as isize - 1) as usize;
+        let (x, y) = (
+            clamp(x as isize + dx, self.width),
+            clamp(y as isize + dy, self.height),
+        );
+        y * self.width + x
+    }
+
+    fn get(&self, x: usize, y: usize, dx: isize, dy: isize) -> u32 {
+        self.pixels[self.index(x, y, dx, dy)]
+    }
+}
+
+// Y, U and V, each scaled by 1000 to keep to integers.
+fn yuv(color: u32) -> [i32; 3] {
+    let (r, g, b) = (
+        (color >> 16) as i32 & 0xff,
+        (color >> 8) as i32 & 0xff,
+        color as i32 & 0xff,
+    );
+    [
+        299 * r + 587 * g + 114 * b,
+        -169 * r - 331 * g + 500 * b,
+        500 * r - 419 * g - 81 * b,
+    ]
+}
+
+//
+// Scale2x and Scale3x
+//
+// Neighbors are named as in the Scale2x documentation:
+//
+//     A B C
+//     D E F
+//     G H I
+//
+
+fn scale2x(picture: &Picture, x: usize, y: usize, block: &mut [u32; 9]) {
+    let p = |dx, dy| picture.get(x, y, dx, dy);
+    let (b, d, e, f, h) = (p(0, -1), p(-1, 0), p(0, 0), p(1, 0), p(0, 1));
+    if b != h && d != f {
+        block[0] = if d == b { d } else { e };
+        block[1] = if b == f { f } else { e };
+        block[2] = if d == h { d } else { e };
+        block[3] = if h == f { f } else { e };
+    } else {
+        for pixel in block[..4].iter_mut() {
+            *pixel = e;
+        }
+    }
+}
+
+fn scale3x(picture: &Picture, x: usize, y: usize, block: &mut [u32; 9]) {
+    let p = |dx, dy| picture.get(x, y, dx, dy);
+    let (a, b, c) = (p(-1, -1), p(0, -1), p(1, -1));
+    let (d, e, f) = (p(-1, 0), p(0, 0), p(1, 0));
+    let (g, h, i) = (p(-1, 1), p(0, 1), p(1, 1));
+    if b != h && d != f {
+        block[0] = if d == b { d } else { e };
+        block[1] = if (d == b && e != c) || (b == f && e != a) {
+            b
+        } else {
+            e
+        };
+        block[2] = if b == f { f } else { e };
+        block[3] = if (d == b && e != g) || (d == h && e != a) {
+            d
+        } else {
+            e
+        };
+        block[4] = e;
+        block[5] = if (b == f && e != i) || (h == f && e != c) {
+            f
+        } else {
+            e
+        };
+        block[6] = if d == h { d } else { e };
+        block[7] = if (d == h && e != i) || (h == f && e != g) {
+            h
+        } else {
+            e
+        };
+        block[8] = if h == f { f } else { e };
+    } else {
+        for pixel in block.iter_mut() {
+            *pixel = e;
+        }
+    }
+}
+
+//
+// HQ2x
+//
+// Each pixel is compared with its eight neighbors by the thresholds below, and each corner of the
+// block is blended with whichever of the three neighbors around it differ, using HQ2x's weights.
+// The original's table of 256 cases is reduced here to the rules for one corner, which is turned
+// like 2xBR's to work out the other three. Neighbors are named as for Scale3x.
+//
+
+/// How far apart Y, U and V (scaled as `yuv` scales them) must be for two colors to differ.
+const HQ_THRESHOLDS: [i32; 3] = [48 * 1000, 7 * 1000, 6 * 1000];
+
+fn hq2x(picture: &Picture, x: usize, y: usize, block: &mut [u32; 9]) {
+    for (turns, &corner) in [3, 1, 0, 2].iter().enumerate() {
+        block[corner] = hq_corner(picture, x, y, turns);
+    }
+}
+
+// The bottom right corner's color, with the neighborhood turned `turns` quarter turns.
+fn hq_corner(picture: &Picture, x: usize, y: usize, turns: usize) -> u32 {
+    let index = |dx: isize, dy: isize| {
+        let (mut dx, mut dy) = (dx, dy);
+        for _ in 0..turns {
+            let (turned_x, turned_y) = (dy, -dx);
+            dx = turned_x;
+            dy = turned_y;
+        }
+        picture.index(x, y, dx, dy)
+    };
+    let (e, f, h, i) = (index(0, 0), index(1, 0), index(0, 1), index(1, 1));
+    let differ = |p: usize, q: usize| {
+        let (p, q) = (picture.yuv[p], picture.yuv[q]);
+        (0..3).any(|channel| (p[channel] - q[channel]).abs() > HQ_THRESHOLDS[channel])
+    };
+    let color = |index: usize| picture.pixels[index];
+    let center = color(e);
+    match (differ(e, f), differ(e, h)) {
+        // An edge runs through the corner: pull it toward the two sides if they're alike, which
+        // rounds the step off, or blend with both a little if they aren't.
+        (true, true) if !differ(f, h) => mix(&[(center, 2), (color(f), 3), (color(h), 3)]),
+        (true, true) => mix(&[(center, 2), (color(f), 1), (color(h), 1)]),
+        // An edge runs along one side: blend with it if it carries on past the corner.
+        (true, false) if differ(e, i) => mix(&[(center, 3), (color(f), 1)]),
+        (false, true) if differ(e, i) => mix(&[(center, 3), (color(h), 1)]),
+        // Only the diagonal neighbor differs: take a little of it.
+        (false, false) if differ(e, i) => mix(&[(center, 3), (color(i), 1)]),
+        _ => center,
+    }
+}
+
+// A weighted average of colors.
+fn mix(colors: &[(u32, u32)]) -> u32 {
+    let total: u32 = colors.iter().map(|&(_, weight)| weight).sum();
+    let mut result = 0;
+    for shift in [16, 8, 0].iter() {
+        let sum: u32 = colors
+            .iter()
+            .map(|&(color, weight)| ((color >> shift) & 0xff) * weight)
+            .sum();
+        result |= (sum / total) << shift;
+    }
+    result
+}
+
+//
+// 2xBR
+//
+// Each corner of the block is worked out the same way, with the neighborhood turned so that the
+// corner is at the bottom right. Neighbors are named as in the xBR documentation:
+//
+//        A1 B1 C1
+//     A0 A  B  C  C4
+//     D0 D  E  F  F4
+//     G0 G  H  I  I4
+//        G5 H5 I5
+//
+
+fn xbr2x(picture: &Picture, x: usize, y: usize, block: &mut [u32; 9]) {
+    // The bottom right, top right, top left and bottom left corners, turning a quarter turn
+    // anticlockwise each time.
+    for (turns, &corner) in [3, 1, 0, 2].iter().enumerate() {
+        block[corner] = xbr_corner(picture, x, y, turns);
+    }
+}
+
+fn xbr_corner(picture: &Picture, x: usize, y: usize, turns: usize) -> u32 {
+    let index = |dx: isize, dy: isize| {
+        let (mut dx, mut dy) = (dx, dy);
+        for _ in 0..turns {
+            let (turned_x, turned_y) = (dy, -dx);
+            dx = turned_x;
+            dy = turned_y;
+        }
+        picture.index(x, y, dx, dy)
+    };
+    let (b, c, d, e, f) = (
+        index(0, -1),
+        index(1, -1),
+        index(-1, 0),
+        index(0, 0),
+        index(1, 0),
+    );
+    let (g, h, i) = (index(-1, 1), index(0, 1), index(1, 1));
+    let (f4, i4, h5, i5) = (index(2, 0), index(2, 1), index(0, 2), index(1, 2));
+
+    let distance = |p: usize, q: usize| {
+        let (p, q) = (picture.yuv[p], picture.yuv[q]);
+        48 * (p[0] - q[0]).abs() + 7 * (p[1] - q[1]).abs() + 6 * (p[2] - q[2]).abs()
+    };
+
+    // There's an edge through the corner if the colors change less along the diagonal from F to
+    // H than across it.
+    let along =
+        distance(e, c) + distance(e, g) + distance(i, h5) + distance(i, f4) + 4 * distance(h, f);
+    let across =
+        distance(h, d) + distance(h, i5) + distance(f, i4) + distance(f, b) + 4 * distance(e, i);
+    let center = picture.pixels[e];
+    if along >= across {
+        return center;
+    }
+    let closer = if distance(e, f) <= distance(e, h) {
+        f
+    } else {
+        h
+    };
+    blend(center, picture.pixels[closer])
+}
+
+//
+// CRT
+//
+
+/// How many pixels wide and tall the CRT draws each of the console's pixels.
+pub const CRT_FACTOR: usize = 3;
+
+/// How far the picture bulges out at the corners, as a fraction of its size.
+const CRT_CURVATURE: f32 = 0.04;
+
+/// How bright the dark gap between scanlines is, out of 256.
+const CRT_SCANLINE_GAP: u32 = 140;
+
+/// How bright the two colors of the shadow mask that each column of pixels doesn't belong to
+/// are, out of 256.
+const CRT_MASK: u32 = 180;
+
+/// How much the glow around bright pixels adds, out of 256.
+const CRT_BLOOM: u32 = 80;
+
+/// How bright a channel must be before it glows. Only the part above this spills into the
+/// neighbors, so dark and middling colors don't glow at all.
+const CRT_BLOOM_THRESHOLD: u32 = 160;
+
+/// A software imitation of a CRT television: the picture curves away at the edges, scanlines are
+/// separated by dark gaps, each column of pixels is tinted by the red, green or blue stripe of the
+/// mask in front of it, and bright pixels glow into their neighbors.
+pub struct Crt {
+    width: usize,
+    height: usize,
+    /// For each pixel of the output, the screen pixel it shows, or `None` for the black beyond the
+    /// curved edges, and its brightness out of 256 for where it falls between scanlines. Curvature
+    /// doesn't change from frame to frame, so this is worked out once.
+    map: Vec<Option<(usize, u32)>>,
+    /// The glow, which is the bright part of the screen blurred, so that bright pixels spill into
+    /// their neighbors.
+    bloom: Vec<u32>,
+    picture: Picture,
+}
+
+impl Crt {
+    /// Sets up for screens of the given size, which are drawn `CRT_FACTOR` times larger.
+    pub fn new(width: usize, height: usize) -> Crt {
+        let (out_width, out_height) = (width * CRT_FACTOR, height * CRT_FACTOR);
+        let mut map = Vec::with_capacity(out_width * out_height);
+        for out_y in 0..out_height {
+            for out_x in 0..out_width {
+                // Positions run from -1 to 1 across the picture, through the middle of each pixel.
+                let u = (out_x as f32 + 0.5) / out_width as f32 * 2.0 - 1.0;
+                let v = (out_y as f32 + 0.5) / out_height as f32 * 2.0 - 1.0;
+                let (u, v) = (
+                    u * (1.0 + CRT_CURVATURE * v * v),
+                    v * (1.0 + CRT_CURVATURE * u * u),
+                );
+                if u.abs() >= 1.0 || v.abs() >= 1.0 {
+                    map.push(None);
+                    continue;
+                }
+                let x = (u + 1.0) / 2.0 * width as f32;
+                let y = (v + 1.0) / 2.0 * height as f32;
+
+                // Each scanline is brightest in the middle and fades toward the gaps on either
+                // side.
+                let distance = (y.fract() - 0.5).abs() * 2.0;
+                let brightness =
+                    256 - ((256 - CRT_SCANLINE_GAP) as f32 * distance * distance) as u32;
+                map.push(Some((y as usize * width + x as usize, brightness)));
+            }
+        }
+        Crt {
+            width: width,
+            height: height,
+            map: map,
+            bloom: vec![0; width * height],
+            picture: Picture::new(),
+        }
+    }
+
+    /// Draws a screen into `dest`, which must have room for `CRT_FACTOR` times as many pixels in
+    /// each direction, in the same format.
+    pub fn run(&mut self, src: &[u8], format: PixelFormat, dest: &mut [u8]) {
+        self.picture
+            .load(src, self.width, self.height, format, false);
+        let picture = &self.picture;
+        // The glow at each pixel is the average of the bright parts of its four neighbors.
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let bright = |dx, dy| bright_part(picture.get(x, y, dx, dy));
+                let horizontal = blend(bright(-1, 0), bright(1, 0));
+                let vertical = blend(bright(0, -1), bright(0, 1));
+                self.bloom[y * self.width + x] = blend(horizontal, vertical);
+            }
+        }
+
+        let out_width = self.width * CRT_FACTOR;
+        for (i, &pixel) in self.map.iter().enumerate() {
+            let color = match pixel {
+                None => 0,
+                Some((index, brightness)) => {
+                    let (color, glow) = (picture.pixels[index], self.bloom[index]);
+                    // The mask's stripes run red, green, blue from left to right.
+                    let stripe = 16 - (i % out_width % 3) * 8;
+                    let mut result = 0;
+                    for shift in [16, 8, 0].iter() {
+                        let mask = if *shift == stripe { 256 } else { CRT_MASK };
+                        let channel = (color >> shift) & 0xff;
+                        let glow = (glow >> shift) & 0xff;
+                        let value =
+                            channel * brightness / 256 * mask / 256 + glow * CRT_BLOOM / 256;
+                        result |= value.min(0xff) << shift;
+                    }
+                    result
+                }
+            };
+            store(dest, i * format.bytes_per_pixel(), format, color);
+        }
+    }
+}
+
+// The part of each channel above `CRT_BLOOM_THRESHOLD`, stretched back out to the full range.
+fn bright_part(color: u32) -> u32 {
+    let mut result = 0;
+    for shift in [16, 8, 0].iter() {
+        let channel = (color >> shift) & 0xff;
+        let bright = channel.saturating_sub(CRT_BLOOM_THRESHOLD);
+        result |= (bright * 0xff / (0xff - CRT_BLOOM_THRESHOLD)) << shift;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const K: u32 = 0x000000;
+    const W: u32 = 0xffffff;
+
+    // Upscales a picture of 0xRRGGBB colors, returning the upscaled colors.
+    fn upscale(upscaler: Upscaler, colors: &[u32], width: usize) -> Vec<u32> {
+        let format = PixelFormat::Rgba32;
+        let height = colors.len() / width;
+        let mut src = vec![0; colors.len() * 4];
+        for (i, &color) in colors.iter().enumerate() {
+            store(&mut src, i * 4, format, color);
+        }
+        let factor = upscaler.factor();
+        let mut dest = vec![0; src.len() * factor * factor];
+        upscaler.run(&mut Picture::new(), &src, width, height, format, &mut dest);
+        (0..dest.len() / 4)
+            .map(|i| load(&dest, i * 4, format))
+            .collect()
+    }
+
+    // A white corner in the top left of a black 3x3 picture, which the middle pixel is next to.
+    const CORNER: [u32; 9] = [K, W, K, W, K, K, K, K, K];
+
+    #[test]
+    fn scale2x_fills_in_corner() {
+        let out = upscale(Upscaler::Scale2x, &CORNER, 3);
+        // The middle pixel's block is at (2, 2) to (3, 3) of the 6x6 output.
+        assert_eq!(&out[2 * 6 + 2..2 * 6 + 4], &[W, K]);
+        assert_eq!(&out[3 * 6 + 2..3 * 6 + 4], &[K, K]);
+    }
+
+    #[test]
+    fn scale3x_fills_in_corner() {
+        let out = upscale(Upscaler::Scale3x, &CORNER, 3);
+        // The middle pixel's block is at (3, 3) to (5, 5) of the 9x9 output.
+        assert_eq!(&out[3 * 9 + 3..3 * 9 + 6], &[W, K, K]);
+        assert_eq!(&out[4 * 9 + 3..4 * 9 + 6], &[K, K, K]);
+        assert_eq!(&out[5 * 9 + 3..5 * 9 + 6], &[K, K, K]);
+    }
+
+    // A 5x5 picture that's white above the diagonal from the bottom left to the top right.
+    fn diagonal() -> Vec<u32> {
+        (0..25)
+            .map(|i| if i % 5 + i / 5 <= 4 { W } else { K })
+            .collect()
+    }
+
+    #[test]
+    fn xbr2x_blends_corner_on_diagonal_edge() {
+        let out = upscale(Upscaler::Xbr2x, &diagonal(), 5);
+        // The middle pixel is white, with black to its right and below.
+        assert_eq!(&out[4 * 10 + 4..4 * 10 + 6], &[W, W]);
+        assert_eq!(&out[5 * 10 + 4..5 * 10 + 6], &[W, blend(W, K)]);
+    }
+
+    #[test]
+    fn hq2x_blends_corner_on_diagonal_edge() {
+        let out = upscale(Upscaler::Hq2x, &diagonal(), 5);
+        assert_eq!(&out[4 * 10 + 4..4 * 10 + 6], &[W, W]);
+        assert_eq!(&out[5 * 10 + 4..5 * 10 + 6], &[W, 0x3f3f3f]);
+    }
+
+    #[test]
+    fn flat_picture_is_unchanged() {
+        for &upscaler in UPSCALERS.iter() {
+            let out = upscale(upscaler, &[0x123456; 16], 4);
+            assert!(out.iter().all(|&color| color == 0x123456));
+        }
+    }
+
+    #[test]
+    fn only_bright_colors_glow() {
+        assert_eq!(bright_part(0x808080), 0);
+        assert_eq!(bright_part(0xffa000), 0xff0000);
+    }
+}
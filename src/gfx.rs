@@ -2,15 +2,22 @@
 // Author: Patrick Walton
 //
 
-use sdl2::render::{Canvas, Texture, TextureAccess};
+use capture::GifWriter;
+use filters::{Crt, Picture, Upscaler, CRT_FACTOR};
+use png;
+use ppu::PixelFormat;
+
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::render::{BlendMode, Canvas, Texture, TextureAccess};
 use sdl2::Sdl;
+use std::mem;
+use std::path::PathBuf;
 
 /// Emulated screen width in pixels
 const SCREEN_WIDTH: usize = 256;
 /// Emulated screen height in pixels
 const SCREEN_HEIGHT: usize = 240;
-/// Screen texture size in bytes
-const SCREEN_SIZE: usize = SCREEN_WIDTH * SCREEN_HEIGHT * 3;
 
 const FONT_HEIGHT: usize = 10;
 const FONT_GLYPH_COUNT: usize = 95;
@@ -21,6 +28,9 @@ const STATUS_LINE_X: usize = STATUS_LINE_PADDING;
 const STATUS_LINE_Y: usize = SCREEN_HEIGHT - STATUS_LINE_PADDING - FONT_HEIGHT;
 const STATUS_LINE_PAUSE_DURATION: usize = 120; // in 1/60 of a second
 
+const OVERLAY_X: usize = STATUS_LINE_PADDING;
+const OVERLAY_Y: usize = STATUS_LINE_PADDING;
+
 //
 // PT Ronda Seven
 //
@@ -235,6 +245,7 @@ enum GlyphColor {
 fn draw_glyph(
     pixels: &mut [u8],
     surface_width: usize,
+    format: PixelFormat,
     x: isize,
     y: isize,
     color: GlyphColor,
@@ -248,9 +259,10 @@ fn draw_glyph(
         let row = FONT_GLYPHS[glyph_index * 10 + y_index as usize];
         for x_index in 0..8 {
             if ((row >> (7 - x_index) as usize) & 1) != 0 {
+                let bytes_per_pixel = format.bytes_per_pixel() as isize;
                 for channel in 0..3 {
                     let mut index =
-                        (y + y_index) * (surface_width as isize) * 3 + (x + x_index) * 3;
+                        ((y + y_index) * (surface_width as isize) + x + x_index) * bytes_per_pixel;
                     index += channel;
 
                     if index >= 0 && index < pixels.len() as isize {
@@ -262,19 +274,45 @@ fn draw_glyph(
     }
 }
 
-pub fn draw_text(pixels: &mut [u8], surface_width: usize, mut x: isize, y: isize, string: &str) {
+/// How many pixels wide a string is drawn.
+pub fn text_width(string: &str) -> usize {
+    string
+        .bytes()
+        .map(|byte| byte.wrapping_sub(32) as usize)
+        .filter(|&glyph_index| glyph_index < FONT_ADVANCES.len())
+        .map(|glyph_index| FONT_ADVANCES[glyph_index] as usize)
+        .sum()
+}
+
+pub fn draw_text(
+    pixels: &mut [u8],
+    surface_width: usize,
+    format: PixelFormat,
+    mut x: isize,
+    y: isize,
+    string: &str,
+) {
     for i in 0..string.len() {
         let glyph_index = (string.as_bytes()[i] - 32) as usize;
         if glyph_index < FONT_ADVANCES.len() {
             draw_glyph(
                 pixels,
                 surface_width,
+                format,
                 x,
                 y + 1,
                 GlyphColor::Black,
                 glyph_index,
             ); // Shadow
-            draw_glyph(pixels, surface_width, x, y, GlyphColor::White, glyph_index); // Main
+            draw_glyph(
+                pixels,
+                surface_width,
+                format,
+                x,
+                y,
+                GlyphColor::White,
+                glyph_index,
+            ); // Main
             x += FONT_ADVANCES[glyph_index] as isize;
         }
     }
@@ -321,7 +359,7 @@ impl StatusLineText {
         }
     }
 
-    fn render(&self, pixels: &mut [u8]) {
+    fn render(&self, pixels: &mut [u8], format: PixelFormat) {
         if self.animation == Idle {
             return;
         }
@@ -333,6 +371,7 @@ impl StatusLineText {
         draw_text(
             pixels,
             SCREEN_WIDTH,
+            format,
             STATUS_LINE_X as isize,
             y,
             &self.string,
@@ -353,8 +392,8 @@ impl StatusLine {
     pub fn set(&mut self, new_text: String) {
         self.text.set(new_text);
     }
-    pub fn render(&self, pixels: &mut [u8]) {
-        self.text.render(pixels);
+    pub fn render(&self, pixels: &mut [u8], format: PixelFormat) {
+        self.text.render(pixels, format);
     }
 }
 
@@ -362,91 +401,423 @@ impl StatusLine {
 // Screen scaling
 //
 
-#[derive(Copy, Clone)]
-pub enum Scale {
-    Scale1x,
-    Scale2x,
-    Scale3x,
+/// The shape of the console's pixels on screen.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Aspect {
+    /// Square pixels, so that the picture is 256:240.
+    Square,
+    /// The pixels of an NTSC television, which are 8:7 as wide as they are tall.
+    Ntsc,
 }
 
-impl Scale {
-    fn factor(self) -> usize {
+impl Aspect {
+    /// How wide a pixel is for its height.
+    fn pixel_aspect(self) -> f64 {
         match self {
-            Scale::Scale1x => 1,
-            Scale::Scale2x => 2,
-            Scale::Scale3x => 3,
+            Aspect::Square => 1.0,
+            Aspect::Ntsc => 8.0 / 7.0,
         }
     }
+
+    /// The size of the picture at the given scale, in window pixels.
+    fn size(self, scale: f64) -> (u32, u32) {
+        let width = SCREEN_WIDTH as f64 * self.pixel_aspect() * scale;
+        let height = SCREEN_HEIGHT as f64 * scale;
+        (width.round() as u32, height.round() as u32)
+    }
+}
+
+/// The rows of the window that darken the gaps between scanlines: the lower half of the rows
+/// each of the console's scanlines covers in the picture. Scanlines a single row tall have no
+/// room for a gap.
+fn scanline_gaps(picture: Rect) -> Vec<Rect> {
+    let height = picture.height() as usize;
+    (0..SCREEN_HEIGHT)
+        .filter_map(|line| {
+            let top = line * height / SCREEN_HEIGHT;
+            let bottom = (line + 1) * height / SCREEN_HEIGHT;
+            let gap = (bottom - top) / 2;
+            if gap == 0 {
+                return None;
+            }
+            let y = picture.y() + (bottom - gap) as i32;
+            Some(Rect::new(picture.x(), y, picture.width(), gap as u32))
+        })
+        .collect()
+}
+
+/// Where the picture is drawn in a window of the given size: centered, as large as fits without
+/// changing its aspect ratio and, if `integer` is set, only at whole multiples of the screen's
+/// height so that every scanline is the same number of pixels tall.
+pub fn picture_rect(window_width: u32, window_height: u32, aspect: Aspect, integer: bool) -> Rect {
+    let (unscaled_width, unscaled_height) = aspect.size(1.0);
+    let mut scale = f64::min(
+        window_width as f64 / unscaled_width as f64,
+        window_height as f64 / unscaled_height as f64,
+    );
+    if integer {
+        scale = f64::max(scale.floor(), 1.0);
+    }
+    let (width, height) = aspect.size(scale);
+    Rect::new(
+        (window_width as i32 - width as i32) / 2,
+        (window_height as i32 - height as i32) / 2,
+        width,
+        height,
+    )
 }
 
 pub struct Gfx {
     pub renderer: Box<Canvas<Window>>,
     pub texture: Texture<'static>,
-    pub scale: Scale,
+    /// The shape of the pixels in the window.
+    pub aspect: Aspect,
+    /// If true, the picture is only scaled by whole numbers, leaving a border if the window
+    /// isn't a multiple of its size.
+    pub integer_scaling: bool,
+    /// If true, the lower half of each scanline is darkened, for a look like a television's
+    /// scanlines without the cost of the CRT filter.
+    pub scanlines: bool,
+    /// How much the scanlines darken the lines they cover, in percent.
+    pub scanline_intensity: u8,
     pub status_line: StatusLine,
-    _texture_creator: TextureCreator<WindowContext>,
+    /// Lines of text drawn in the top left corner every frame until cleared.
+    pub overlay: Vec<String>,
+    /// Lines of text drawn in the top right corner every frame until cleared.
+    pub corner_overlay: Vec<String>,
+    /// The layout of the screens passed to `composite`, which the texture is created to match.
+    pixel_format: PixelFormat,
+    /// What the screen is scaled up with before it's copied to the texture, where the screen is
+    /// unpacked for it, and where the upscaled screen goes.
+    upscaler: Upscaler,
+    picture: Picture,
+    upscaled: Vec<u8>,
+    /// If set, the screen is drawn like a CRT television instead of going through the upscaler.
+    crt: Option<Crt>,
+    /// The clip being captured, if any, which is fed each running frame before the overlays.
+    capture: Option<GifWriter>,
+    /// Where the next screen is saved, before the overlays, if a screenshot was asked for.
+    screenshot: Option<PathBuf>,
+    texture_creator: TextureCreator<WindowContext>,
+}
+
+// Creates the streaming texture that frames are copied to, `factor` times the size of the
+// screen. The texture mustn't outlive the creator, which `Gfx` keeps alongside it.
+fn create_texture(
+    texture_creator: &TextureCreator<WindowContext>,
+    pixel_format: PixelFormat,
+    factor: usize,
+) -> Texture<'static> {
+    let texture_creator_pointer = texture_creator as *const TextureCreator<WindowContext>;
+    unsafe { &*texture_creator_pointer }
+        .create_texture(
+            match pixel_format {
+                PixelFormat::Bgr24 => PixelFormatEnum::BGR24,
+                PixelFormat::Rgba32 => PixelFormatEnum::RGBA32,
+            },
+            TextureAccess::Streaming,
+            (SCREEN_WIDTH * factor) as u32,
+            (SCREEN_HEIGHT * factor) as u32,
+        )
+        .unwrap()
 }
 
 impl Gfx {
-    pub fn new(scale: Scale) -> (Gfx, Sdl) {
+    /// Opens a window `scale` times the size of the picture. It can be resized after.
+    pub fn new(scale: u32, aspect: Aspect, vsync: bool, pixel_format: PixelFormat) -> (Gfx, Sdl) {
         // FIXME: Handle SDL better
 
         let sdl = sdl2::init().unwrap();
         let video_subsystem = sdl.video().unwrap();
 
-        let mut window_builder = video_subsystem.window(
-            "sprocketnes",
-            (SCREEN_WIDTH as usize * scale.factor()) as u32,
-            (SCREEN_HEIGHT as usize * scale.factor()) as u32,
-        );
-        let window = window_builder.position_centered().build().unwrap();
-
-        let renderer = window
-            .into_canvas()
-            .accelerated()
-            .present_vsync()
+        let (width, height) = aspect.size(scale as f64);
+        let mut window_builder = video_subsystem.window("sprocketnes", width, height);
+        // The window stays hidden until the emulator is ready to draw into it.
+        let window = window_builder
+            .position_centered()
+            .resizable()
+            .hidden()
             .build()
             .unwrap();
+
+        let mut renderer_builder = window.into_canvas().accelerated();
+        if vsync {
+            renderer_builder = renderer_builder.present_vsync();
+        }
+        let renderer = renderer_builder.build().unwrap();
         let texture_creator = renderer.texture_creator();
-        let texture_creator_pointer = &texture_creator as *const TextureCreator<WindowContext>;
-        let texture = unsafe { &*texture_creator_pointer }
-            .create_texture(
-                PixelFormatEnum::BGR24,
-                TextureAccess::Streaming,
-                SCREEN_WIDTH as u32,
-                SCREEN_HEIGHT as u32,
-            )
-            .unwrap();
+        let texture = create_texture(&texture_creator, pixel_format, 1);
 
         (
             Gfx {
                 renderer: Box::new(renderer),
                 texture,
-                scale,
+                aspect: aspect,
+                integer_scaling: false,
+                scanlines: false,
+                scanline_intensity: 50,
                 status_line: StatusLine::new(),
-                _texture_creator: texture_creator,
+                overlay: vec![],
+                corner_overlay: vec![],
+                pixel_format: pixel_format,
+                upscaler: Upscaler::None,
+                picture: Picture::new(),
+                upscaled: vec![],
+                crt: None,
+                capture: None,
+                screenshot: None,
+                texture_creator: texture_creator,
             },
             sdl,
         )
     }
 
+    pub fn show(&mut self) {
+        self.renderer.window_mut().show();
+    }
+
+    pub fn upscaler(&self) -> Upscaler {
+        self.upscaler
+    }
+
+    /// Switches to another upscaler. It has no effect while the CRT look is on.
+    pub fn set_upscaler(&mut self, upscaler: Upscaler) {
+        self.upscaler = upscaler;
+        self.resize_texture();
+    }
+
+    pub fn crt(&self) -> bool {
+        self.crt.is_some()
+    }
+
+    /// Turns the imitation of a CRT television on or off.
+    pub fn set_crt(&mut self, enabled: bool) {
+        self.crt = if enabled {
+            Some(Crt::new(SCREEN_WIDTH, SCREEN_HEIGHT))
+        } else {
+            None
+        };
+        self.resize_texture();
+    }
+
+    pub fn capturing(&self) -> bool {
+        self.capture.is_some()
+    }
+
+    /// Starts feeding every screen composited to a clip, or with `None`, stops. Returns the clip
+    /// that was being captured, which must be finished.
+    pub fn set_capture(&mut self, capture: Option<GifWriter>) -> Option<GifWriter> {
+        mem::replace(&mut self.capture, capture)
+    }
+
+    /// Saves the next screen composited to a PNG, without the overlays drawn over it.
+    pub fn request_screenshot(&mut self, path: PathBuf) {
+        self.screenshot = Some(path);
+    }
+
+    // Makes a texture of the size the screen is scaled up to.
+    fn resize_texture(&mut self) {
+        let factor = match self.crt {
+            Some(_) => CRT_FACTOR,
+            None => self.upscaler.factor(),
+        };
+        self.texture = create_texture(&self.texture_creator, self.pixel_format, factor);
+        self.upscaled = vec![
+            0;
+            SCREEN_WIDTH
+                * SCREEN_HEIGHT
+                * factor
+                * factor
+                * self.pixel_format.bytes_per_pixel()
+        ];
+    }
+
     pub fn tick(&mut self) {
         self.status_line.text.tick();
     }
 
-    /// Copies the overlay onto the given screen and displays it to the SDL window.
-    pub fn composite(&mut self, ppu_screen: &mut [u8; SCREEN_SIZE]) {
-        self.status_line.render(ppu_screen);
+    /// Feeds a frame of the running game to the clip being captured, if any. Frames drawn while
+    /// paused aren't fed, so that the clip only shows the game playing.
+    pub fn feed_capture(&mut self, ppu_screen: &[u8]) {
+        let failed = match self.capture {
+            Some(ref mut capture) => capture.frame(ppu_screen, self.pixel_format).is_err(),
+            None => false,
+        };
+        if failed {
+            self.capture = None;
+            self.status_line.set("GIF capture failed".to_string());
+        }
+    }
+
+    /// Copies the overlays onto the given screen and displays it to the SDL window.
+    pub fn composite(&mut self, ppu_screen: &mut [u8]) {
+        if let Some(path) = self.screenshot.take() {
+            let (width, height) = (SCREEN_WIDTH, SCREEN_HEIGHT);
+            match png::save(&path, ppu_screen, width, height, self.pixel_format) {
+                Ok(()) => self.status_line.set(format!("Saved {}", path.display())),
+                Err(err) => {
+                    println!("Couldn't write {}: {}", path.display(), err);
+                    self.status_line.set("Screenshot failed".to_string());
+                }
+            }
+        }
+        for (i, line) in self.overlay.iter().enumerate() {
+            let y = OVERLAY_Y + i * FONT_HEIGHT;
+            draw_text(
+                ppu_screen,
+                SCREEN_WIDTH,
+                self.pixel_format,
+                OVERLAY_X as isize,
+                y as isize,
+                line,
+            );
+        }
+        for (i, line) in self.corner_overlay.iter().enumerate() {
+            let x = SCREEN_WIDTH - OVERLAY_X - text_width(line);
+            let y = OVERLAY_Y + i * FONT_HEIGHT;
+            draw_text(
+                ppu_screen,
+                SCREEN_WIDTH,
+                self.pixel_format,
+                x as isize,
+                y as isize,
+                line,
+            );
+        }
+        self.status_line.render(ppu_screen, self.pixel_format);
         self.blit(ppu_screen);
         self.renderer.clear();
-        let _ = self.renderer.copy(&self.texture, None, None);
+        // The window's size is the drawable size unless the display scales it, so it's the best
+        // guess if SDL can't say.
+        let (width, height) = match self.renderer.output_size() {
+            Ok(size) => size,
+            Err(_) => self.renderer.window().size(),
+        };
+        let dest = picture_rect(width, height, self.aspect, self.integer_scaling);
+        let _ = self.renderer.copy(&self.texture, None, dest);
+        if self.scanlines {
+            self.draw_scanlines(dest);
+        }
         self.renderer.present();
     }
 
+    // Darkens the lower half of each of the console's scanlines across the picture.
+    fn draw_scanlines(&mut self, picture: Rect) {
+        let lines = scanline_gaps(picture);
+        let alpha = (self.scanline_intensity.min(100) as u32 * 255 / 100) as u8;
+        self.renderer.set_blend_mode(BlendMode::Blend);
+        self.renderer.set_draw_color(Color::RGBA(0, 0, 0, alpha));
+        let _ = self.renderer.fill_rects(&lines);
+        // The window is cleared to the draw color.
+        self.renderer.set_draw_color(Color::RGB(0, 0, 0));
+    }
+
     /// Updates the window texture with new screen data.
-    fn blit(&mut self, ppu_screen: &[u8; SCREEN_SIZE]) {
+    fn blit(&mut self, ppu_screen: &[u8]) {
+        let pitch = SCREEN_WIDTH * self.pixel_format.bytes_per_pixel();
+        let factor = match self.crt {
+            Some(ref mut crt) => {
+                crt.run(ppu_screen, self.pixel_format, &mut self.upscaled);
+                CRT_FACTOR
+            }
+            None if self.upscaler == Upscaler::None => {
+                return self.texture.update(None, ppu_screen, pitch).unwrap();
+            }
+            None => {
+                self.upscaler.run(
+                    &mut self.picture,
+                    ppu_screen,
+                    SCREEN_WIDTH,
+                    SCREEN_HEIGHT,
+                    self.pixel_format,
+                    &mut self.upscaled,
+                );
+                self.upscaler.factor()
+            }
+        };
+        let upscaled_pitch = pitch * factor;
         self.texture
-            .update(None, ppu_screen, SCREEN_WIDTH * 3)
+            .update(None, &self.upscaled, upscaled_pitch)
             .unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scanline_gaps_darken_lower_half_of_each_line() {
+        let gaps = scanline_gaps(Rect::new(10, 20, 512, 480));
+        assert_eq!(gaps.len(), SCREEN_HEIGHT);
+        assert_eq!(gaps[0], Rect::new(10, 21, 512, 1));
+        assert_eq!(gaps[239], Rect::new(10, 499, 512, 1));
+
+        let gaps = scanline_gaps(Rect::new(0, 0, 768, 720));
+        assert_eq!(gaps[1], Rect::new(0, 5, 768, 1));
+
+        // At 2.5 times, lines alternate between 2 and 3 rows, and each gets a gap of 1.
+        let gaps = scanline_gaps(Rect::new(0, 0, 640, 600));
+        assert_eq!(gaps.len(), SCREEN_HEIGHT);
+        assert_eq!(gaps[1], Rect::new(0, 4, 640, 1));
+
+        assert!(scanline_gaps(Rect::new(0, 0, 256, 240)).is_empty());
+    }
+
+    #[test]
+    fn picture_fills_the_window_without_changing_shape() {
+        assert_eq!(
+            picture_rect(512, 480, Aspect::Square, false),
+            Rect::new(0, 0, 512, 480)
+        );
+        // Wider than the picture, so it's as tall as the window and centered across it.
+        assert_eq!(
+            picture_rect(800, 600, Aspect::Square, false),
+            Rect::new(80, 0, 640, 600)
+        );
+        // Taller than the picture
+        assert_eq!(
+            picture_rect(512, 600, Aspect::Square, false),
+            Rect::new(0, 60, 512, 480)
+        );
+    }
+
+    #[test]
+    fn ntsc_pixels_are_wider() {
+        // 256 * 8 / 7 * 2.5 = 731.4
+        assert_eq!(
+            picture_rect(800, 600, Aspect::Ntsc, false),
+            Rect::new(34, 0, 731, 600)
+        );
+        assert_eq!(
+            picture_rect(800, 600, Aspect::Ntsc, true),
+            Rect::new(107, 60, 585, 480)
+        );
+    }
+
+    #[test]
+    fn integer_scaling_rounds_down_to_whole_multiples() {
+        assert_eq!(
+            picture_rect(800, 600, Aspect::Square, true),
+            Rect::new(144, 60, 512, 480)
+        );
+        assert_eq!(
+            picture_rect(768, 720, Aspect::Square, true),
+            Rect::new(0, 0, 768, 720)
+        );
+    }
+
+    #[test]
+    fn windows_smaller_than_the_screen() {
+        // The picture shrinks to fit, unless it's only scaled by whole numbers, in which case it
+        // stays at 1x and the window shows its middle.
+        assert_eq!(
+            picture_rect(128, 120, Aspect::Square, false),
+            Rect::new(0, 0, 128, 120)
+        );
+        assert_eq!(
+            picture_rect(128, 120, Aspect::Square, true),
+            Rect::new(-64, -60, 256, 240)
+        );
+    }
+}
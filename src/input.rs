@@ -2,11 +2,15 @@
 // Author: Patrick Walton
 //
 
+use apu::Channel;
+use config::Config;
+use debugger::DebugCommand;
 use mem::Mem;
 
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
-use sdl2::Sdl;
+use sdl2::controller::GameController;
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::{Keycode, Mod};
+use sdl2::{GameControllerSubsystem, Sdl};
 
 use std::ops::Deref;
 
@@ -81,20 +85,241 @@ pub struct GamePadState {
     strobe_state: StrobeState,
 }
 
+//
+// Input profiles: which keys map to which buttons
+//
+
+pub struct InputProfile {
+    /// A name shown on the status line when the profile is applied.
+    pub name: String,
+    pub a: Keycode,
+    pub b: Keycode,
+    pub select: Keycode,
+    pub start: Keycode,
+    pub up: Keycode,
+    pub down: Keycode,
+    pub left: Keycode,
+    pub right: Keycode,
+}
+
+impl InputProfile {
+    /// The standard bindings, as documented in the README.
+    pub fn standard() -> InputProfile {
+        InputProfile {
+            name: "standard".to_string(),
+            a: Keycode::Z,
+            b: Keycode::X,
+            select: Keycode::RShift,
+            start: Keycode::Return,
+            up: Keycode::Up,
+            down: Keycode::Down,
+            left: Keycode::Left,
+            right: Keycode::Right,
+        }
+    }
+
+    /// Whether any button is bound to the key.
+    pub fn binds(&self, key: Keycode) -> bool {
+        [
+            self.a,
+            self.b,
+            self.select,
+            self.start,
+            self.up,
+            self.down,
+            self.left,
+            self.right,
+        ]
+        .contains(&key)
+    }
+
+    /// Returns one of the built-in profiles by name.
+    pub fn builtin(name: &str) -> Option<InputProfile> {
+        match name {
+            "standard" => Some(InputProfile::standard()),
+            "swap-ab" => {
+                // Handy for arcade ports, where the buttons are often the other way around.
+                let mut profile = InputProfile::standard();
+                profile.name = name.to_string();
+                profile.a = Keycode::X;
+                profile.b = Keycode::Z;
+                Some(profile)
+            }
+            _ => None,
+        }
+    }
+
+    /// Builds a profile from the `input.*` keys of a configuration file. `input.profile` names a
+    /// built-in profile (or a custom one, which starts from the standard bindings), and
+    /// `input.a`, `input.start`, etc. rebind individual buttons to SDL key names.
+    pub fn from_config(config: &Config) -> Result<InputProfile, String> {
+        let mut profile = match config.get("input.profile") {
+            None => InputProfile::standard(),
+            Some(name) => match InputProfile::builtin(name) {
+                Some(profile) => profile,
+                None => {
+                    let mut profile = InputProfile::standard();
+                    profile.name = name.to_string();
+                    profile
+                }
+            },
+        };
+
+        for (key, value) in config.with_prefix("input.") {
+            if key == "input.profile" {
+                continue;
+            }
+            let keycode = match Keycode::from_name(value) {
+                Some(keycode) => keycode,
+                None => return Err(format!("unknown key name for {}: {}", key, value)),
+            };
+            match key {
+                "input.a" => profile.a = keycode,
+                "input.b" => profile.b = keycode,
+                "input.select" => profile.select = keycode,
+                "input.start" => profile.start = keycode,
+                "input.up" => profile.up = keycode,
+                "input.down" => profile.down = keycode,
+                "input.left" => profile.left = keycode,
+                "input.right" => profile.right = keycode,
+                _ => return Err(format!("unknown input setting: {}", key)),
+            }
+        }
+
+        Ok(profile)
+    }
+}
+
+//
+// Rumble
+//
+
+/// When a rumble trigger fires.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum RumbleCondition {
+    /// Whenever a different value is written.
+    Changed,
+    /// Whenever a smaller value is written, such as a health counter going down.
+    Decreased,
+    /// Whenever the given value is written.
+    Equals(u8),
+}
+
+/// Rumbles attached game controllers when the game writes to an address in CPU RAM. This lets
+/// users add haptics to games that know nothing about them, e.g. by watching the player's health.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct RumbleTrigger {
+    /// The CPU RAM address to watch ($0000-$1FFF).
+    pub addr: u16,
+    pub condition: RumbleCondition,
+    /// Low-frequency (left) motor intensity.
+    pub low_frequency: u16,
+    /// High-frequency (right) motor intensity.
+    pub high_frequency: u16,
+    pub duration_ms: u32,
+}
+
+impl RumbleTrigger {
+    /// Parses a trigger written as the address, the condition, the two motor intensities and the
+    /// duration in milliseconds, e.g. `$0075 decreased 40000 40000 250`. The address is in hex,
+    /// and the condition is `changed`, `decreased`, or `=` and a hex value, like `=00`.
+    pub fn parse(text: &str) -> Result<RumbleTrigger, String> {
+        let error = || format!("expected `addr condition low high ms` for rumble: {}", text);
+        let fields: Vec<&str> = text.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(error());
+        }
+        let addr =
+            u16::from_str_radix(fields[0].trim_start_matches('$'), 16).map_err(|_| error())?;
+        let condition = match fields[1] {
+            "changed" => RumbleCondition::Changed,
+            "decreased" => RumbleCondition::Decreased,
+            value if value.starts_with('=') => {
+                let value = value[1..].trim_start_matches('$');
+                RumbleCondition::Equals(u8::from_str_radix(value, 16).map_err(|_| error())?)
+            }
+            _ => return Err(error()),
+        };
+        Ok(RumbleTrigger {
+            addr: addr,
+            condition: condition,
+            low_frequency: fields[2].parse().map_err(|_| error())?,
+            high_frequency: fields[3].parse().map_err(|_| error())?,
+            duration_ms: fields[4].parse().map_err(|_| error())?,
+        })
+    }
+
+    fn fires(&self, addr: u16, old_val: u8, new_val: u8) -> bool {
+        if (addr & 0x7ff) != (self.addr & 0x7ff) {
+            return false;
+        }
+        match self.condition {
+            RumbleCondition::Changed => new_val != old_val,
+            RumbleCondition::Decreased => new_val < old_val,
+            RumbleCondition::Equals(val) => new_val == val && old_val != val,
+        }
+    }
+}
+
 pub struct Input {
     pub gamepad_0: GamePadState,
-    sdl: Sdl, // FIXME: Use a `&'a mut EventPump` instead
+    profile: InputProfile,
+    sdl: Option<Sdl>, // FIXME: Use a `&'a mut EventPump` instead
+    controller_subsystem: Option<GameControllerSubsystem>,
+    controllers: Vec<GameController>,
+    rumble_triggers: Vec<RumbleTrigger>,
+    /// Whether the fast-forward key, the backquote, is held down.
+    fast_forward: bool,
 }
 
 pub enum InputResult {
-    Continue,  // Keep playing.
-    Quit,      // Quit the emulator.
-    SaveState, // Save a state.
-    LoadState, // Load a state.
+    Continue,            // Keep playing.
+    Quit,                // Quit the emulator.
+    SaveState,           // Save a state.
+    LoadState,           // Load a state.
+    ExportFceux,         // Write the state as an FCEUX savestate.
+    ToggleSpriteLimit,   // Toggle the eight-sprites-per-scanline limit.
+    ToggleSoundReadout,  // Toggle the sound channel frequency readout.
+    TogglePerformance,   // Toggle the frame rate and speed readout.
+    ToggleSolo(Channel), // Solo a sound channel, or unsolo it.
+    ToggleMute(Channel), // Mute a sound channel, or unmute it.
+    ToggleTrace,         // Start or stop logging instructions.
+    ToggleRecording,     // Start or stop recording the audio.
+    ToggleCapture,       // Start or stop capturing the screen to a GIF.
+    Screenshot,          // Save the screen to a PNG.
+    Debug(DebugCommand), // Pause, resume or step the console.
+    CycleViewer,         // Show the next PPU viewer, or the game after the last.
+    CycleViewerPalette,  // Color the pattern table viewer with the next palette.
+    CycleUpscaler,       // Smooth the screen with the next upscaler.
+    ToggleCrt,           // Draw the screen like a CRT television, or stop.
+    ToggleScanlines,     // Darken the gaps between scanlines, or stop.
+}
+
+// The number keys 1-5 mute the sound channels in the order F5-F9 solo them: pulse 1, pulse 2,
+// triangle, noise and cartridge audio. The DMC has no key, since it's always silent.
+fn channel_key(key: Keycode) -> Option<Channel> {
+    match key {
+        Keycode::Num1 => Some(Channel::Pulse1),
+        Keycode::Num2 => Some(Channel::Pulse2),
+        Keycode::Num3 => Some(Channel::Triangle),
+        Keycode::Num4 => Some(Channel::Noise),
+        Keycode::Num5 => Some(Channel::Expansion),
+        _ => None,
+    }
 }
 
 impl Input {
     pub fn new(sdl: Sdl) -> Input {
+        Input::with_sdl(Some(sdl))
+    }
+
+    /// Creates an input device with no window to read events from. The gamepad can still be
+    /// driven by writing to `gamepad_0`.
+    pub fn headless() -> Input {
+        Input::with_sdl(None)
+    }
+
+    fn with_sdl(sdl: Option<Sdl>) -> Input {
         Input {
             gamepad_0: GamePadState {
                 left: false,
@@ -110,51 +335,273 @@ impl Input {
                     val: STROBE_STATE_A,
                 },
             },
+            profile: InputProfile::standard(),
+            controller_subsystem: sdl.as_ref().and_then(|sdl| sdl.game_controller().ok()),
+            controllers: vec![],
+            rumble_triggers: vec![],
+            fast_forward: false,
             sdl: sdl,
         }
     }
 
+    /// Rumbles all attached game controllers that support it. Intensities range from 0 to 0xffff.
+    pub fn rumble(&mut self, low_frequency: u16, high_frequency: u16, duration_ms: u32) {
+        for controller in self.controllers.iter_mut() {
+            // Not every controller has motors; that's fine.
+            let _ = controller.set_rumble(low_frequency, high_frequency, duration_ms);
+        }
+    }
+
+    /// Registers a trigger that rumbles the controllers when the game writes to CPU RAM.
+    pub fn add_rumble_trigger(&mut self, trigger: RumbleTrigger) {
+        self.rumble_triggers.push(trigger);
+    }
+
+    pub fn clear_rumble_triggers(&mut self) {
+        self.rumble_triggers.clear();
+    }
+
+    pub fn has_rumble_triggers(&self) -> bool {
+        !self.rumble_triggers.is_empty()
+    }
+
+    /// Called by the memory map when the CPU stores `new_val` over `old_val` in RAM.
+    pub fn check_rumble_triggers(&mut self, addr: u16, old_val: u8, new_val: u8) {
+        let mut rumble = None;
+        for trigger in self.rumble_triggers.iter() {
+            if trigger.fires(addr, old_val, new_val) {
+                rumble = Some(*trigger);
+            }
+        }
+        if let Some(trigger) = rumble {
+            self.rumble(
+                trigger.low_frequency,
+                trigger.high_frequency,
+                trigger.duration_ms,
+            );
+        }
+    }
+
+    fn add_controller(&mut self, joystick_index: u32) {
+        if let Some(ref subsystem) = self.controller_subsystem {
+            if let Ok(controller) = subsystem.open(joystick_index) {
+                self.controllers.push(controller);
+            }
+        }
+    }
+
+    fn remove_controller(&mut self, instance_id: i32) {
+        self.controllers
+            .retain(|controller| controller.instance_id() != instance_id);
+    }
+
+    /// Returns true while the backquote key is held. The game runs as fast as it can meanwhile.
+    pub fn fast_forward(&self) -> bool {
+        self.fast_forward
+    }
+
+    pub fn profile(&self) -> &InputProfile {
+        &self.profile
+    }
+
+    pub fn set_profile(&mut self, profile: InputProfile) {
+        self.profile = profile;
+    }
+
     fn handle_gamepad_event(&mut self, key: Keycode, down: bool) {
-        match key {
-            Keycode::Left => self.gamepad_0.left = down,
-            Keycode::Down => self.gamepad_0.down = down,
-            Keycode::Up => self.gamepad_0.up = down,
-            Keycode::Right => self.gamepad_0.right = down,
-            Keycode::Z => self.gamepad_0.a = down,
-            Keycode::X => self.gamepad_0.b = down,
-            Keycode::RShift => self.gamepad_0.select = down,
-            Keycode::Return => self.gamepad_0.start = down,
-            _ => {}
+        // NB: Not a match, since a profile may bind one key to several buttons.
+        let (profile, gamepad) = (&self.profile, &mut self.gamepad_0);
+        if key == profile.left {
+            gamepad.left = down
+        }
+        if key == profile.down {
+            gamepad.down = down
+        }
+        if key == profile.up {
+            gamepad.up = down
+        }
+        if key == profile.right {
+            gamepad.right = down
+        }
+        if key == profile.a {
+            gamepad.a = down
+        }
+        if key == profile.b {
+            gamepad.b = down
+        }
+        if key == profile.select {
+            gamepad.select = down
+        }
+        if key == profile.start {
+            gamepad.start = down
         }
     }
 
     pub fn check_input(&mut self) -> InputResult {
-        while let Some(ev) = self.sdl.event_pump().unwrap().poll_event() {
+        let mut event_pump = match self.sdl {
+            Some(ref sdl) => sdl.event_pump().unwrap(),
+            None => return InputResult::Continue,
+        };
+        while let Some(ev) = event_pump.poll_event() {
             match ev {
                 Event::KeyDown {
                     keycode: Some(Keycode::Escape),
                     ..
                 } => return InputResult::Quit,
+                // Keys bound to buttons by the profile play the game instead of their usual jobs.
+                Event::KeyDown {
+                    keycode: Some(key), ..
+                } if self.profile.binds(key) => self.handle_gamepad_event(key, true),
+                Event::KeyUp {
+                    keycode: Some(key), ..
+                } if self.profile.binds(key) => self.handle_gamepad_event(key, false),
                 Event::KeyDown {
                     keycode: Some(Keycode::S),
+                    repeat: false,
+                    keymod,
                     ..
-                } => return InputResult::SaveState,
+                } => {
+                    if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                        return InputResult::ExportFceux;
+                    }
+                    return InputResult::SaveState;
+                }
                 Event::KeyDown {
                     keycode: Some(Keycode::L),
+                    repeat: false,
                     ..
                 } => return InputResult::LoadState,
                 Event::KeyDown {
-                    keycode: Some(key), ..
-                } => self.handle_gamepad_event(key, true),
+                    keycode: Some(Keycode::R),
+                    repeat: false,
+                    ..
+                } => return InputResult::ToggleRecording,
+                Event::KeyDown {
+                    keycode: Some(Keycode::G),
+                    repeat: false,
+                    ..
+                } => return InputResult::ToggleCapture,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F1),
+                    repeat: false,
+                    ..
+                } => return InputResult::CycleViewer,
+                Event::KeyDown {
+                    keycode: Some(Keycode::Tab),
+                    repeat: false,
+                    ..
+                } => return InputResult::CycleViewerPalette,
+                Event::KeyDown {
+                    keycode: Some(Keycode::Backquote),
+                    ..
+                } => self.fast_forward = true,
                 Event::KeyUp {
-                    keycode: Some(key), ..
-                } => self.handle_gamepad_event(key, false),
+                    keycode: Some(Keycode::Backquote),
+                    ..
+                } => self.fast_forward = false,
+                // The key may be let go in another window, which this one never hears about.
+                Event::Window {
+                    win_event: WindowEvent::FocusLost,
+                    ..
+                } => self.fast_forward = false,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F),
+                    repeat: false,
+                    ..
+                } => return InputResult::TogglePerformance,
+                Event::KeyDown {
+                    keycode: Some(Keycode::U),
+                    repeat: false,
+                    ..
+                } => return InputResult::CycleUpscaler,
+                Event::KeyDown {
+                    keycode: Some(Keycode::C),
+                    repeat: false,
+                    keymod,
+                    ..
+                } => {
+                    if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                        return InputResult::ToggleScanlines;
+                    }
+                    return InputResult::ToggleCrt;
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F2),
+                    repeat: false,
+                    ..
+                } => return InputResult::ToggleSpriteLimit,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F3),
+                    repeat: false,
+                    ..
+                } => return InputResult::ToggleSoundReadout,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F4),
+                    repeat: false,
+                    ..
+                } => return InputResult::ToggleTrace,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F5),
+                    repeat: false,
+                    ..
+                } => return InputResult::ToggleSolo(Channel::Pulse1),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F6),
+                    repeat: false,
+                    ..
+                } => return InputResult::ToggleSolo(Channel::Pulse2),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F7),
+                    repeat: false,
+                    ..
+                } => return InputResult::ToggleSolo(Channel::Triangle),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F8),
+                    repeat: false,
+                    ..
+                } => return InputResult::ToggleSolo(Channel::Noise),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F9),
+                    repeat: false,
+                    ..
+                } => return InputResult::ToggleSolo(Channel::Expansion),
+                Event::KeyDown {
+                    keycode: Some(Keycode::P),
+                    repeat: false,
+                    ..
+                } => return InputResult::Debug(DebugCommand::TogglePause),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F10),
+                    ..
+                } => return InputResult::Debug(DebugCommand::Step),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F11),
+                    ..
+                } => return InputResult::Debug(DebugCommand::StepOver),
+                Event::KeyDown {
+                    keycode: Some(Keycode::N),
+                    ..
+                } => return InputResult::Debug(DebugCommand::FrameAdvance),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F12),
+                    repeat: false,
+                    ..
+                } => return InputResult::Screenshot,
+                Event::KeyDown {
+                    keycode: Some(key),
+                    repeat: false,
+                    ..
+                } if channel_key(key).is_some() => {
+                    return InputResult::ToggleMute(channel_key(key).unwrap())
+                }
+                Event::ControllerDeviceAdded { which, .. } => self.add_controller(which),
+                Event::ControllerDeviceRemoved { which, .. } => self.remove_controller(which),
                 Event::Quit { .. } => return InputResult::Quit,
                 _ => {}
             }
         }
 
-        return InputResult::Continue;
+        InputResult::Continue
     }
 }
 
@@ -176,4 +623,70 @@ impl Mem for Input {
             self.gamepad_0.strobe_state.reset();
         }
     }
+
+    // Returns the button the next read will return, without moving on to the one after.
+    fn peekb(&self, addr: u16) -> u8 {
+        if addr == 0x4016 {
+            self.gamepad_0.strobe_state.get(&self.gamepad_0) as u8
+        } else {
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rumble_triggers_parse() {
+        assert_eq!(
+            RumbleTrigger::parse(" $0075 decreased 40000 30000 250 "),
+            Ok(RumbleTrigger {
+                addr: 0x0075,
+                condition: RumbleCondition::Decreased,
+                low_frequency: 40000,
+                high_frequency: 30000,
+                duration_ms: 250,
+            })
+        );
+        let trigger = RumbleTrigger::parse("ed =1F 1 2 3").unwrap();
+        assert_eq!(trigger.addr, 0x00ed);
+        assert_eq!(trigger.condition, RumbleCondition::Equals(0x1f));
+        assert_eq!(
+            RumbleTrigger::parse("75 changed 1 2").unwrap_err(),
+            "expected `addr condition low high ms` for rumble: 75 changed 1 2"
+        );
+        assert!(RumbleTrigger::parse("75 more 1 2 3").is_err());
+        assert!(RumbleTrigger::parse("75 changed 70000 2 3").is_err());
+    }
+
+    #[test]
+    fn rumble_triggers_fire() {
+        let trigger = RumbleTrigger::parse("$0075 decreased 1 1 1").unwrap();
+        assert!(trigger.fires(0x0075, 3, 2));
+        // RAM is mirrored every 2K.
+        assert!(trigger.fires(0x0875, 3, 2));
+        assert!(!trigger.fires(0x0075, 2, 3));
+        assert!(!trigger.fires(0x0076, 3, 2));
+
+        let trigger = RumbleTrigger::parse("$0075 =00 1 1 1").unwrap();
+        assert!(trigger.fires(0x0075, 1, 0));
+        assert!(!trigger.fires(0x0075, 0, 0));
+    }
+
+    #[test]
+    fn profile_binds_its_keys() {
+        let profile = InputProfile::builtin("swap-ab").unwrap();
+        assert!(profile.binds(Keycode::X));
+        assert!(profile.binds(Keycode::Return));
+        assert!(!profile.binds(Keycode::C));
+    }
+
+    #[test]
+    fn number_keys_mute_audible_channels() {
+        assert_eq!(channel_key(Keycode::Num1), Some(Channel::Pulse1));
+        assert_eq!(channel_key(Keycode::Num5), Some(Channel::Expansion));
+        assert_eq!(channel_key(Keycode::Num6), None);
+    }
 }
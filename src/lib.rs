@@ -2,9 +2,14 @@
 // Author: Patrick Walton
 //
 
-#[macro_use]
-extern crate lazy_static;
-extern crate libc;
+// The code keeps the 2015 edition's bare trait objects, `...` range patterns and `field: field`
+// struct literals, and gives types a `new` rather than a `Default`, so those lints stay quiet.
+#![allow(bare_trait_objects, ellipsis_inclusive_range_patterns)]
+#![allow(clippy::redundant_field_names, clippy::new_without_default)]
+
+#[cfg(feature = "cpal")]
+extern crate cpal;
+extern crate flate2;
 extern crate sdl2;
 extern crate time;
 
@@ -14,101 +19,1174 @@ pub mod util;
 
 pub mod apu;
 pub mod audio;
+pub mod capture;
+pub mod config;
 #[macro_use]
 pub mod cpu;
+pub mod debugger;
 pub mod disasm;
+pub mod fceux;
+pub mod filters;
 pub mod gfx;
 pub mod input;
 pub mod mapper;
 pub mod mem;
+pub mod palette;
+pub mod patch;
+pub mod png;
 pub mod ppu;
 pub mod rom;
-
-// C library support
-pub mod speex;
+pub mod romdb;
+pub mod trace;
+pub mod wav;
+pub mod zip;
 
 use apu::Apu;
-use cpu::Cpu;
-use gfx::{Gfx, Scale};
-use input::{Input, InputResult};
-use mapper::Mapper;
-use mem::MemMap;
-use ppu::{Oam, Ppu, Vram};
-use rom::Rom;
-use util::Save;
+use audio::{AudioBackend, AudioSink};
+use capture::GifWriter;
+use config::Config;
+use cpu::{Break, Cpu};
+use debugger::{Debugger, Run};
+use filters::Upscaler;
+use gfx::{Aspect, Gfx};
+use input::{Input, InputProfile, InputResult, RumbleTrigger};
+use mapper::{Mapper, MapperError, MapperRegistry, Mirroring};
+use mem::{IrqLine, MemMap};
+use palette::Palette;
+use ppu::{Oam, PixelFormat, Ppu, PpuEvent, PpuView, Vram};
+use rom::{Region, Rom};
+use trace::Tracer;
+use wav::WavWriter;
 
 use std::cell::RefCell;
 use std::fs::File;
-use std::path::Path;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Roughly how long the PPU takes to warm up after power-on, in CPU cycles: until the pre-render
+/// scanline of the first frame. Games spin on PPUSTATUS for this long before they draw anything.
+fn ppu_warm_up_cycles(region: Region) -> u64 {
+    match region.timing() {
+        Region::Pal => 33132,
+        Region::Dendy => 35350,
+        _ => 29658,
+    }
+}
+
+/// How often battery-backed RAM is written out while a game runs, in frames, so that a crash
+/// doesn't lose much progress. It's only written if it changed.
+const BATTERY_FLUSH_FRAMES: u64 = 300;
 
-fn record_fps(last_time: &mut f64, frames: &mut usize) {
-    if cfg!(debug) {
+/// Measures how fast the emulator is running, once a second.
+struct PerformanceMeter {
+    last_time: f64,
+    /// Frames emulated, and frames drawn and shown, since `last_time`.
+    emulated: usize,
+    shown: usize,
+    /// Frames shown a second, and frames emulated a second, over the last second measured.
+    fps: f64,
+    emulated_rate: f64,
+}
+
+impl PerformanceMeter {
+    fn new() -> PerformanceMeter {
+        PerformanceMeter {
+            last_time: time::precise_time_s(),
+            emulated: 0,
+            shown: 0,
+            fps: 0.0,
+            emulated_rate: 0.0,
+        }
+    }
+
+    /// Counts a finished frame. Returns true if a second has been measured since the last time it
+    /// did.
+    fn frame(&mut self, shown: bool) -> bool {
+        self.emulated += 1;
+        if shown {
+            self.shown += 1;
+        }
         let now = time::precise_time_s();
-        if now >= *last_time + 1f64 {
-            println!("{} FPS", *frames);
-            *frames = 0;
-            *last_time = now;
+        let elapsed = now - self.last_time;
+        if elapsed < 1.0 {
+            return false;
+        }
+        self.fps = self.shown as f64 / elapsed;
+        self.emulated_rate = self.emulated as f64 / elapsed;
+        self.emulated = 0;
+        self.shown = 0;
+        self.last_time = now;
+        true
+    }
+}
+
+/// Describes the frame rate, the speed relative to the console, and how the audio device is
+/// keeping up, for the top right corner.
+fn performance_readout(meter: &PerformanceMeter, region: Region, apu: &Apu) -> Vec<String> {
+    let mut lines = vec![
+        format!("{:.0} FPS", meter.fps),
+        format!(
+            "{:.0}% speed",
+            meter.emulated_rate / region.frame_rate() * 100.0
+        ),
+    ];
+    if let Some(stats) = apu.audio_stats() {
+        lines.push(format!(
+            "Audio {:.0}%, {} underruns",
+            stats.fill() * 100.0,
+            stats.underruns
+        ));
+    }
+    lines
+}
+
+/// While fast-forwarding, one frame in this many is drawn.
+const FAST_FORWARD_DRAWN_FRAMES: u64 = 4;
+
+/// Keeps the emulator to the console's frame rate, whatever the monitor's refresh rate and
+/// whether or not presenting waits for vsync.
+struct FrameLimiter {
+    frame_duration: Duration,
+    /// When the next frame is due to be shown.
+    next_frame: Instant,
+}
+
+impl FrameLimiter {
+    fn new(frame_rate: f64) -> FrameLimiter {
+        FrameLimiter {
+            frame_duration: Duration::from_secs_f64(1.0 / frame_rate),
+            next_frame: Instant::now(),
+        }
+    }
+
+    /// Sleeps until the next frame is due. A frame that comes too late, because the host
+    /// couldn't keep up or the window was being dragged, restarts the pacing rather than running
+    /// fast to catch up.
+    fn wait(&mut self) {
+        let now = Instant::now();
+        if now < self.next_frame {
+            thread::sleep(self.next_frame - now);
+            self.next_frame += self.frame_duration;
+        } else if now - self.next_frame > self.frame_duration {
+            self.next_frame = now + self.frame_duration;
         } else {
-            *frames += 1;
+            self.next_frame += self.frame_duration;
         }
     }
 }
 
-/// Starts the emulator main loop with a ROM and window scaling. Returns when the user presses ESC.
-pub fn start_emulator(rom: Rom, scale: Scale) {
-    let rom = Box::new(rom);
-    println!("Loaded ROM: {}", rom.header);
+/// Options controlling how the emulator runs.
+pub struct EmulatorOptions {
+    /// The window's size to start with, in multiples of the picture's. It can be resized after.
+    pub scale: u32,
+    /// The shape of the console's pixels in the window.
+    pub aspect: Aspect,
+    /// If true, the picture is only scaled by whole numbers.
+    pub integer_scaling: bool,
+    /// What the screen is smoothed with as it's scaled up. U cycles through them at runtime.
+    pub upscaler: Upscaler,
+    /// If true, the screen is drawn like a CRT television, in place of the upscaler. C toggles it
+    /// at runtime.
+    pub crt: bool,
+    /// If true, the gaps between scanlines are darkened. Shift+C toggles them at runtime.
+    pub scanlines: bool,
+    /// How much the scanlines darken, in percent.
+    pub scanline_intensity: u8,
+    /// If true, frames are shown in step with the monitor's refresh, which avoids tearing. Either
+    /// way the emulator runs at the console's frame rate.
+    pub vsync: bool,
+    /// Whether to emulate the eight-sprites-per-scanline limit. Can be toggled at runtime.
+    pub sprite_limit: bool,
+    /// The key bindings to use, typically loaded from the ROM's configuration file.
+    pub input_profile: InputProfile,
+    /// Writes to CPU RAM that rumble the game controllers.
+    pub rumble_triggers: Vec<RumbleTrigger>,
+    /// If true, the PPU warm-up period is run before the window is shown. A convenience for
+    /// developers who restart the emulator a lot.
+    pub fast_boot: bool,
+    /// An FCEUX savestate to restore once the console has been reset.
+    pub fceux_state: Option<PathBuf>,
+    /// The directory that savestates are written to and read from.
+    pub save_dir: PathBuf,
+    /// If true, an IPS or BPS patch next to the ROM is applied when the ROM is loaded.
+    pub softpatch: bool,
+    /// If true, the CPU runs the rest of the system a cycle at a time, so that every memory access
+    /// happens on its real cycle. Slower, but closer to hardware for timing-sensitive games.
+    pub cycle_stepped: bool,
+    /// If true, the PPU runs its rendering pipeline a dot at a time instead of drawing whole
+    /// scanlines, so that raster effects land mid-scanline where they should. Slower again.
+    pub dot_stepped_ppu: bool,
+    /// Addresses the debugger pauses at when the CPU reaches them.
+    pub breakpoints: Vec<u16>,
+    /// A file to log every instruction to from the start, in the format of nestest.log. F4
+    /// toggles tracing at runtime, to this file or to `trace.log` in `save_dir`.
+    pub trace: Option<PathBuf>,
+    /// The addresses, inclusive, of the instructions that are traced.
+    pub trace_range: (u16, u16),
+    /// A `.wav` file to record the audio to from the start. R toggles recording at runtime, to
+    /// this file or to `recording.wav` in `save_dir`.
+    pub record: Option<PathBuf>,
+    /// If set, the console starts executing here instead of at the reset vector. nestest, for
+    /// one, runs all of its tests unattended when started at $C000.
+    pub start_pc: Option<u16>,
+    /// Screenshots and GIF clips are saved as this path with the time and extension added,
+    /// usually the ROM's path without its extension. If not set, they go to `screenshot` in
+    /// `save_dir`.
+    pub screenshot_base: Option<PathBuf>,
+    /// Where battery-backed PRG-RAM is kept between sessions, for ROMs whose header says they
+    /// have a battery. Usually the ROM's path with a `.sav` extension, or the ROM's name with
+    /// one in the directory for saves.
+    pub battery_path: Option<PathBuf>,
+    /// The boards the emulator can create mappers for. Register extra boards here to run ROMs
+    /// that need them.
+    pub mappers: MapperRegistry,
+    /// The layout of the pixels the PPU draws, and of the texture they're shown with.
+    pub pixel_format: PixelFormat,
+    /// A `.pal` file to draw with instead of the built-in palette.
+    pub palette: Option<PathBuf>,
+    /// The console to run the game as, overriding the region in its header.
+    pub region: Option<Region>,
+    /// Whether the mixed audio goes through the high- and low-pass filters of the console's
+    /// output stage.
+    pub audio_filters: bool,
+    /// How samples are handed to SDL.
+    pub audio_backend: AudioBackend,
+}
+
+/// Every configuration key that `EmulatorOptions::from_config` understands.
+pub const CONFIG_KEYS: [&str; 34] = [
+    "scale",
+    "aspect",
+    "integer_scaling",
+    "upscaler",
+    "crt",
+    "scanlines",
+    "scanline_intensity",
+    "vsync",
+    "palette",
+    "region",
+    "audio_filters",
+    "audio_backend",
+    "sprite_limit",
+    "fast_boot",
+    "fceux_state",
+    "save_dir",
+    "softpatch",
+    "cycle_stepped",
+    "dot_stepped_ppu",
+    "breakpoints",
+    "trace",
+    "trace_range",
+    "record",
+    "start_pc",
+    "rumble",
+    "input.profile",
+    "input.a",
+    "input.b",
+    "input.select",
+    "input.start",
+    "input.up",
+    "input.down",
+    "input.left",
+    "input.right",
+];
+
+impl EmulatorOptions {
+    pub fn new() -> EmulatorOptions {
+        EmulatorOptions {
+            scale: 1,
+            aspect: Aspect::Square,
+            integer_scaling: false,
+            upscaler: Upscaler::None,
+            crt: false,
+            scanlines: false,
+            scanline_intensity: 50,
+            vsync: true,
+            sprite_limit: true,
+            input_profile: InputProfile::standard(),
+            rumble_triggers: vec![],
+            fast_boot: false,
+            fceux_state: None,
+            save_dir: PathBuf::from("."),
+            softpatch: true,
+            cycle_stepped: false,
+            dot_stepped_ppu: false,
+            breakpoints: vec![],
+            trace: None,
+            trace_range: (0x0000, 0xffff),
+            record: None,
+            start_pc: None,
+            battery_path: None,
+            screenshot_base: None,
+            mappers: MapperRegistry::new(),
+            pixel_format: PixelFormat::Bgr24,
+            palette: None,
+            region: None,
+            audio_filters: true,
+            audio_backend: AudioBackend::Callback,
+        }
+    }
+
+    /// Builds options from configuration settings, starting from the defaults.
+    pub fn from_config(config: &Config) -> Result<EmulatorOptions, String> {
+        let mut options = EmulatorOptions::new();
+        if let Some(scale) = config.get("scale") {
+            options.scale = match scale.parse() {
+                Ok(scale) if scale > 0 => scale,
+                _ => return Err(format!("expected a positive number for scale: {}", scale)),
+            };
+        }
+        match config.get("aspect") {
+            None => {}
+            Some("square") => options.aspect = Aspect::Square,
+            Some("8:7") => options.aspect = Aspect::Ntsc,
+            Some(value) => return Err(format!("expected square or 8:7 for aspect: {}", value)),
+        }
+        if let Some(integer_scaling) = config.get_bool("integer_scaling")? {
+            options.integer_scaling = integer_scaling;
+        }
+        match config.get("upscaler") {
+            None => {}
+            Some("none") => options.upscaler = Upscaler::None,
+            Some("scale2x") => options.upscaler = Upscaler::Scale2x,
+            Some("scale3x") => options.upscaler = Upscaler::Scale3x,
+            Some("hq2x") => options.upscaler = Upscaler::Hq2x,
+            Some("2xbr") => options.upscaler = Upscaler::Xbr2x,
+            Some(value) => {
+                return Err(format!(
+                    "expected none, scale2x, scale3x, hq2x or 2xbr for upscaler: {}",
+                    value
+                ))
+            }
+        }
+        if let Some(crt) = config.get_bool("crt")? {
+            options.crt = crt;
+        }
+        if let Some(scanlines) = config.get_bool("scanlines")? {
+            options.scanlines = scanlines;
+        }
+        if let Some(intensity) = config.get("scanline_intensity") {
+            options.scanline_intensity = match intensity.parse() {
+                Ok(intensity) if intensity <= 100 => intensity,
+                _ => {
+                    return Err(format!(
+                        "expected a percentage for scanline_intensity: {}",
+                        intensity
+                    ))
+                }
+            };
+        }
+        if let Some(vsync) = config.get_bool("vsync")? {
+            options.vsync = vsync;
+        }
+        if let Some(path) = config.get("palette") {
+            options.palette = Some(PathBuf::from(path));
+        }
+        match config.get("region") {
+            None => {}
+            Some("ntsc") => options.region = Some(Region::Ntsc),
+            Some("pal") => options.region = Some(Region::Pal),
+            Some("dendy") => options.region = Some(Region::Dendy),
+            Some(value) => {
+                return Err(format!("expected ntsc, pal or dendy for region: {}", value))
+            }
+        }
+        if let Some(audio_filters) = config.get_bool("audio_filters")? {
+            options.audio_filters = audio_filters;
+        }
+        match config.get("audio_backend") {
+            None => {}
+            Some("callback") => options.audio_backend = AudioBackend::Callback,
+            Some("queue") => options.audio_backend = AudioBackend::Queue,
+            #[cfg(feature = "cpal")]
+            Some("cpal") => options.audio_backend = AudioBackend::Cpal,
+            Some("null") => options.audio_backend = AudioBackend::Null,
+            Some(value) => {
+                return Err(format!(
+                    "expected callback, queue, null or (with the cpal feature) cpal for \
+                     audio_backend: {}",
+                    value
+                ))
+            }
+        }
+        if let Some(sprite_limit) = config.get_bool("sprite_limit")? {
+            options.sprite_limit = sprite_limit;
+        }
+        if let Some(fast_boot) = config.get_bool("fast_boot")? {
+            options.fast_boot = fast_boot;
+        }
+        if let Some(path) = config.get("fceux_state") {
+            options.fceux_state = Some(PathBuf::from(path));
+        }
+        if let Some(path) = config.get("save_dir") {
+            options.save_dir = PathBuf::from(path);
+        }
+        if let Some(softpatch) = config.get_bool("softpatch")? {
+            options.softpatch = softpatch;
+        }
+        if let Some(cycle_stepped) = config.get_bool("cycle_stepped")? {
+            options.cycle_stepped = cycle_stepped;
+        }
+        if let Some(dot_stepped_ppu) = config.get_bool("dot_stepped_ppu")? {
+            options.dot_stepped_ppu = dot_stepped_ppu;
+        }
+        if let Some(breakpoints) = config.get("breakpoints") {
+            for breakpoint in breakpoints.split(',') {
+                options
+                    .breakpoints
+                    .push(parse_address("breakpoints", breakpoint)?);
+            }
+        }
+        if let Some(path) = config.get("trace") {
+            options.trace = Some(PathBuf::from(path));
+        }
+        if let Some(range) = config.get("trace_range") {
+            let mut addresses = range.splitn(2, '-');
+            let start = parse_address("trace_range", addresses.next().unwrap())?;
+            let end = match addresses.next() {
+                Some(end) => parse_address("trace_range", end)?,
+                None => return Err(format!("expected start-end for trace_range: {}", range)),
+            };
+            options.trace_range = (start, end);
+        }
+        if let Some(path) = config.get("record") {
+            options.record = Some(PathBuf::from(path));
+        }
+        if let Some(pc) = config.get("start_pc") {
+            options.start_pc = Some(parse_address("start_pc", pc)?);
+        }
+        if let Some(triggers) = config.get("rumble") {
+            for trigger in triggers.split(';') {
+                options.rumble_triggers.push(RumbleTrigger::parse(trigger)?);
+            }
+        }
+        options.input_profile = InputProfile::from_config(config)?;
+        Ok(options)
+    }
+}
+
+/// Parses a hexadecimal address such as `C000` or `$C000` from the setting `key`.
+fn parse_address(key: &str, address: &str) -> Result<u16, String> {
+    let digits = address.trim().trim_start_matches('$');
+    u16::from_str_radix(digits, 16)
+        .map_err(|_| format!("expected a hex address for {}: {}", key, address))
+}
+
+/// Describes what each sound channel is playing, one line per channel, and how the audio device
+/// is keeping up.
+fn sound_readout(apu: &Apu) -> Vec<String> {
+    let mut lines: Vec<String> = apu::CHANNELS
+        .iter()
+        .map(|&channel| {
+            let solo = if apu.solo() == Some(channel) {
+                "*"
+            } else {
+                " "
+            };
+            match apu.channel_frequency(channel) {
+                Some(frequency) if channel.pitched() => {
+                    let (note, cents) = apu::note_name(frequency);
+                    format!(
+                        "{}{}: {:.1} Hz {} {:+}c",
+                        solo,
+                        channel.name(),
+                        frequency,
+                        note,
+                        cents
+                    )
+                }
+                Some(frequency) => format!("{}{}: {:.1} Hz", solo, channel.name(), frequency),
+                None => format!("{}{}: -", solo, channel.name()),
+            }
+        })
+        .collect();
+    if let Some(stats) = apu.audio_stats() {
+        lines.push(format!(
+            "Queue {:.0}% {:.0} ms, {} underruns",
+            stats.fill() * 100.0,
+            stats.latency_ms(),
+            stats.underruns
+        ));
+    }
+    lines
+}
+
+/// Lists the muted sound channels, for the status line.
+fn mute_status(apu: &Apu) -> String {
+    let muted: Vec<&str> = apu::CHANNELS
+        .iter()
+        .filter(|&&channel| apu.muted(channel))
+        .map(|channel| channel.name())
+        .collect();
+    if muted.is_empty() {
+        "All channels on".to_string()
+    } else {
+        format!("Muted: {}", muted.join(", "))
+    }
+}
+
+/// Starts tracing to `path`, or stops. A stopped tracer is kept in `idle_tracer` so that
+/// turning tracing back on appends to the same file rather than starting it over.
+fn toggle_trace(
+    cpu: &mut Cpu<MemMap>,
+    idle_tracer: &mut Option<Tracer>,
+    path: &Path,
+    range: (u16, u16),
+    gfx: &mut Gfx,
+) {
+    if let Some(mut tracer) = cpu.set_tracer(None) {
+        if let Err(err) = tracer.flush() {
+            println!("Couldn't write {}: {}", path.display(), err);
+        }
+        *idle_tracer = Some(tracer);
+        gfx.status_line.set("Trace off".to_string());
+        return;
+    }
+
+    let tracer = match idle_tracer.take() {
+        Some(tracer) => tracer,
+        None => match Tracer::create(path) {
+            Ok(mut tracer) => {
+                tracer.set_range(range.0, range.1);
+                tracer
+            }
+            Err(err) => {
+                println!("Couldn't create {}: {}", path.display(), err);
+                gfx.status_line.set("Trace failed".to_string());
+                return;
+            }
+        },
+    };
+    cpu.set_tracer(Some(tracer));
+    gfx.status_line
+        .set(format!("Tracing to {}", path.display()));
+}
+
+/// Starts recording the audio to `path`, replacing any recording there, or stops and finishes the
+/// recording in progress.
+fn toggle_recording(cpu: &mut Cpu<MemMap>, path: &Path, gfx: &mut Gfx) {
+    if let Some(recorder) = cpu.mem.apu.set_recorder(None) {
+        finish_recording(recorder, path);
+        gfx.status_line.set("Recording off".to_string());
+        return;
+    }
+
+    match WavWriter::create(path, apu::OUTPUT_SAMPLE_RATE) {
+        Ok(recorder) => {
+            cpu.mem.apu.set_recorder(Some(recorder));
+            gfx.status_line
+                .set(format!("Recording to {}", path.display()));
+        }
+        Err(err) => {
+            println!("Couldn't create {}: {}", path.display(), err);
+            gfx.status_line.set("Recording failed".to_string());
+        }
+    }
+}
+
+fn finish_recording(recorder: WavWriter, path: &Path) {
+    if let Err(err) = recorder.finish() {
+        println!("Couldn't write {}: {}", path.display(), err);
+    }
+}
+
+fn save_screen(cpu: &Cpu<MemMap>, path: &Path) -> io::Result<()> {
+    let ppu = &cpu.mem.ppu;
+    png::save(
+        path,
+        &ppu.screen[..],
+        ppu::SCREEN_WIDTH,
+        ppu::SCREEN_HEIGHT,
+        ppu.pixel_format(),
+    )
+}
+
+/// Starts capturing the screen to a GIF named after `base` and the time, or stops and finishes
+/// the clip in progress.
+fn toggle_capture(base: &Path, frame_rate: f64, gfx: &mut Gfx) {
+    if let Some(capture) = gfx.set_capture(None) {
+        match capture.finish() {
+            Ok(()) => gfx.status_line.set("GIF capture off".to_string()),
+            Err(err) => {
+                println!("Couldn't finish the GIF: {}", err);
+                gfx.status_line.set("GIF capture failed".to_string());
+            }
+        }
+        return;
+    }
+
+    let path = timestamped(base, "gif");
+    match GifWriter::create(&path, frame_rate) {
+        Ok(capture) => {
+            gfx.set_capture(Some(capture));
+            gfx.status_line
+                .set(format!("Capturing to {}", path.display()));
+        }
+        Err(err) => {
+            println!("Couldn't create {}: {}", path.display(), err);
+            gfx.status_line.set("GIF capture failed".to_string());
+        }
+    }
+}
+
+// Names a new file after `base` and the time, like `base-20240101-120000.png`. If there's
+// already a file by that name from earlier in the same second, a number is added, as in
+// `base-20240101-120000-2.png`.
+fn timestamped(base: &Path, extension: &str) -> PathBuf {
+    let timestamp = time::strftime("%Y%m%d-%H%M%S", &time::now()).unwrap();
+    let mut number = 1;
+    loop {
+        let mut name = base.file_name().unwrap_or_default().to_os_string();
+        name.push(format!("-{}", timestamp));
+        if number > 1 {
+            name.push(format!("-{}", number));
+        }
+        name.push(format!(".{}", extension));
+        let path = base.with_file_name(name);
+        if !path.exists() {
+            return path;
+        }
+        number += 1;
+    }
+}
+
+/// Saves the next screen, without anything drawn over it, to a PNG named after `base` and the
+/// time.
+fn take_screenshot(base: &Path, gfx: &mut Gfx) {
+    gfx.request_screenshot(timestamped(base, "png"));
+}
+
+/// Restores an FCEUX savestate, reporting anything that couldn't be restored.
+fn import_fceux_state(cpu: &mut Cpu<MemMap>, path: &Path, gfx: &mut Gfx) {
+    let result = match File::open(path) {
+        Ok(mut file) => fceux::import(cpu, &mut file),
+        Err(err) => Err(err.into()),
+    };
+    match result {
+        Ok(warnings) => {
+            for warning in warnings.iter() {
+                println!("{}: {}", path.display(), warning);
+            }
+            gfx.status_line.set("Imported FCEUX state".to_string());
+        }
+        Err(err) => {
+            println!("Couldn't import {}: {}", path.display(), err);
+            gfx.status_line.set("FCEUX state import failed".to_string());
+        }
+    }
+}
+
+/// Writes the state as an FCEUX savestate, which FCEUX can load to carry on from here.
+fn export_fceux_state(cpu: &mut Cpu<MemMap>, path: &Path, gfx: &mut Gfx) {
+    let result = match File::create(path) {
+        Ok(mut file) => fceux::export(cpu, &mut file),
+        Err(err) => Err(err.into()),
+    };
+    match result {
+        Ok(()) => gfx.status_line.set(format!("Exported {}", path.display())),
+        Err(err) => {
+            println!("Couldn't export {}: {}", path.display(), err);
+            gfx.status_line.set("FCEUX state export failed".to_string());
+        }
+    }
+}
+
+/// Shows the next PPU viewer in place of the screen, or the screen again after the last.
+fn cycle_viewer(viewer: &mut Option<PpuView>, gfx: &mut Gfx) {
+    *viewer = match *viewer {
+        None => Some(PpuView::Nametables),
+        Some(view) => view.next(),
+    };
+    let name = match *viewer {
+        Some(view) => view.name(),
+        None => "Game".to_string(),
+    };
+    gfx.status_line.set(name);
+}
+
+/// Colors the pattern table viewer, if it's showing, with the next of the eight palettes.
+fn cycle_viewer_palette(viewer: &mut Option<PpuView>, gfx: &mut Gfx) {
+    if let Some(PpuView::PatternTables(palette)) = *viewer {
+        let view = PpuView::PatternTables((palette + 1) % 8);
+        *viewer = Some(view);
+        gfx.status_line.set(view.name());
+    }
+}
+
+/// Switches the PPU to the palette in a `.pal` file, keeping the built-in one if it can't be read.
+fn load_palette(cpu: &mut Cpu<MemMap>, path: &Path, gfx: &mut Gfx) {
+    match Palette::load(path) {
+        Ok(palette) => cpu.mem.ppu.set_palette(Some(&palette)),
+        Err(err) => {
+            println!("Couldn't load palette {}: {}", path.display(), err);
+            gfx.status_line.set("Palette load failed".to_string());
+        }
+    }
+}
 
-    let (mut gfx, sdl) = Gfx::new(scale);
-    let audio_buffer = audio::open(&sdl);
+/// Fills the cartridge's PRG-RAM from its battery save, if there is one yet. Returns what the
+/// PRG-RAM now holds.
+fn load_battery_ram(cpu: &mut Cpu<MemMap>, path: &Path) -> Vec<u8> {
+    let mut mapper = cpu.mem.mapper.borrow_mut();
+    let ram = match mapper.prg_ram() {
+        Some(ram) => ram,
+        None => return vec![],
+    };
+    if let Ok(mut file) = File::open(path) {
+        if let Err(err) = util::read_to_buf(ram, &mut file) {
+            println!("Couldn't read {}: {}", path.display(), err);
+        }
+    }
+    ram.to_vec()
+}
 
-    let mapper: Box<Mapper + Send> = mapper::create_mapper(rom);
+/// Writes the cartridge's PRG-RAM to its battery save if it differs from `written`, which is
+/// what was last written (or loaded).
+fn flush_battery_ram(cpu: &mut Cpu<MemMap>, path: &Path, written: &mut Vec<u8>) {
+    let mut mapper = cpu.mem.mapper.borrow_mut();
+    let ram = match mapper.prg_ram() {
+        Some(ram) => ram,
+        None => return,
+    };
+    if *ram == written[..] {
+        return;
+    }
+    match File::create(path).and_then(|mut file| file.write_all(ram)) {
+        Ok(()) => *written = ram.to_vec(),
+        Err(err) => println!("Couldn't write {}: {}", path.display(), err),
+    }
+}
+
+/// Executes one CPU instruction, brings the rest of the system up to date with it and handles
+/// what the PPU did meanwhile. Returns true if the PPU finished a frame.
+fn step(cpu: &mut Cpu<MemMap>) -> bool {
+    if cpu.cycle_stepped() {
+        // The CPU runs the rest of the system along with it, and takes NMIs as they happen.
+        cpu.step();
+    } else {
+        let last_cy = cpu.cy;
+        cpu.step();
+        cpu.mem.catch_up(last_cy, cpu.cy);
+    }
+
+    let mut frame_complete = false;
+    while let Some(event) = cpu.mem.ppu.pop_event() {
+        match event {
+            PpuEvent::VblankNmi => cpu.nmi(),
+            // The mapper has already asserted the IRQ line, which the CPU polls by itself.
+            PpuEvent::ScanlineIrq { .. } => {}
+            PpuEvent::FrameComplete => frame_complete = true,
+        }
+    }
+    frame_complete
+}
+
+/// Wires a cartridge, with the given region and header mirroring, an input device and audio
+/// output into a console drawing pixels in the given format, then resets it, or starts it at
+/// `start_pc` if that's set.
+fn create_console(
+    mut mapper: Box<Mapper + Send>,
+    input: Input,
+    audio: Option<Box<AudioSink>>,
+    region: Region,
+    mirroring: Mirroring,
+    pixel_format: PixelFormat,
+    start_pc: Option<u16>,
+) -> Cpu<MemMap> {
+    let irq = IrqLine::new();
+    mapper.connect_irq(irq.clone());
     let mapper = Rc::new(RefCell::new(mapper));
-    let ppu = Ppu::new(Vram::new(mapper.clone()), Oam::new());
-    let input = Input::new(sdl);
-    let apu = Apu::new(audio_buffer);
+    let vram = Vram::new(mapper.clone(), mirroring);
+    let mut ppu = Ppu::new(vram, Oam::new(), region, pixel_format);
+    ppu.connect_irq(irq.clone());
+    let mut apu = Apu::new(audio, mapper.clone(), region);
+    apu.connect_irq(irq.clone());
     let memmap = MemMap::new(ppu, input, mapper, apu);
     let mut cpu = Cpu::new(memmap);
+    cpu.connect_irq(irq);
 
-    // TODO: Add a flag to not reset for nestest.log
-    cpu.reset();
+    match start_pc {
+        Some(pc) => cpu.start_at(pc),
+        None => cpu.power_on(),
+    }
+    cpu
+}
 
-    let mut last_time = time::precise_time_s();
-    let mut frames = 0;
+/// A console with no window, audio device or keyboard: the whole pipeline from ROM to screen and
+/// samples, for tests and tools.
+pub struct Headless {
+    pub cpu: Cpu<MemMap>,
+}
 
-    loop {
-        cpu.step();
+impl Headless {
+    pub fn new(rom: Rom) -> Result<Headless, MapperError> {
+        Headless::with_mappers(rom, &MapperRegistry::new())
+    }
+
+    /// Like `new`, but creates the mapper from the given registry.
+    pub fn with_mappers(rom: Rom, mappers: &MapperRegistry) -> Result<Headless, MapperError> {
+        Headless::create(rom, mappers, PixelFormat::Bgr24, None)
+    }
+
+    /// Like `new`, but draws the screen in the given pixel format instead of BGR.
+    pub fn with_pixel_format(rom: Rom, format: PixelFormat) -> Result<Headless, MapperError> {
+        Headless::create(rom, &MapperRegistry::new(), format, None)
+    }
+
+    /// Like `new`, but starts executing at `pc` instead of resetting.
+    pub fn starting_at(rom: Rom, pc: u16) -> Result<Headless, MapperError> {
+        Headless::create(rom, &MapperRegistry::new(), PixelFormat::Bgr24, Some(pc))
+    }
+
+    fn create(
+        rom: Rom,
+        mappers: &MapperRegistry,
+        pixel_format: PixelFormat,
+        start_pc: Option<u16>,
+    ) -> Result<Headless, MapperError> {
+        let (region, mirroring) = (rom.region(), rom.header.mirroring());
+        let mapper = mappers.create(Box::new(rom))?;
+        let input = Input::headless();
+        Ok(Headless {
+            cpu: create_console(
+                mapper,
+                input,
+                None,
+                region,
+                mirroring,
+                pixel_format,
+                start_pc,
+            ),
+        })
+    }
+
+    /// Runs one instruction, and the rest of the system along with it.
+    pub fn step(&mut self) {
+        step(&mut self.cpu);
+    }
+
+    /// Runs until the PPU finishes a frame, or until the CPU stops at a breakpoint or watchpoint
+    /// (see `Cpu::take_break`). Any audio the APU mixed in the meantime is appended to `samples`.
+    pub fn run_frame(&mut self, samples: &mut Vec<i16>) {
+        while !step(&mut self.cpu) && self.cpu.pending_break().is_none() {}
+        if let Some(mixed) = self.cpu.mem.apu.play_channels() {
+            samples.extend_from_slice(mixed);
+        }
+    }
+
+    /// The most recently rendered frame, in the pixel format the console was created with.
+    pub fn screen(&self) -> &[u8] {
+        &self.cpu.mem.ppu.screen
+    }
+
+    /// Writes the most recently rendered frame to a PNG file.
+    pub fn screenshot(&self, path: &Path) -> io::Result<()> {
+        save_screen(&self.cpu, path)
+    }
+}
+
+/// Starts the emulator main loop with a ROM and options. Returns when the user presses ESC, or
+/// before opening the window if the ROM's mapper isn't supported.
+pub fn start_emulator(rom: Rom, options: EmulatorOptions) -> Result<(), MapperError> {
+    let rom = Box::new(rom);
+    println!("Loaded ROM: {}", rom.header);
+    let region = options.region.unwrap_or(rom.region()).timing();
+    let mirroring = rom.header.mirroring();
+    let battery_path = if rom.header.battery() {
+        options.battery_path.clone()
+    } else {
+        None
+    };
+
+    let mapper = options.mappers.create(rom)?;
+
+    let (mut gfx, sdl) = Gfx::new(
+        options.scale,
+        options.aspect,
+        options.vsync,
+        options.pixel_format,
+    );
+    gfx.integer_scaling = options.integer_scaling;
+    gfx.set_upscaler(options.upscaler);
+    gfx.set_crt(options.crt);
+    gfx.scanlines = options.scanlines;
+    gfx.scanline_intensity = options.scanline_intensity;
+    let audio = audio::open(&sdl, options.audio_backend);
+
+    let mut input = Input::new(sdl);
+    if options.input_profile.name != InputProfile::standard().name {
+        gfx.status_line
+            .set(format!("Input profile: {}", options.input_profile.name));
+    }
+    input.set_profile(options.input_profile);
+    for &trigger in options.rumble_triggers.iter() {
+        input.add_rumble_trigger(trigger);
+    }
+    let mut cpu = create_console(
+        mapper,
+        input,
+        audio,
+        region,
+        mirroring,
+        options.pixel_format,
+        options.start_pc,
+    );
+    cpu.set_cycle_stepped(options.cycle_stepped);
+    cpu.mem.ppu.set_dot_stepped(options.dot_stepped_ppu);
+    if let Some(ref path) = options.palette {
+        load_palette(&mut cpu, path, &mut gfx);
+    }
+    for &pc in options.breakpoints.iter() {
+        cpu.add_breakpoint(pc);
+    }
+    let trace_path = match options.trace {
+        Some(ref path) => {
+            toggle_trace(&mut cpu, &mut None, path, options.trace_range, &mut gfx);
+            path.clone()
+        }
+        None => options.save_dir.join("trace.log"),
+    };
+    let mut idle_tracer = None;
+    let recording_path = match options.record {
+        Some(ref path) => {
+            toggle_recording(&mut cpu, path, &mut gfx);
+            path.clone()
+        }
+        None => options.save_dir.join("recording.wav"),
+    };
+    cpu.mem.ppu.set_sprite_limit(options.sprite_limit);
+    cpu.mem.apu.set_filtered(options.audio_filters);
+    let mut battery_ram = match battery_path {
+        Some(ref path) => load_battery_ram(&mut cpu, path),
+        None => vec![],
+    };
 
-        let ppu_result = cpu.mem.ppu.step(cpu.cy);
-        if ppu_result.vblank_nmi {
-            cpu.nmi();
-        } else if ppu_result.scanline_irq {
-            cpu.irq();
+    if options.fast_boot {
+        while cpu.cy < ppu_warm_up_cycles(region) {
+            step(&mut cpu);
         }
+    }
+    if let Some(ref path) = options.fceux_state {
+        import_fceux_state(&mut cpu, path, &mut gfx);
+    }
+    gfx.show();
+
+    let mut frame_count: u64 = 0;
+    let mut show_sound_readout = false;
+    let mut show_performance = false;
+    let mut meter = PerformanceMeter::new();
+    let mut reported_halt = false;
+    let state_path = options.save_dir.join("state.sav");
+    let fceux_path = options.save_dir.join("state.fc0");
+    let screenshot_base = match options.screenshot_base {
+        Some(ref base) => base.clone(),
+        None => options.save_dir.join("screenshot"),
+    };
 
-        cpu.mem.apu.step(cpu.cy);
+    let mut debugger = Debugger::new();
+    let mut viewer = None;
+    let mut limiter = FrameLimiter::new(region.frame_rate());
+    // Whether the PPU is skipping drawing the frame in progress.
+    let mut skipped_drawing = false;
 
-        if ppu_result.new_frame {
+    loop {
+        if debugger.paused() {
+            gfx.overlay = debugger.view(&cpu);
             gfx.tick();
-            gfx.composite(&mut *cpu.mem.ppu.screen);
-            record_fps(&mut last_time, &mut frames);
+            // Draw on a copy, so that the overlay doesn't pile up on the frozen screen.
+            let mut screen = match viewer {
+                Some(view) => cpu.mem.ppu.draw_view(view),
+                None => cpu.mem.ppu.screen.clone(),
+            };
+            gfx.composite(&mut screen);
+            limiter.wait();
+
+            match cpu.mem.input.check_input() {
+                InputResult::Quit => break,
+                InputResult::CycleViewer => cycle_viewer(&mut viewer, &mut gfx),
+                InputResult::CycleViewerPalette => cycle_viewer_palette(&mut viewer, &mut gfx),
+                InputResult::Screenshot => take_screenshot(&screenshot_base, &mut gfx),
+                InputResult::Debug(command) => match debugger.command(&mut cpu, command) {
+                    Run::Instruction => {
+                        step(&mut cpu);
+                        // Stepping onto a breakpoint stops before the instruction runs.
+                        if let Some(Break::Breakpoint(_)) = cpu.pending_break() {
+                            cpu.take_break();
+                            step(&mut cpu);
+                        }
+                    }
+                    Run::Frame => while !step(&mut cpu) && cpu.pending_break().is_none() {},
+                    Run::Nothing | Run::Continue => {}
+                },
+                _ => {}
+            }
+            if let Some(reason) = cpu.take_break() {
+                gfx.status_line.set(debugger.stopped(&mut cpu, reason));
+            }
+            if !debugger.paused() {
+                gfx.overlay.clear();
+            }
+            continue;
+        }
+
+        let frame_complete = step(&mut cpu);
+        if let Some(reason) = cpu.take_break() {
+            gfx.status_line.set(debugger.stopped(&mut cpu, reason));
+            continue;
+        }
+        if frame_complete {
+            gfx.tick();
+            match cpu.halted() {
+                Some(pc) if !reported_halt => gfx.status_line.set(format!(
+                    "CPU jammed at ${:04X}; load a state to continue",
+                    pc
+                )),
+                _ => {}
+            }
+            reported_halt = cpu.halted().is_some();
+            if show_sound_readout {
+                gfx.overlay = sound_readout(&cpu.mem.apu);
+            }
+            let shown = !skipped_drawing;
+            if meter.frame(shown) && show_performance {
+                gfx.corner_overlay = performance_readout(&meter, region, &cpu.mem.apu);
+            }
+            if shown {
+                match viewer {
+                    Some(view) => {
+                        let mut screen = cpu.mem.ppu.draw_view(view);
+                        gfx.feed_capture(&screen);
+                        gfx.composite(&mut screen);
+                    }
+                    None => {
+                        gfx.feed_capture(&cpu.mem.ppu.screen);
+                        gfx.composite(&mut cpu.mem.ppu.screen);
+                    }
+                }
+            }
+
+            // While fast-forwarding, frames aren't paced or played, and most aren't drawn.
+            let fast_forward = cpu.mem.input.fast_forward();
+            if !fast_forward {
+                limiter.wait();
+            }
+            skipped_drawing = fast_forward && (frame_count + 1) % FAST_FORWARD_DRAWN_FRAMES != 0;
+            cpu.mem.ppu.set_skip_drawing(skipped_drawing);
+            cpu.mem.apu.set_fast_forward(fast_forward);
             cpu.mem.apu.play_channels();
 
+            frame_count += 1;
+            if frame_count % BATTERY_FLUSH_FRAMES == 0 {
+                if let Some(ref path) = battery_path {
+                    flush_battery_ram(&mut cpu, path, &mut battery_ram);
+                }
+            }
+
             match cpu.mem.input.check_input() {
                 InputResult::Continue => {}
                 InputResult::Quit => break,
-                InputResult::SaveState => {
-                    cpu.save(&mut File::create(&Path::new("state.sav")).unwrap());
-                    gfx.status_line.set("Saved state".to_string());
+                InputResult::SaveState => match util::save_state(&mut cpu, &state_path) {
+                    Ok(()) => gfx.status_line.set("Saved state".to_string()),
+                    Err(err) => {
+                        println!("Couldn't write {}: {}", state_path.display(), err);
+                        gfx.status_line.set("Saving state failed".to_string());
+                    }
+                },
+                InputResult::ExportFceux => export_fceux_state(&mut cpu, &fceux_path, &mut gfx),
+                InputResult::LoadState => match util::load_state(&mut cpu, &state_path) {
+                    Ok(()) => gfx.status_line.set("Loaded state".to_string()),
+                    Err(err) => {
+                        println!("Couldn't load {}: {}", state_path.display(), err);
+                        gfx.status_line.set("Loading state failed".to_string());
+                    }
+                },
+                InputResult::ToggleSpriteLimit => {
+                    let sprite_limit = !cpu.mem.ppu.sprite_limit();
+                    cpu.mem.ppu.set_sprite_limit(sprite_limit);
+                    let state = if sprite_limit { "on" } else { "off" };
+                    gfx.status_line.set(format!("Sprite limit {}", state));
                 }
-                InputResult::LoadState => {
-                    cpu.load(&mut File::open(&Path::new("state.sav")).unwrap());
-                    gfx.status_line.set("Loaded state".to_string());
+                InputResult::ToggleSoundReadout => {
+                    show_sound_readout = !show_sound_readout;
+                    if !show_sound_readout {
+                        gfx.overlay.clear();
+                    }
+                }
+                InputResult::ToggleTrace => toggle_trace(
+                    &mut cpu,
+                    &mut idle_tracer,
+                    &trace_path,
+                    options.trace_range,
+                    &mut gfx,
+                ),
+                InputResult::Screenshot => take_screenshot(&screenshot_base, &mut gfx),
+                InputResult::Debug(command) => {
+                    debugger.command(&mut cpu, command);
+                    gfx.status_line.set("Paused".to_string());
+                }
+                InputResult::CycleViewer => cycle_viewer(&mut viewer, &mut gfx),
+                InputResult::CycleViewerPalette => cycle_viewer_palette(&mut viewer, &mut gfx),
+                InputResult::ToggleCrt => {
+                    let crt = !gfx.crt();
+                    gfx.set_crt(crt);
+                    let state = if crt { "on" } else { "off" };
+                    gfx.status_line.set(format!("CRT {}", state));
+                }
+                InputResult::TogglePerformance => {
+                    show_performance = !show_performance;
+                    gfx.corner_overlay = if show_performance {
+                        performance_readout(&meter, region, &cpu.mem.apu)
+                    } else {
+                        vec![]
+                    };
+                }
+                InputResult::ToggleScanlines => {
+                    gfx.scanlines = !gfx.scanlines;
+                    let state = if gfx.scanlines { "on" } else { "off" };
+                    gfx.status_line.set(format!("Scanlines {}", state));
+                }
+                InputResult::CycleUpscaler => {
+                    let upscaler = gfx.upscaler().next();
+                    gfx.set_upscaler(upscaler);
+                    gfx.status_line.set(upscaler.name().to_string());
+                }
+                InputResult::ToggleRecording => {
+                    toggle_recording(&mut cpu, &recording_path, &mut gfx)
+                }
+                InputResult::ToggleCapture => {
+                    toggle_capture(&screenshot_base, region.frame_rate(), &mut gfx)
+                }
+                InputResult::ToggleMute(channel) => {
+                    let muted = !cpu.mem.apu.muted(channel);
+                    cpu.mem.apu.set_muted(channel, muted);
+                    gfx.status_line.set(mute_status(&cpu.mem.apu));
+                }
+                InputResult::ToggleSolo(channel) => {
+                    if cpu.mem.apu.solo() == Some(channel) {
+                        cpu.mem.apu.set_solo(None);
+                        gfx.status_line.set("Solo off".to_string());
+                    } else {
+                        cpu.mem.apu.set_solo(Some(channel));
+                        gfx.status_line.set(format!("Solo: {}", channel.name()));
+                    }
                 }
             }
         }
     }
 
-    audio::close();
+    if let Some(ref path) = battery_path {
+        flush_battery_ram(&mut cpu, path, &mut battery_ram);
+    }
+    if let Some(recorder) = cpu.mem.apu.set_recorder(None) {
+        finish_recording(recorder, &recording_path);
+    }
+    if let Some(capture) = gfx.set_capture(None) {
+        if let Err(err) = capture.finish() {
+            println!("Couldn't finish the GIF: {}", err);
+        }
+    }
+    Ok(())
 }
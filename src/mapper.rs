@@ -5,62 +5,259 @@
 // Author: Patrick Walton
 //
 
+use fceux::FceuxSection;
+use mem::{IrqLine, IrqSource};
 use rom::Rom;
+use romdb;
+use util::{read_to_buf, Save};
 
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
 use std::ops::Deref;
 
-#[derive(PartialEq, Eq)]
-pub enum MapperResult {
-    Continue,
-    Irq,
-}
-
 pub trait Mapper {
     fn prg_loadb(&mut self, addr: u16) -> u8;
     fn prg_storeb(&mut self, addr: u16, val: u8);
     fn chr_loadb(&mut self, addr: u16) -> u8;
     fn chr_storeb(&mut self, addr: u16, val: u8);
-    fn next_scanline(&mut self) -> MapperResult;
+
+    /// Reads PRG space without side effects, for debuggers and tracers. This defaults to
+    /// `prg_loadb`, so boards whose reads change their state, like the Namco 163's RAM port with
+    /// auto-increment on, must override it.
+    fn prg_peekb(&mut self, addr: u16) -> u8 {
+        self.prg_loadb(addr)
+    }
+    /// Like `prg_peekb`, but for CHR space.
+    fn chr_peekb(&mut self, addr: u16) -> u8 {
+        self.chr_loadb(addr)
+    }
+
+    /// Gives the mapper the CPU's IRQ line. Boards with an IRQ counter keep it, assert
+    /// `IrqSource::Mapper` on it when the counter fires, and acknowledge it when the game does.
+    fn connect_irq(&mut self, _irq: IrqLine) {}
+
+    /// Called at the end of every scanline. Prefer `ppu_a12`, which sees the fetches that real
+    /// scanline counters are clocked by.
+    fn next_scanline(&mut self) {}
+
+    /// Called for each memory fetch the PPU makes while rendering, with the level of PPU address
+    /// line A12 during the fetch and the PPU cycle it happened on. Boards that clock a scanline
+    /// counter from A12, like the MMC3, override this.
+    fn ppu_a12(&mut self, _high: bool, _ppu_cycle: u64) {}
+
+    /// Returns the nametable mirroring the mapper has selected, or `None` if the mirroring is
+    /// fixed by the board and the PPU should use its default. Boards with 2K of nametable RAM of
+    /// their own return `FourScreen` from the start, whatever the header says.
+    fn mirroring(&self) -> Option<Mirroring> {
+        None
+    }
+
+    /// Advances the mapper by the given number of CPU cycles. Mappers with cycle-based IRQ
+    /// counters override this.
+    fn step(&mut self, _cycles: u64) {}
+
+    /// Fills `samples` with expansion audio generated by the cartridge, one sample per CPU cycle.
+    /// Returns false if the cartridge produces no sound, in which case `samples` is untouched.
+    ///
+    /// The APU averages the samples down to its output rate and adds them to its own mix, on the
+    /// same scale: a pulse channel at full volume peaks at about 4900, and both pulses, the
+    /// triangle and the noise together at about 21000.
+    fn expansion_audio(&mut self, _samples: &mut [i16]) -> bool {
+        false
+    }
+
+    /// Returns the cartridge's PRG-RAM, which is what a battery keeps alive on boards that have
+    /// one, or `None` if there is none.
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+
+    /// Restores the mapper registers from the mapper section of an FCEUX savestate. Returns false
+    /// if this mapper can't read FCEUX's representation of its state.
+    fn import_fceux(&mut self, _section: &FceuxSection) -> bool {
+        false
+    }
+
+    /// Writes the board's registers and RAM to a savestate. Boards with no state of their own
+    /// keep the default, which writes nothing.
+    fn save(&mut self, _fd: &mut File) {}
+    /// Restores what `save` wrote.
+    fn load(&mut self, _fd: &mut File) {}
+}
+
+// Like `save_struct!`, but for the `save` and `load` methods of a `Mapper` impl, listing the
+// fields that aren't fixed by the ROM.
+macro_rules! save_mapper(
+    ($($field:ident),*) => (
+        fn save(&mut self, fd: &mut File) {
+            $(self.$field.save(fd);)*
+        }
+        fn load(&mut self, fd: &mut File) {
+            $(self.$field.load(fd);)*
+        }
+    )
+);
+
+#[derive(Debug)]
+pub enum MapperError {
+    /// The ROM uses a mapper with the given number that isn't emulated
+    UnsupportedMapper(u16),
+}
+
+impl fmt::Display for MapperError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            MapperError::UnsupportedMapper(mapper) => write!(f, "unsupported mapper {}", mapper),
+        }
+    }
+}
+
+/// Builds a mapper for a ROM. Registered with a `MapperRegistry` under a mapper number.
+pub type MapperConstructor = fn(Box<Rom>) -> Box<Mapper + Send>;
+
+/// Maps iNES mapper numbers to the boards that implement them. `MapperRegistry::new` knows every
+/// built-in mapper; library users can `register` their own boards, or replace built-in ones,
+/// before handing the registry to the emulator.
+#[derive(Clone)]
+pub struct MapperRegistry {
+    boards: BTreeMap<u16, (String, MapperConstructor)>,
 }
 
-pub fn create_mapper(rom: Box<Rom>) -> Box<Mapper + Send> {
-    match rom.header.ines_mapper() {
-        0 => Box::new(Nrom { rom: rom }) as Box<Mapper + Send>,
-        1 => Box::new(SxRom::new(rom)) as Box<Mapper + Send>,
-        4 => Box::new(TxRom::new(rom)) as Box<Mapper + Send>,
-        _ => panic!("unsupported mapper"),
+impl MapperRegistry {
+    pub fn new() -> MapperRegistry {
+        let mut registry = MapperRegistry {
+            boards: BTreeMap::new(),
+        };
+        registry.register(0, "NROM", |rom| Box::new(Nrom::new(rom)));
+        registry.register(1, "SxROM/MMC1", |rom| Box::new(SxRom::new(rom)));
+        registry.register(2, "UxROM", |rom| Box::new(Uxrom::new(rom)));
+        registry.register(3, "CNROM", |rom| Box::new(Cnrom::new(rom)));
+        registry.register(4, "TxROM/MMC3", |rom| {
+            Box::new(TxRom::new(rom, TxBoard::Mmc3))
+        });
+        registry.register(7, "AxROM", |rom| Box::new(Axrom::new(rom)));
+        registry.register(19, "Namco 163", |rom| Box::new(Namco163::new(rom)));
+        registry.register(34, "BNROM, NINA-001", |rom| {
+            // Mapper 34 covers two unrelated boards. NES 2.0 headers tell them apart by submapper;
+            // otherwise, only NINA-001 has more than 8K of CHR-ROM.
+            match rom.header.submapper() {
+                1 => Box::new(Nina001::new(rom)),
+                2 => Box::new(Bnrom::new(rom)),
+                _ if rom.chr.len() > 8192 => Box::new(Nina001::new(rom)),
+                _ => Box::new(Bnrom::new(rom)),
+            }
+        });
+        registry.register(64, "Tengen RAMBO-1", |rom| {
+            Box::new(TxRom::new(rom, TxBoard::Rambo1))
+        });
+        registry.register(71, "Camerica/Codemasters", |rom| {
+            Box::new(Camerica::new(rom))
+        });
+        registry.register(206, "DxROM/Namco 108", |rom| {
+            Box::new(TxRom::new(rom, TxBoard::Namco108))
+        });
+        registry.register(228, "Action 52", |rom| Box::new(Action52::new(rom)));
+        registry
+    }
+
+    /// Makes ROMs with the given mapper number use `constructor`, replacing any board already
+    /// registered under that number. `name` is shown to users, e.g. in the list of supported
+    /// mappers.
+    pub fn register(&mut self, number: u16, name: &str, constructor: MapperConstructor) {
+        self.boards.insert(number, (name.to_string(), constructor));
+    }
+
+    /// The registered mapper numbers in ascending order, along with the boards they stand for.
+    pub fn supported(&self) -> Vec<(u16, &str)> {
+        self.boards
+            .iter()
+            .map(|(&number, (name, _))| (number, &**name))
+            .collect()
+    }
+
+    /// Creates the mapper for a ROM. If the ROM has a trainer, it is written to PRG-RAM at $7000.
+    pub fn create(&self, mut rom: Box<Rom>) -> Result<Box<Mapper + Send>, MapperError> {
+        let number = rom.header.mapper();
+        let constructor = match self.boards.get(&number) {
+            Some(&(_, constructor)) => constructor,
+            None => return Err(MapperError::UnsupportedMapper(number)),
+        };
+        let trainer = rom.trainer.take();
+        let mut mapper = constructor(rom);
+        if let Some(trainer) = trainer {
+            for (i, &byte) in trainer.iter().enumerate() {
+                mapper.prg_storeb(0x7000 + i as u16, byte);
+            }
+        }
+        Ok(mapper)
     }
 }
 
+/// Creates a mapper using only the built-in boards.
+pub fn create_mapper(rom: Box<Rom>) -> Result<Box<Mapper + Send>, MapperError> {
+    MapperRegistry::new().create(rom)
+}
+
 //
 // Mapper 0 (NROM)
 //
 // See http://wiki.nesdev.com/w/index.php/NROM
 //
 
-// TODO: RAM.
+/// Family Basic has 2K or 4K of PRG-RAM at $6000, mirrored through $7FFF. The header can't say
+/// which, so every board gets 4K; the 2K version of Family Basic works with more, and test ROMs
+/// that report their results at $6000 expect RAM there too.
+const NROM_PRG_RAM_SIZE: usize = 4096;
+
 pub struct Nrom {
     pub rom: Box<Rom>,
+    prg_ram: Box<[u8; NROM_PRG_RAM_SIZE]>,
+}
+
+impl Nrom {
+    pub fn new(rom: Box<Rom>) -> Nrom {
+        Nrom {
+            rom: rom,
+            prg_ram: Box::new([0; NROM_PRG_RAM_SIZE]),
+        }
+    }
 }
 
 impl Mapper for Nrom {
     fn prg_loadb(&mut self, addr: u16) -> u8 {
-        if addr < 0x8000 {
+        if addr < 0x6000 {
             0u8
+        } else if addr < 0x8000 {
+            self.prg_ram[addr as usize & (NROM_PRG_RAM_SIZE - 1)]
         } else if self.rom.prg.len() > 16384 {
             self.rom.prg[addr as usize & 0x7fff]
         } else {
             self.rom.prg[addr as usize & 0x3fff]
         }
     }
-    fn prg_storeb(&mut self, _: u16, _: u8) {} // Can't store to PRG-ROM.
+    fn prg_storeb(&mut self, addr: u16, val: u8) {
+        // Can't store to PRG-ROM.
+        if (0x6000..0x8000).contains(&addr) {
+            self.prg_ram[addr as usize & (NROM_PRG_RAM_SIZE - 1)] = val;
+        }
+    }
     fn chr_loadb(&mut self, addr: u16) -> u8 {
         self.rom.chr[addr as usize]
     }
     fn chr_storeb(&mut self, _: u16, _: u8) {} // Can't store to CHR-ROM.
-    fn next_scanline(&mut self) -> MapperResult {
-        MapperResult::Continue
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        Some(&mut *self.prg_ram)
+    }
+    fn import_fceux(&mut self, section: &FceuxSection) -> bool {
+        // FCEUX gives NROM a full 8K of work RAM.
+        if let Some(wram) = section.get_exact("WRAM", 8192) {
+            self.prg_ram.copy_from_slice(&wram[..NROM_PRG_RAM_SIZE]);
+        }
+        true
     }
+    save_mapper!(prg_ram);
 }
 
 //
@@ -74,11 +271,74 @@ struct SxCtrl {
     val: u8,
 }
 
+save_struct!(SxCtrl { val });
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum Mirroring {
     OneScreenLower,
     OneScreenUpper,
     Vertical,
     Horizontal,
+    /// Four separate nametables, using 2K of RAM on the cartridge as well as the console's 2K
+    FourScreen,
+    /// Each of the four nametables shows the given 1K page (0 or 1) of the console's RAM, for
+    /// boards with a register per nametable
+    Pages([u8; 4]),
+}
+
+impl Mirroring {
+    /// Translates a nametable address in $2000-$2FFF (or its mirror at $3000-$3EFF) to an offset
+    /// into the console's 2K of nametable RAM, followed by the cartridge's 2K for four-screen
+    /// boards.
+    pub fn nametable_offset(self, addr: u16) -> usize {
+        let addr = addr as usize;
+        match self {
+            Mirroring::OneScreenLower => addr & 0x3ff,
+            Mirroring::OneScreenUpper => 0x400 | (addr & 0x3ff),
+            Mirroring::Vertical => addr & 0x7ff,
+            Mirroring::Horizontal => ((addr >> 1) & 0x400) | (addr & 0x3ff),
+            Mirroring::FourScreen => addr & 0xfff,
+            Mirroring::Pages(pages) => {
+                ((pages[(addr >> 10) & 3] as usize & 1) << 10) | (addr & 0x3ff)
+            }
+        }
+    }
+}
+
+// Saved as a byte, 0 for none, followed by the pages for `Pages`.
+impl Save for Option<Mirroring> {
+    fn save(&mut self, fd: &mut File) {
+        let (tag, mut pages) = match *self {
+            None => (0, [0; 4]),
+            Some(Mirroring::OneScreenLower) => (1, [0; 4]),
+            Some(Mirroring::OneScreenUpper) => (2, [0; 4]),
+            Some(Mirroring::Vertical) => (3, [0; 4]),
+            Some(Mirroring::Horizontal) => (4, [0; 4]),
+            Some(Mirroring::FourScreen) => (5, [0; 4]),
+            Some(Mirroring::Pages(pages)) => (6, pages),
+        };
+        fd.write_all(&[tag]).unwrap();
+        if tag == 6 {
+            pages.save(fd);
+        }
+    }
+    fn load(&mut self, fd: &mut File) {
+        let mut tag = [0];
+        read_to_buf(&mut tag, fd).unwrap();
+        *self = match tag[0] {
+            1 => Some(Mirroring::OneScreenLower),
+            2 => Some(Mirroring::OneScreenUpper),
+            3 => Some(Mirroring::Vertical),
+            4 => Some(Mirroring::Horizontal),
+            5 => Some(Mirroring::FourScreen),
+            6 => {
+                let mut pages = [0; 4];
+                pages.load(fd);
+                Some(Mirroring::Pages(pages))
+            }
+            _ => None,
+        };
+    }
 }
 
 enum SxPrgBankMode {
@@ -90,7 +350,32 @@ enum SxPrgBankMode {
     FixLastBank,
 }
 
+enum SxChrBankMode {
+    /// Switch 8K at a time, ignoring the low bit of CHR bank 0
+    Switch8K,
+    /// Switch two separate 4K banks
+    Switch4K,
+}
+
 impl SxCtrl {
+    fn chr_rom_mode(self) -> SxChrBankMode {
+        if (self.val & 0x10) == 0 {
+            SxChrBankMode::Switch8K
+        } else {
+            SxChrBankMode::Switch4K
+        }
+    }
+
+    fn mirroring(self) -> Mirroring {
+        match self.val & 3 {
+            0 => Mirroring::OneScreenLower,
+            1 => Mirroring::OneScreenUpper,
+            2 => Mirroring::Vertical,
+            3 => Mirroring::Horizontal,
+            _ => panic!("can't happen"),
+        }
+    }
+
     fn prg_rom_mode(self) -> SxPrgBankMode {
         match (self.val >> 2) & 3 {
             0 | 1 => SxPrgBankMode::Switch32K,
@@ -109,10 +394,17 @@ struct SxRegs {
     chr_bank_0: u8,
     /// $C000-$DFFF
     chr_bank_1: u8,
-    /// $E000-$FFFF
+    /// $E000-$FFFF. Bit 4 disables PRG-RAM.
     prg_bank: u8,
 }
 
+save_struct!(SxRegs {
+    ctrl,
+    chr_bank_0,
+    chr_bank_1,
+    prg_bank
+});
+
 pub struct SxRom {
     rom: Box<Rom>,
     regs: SxRegs,
@@ -120,7 +412,7 @@ pub struct SxRom {
     accum: u8,
     /// The write count. At the 5th write, we update the register.
     write_count: u8,
-    //prg_ram: Box<[u8; 8192]>,
+    prg_ram: Box<[u8; 8192]>,
     chr_ram: Box<[u8; 8192]>,
 }
 
@@ -136,35 +428,63 @@ impl SxRom {
             },
             accum: 0,
             write_count: 0,
-            //prg_ram: box() ([ 0, ..8192 ]),
+            prg_ram: Box::new([0; 8192]),
             chr_ram: Box::new([0; 8192]),
         }
     }
+
+    fn prg_ram_enabled(&self) -> bool {
+        (self.regs.prg_bank & 0x10) == 0
+    }
+
+    /// Translates a PPU address into an offset into CHR memory, according to the CHR banks.
+    fn chr_addr(&self, addr: u16) -> usize {
+        let bank = match self.regs.ctrl.chr_rom_mode() {
+            SxChrBankMode::Switch8K => (self.regs.chr_bank_0 & 0x1e) | (addr >> 12) as u8,
+            SxChrBankMode::Switch4K if addr < 0x1000 => self.regs.chr_bank_0,
+            SxChrBankMode::Switch4K => self.regs.chr_bank_1,
+        };
+        (bank as usize * 4096) | (addr as usize & 0xfff)
+    }
 }
 
 impl Mapper for SxRom {
     fn prg_loadb(&mut self, addr: u16) -> u8 {
-        if addr < 0x8000 {
+        // Only the low four bits of the PRG bank register select a bank.
+        let prg_bank = self.regs.prg_bank & 0x0f;
+        if addr < 0x6000 {
             0u8
+        } else if addr < 0x8000 {
+            if self.prg_ram_enabled() {
+                self.prg_ram[addr as usize & 0x1fff]
+            } else {
+                0u8
+            }
         } else if addr < 0xc000 {
             let bank = match self.regs.ctrl.prg_rom_mode() {
-                SxPrgBankMode::Switch32K => self.regs.prg_bank & 0xfe,
+                SxPrgBankMode::Switch32K => prg_bank & 0xfe,
                 SxPrgBankMode::FixFirstBank => 0,
-                SxPrgBankMode::FixLastBank => self.regs.prg_bank,
+                SxPrgBankMode::FixLastBank => prg_bank,
             };
             self.rom.prg[(bank as usize * 16384) | ((addr & 0x3fff) as usize)]
         } else {
             let bank = match self.regs.ctrl.prg_rom_mode() {
-                SxPrgBankMode::Switch32K => (self.regs.prg_bank & 0xfe) | 1,
-                SxPrgBankMode::FixFirstBank => self.regs.prg_bank,
-                SxPrgBankMode::FixLastBank => (*self.rom).header.prg_rom_size - 1,
+                SxPrgBankMode::Switch32K => (prg_bank & 0xfe) | 1,
+                SxPrgBankMode::FixFirstBank => prg_bank,
+                SxPrgBankMode::FixLastBank => (self.rom.prg.len() / 16384) as u8 - 1,
             };
             self.rom.prg[(bank as usize * 16384) | ((addr & 0x3fff) as usize)]
         }
     }
 
     fn prg_storeb(&mut self, addr: u16, val: u8) {
+        if addr < 0x6000 {
+            return;
+        }
         if addr < 0x8000 {
+            if self.prg_ram_enabled() {
+                self.prg_ram[addr as usize & 0x1fff] = val;
+            }
             return;
         }
 
@@ -179,7 +499,7 @@ impl Mapper for SxRom {
         }
 
         // Write the lowest bit of the value into the right location of the accumulator.
-        self.accum = self.accum | ((val & 1) << (self.write_count as usize));
+        self.accum |= (val & 1) << (self.write_count as usize);
 
         self.write_count += 1;
         if self.write_count == 5 {
@@ -200,31 +520,227 @@ impl Mapper for SxRom {
         }
     }
 
-    // FIXME: Apparently this mapper can have CHR-ROM as well. Handle this case.
+    // Boards without CHR-ROM have 8K of CHR-RAM instead.
     fn chr_loadb(&mut self, addr: u16) -> u8 {
-        self.chr_ram[addr as usize]
+        let chr_addr = self.chr_addr(addr);
+        if self.rom.chr.is_empty() {
+            self.chr_ram[chr_addr & 0x1fff]
+        } else {
+            self.rom.chr[chr_addr % self.rom.chr.len()]
+        }
     }
 
     fn chr_storeb(&mut self, addr: u16, val: u8) {
-        self.chr_ram[addr as usize] = val
+        if self.rom.chr.is_empty() {
+            self.chr_ram[self.chr_addr(addr) & 0x1fff] = val
+        }
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(self.regs.ctrl.mirroring())
+    }
+
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        Some(&mut *self.prg_ram)
+    }
+
+    fn import_fceux(&mut self, section: &FceuxSection) -> bool {
+        let regs = match section.get_exact("DREG", 4) {
+            Some(regs) => regs,
+            None => return false,
+        };
+        self.regs.ctrl = SxCtrl { val: regs[0] };
+        self.regs.chr_bank_0 = regs[1];
+        self.regs.chr_bank_1 = regs[2];
+        self.regs.prg_bank = regs[3];
+        self.accum = section.get_u8("BFFR").unwrap_or(0);
+        self.write_count = section.get_u8("BFRS").unwrap_or(0);
+        section.copy_to("CHRR", &mut *self.chr_ram);
+        section.copy_to("WRAM", &mut *self.prg_ram);
+        true
+    }
+    save_mapper!(regs, accum, write_count, prg_ram, chr_ram);
+}
+
+//
+// Discrete boards
+//
+// Boards built from a latch and a few logic chips, like UxROM, CNROM, AxROM and BNROM, take their
+// bank number from any write to $8000-$FFFF. On many of them the PRG-ROM drives the data bus at
+// the same time, so the value written is ANDed with the ROM byte at that address.
+//
+
+/// Whether the board has bus conflicts: as the ROM database says, or else as the NES 2.0
+/// submapper of mappers 2, 3 and 7 says (1 for none, 2 for conflicts), or else `default`, which
+/// is what the original board did.
+fn bus_conflicts(rom: &Rom, default: bool) -> bool {
+    if let Some(conflicts) = romdb::lookup(romdb::rom_crc(rom)).and_then(|game| game.bus_conflicts)
+    {
+        return conflicts;
+    }
+    match rom.header.submapper() {
+        1 if DISCRETE_SUBMAPPER_MAPPERS.contains(&rom.header.mapper()) => false,
+        2 if DISCRETE_SUBMAPPER_MAPPERS.contains(&rom.header.mapper()) => true,
+        _ => default,
+    }
+}
+
+/// The mappers whose NES 2.0 submappers say whether the board has bus conflicts.
+const DISCRETE_SUBMAPPER_MAPPERS: [u16; 3] = [2, 3, 7];
+
+/// The value a discrete board latches when `val` is written to `addr`.
+fn latched_value(mapper: &mut Mapper, bus_conflicts: bool, addr: u16, val: u8) -> u8 {
+    if bus_conflicts {
+        val & mapper.prg_loadb(addr)
+    } else {
+        val
+    }
+}
+
+//
+// Mapper 2 (UxROM)
+//
+// See http://wiki.nesdev.com/w/index.php/UxROM
+//
+
+struct Uxrom {
+    rom: Box<Rom>,
+    prg_bank: u8, // 16KB PRG-ROM bank at $8000
+    chr_ram: Box<[u8; 8192]>,
+    bus_conflicts: bool,
+}
+
+impl Uxrom {
+    fn new(rom: Box<Rom>) -> Uxrom {
+        let bus_conflicts = bus_conflicts(&rom, true);
+        Uxrom {
+            rom: rom,
+            prg_bank: 0,
+            chr_ram: Box::new([0; 8192]),
+            bus_conflicts: bus_conflicts,
+        }
+    }
+}
+
+impl Mapper for Uxrom {
+    fn prg_loadb(&mut self, addr: u16) -> u8 {
+        if addr < 0x8000 {
+            return 0;
+        }
+        // $C000-$FFFF is fixed to the last bank.
+        let bank_count = self.rom.prg.len() / 16384;
+        let bank = if addr < 0xc000 {
+            self.prg_bank as usize % bank_count
+        } else {
+            bank_count - 1
+        };
+        self.rom.prg[(bank * 16384) | (addr as usize & 0x3fff)]
+    }
+
+    fn prg_storeb(&mut self, addr: u16, val: u8) {
+        if addr >= 0x8000 {
+            let bus_conflicts = self.bus_conflicts;
+            self.prg_bank = latched_value(self, bus_conflicts, addr, val);
+        }
+    }
+
+    fn chr_loadb(&mut self, addr: u16) -> u8 {
+        if self.rom.chr.is_empty() {
+            self.chr_ram[addr as usize]
+        } else {
+            self.rom.chr[addr as usize]
+        }
+    }
+
+    fn chr_storeb(&mut self, addr: u16, val: u8) {
+        if self.rom.chr.is_empty() {
+            self.chr_ram[addr as usize] = val
+        }
+    }
+    save_mapper!(prg_bank, chr_ram);
+}
+
+//
+// Mapper 3 (CNROM)
+//
+// See http://wiki.nesdev.com/w/index.php/CNROM
+//
+
+struct Cnrom {
+    rom: Box<Rom>,
+    chr_bank: u8, // 8KB CHR-ROM bank
+    bus_conflicts: bool,
+}
+
+impl Cnrom {
+    fn new(rom: Box<Rom>) -> Cnrom {
+        let bus_conflicts = bus_conflicts(&rom, true);
+        Cnrom {
+            rom: rom,
+            chr_bank: 0,
+            bus_conflicts: bus_conflicts,
+        }
+    }
+}
+
+impl Mapper for Cnrom {
+    fn prg_loadb(&mut self, addr: u16) -> u8 {
+        if addr < 0x8000 {
+            return 0;
+        }
+        // 16K of PRG-ROM is mirrored at $C000.
+        self.rom.prg[(addr as usize & 0x7fff) % self.rom.prg.len()]
+    }
+
+    fn prg_storeb(&mut self, addr: u16, val: u8) {
+        if addr >= 0x8000 {
+            let bus_conflicts = self.bus_conflicts;
+            self.chr_bank = latched_value(self, bus_conflicts, addr, val);
+        }
     }
 
-    fn next_scanline(&mut self) -> MapperResult {
-        MapperResult::Continue
+    fn chr_loadb(&mut self, addr: u16) -> u8 {
+        let offset = (self.chr_bank as usize * 8192) | addr as usize;
+        self.rom.chr[offset % self.rom.chr.len()]
     }
+
+    fn chr_storeb(&mut self, _: u16, _: u8) {} // Can't store to CHR-ROM.
+    save_mapper!(chr_bank);
 }
 
 //
-// Mapper 4 (TxROM/MMC3)
+// Mapper 4 (TxROM/MMC3), mapper 64 (Tengen RAMBO-1) and mapper 206 (DxROM/Namco 108)
 //
-// See http://wiki.nesdev.com/w/index.php/MMC3
+// See http://wiki.nesdev.com/w/index.php/MMC3,
+// http://wiki.nesdev.com/w/index.php/RAMBO-1 and
+// http://wiki.nesdev.com/w/index.php/INES_Mapper_206
 //
 
+/// How many PPU cycles A12 must stay low before a rise clocks the scanline counter. The 4-cycle
+/// gaps between background tile fetches are filtered out, while the 64 cycles of sprite fetches
+/// aren't.
+const A12_FILTER_CYCLES: u64 = 8;
+
+/// The Namco 108 is the MMC3's predecessor. It has the same bank registers, but lacks the PRG and
+/// CHR mode bits, the mirroring and PRG-RAM registers, and the IRQ counter.
+///
+/// The RAMBO-1 is Tengen's MMC3 clone. It adds a third switchable PRG bank, two more CHR banks
+/// for a mode with eight 1KB CHR windows, and an IRQ counter that can count CPU cycles instead of
+/// scanlines. It has no PRG-RAM.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum TxBoard {
+    Mmc3,
+    Rambo1,
+    Namco108,
+}
+
 #[derive(Copy, Clone)]
 struct TxBankSelect {
     val: u8,
 }
 
+save_struct!(TxBankSelect { val });
+
 impl Deref for TxBankSelect {
     type Target = u8;
 
@@ -243,6 +759,16 @@ impl TxBankSelect {
         self.val & 0x7
     }
 
+    /// The RAMBO-1 has sixteen bank registers, though only R0-R9 and RF exist.
+    fn rambo1_bank_update_select(&self) -> u8 {
+        self.val & 0xf
+    }
+
+    /// RAMBO-1 only: splits the two 2KB CHR windows into four 1KB windows.
+    fn rambo1_chr_1k_mode(&self) -> bool {
+        (self.val & 0x20) != 0
+    }
+
     fn prg_bank_mode(&self) -> TxPrgBankMode {
         if (self.val & 0x40) == 0 {
             TxPrgBankMode::Swappable8000
@@ -261,8 +787,11 @@ struct TxRegs {
     bank_select: TxBankSelect, // Bank select (0x8000-0x9ffe even)
 }
 
+save_struct!(TxRegs { bank_select });
+
 struct TxRom {
     rom: Box<Rom>,
+    board: TxBoard,
     regs: TxRegs,
     prg_ram: Box<[u8; 8192]>,
 
@@ -270,15 +799,32 @@ struct TxRom {
     chr_banks_1k: [u8; 4], // 1KB CHR-ROM banks
     prg_banks: [u8; 2],    // 8KB PRG-ROM banks
 
+    mirroring: Option<Mirroring>, // None until the game selects one.
+    prg_ram_enabled: bool,
+    prg_ram_writable: bool,
+
     scanline_counter: u8,
     irq_reload: u8, // Copied into the scanline counter when it hits zero.
     irq_enabled: bool,
+    irq_reload_pending: bool, // Set by $C001; the next clock reloads the counter.
+    irq: IrqLine,
+
+    // The scanline counter is clocked when PPU A12 rises after staying low for a while.
+    a12_high: bool,
+    a12_low_since: u64, // The PPU cycle A12 last fell on.
+
+    // RAMBO-1 only.
+    chr_banks_extra: [u8; 2], // R8 and R9: 1KB CHR-ROM banks used in 1KB mode
+    prg_bank_extra: u8,       // RF: the third 8KB PRG-ROM bank
+    irq_cycle_mode: bool,     // Count every 4 CPU cycles instead of scanlines.
+    irq_prescaler: u64,
 }
 
 impl TxRom {
-    fn new(rom: Box<Rom>) -> TxRom {
+    fn new(rom: Box<Rom>, board: TxBoard) -> TxRom {
         TxRom {
             rom: rom,
+            board: board,
             regs: TxRegs {
                 bank_select: TxBankSelect { val: 0 },
             },
@@ -288,44 +834,210 @@ impl TxRom {
             chr_banks_1k: [0, 0, 0, 0],
             prg_banks: [0, 0],
 
+            mirroring: None,
+            prg_ram_enabled: true,
+            prg_ram_writable: true,
+
             scanline_counter: 0,
             irq_reload: 0,
             irq_enabled: false,
+            irq_reload_pending: false,
+            irq: IrqLine::new(),
+
+            a12_high: false,
+            a12_low_since: 0,
+
+            chr_banks_extra: [0, 0],
+            prg_bank_extra: 0,
+            irq_cycle_mode: false,
+            irq_prescaler: 0,
         }
     }
 
     fn prg_bank_count(&self) -> u8 {
-        self.rom.header.prg_rom_size * 2
+        (self.rom.prg.len() / 8192) as u8
+    }
+
+    /// Reads PRG-ROM through an 8KB bank. Games only use as many bank bits as the ROM needs; the
+    /// rest are mirrored.
+    fn prg_rom_byte(&self, bank: u8, addr: u16) -> u8 {
+        let offset = (bank as usize * 8192) | (addr as usize & 0x1fff);
+        self.rom.prg[offset % self.rom.prg.len()]
+    }
+
+    /// Reads CHR-ROM `offset` bytes into a 1KB bank, mirroring the bank bits as for PRG-ROM.
+    fn chr_rom_byte(&self, bank: u8, offset: usize) -> u8 {
+        self.rom.chr[(bank as usize * 1024 + offset) % self.rom.chr.len()]
+    }
+
+    /// The mirroring register at $A000: 0 is vertical and 1 is horizontal.
+    fn set_mirroring(&mut self, val: u8) {
+        self.mirroring = Some(if (val & 1) == 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        });
+    }
+
+    fn namco108_storeb(&mut self, addr: u16, val: u8) {
+        // Only the bank registers exist, and only their low bits are connected.
+        if !(0x8000..0xa000).contains(&addr) {
+            return;
+        }
+        if (addr & 1) == 0 {
+            self.regs.bank_select = TxBankSelect { val: val & 0x7 };
+        } else {
+            let bank_update_select = self.regs.bank_select.bank_update_select() as usize;
+            match bank_update_select {
+                0...1 => self.chr_banks_2k[bank_update_select] = val & 0x3e,
+                2...5 => self.chr_banks_1k[bank_update_select - 2] = val & 0x3f,
+                6...7 => self.prg_banks[bank_update_select - 6] = val & 0xf,
+                _ => panic!(),
+            }
+        }
+    }
+
+    fn rambo1_storeb(&mut self, addr: u16, val: u8) {
+        match addr & 0xe001 {
+            0x8000 => self.regs.bank_select = TxBankSelect { val: val },
+            0x8001 => {
+                let bank_update_select = self.regs.bank_select.rambo1_bank_update_select() as usize;
+                match bank_update_select {
+                    0...1 => self.chr_banks_2k[bank_update_select] = val,
+                    2...5 => self.chr_banks_1k[bank_update_select - 2] = val,
+                    6...7 => self.prg_banks[bank_update_select - 6] = val,
+                    8...9 => self.chr_banks_extra[bank_update_select - 8] = val,
+                    0xf => self.prg_bank_extra = val,
+                    _ => {}
+                }
+            }
+            0xa000 => self.set_mirroring(val),
+            0xc000 => self.irq_reload = val,
+            0xc001 => {
+                self.irq_cycle_mode = (val & 1) != 0;
+                self.irq_prescaler = 0;
+                self.irq_reload_pending = true;
+            }
+            0xe000 => self.disable_irq(),
+            0xe001 => self.irq_enabled = true,
+            _ => {}
+        }
+    }
+
+    /// Returns the 8KB bank mapped at `addr`, which must be in $8000-$FFFF.
+    fn rambo1_prg_bank(&self, addr: u16) -> u8 {
+        let banks = match self.regs.bank_select.prg_bank_mode() {
+            TxPrgBankMode::Swappable8000 => {
+                [self.prg_banks[0], self.prg_banks[1], self.prg_bank_extra]
+            }
+            TxPrgBankMode::SwappableC000 => {
+                [self.prg_bank_extra, self.prg_banks[0], self.prg_banks[1]]
+            }
+        };
+        match addr {
+            0x8000...0x9fff => banks[0],
+            0xa000...0xbfff => banks[1],
+            0xc000...0xdfff => banks[2],
+            _ => self.prg_bank_count() - 1,
+        }
+    }
+
+    /// Returns the 1KB bank mapped at `addr`, which must be in $0000-$1FFF.
+    fn rambo1_chr_bank(&self, addr: u16) -> u8 {
+        let addr = if self.regs.bank_select.chr_a12_inversion() {
+            addr ^ 0x1000
+        } else {
+            addr
+        };
+        let chr_1k_mode = self.regs.bank_select.rambo1_chr_1k_mode();
+        match addr >> 10 {
+            0 if chr_1k_mode => self.chr_banks_2k[0],
+            1 if chr_1k_mode => self.chr_banks_extra[0],
+            2 if chr_1k_mode => self.chr_banks_2k[1],
+            3 if chr_1k_mode => self.chr_banks_extra[1],
+            0 => self.chr_banks_2k[0] & 0xfe,
+            1 => self.chr_banks_2k[0] | 1,
+            2 => self.chr_banks_2k[1] & 0xfe,
+            3 => self.chr_banks_2k[1] | 1,
+            window => self.chr_banks_1k[window as usize - 4],
+        }
+    }
+
+    /// Clocks the RAMBO-1 IRQ counter, either at the end of a scanline or every four CPU cycles.
+    fn mmc3_clock_irq(&mut self) {
+        if self.scanline_counter == 0 || self.irq_reload_pending {
+            self.irq_reload_pending = false;
+            self.scanline_counter = self.irq_reload;
+        } else {
+            self.scanline_counter -= 1;
+        }
+
+        if self.scanline_counter == 0 && self.irq_enabled {
+            self.irq.assert(IrqSource::Mapper);
+        }
+    }
+
+    fn rambo1_clock_irq(&mut self) {
+        if self.irq_reload_pending {
+            // Unlike the MMC3, a reload via $C001 takes one extra clock if the latch is nonzero.
+            self.irq_reload_pending = false;
+            self.scanline_counter = if self.irq_reload == 0 {
+                0
+            } else {
+                self.irq_reload.wrapping_add(1)
+            };
+        } else if self.scanline_counter == 0 {
+            self.scanline_counter = self.irq_reload;
+        } else {
+            self.scanline_counter -= 1;
+        }
+
+        if self.scanline_counter == 0 && self.irq_enabled {
+            self.irq.assert(IrqSource::Mapper);
+        }
+    }
+
+    /// The IRQ disable register ($E000) also acknowledges a pending IRQ.
+    fn disable_irq(&mut self) {
+        self.irq_enabled = false;
+        self.irq.acknowledge(IrqSource::Mapper);
     }
 }
 
 impl Mapper for TxRom {
     fn prg_loadb(&mut self, addr: u16) -> u8 {
-        if addr < 0x6000 {
+        if addr < 0x6000 || (addr < 0x8000 && self.board != TxBoard::Mmc3) {
             0u8
         } else if addr < 0x8000 {
-            self.prg_ram[addr as usize & 0x1fff]
+            if self.prg_ram_enabled {
+                self.prg_ram[addr as usize & 0x1fff]
+            } else {
+                0u8
+            }
+        } else if self.board == TxBoard::Rambo1 {
+            let bank = self.rambo1_prg_bank(addr);
+            self.prg_rom_byte(bank, addr)
         } else if addr < 0xa000 {
             // $8000-$9FFF might be switchable or fixed to the second to last bank.
             let bank = match self.regs.bank_select.prg_bank_mode() {
                 TxPrgBankMode::Swappable8000 => self.prg_banks[0],
                 TxPrgBankMode::SwappableC000 => self.prg_bank_count() - 2,
             };
-            self.rom.prg[(bank as usize * 8192) | (addr as usize & 0x1fff)]
+            self.prg_rom_byte(bank, addr)
         } else if addr < 0xc000 {
             // $A000-$BFFF is switchable.
-            self.rom.prg[(self.prg_banks[1] as usize * 8192) | (addr as usize & 0x1fff)]
+            self.prg_rom_byte(self.prg_banks[1], addr)
         } else if addr < 0xe000 {
             // $C000-$DFFF might be switchable or fixed to the second to last bank.
             let bank = match self.regs.bank_select.prg_bank_mode() {
                 TxPrgBankMode::Swappable8000 => self.prg_bank_count() - 2,
                 TxPrgBankMode::SwappableC000 => self.prg_banks[0],
             };
-            self.rom.prg[(bank as usize * 8192) | (addr as usize & 0x1fff)]
+            self.prg_rom_byte(bank, addr)
         } else {
             // $E000-$FFFF is fixed to the last bank.
             let bank = self.prg_bank_count() - 1;
-            self.rom.prg[(bank as usize * 8192) | (addr as usize & 0x1fff)]
+            self.prg_rom_byte(bank, addr)
         }
     }
 
@@ -334,8 +1046,22 @@ impl Mapper for TxRom {
             return;
         }
 
-        if addr < 0x8000 {
-            self.prg_ram[addr as usize & 0x1fff] = val;
+        match self.board {
+            TxBoard::Mmc3 => {}
+            TxBoard::Rambo1 => {
+                self.rambo1_storeb(addr, val);
+                return;
+            }
+            TxBoard::Namco108 => {
+                self.namco108_storeb(addr, val);
+                return;
+            }
+        }
+
+        if addr < 0x8000 {
+            if self.prg_ram_enabled && self.prg_ram_writable {
+                self.prg_ram[addr as usize & 0x1fff] = val;
+            }
         } else if addr < 0xa000 {
             if (addr & 1) == 0 {
                 // Bank select.
@@ -351,22 +1077,36 @@ impl Mapper for TxRom {
                 }
             }
         } else if addr < 0xc000 {
-            // TODO: Mirroring and PRG-RAM protect
+            if (addr & 1) == 0 {
+                // Mirroring.
+                self.set_mirroring(val);
+            } else {
+                // PRG-RAM protect: bit 7 enables the chip and bit 6 denies writes.
+                self.prg_ram_enabled = (val & 0x80) != 0;
+                self.prg_ram_writable = (val & 0x40) == 0;
+            }
         } else if addr < 0xe000 {
             if (addr & 1) == 0 {
                 // IRQ latch.
                 self.irq_reload = val;
             } else {
                 // IRQ reload.
-                self.scanline_counter = self.irq_reload;
+                self.scanline_counter = 0;
+                self.irq_reload_pending = true;
             }
+        } else if (addr & 1) == 0 {
+            self.disable_irq();
         } else {
-            // IRQ enable.
-            self.irq_enabled = (addr & 1) == 1;
+            self.irq_enabled = true;
         }
     }
 
     fn chr_loadb(&mut self, addr: u16) -> u8 {
+        if self.board == TxBoard::Rambo1 {
+            let bank = self.rambo1_chr_bank(addr);
+            return self.chr_rom_byte(bank, addr as usize & 0x3ff);
+        }
+
         let (bank, two_kb) = match (addr, self.regs.bank_select.chr_a12_inversion()) {
             (0x0000...0x07ff, false) | (0x1000...0x17ff, true) => (self.chr_banks_2k[0], true),
             (0x0800...0x0fff, false) | (0x1800...0x1fff, true) => (self.chr_banks_2k[1], true),
@@ -377,9 +1117,9 @@ impl Mapper for TxRom {
             _ => return 0,
         };
         if two_kb {
-            self.rom.chr[(bank as usize * 1024) + (addr as usize & 0x7ff)]
+            self.chr_rom_byte(bank, addr as usize & 0x7ff)
         } else {
-            self.rom.chr[(bank as usize * 1024) | (addr as usize & 0x3ff)]
+            self.chr_rom_byte(bank, addr as usize & 0x3ff)
         }
     }
 
@@ -387,18 +1127,1198 @@ impl Mapper for TxRom {
         // TODO: CHR-RAM
     }
 
-    fn next_scanline(&mut self) -> MapperResult {
-        if self.scanline_counter != 0 {
-            self.scanline_counter -= 1;
-            if self.scanline_counter == 0 {
-                self.scanline_counter = self.irq_reload;
+    fn connect_irq(&mut self, irq: IrqLine) {
+        self.irq = irq;
+    }
 
-                if self.irq_enabled {
-                    //debug!("*** Generated IRQ! ***");
-                    return MapperResult::Irq;
-                }
+    fn ppu_a12(&mut self, high: bool, ppu_cycle: u64) {
+        // The counter ignores A12 rising again too soon after it fell, as it does between
+        // background tiles when both tables are in use. In practice that leaves one clock per
+        // scanline.
+        let rising = high
+            && !self.a12_high
+            && ppu_cycle.saturating_sub(self.a12_low_since) >= A12_FILTER_CYCLES;
+        if !high && self.a12_high {
+            self.a12_low_since = ppu_cycle;
+        }
+        self.a12_high = high;
+
+        if !rising {
+            return;
+        }
+        match self.board {
+            TxBoard::Mmc3 => self.mmc3_clock_irq(),
+            TxBoard::Rambo1 if !self.irq_cycle_mode => self.rambo1_clock_irq(),
+            TxBoard::Rambo1 | TxBoard::Namco108 => {}
+        }
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        self.mirroring
+    }
+
+    fn step(&mut self, cycles: u64) {
+        if self.board != TxBoard::Rambo1 || !self.irq_cycle_mode {
+            return;
+        }
+
+        self.irq_prescaler += cycles;
+        while self.irq_prescaler >= 4 {
+            self.irq_prescaler -= 4;
+            self.rambo1_clock_irq();
+        }
+    }
+
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        match self.board {
+            TxBoard::Mmc3 => Some(&mut *self.prg_ram),
+            TxBoard::Rambo1 | TxBoard::Namco108 => None,
+        }
+    }
+
+    fn import_fceux(&mut self, section: &FceuxSection) -> bool {
+        // FCEUX stores the Namco 108 and RAMBO-1 differently, so only the MMC3 is supported.
+        if self.board != TxBoard::Mmc3 {
+            return false;
+        }
+        let (regs, cmd) = match (section.get_exact("REGS", 8), section.get_u8("CMD")) {
+            (Some(regs), Some(cmd)) => (regs, cmd),
+            _ => return false,
+        };
+        self.regs.bank_select = TxBankSelect { val: cmd };
+        self.chr_banks_2k.copy_from_slice(&regs[0..2]);
+        self.chr_banks_1k.copy_from_slice(&regs[2..6]);
+        self.prg_banks.copy_from_slice(&regs[6..8]);
+        self.irq_reload = section.get_u8("IRQL").unwrap_or(0);
+        self.scanline_counter = section.get_u8("IRQC").unwrap_or(0);
+        self.irq_reload_pending = section.get_u8("IRQR").unwrap_or(0) != 0;
+        self.irq_enabled = section.get_u8("IRQA").unwrap_or(0) != 0;
+        if let Some(val) = section.get_u8("A000") {
+            self.set_mirroring(val);
+        }
+        if let Some(val) = section.get_u8("A001") {
+            self.prg_ram_enabled = (val & 0x80) != 0;
+            self.prg_ram_writable = (val & 0x40) == 0;
+        }
+        section.copy_to("WRAM", &mut *self.prg_ram);
+        true
+    }
+    save_mapper!(
+        regs,
+        prg_ram,
+        chr_banks_2k,
+        chr_banks_1k,
+        prg_banks,
+        mirroring,
+        prg_ram_enabled,
+        prg_ram_writable,
+        scanline_counter,
+        irq_reload,
+        irq_enabled,
+        irq_reload_pending,
+        a12_high,
+        a12_low_since,
+        chr_banks_extra,
+        prg_bank_extra,
+        irq_cycle_mode,
+        irq_prescaler
+    );
+}
+
+//
+// Mapper 7 (AxROM)
+//
+// See http://wiki.nesdev.com/w/index.php/AxROM
+//
+
+struct Axrom {
+    rom: Box<Rom>,
+    prg_bank: u8, // 32KB PRG-ROM bank
+    /// Which nametable is shown on every quarter of the screen: the upper if set.
+    upper_nametable: bool,
+    chr_ram: Box<[u8; 8192]>,
+    /// ANROM has none, but AMROM and AOROM do. Games for the latter mostly avoid them, so they're
+    /// off unless the header or the ROM database says otherwise.
+    bus_conflicts: bool,
+}
+
+impl Axrom {
+    fn new(rom: Box<Rom>) -> Axrom {
+        let bus_conflicts = bus_conflicts(&rom, false);
+        Axrom {
+            rom: rom,
+            prg_bank: 0,
+            upper_nametable: false,
+            chr_ram: Box::new([0; 8192]),
+            bus_conflicts: bus_conflicts,
+        }
+    }
+}
+
+impl Mapper for Axrom {
+    fn prg_loadb(&mut self, addr: u16) -> u8 {
+        if addr < 0x8000 {
+            return 0;
+        }
+        let offset = (self.prg_bank as usize * 32768) | (addr as usize & 0x7fff);
+        self.rom.prg[offset % self.rom.prg.len()]
+    }
+
+    fn prg_storeb(&mut self, addr: u16, val: u8) {
+        if addr >= 0x8000 {
+            let bus_conflicts = self.bus_conflicts;
+            let val = latched_value(self, bus_conflicts, addr, val);
+            self.prg_bank = val & 0x07;
+            self.upper_nametable = (val & 0x10) != 0;
+        }
+    }
+
+    fn chr_loadb(&mut self, addr: u16) -> u8 {
+        if self.rom.chr.is_empty() {
+            self.chr_ram[addr as usize]
+        } else {
+            self.rom.chr[addr as usize]
+        }
+    }
+
+    fn chr_storeb(&mut self, addr: u16, val: u8) {
+        if self.rom.chr.is_empty() {
+            self.chr_ram[addr as usize] = val
+        }
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(if self.upper_nametable {
+            Mirroring::OneScreenUpper
+        } else {
+            Mirroring::OneScreenLower
+        })
+    }
+    save_mapper!(prg_bank, upper_nametable, chr_ram);
+}
+
+//
+// Mapper 19 (Namco 163)
+//
+// See http://wiki.nesdev.com/w/index.php/INES_Mapper_019 and
+// http://wiki.nesdev.com/w/index.php/Namco_163_audio
+//
+
+/// The number of CPU cycles it takes the N163 to update one wavetable channel.
+const N163_CYCLES_PER_CHANNEL: u8 = 15;
+
+/// The value the IRQ counter stops at, raising an IRQ.
+const N163_IRQ_COUNTER_MAX: u16 = 0x7fff;
+
+struct Namco163 {
+    rom: Box<Rom>,
+    prg_ram: Box<[u8; 8192]>,
+
+    prg_banks: [u8; 3],       // 8KB PRG-ROM banks at $8000, $A000 and $C000
+    chr_banks: [u8; 8],       // 1KB CHR-ROM banks
+    nametable_banks: [u8; 4], // Nametable sources ($C000-$DFFF)
+
+    irq_counter: u16,
+    irq_enabled: bool,
+    irq: IrqLine,
+
+    /// The 128 bytes of internal RAM, which also hold the wavetables and sound registers.
+    ram: [u8; 128],
+    /// The internal RAM address port ($F800). Bit 7 enables auto-increment.
+    ram_addr: u8,
+
+    sound_disabled: bool,
+    /// CPU cycles since the last channel update.
+    sound_cycles: u8,
+    /// The index of the channel that will be updated next, in 0..8.
+    sound_channel: u8,
+    /// The most recent output of each channel.
+    sound_outputs: [i16; 8],
+}
+
+impl Namco163 {
+    fn new(rom: Box<Rom>) -> Namco163 {
+        Namco163 {
+            rom: rom,
+            prg_ram: Box::new([0; 8192]),
+
+            prg_banks: [0, 0, 0],
+            chr_banks: [0; 8],
+            nametable_banks: [0; 4],
+
+            irq_counter: 0,
+            irq_enabled: false,
+            irq: IrqLine::new(),
+
+            ram: [0; 128],
+            ram_addr: 0,
+
+            sound_disabled: false,
+            sound_cycles: 0,
+            sound_channel: 7,
+            sound_outputs: [0; 8],
+        }
+    }
+
+    fn prg_bank_count(&self) -> usize {
+        self.rom.prg.len() / 8192
+    }
+
+    fn load_ram(&mut self) -> u8 {
+        let val = self.peek_ram();
+        self.bump_ram_addr();
+        val
+    }
+
+    fn peek_ram(&self) -> u8 {
+        self.ram[(self.ram_addr & 0x7f) as usize]
+    }
+
+    fn store_ram(&mut self, val: u8) {
+        self.ram[(self.ram_addr & 0x7f) as usize] = val;
+        self.bump_ram_addr();
+    }
+
+    fn bump_ram_addr(&mut self) {
+        if (self.ram_addr & 0x80) != 0 {
+            self.ram_addr = 0x80 | (((self.ram_addr & 0x7f) + 1) & 0x7f);
+        }
+    }
+
+    /// The number of enabled sound channels, from 1 to 8. Channels are enabled from the last one
+    /// downward.
+    fn sound_channel_count(&self) -> u8 {
+        ((self.ram[0x7f] >> 4) & 7) + 1
+    }
+
+    /// Runs one wavetable channel: advances its phase and latches its new output.
+    fn update_sound_channel(&mut self, channel: u8) {
+        let base = 0x40 + channel as usize * 8;
+        let freq = self.ram[base] as u32
+            | (self.ram[base + 2] as u32) << 8
+            | (self.ram[base + 4] as u32 & 3) << 16;
+        let mut phase = self.ram[base + 1] as u32
+            | (self.ram[base + 3] as u32) << 8
+            | (self.ram[base + 5] as u32) << 16;
+        let length = 256 - (self.ram[base + 4] & 0xfc) as u32;
+
+        phase = (phase + freq) % (length << 16);
+        self.ram[base + 1] = phase as u8;
+        self.ram[base + 3] = (phase >> 8) as u8;
+        self.ram[base + 5] = (phase >> 16) as u8;
+
+        // Each byte of the wavetable holds two 4-bit samples, low nibble first.
+        let sample_addr = (self.ram[base + 6] as u32 + (phase >> 16)) & 0xff;
+        let sample = (self.ram[sample_addr as usize >> 1] >> ((sample_addr & 1) * 4)) & 0xf;
+        let volume = self.ram[base + 7] & 0xf;
+        self.sound_outputs[channel as usize] = (sample as i16 - 8) * volume as i16;
+    }
+}
+
+impl Mapper for Namco163 {
+    fn prg_loadb(&mut self, addr: u16) -> u8 {
+        if addr < 0x4800 {
+            0
+        } else if addr < 0x5000 {
+            self.load_ram()
+        } else if addr < 0x5800 {
+            self.irq_counter as u8
+        } else if addr < 0x6000 {
+            ((self.irq_enabled as u8) << 7) | (self.irq_counter >> 8) as u8
+        } else if addr < 0x8000 {
+            self.prg_ram[addr as usize & 0x1fff]
+        } else {
+            // $E000-$FFFF is fixed to the last bank.
+            let bank = match addr {
+                0x8000...0x9fff => self.prg_banks[0] as usize,
+                0xa000...0xbfff => self.prg_banks[1] as usize,
+                0xc000...0xdfff => self.prg_banks[2] as usize,
+                _ => self.prg_bank_count() - 1,
+            } % self.prg_bank_count();
+            self.rom.prg[(bank * 8192) | (addr as usize & 0x1fff)]
+        }
+    }
+
+    fn prg_storeb(&mut self, addr: u16, val: u8) {
+        if addr < 0x4800 {
+            return;
+        }
+
+        if addr < 0x5000 {
+            self.store_ram(val);
+        } else if addr < 0x5800 {
+            // Writing to either half of the IRQ counter acknowledges the IRQ.
+            self.irq_counter = (self.irq_counter & 0x7f00) | val as u16;
+            self.irq.acknowledge(IrqSource::Mapper);
+        } else if addr < 0x6000 {
+            self.irq_counter = (self.irq_counter & 0x00ff) | ((val as u16 & 0x7f) << 8);
+            self.irq_enabled = (val & 0x80) != 0;
+            self.irq.acknowledge(IrqSource::Mapper);
+        } else if addr < 0x8000 {
+            self.prg_ram[addr as usize & 0x1fff] = val;
+        } else if addr < 0xc000 {
+            self.chr_banks[((addr - 0x8000) >> 11) as usize] = val;
+        } else if addr < 0xe000 {
+            self.nametable_banks[((addr - 0xc000) >> 11) as usize] = val;
+        } else if addr < 0xe800 {
+            self.prg_banks[0] = val & 0x3f;
+            self.sound_disabled = (val & 0x40) != 0;
+        } else if addr < 0xf000 {
+            self.prg_banks[1] = val & 0x3f;
+        } else if addr < 0xf800 {
+            self.prg_banks[2] = val & 0x3f;
+        } else {
+            self.ram_addr = val;
+        }
+    }
+
+    fn chr_loadb(&mut self, addr: u16) -> u8 {
+        // TODO: Bank numbers $E0 and above can map the console's nametable RAM into the pattern
+        // tables. Nothing we support uses this, so treat them as ROM banks.
+        let bank = self.chr_banks[(addr >> 10) as usize & 7] as usize;
+        let offset = (bank * 1024) | (addr as usize & 0x3ff);
+        self.rom.chr[offset % self.rom.chr.len()]
+    }
+
+    fn chr_storeb(&mut self, _: u16, _: u8) {} // Can't store to CHR-ROM.
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        // Banks $E0 and above pick a page of the console's nametable RAM by their low bit. Lower
+        // banks would show CHR-ROM as a nametable, which, as in `chr_loadb`, we don't support;
+        // they also pick a page by their low bit.
+        let banks = self.nametable_banks;
+        Some(Mirroring::Pages([
+            banks[0] & 1,
+            banks[1] & 1,
+            banks[2] & 1,
+            banks[3] & 1,
+        ]))
+    }
+
+    fn prg_peekb(&mut self, addr: u16) -> u8 {
+        // Reading the RAM port advances the address when auto-increment is on.
+        if (0x4800..0x5000).contains(&addr) {
+            self.peek_ram()
+        } else {
+            self.prg_loadb(addr)
+        }
+    }
+
+    fn connect_irq(&mut self, irq: IrqLine) {
+        self.irq = irq;
+    }
+
+    fn step(&mut self, cycles: u64) {
+        if !self.irq_enabled || self.irq_counter == N163_IRQ_COUNTER_MAX {
+            return;
+        }
+
+        let remaining = (N163_IRQ_COUNTER_MAX - self.irq_counter) as u64;
+        if cycles < remaining {
+            self.irq_counter += cycles as u16;
+            return;
+        }
+
+        self.irq_counter = N163_IRQ_COUNTER_MAX;
+        self.irq.assert(IrqSource::Mapper);
+    }
+
+    fn expansion_audio(&mut self, samples: &mut [i16]) -> bool {
+        if self.sound_disabled {
+            return false;
+        }
+
+        let channel_count = self.sound_channel_count();
+        let first_channel = 8 - channel_count;
+        for dest in samples.iter_mut() {
+            // The chip updates one channel at a time, cycling through the enabled channels.
+            self.sound_cycles += 1;
+            if self.sound_cycles >= N163_CYCLES_PER_CHANNEL {
+                self.sound_cycles = 0;
+                let channel = if self.sound_channel < first_channel {
+                    7
+                } else {
+                    self.sound_channel
+                };
+                self.update_sound_channel(channel);
+                self.sound_channel = if channel <= first_channel {
+                    7
+                } else {
+                    channel - 1
+                };
+            }
+
+            // The real chip multiplexes the channels in time; average them instead so that the
+            // multiplexing rate doesn't alias into the audible range.
+            let mut mix = 0;
+            for channel in first_channel..8 {
+                mix += self.sound_outputs[channel as usize] as i32;
             }
+            *dest = (mix * 64 / channel_count as i32) as i16;
+        }
+        true
+    }
+
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        Some(&mut *self.prg_ram)
+    }
+    save_mapper!(
+        prg_ram,
+        prg_banks,
+        chr_banks,
+        nametable_banks,
+        irq_counter,
+        irq_enabled,
+        ram,
+        ram_addr,
+        sound_disabled,
+        sound_cycles,
+        sound_channel
+    );
+}
+
+//
+// Mapper 34 (BNROM)
+//
+// See http://wiki.nesdev.com/w/index.php/BNROM
+//
+
+struct Bnrom {
+    rom: Box<Rom>,
+    prg_bank: u8, // 32KB PRG-ROM bank
+    chr_ram: Box<[u8; 8192]>,
+    /// True of the original board; some clones and homebrew boards avoid them, which the ROM
+    /// database can say.
+    bus_conflicts: bool,
+}
+
+impl Bnrom {
+    fn new(rom: Box<Rom>) -> Bnrom {
+        let bus_conflicts = bus_conflicts(&rom, true);
+        Bnrom {
+            rom: rom,
+            prg_bank: 0,
+            chr_ram: Box::new([0; 8192]),
+            bus_conflicts: bus_conflicts,
+        }
+    }
+}
+
+impl Mapper for Bnrom {
+    fn prg_loadb(&mut self, addr: u16) -> u8 {
+        if addr < 0x8000 {
+            return 0;
         }
-        MapperResult::Continue
+        let offset = (self.prg_bank as usize * 32768) | (addr as usize & 0x7fff);
+        self.rom.prg[offset % self.rom.prg.len()]
+    }
+
+    fn prg_storeb(&mut self, addr: u16, val: u8) {
+        if addr >= 0x8000 {
+            let bus_conflicts = self.bus_conflicts;
+            self.prg_bank = latched_value(self, bus_conflicts, addr, val);
+        }
+    }
+
+    fn chr_loadb(&mut self, addr: u16) -> u8 {
+        if self.rom.chr.is_empty() {
+            self.chr_ram[addr as usize]
+        } else {
+            self.rom.chr[addr as usize]
+        }
+    }
+
+    fn chr_storeb(&mut self, addr: u16, val: u8) {
+        if self.rom.chr.is_empty() {
+            self.chr_ram[addr as usize] = val
+        }
+    }
+    save_mapper!(prg_bank, chr_ram);
+}
+
+//
+// Mapper 34 (NINA-001)
+//
+// See http://wiki.nesdev.com/w/index.php/NINA-001
+//
+
+struct Nina001 {
+    rom: Box<Rom>,
+    prg_ram: Box<[u8; 8192]>,
+    prg_bank: u8,       // 32KB PRG-ROM bank ($7FFD)
+    chr_banks: [u8; 2], // 4KB CHR-ROM banks ($7FFE and $7FFF)
+}
+
+impl Nina001 {
+    fn new(rom: Box<Rom>) -> Nina001 {
+        Nina001 {
+            rom: rom,
+            prg_ram: Box::new([0; 8192]),
+            prg_bank: 0,
+            chr_banks: [0, 1],
+        }
+    }
+}
+
+impl Mapper for Nina001 {
+    fn prg_loadb(&mut self, addr: u16) -> u8 {
+        if addr < 0x6000 {
+            0
+        } else if addr < 0x8000 {
+            self.prg_ram[addr as usize & 0x1fff]
+        } else {
+            let offset = (self.prg_bank as usize * 32768) | (addr as usize & 0x7fff);
+            self.rom.prg[offset % self.rom.prg.len()]
+        }
+    }
+
+    fn prg_storeb(&mut self, addr: u16, val: u8) {
+        if !(0x6000..0x8000).contains(&addr) {
+            return;
+        }
+
+        // The registers sit on top of the last bytes of PRG-RAM, which still get written.
+        self.prg_ram[addr as usize & 0x1fff] = val;
+        match addr {
+            0x7ffd => self.prg_bank = val & 1,
+            0x7ffe => self.chr_banks[0] = val & 0xf,
+            0x7fff => self.chr_banks[1] = val & 0xf,
+            _ => {}
+        }
+    }
+
+    fn chr_loadb(&mut self, addr: u16) -> u8 {
+        let bank = self.chr_banks[(addr >> 12) as usize & 1] as usize;
+        let offset = (bank * 4096) | (addr as usize & 0xfff);
+        self.rom.chr[offset % self.rom.chr.len()]
+    }
+
+    fn chr_storeb(&mut self, _: u16, _: u8) {} // Can't store to CHR-ROM.
+
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        Some(&mut *self.prg_ram)
+    }
+    save_mapper!(prg_ram, prg_bank, chr_banks);
+}
+
+//
+// Mapper 71 (Camerica/Codemasters)
+//
+// See http://wiki.nesdev.com/w/index.php/INES_Mapper_071
+//
+
+struct Camerica {
+    rom: Box<Rom>,
+    prg_bank: u8, // 16KB PRG-ROM bank at $8000
+    /// Only the BF9097 board (Fire Hawk) can select one-screen mirroring; other boards leave this
+    /// alone and use the mirroring from the header.
+    mirroring: Option<Mirroring>,
+    chr_ram: Box<[u8; 8192]>,
+}
+
+impl Camerica {
+    fn new(rom: Box<Rom>) -> Camerica {
+        Camerica {
+            rom: rom,
+            prg_bank: 0,
+            mirroring: None,
+            chr_ram: Box::new([0; 8192]),
+        }
+    }
+}
+
+impl Mapper for Camerica {
+    fn prg_loadb(&mut self, addr: u16) -> u8 {
+        let bank_count = self.rom.prg.len() / 16384;
+        let bank = if addr < 0x8000 {
+            return 0;
+        } else if addr < 0xc000 {
+            self.prg_bank as usize % bank_count
+        } else {
+            // $C000-$FFFF is fixed to the last bank.
+            bank_count - 1
+        };
+        self.rom.prg[(bank * 16384) | (addr as usize & 0x3fff)]
+    }
+
+    fn prg_storeb(&mut self, addr: u16, val: u8) {
+        if addr >= 0xc000 {
+            self.prg_bank = val;
+        } else if (0x9000..0xa000).contains(&addr) {
+            self.mirroring = Some(if (val & 0x10) == 0 {
+                Mirroring::OneScreenLower
+            } else {
+                Mirroring::OneScreenUpper
+            });
+        }
+    }
+
+    fn chr_loadb(&mut self, addr: u16) -> u8 {
+        self.chr_ram[addr as usize]
+    }
+
+    fn chr_storeb(&mut self, addr: u16, val: u8) {
+        self.chr_ram[addr as usize] = val
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        self.mirroring
+    }
+    save_mapper!(prg_bank, mirroring, chr_ram);
+}
+
+//
+// Mapper 228 (Action 52)
+//
+// See http://wiki.nesdev.com/w/index.php/INES_Mapper_228
+//
+
+/// The size of each of Action 52's PRG-ROM chips.
+const ACTION52_PRG_CHIP_SIZE: usize = 512 * 1024;
+
+/// Action 52 and Cheetahmen II. The board latches the address of every write to $8000-$FFFF as
+/// well as the low two bits of the value:
+///
+/// * A13: mirroring (0 = vertical, 1 = horizontal)
+/// * A12-A11: PRG-ROM chip
+/// * A10-A6: 16KB PRG-ROM bank within the chip
+/// * A5: PRG mode (0 = 32KB, 1 = 16KB)
+/// * A3-A0 and D1-D0: 8KB CHR-ROM bank
+struct Action52 {
+    rom: Box<Rom>,
+    addr: u16, // The address latched by the last write
+    chr_bank_low: u8,
+    ram: [u8; 4], // Four nibbles of RAM at $5FF0-$5FFF
+}
+
+impl Action52 {
+    fn new(rom: Box<Rom>) -> Action52 {
+        Action52 {
+            rom: rom,
+            addr: 0,
+            chr_bank_low: 0,
+            ram: [0; 4],
+        }
+    }
+
+    /// Returns the offset of the selected chip's first byte in the PRG-ROM. Only three chips are
+    /// fitted, as chips 0, 1 and 3, and dumps leave out the empty socket.
+    fn prg_chip_offset(&self) -> Option<usize> {
+        match (self.addr >> 11) & 3 {
+            2 => None,
+            3 => Some(2 * ACTION52_PRG_CHIP_SIZE),
+            chip => Some(chip as usize * ACTION52_PRG_CHIP_SIZE),
+        }
+    }
+}
+
+impl Mapper for Action52 {
+    fn prg_loadb(&mut self, addr: u16) -> u8 {
+        if (0x5ff0..0x6000).contains(&addr) {
+            return self.ram[addr as usize & 3];
+        }
+        if addr < 0x8000 {
+            return 0;
+        }
+        let chip_offset = match self.prg_chip_offset() {
+            Some(offset) => offset,
+            None => return 0,
+        };
+        let bank = (self.addr >> 6) as usize & 0x1f;
+        let offset = if (self.addr & 0x20) == 0 {
+            // 32KB mode ignores the low bit of the bank.
+            ((bank & !1) * 16384) | (addr as usize & 0x7fff)
+        } else {
+            (bank * 16384) | (addr as usize & 0x3fff)
+        };
+        self.rom.prg[(chip_offset + offset) % self.rom.prg.len()]
+    }
+
+    fn prg_storeb(&mut self, addr: u16, val: u8) {
+        if (0x5ff0..0x6000).contains(&addr) {
+            self.ram[addr as usize & 3] = val & 0xf;
+        } else if addr >= 0x8000 {
+            self.addr = addr;
+            self.chr_bank_low = val & 3;
+        }
+    }
+
+    fn chr_loadb(&mut self, addr: u16) -> u8 {
+        let bank = ((self.addr as usize & 0xf) << 2) | self.chr_bank_low as usize;
+        self.rom.chr[((bank * 8192) | addr as usize) % self.rom.chr.len()]
+    }
+
+    fn chr_storeb(&mut self, _: u16, _: u8) {} // Can't store to CHR-ROM.
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(if (self.addr & 0x2000) == 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        })
+    }
+    save_mapper!(addr, chr_bank_low, ram);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::{Seek, SeekFrom};
+
+    // An iNES image for the given mapper with 16K PRG-ROM and 8K CHR-ROM banks. Each byte of
+    // PRG-ROM holds the number of the 8K bank it's in.
+    fn image(mapper: u8, prg_banks: u8, chr_banks: u8) -> Vec<u8> {
+        let mut image = b"NES\x1a".to_vec();
+        image.extend_from_slice(&[prg_banks, chr_banks, mapper << 4, mapper & 0xf0]);
+        image.resize(16, 0);
+        for bank in 0..prg_banks as usize * 2 {
+            image.extend(std::iter::repeat(bank as u8).take(8192));
+        }
+        image.resize(image.len() + chr_banks as usize * 8192, 0);
+        image
+    }
+
+    fn rom(mapper: u8, prg_banks: u8, chr_banks: u8) -> Box<Rom> {
+        Box::new(Rom::from_bytes(&image(mapper, prg_banks, chr_banks)).unwrap())
+    }
+
+    // Like `rom`, but with an NES 2.0 header giving the submapper.
+    fn nes2_rom(mapper: u8, submapper: u8, prg_banks: u8, chr_banks: u8) -> Box<Rom> {
+        let mut image = image(mapper, prg_banks, chr_banks);
+        image[7] |= 0x08;
+        image[8] = submapper << 4;
+        Box::new(Rom::from_bytes(&image).unwrap())
+    }
+
+    #[test]
+    fn uxrom_bus_conflicts() {
+        // The byte at $C000, in the last of eight banks, is 14, so writing 7 there latches 6.
+        let mut mapper = Uxrom::new(rom(2, 8, 0));
+        mapper.prg_storeb(0xc000, 7);
+        assert_eq!(mapper.prg_loadb(0x8000), 12);
+
+        let mut mapper = Uxrom::new(nes2_rom(2, 1, 8, 0));
+        mapper.prg_storeb(0xc000, 7);
+        assert_eq!(mapper.prg_loadb(0x8000), 14);
+        assert_eq!(mapper.prg_loadb(0xc000), 14);
+    }
+
+    #[test]
+    fn cnrom_bus_conflicts() {
+        // Every byte of the one PRG-ROM bank is 0, so with conflicts nothing switches.
+        let mut cart = rom(3, 1, 4);
+        cart.chr[8192] = 0xaa;
+        let mut mapper = Cnrom::new(cart);
+        mapper.prg_storeb(0x8000, 1);
+        assert_eq!(mapper.chr_loadb(0), 0);
+
+        let mut cart = nes2_rom(3, 1, 1, 4);
+        cart.chr[8192] = 0xaa;
+        let mut mapper = Cnrom::new(cart);
+        mapper.prg_storeb(0x8000, 1);
+        assert_eq!(mapper.chr_loadb(0), 0xaa);
+    }
+
+    #[test]
+    fn axrom_bus_conflicts_follow_submapper() {
+        let mut mapper = Axrom::new(nes2_rom(7, 2, 8, 0));
+        // The byte at $8000 in the first bank is 0.
+        mapper.prg_storeb(0x8000, 0x12);
+        assert_eq!(mapper.prg_loadb(0x8000), 0);
+        assert_eq!(mapper.mirroring(), Some(Mirroring::OneScreenLower));
+    }
+
+    #[test]
+    fn axrom_switches_prg_and_nametable() {
+        let mut mapper = Axrom::new(rom(7, 8, 0));
+        assert_eq!(mapper.mirroring(), Some(Mirroring::OneScreenLower));
+        mapper.prg_storeb(0x8000, 0x12);
+        assert_eq!(mapper.prg_loadb(0x8000), 8);
+        assert_eq!(mapper.prg_loadb(0xffff), 11);
+        assert_eq!(mapper.mirroring(), Some(Mirroring::OneScreenUpper));
+        mapper.prg_storeb(0xc000, 0x01);
+        assert_eq!(mapper.prg_loadb(0x8000), 4);
+        assert_eq!(mapper.mirroring(), Some(Mirroring::OneScreenLower));
+    }
+
+    #[test]
+    fn rambo1_banks_wrap_around_the_rom() {
+        // 32K of PRG-ROM is four 8K banks, and 8K of CHR-ROM is eight 1K banks.
+        let mut cart = rom(64, 2, 1);
+        cart.chr[3 * 1024] = 0xaa;
+        let mut mapper = TxRom::new(cart, TxBoard::Rambo1);
+        mapper.prg_storeb(0x8000, 6);
+        mapper.prg_storeb(0x8001, 0x13);
+        assert_eq!(mapper.prg_loadb(0x8000), 3);
+        mapper.prg_storeb(0x8000, 0x0f);
+        mapper.prg_storeb(0x8001, 0xfe);
+        assert_eq!(mapper.prg_loadb(0xc000), 2);
+        assert_eq!(mapper.prg_loadb(0xe000), 3);
+
+        // R2 selects the 1K bank at $1000.
+        mapper.prg_storeb(0x8000, 2);
+        mapper.prg_storeb(0x8001, 0xfb);
+        assert_eq!(mapper.chr_loadb(0x1000), 0xaa);
+    }
+
+    #[test]
+    fn namco108_banks_wrap_around_the_rom() {
+        // 64K of PRG-ROM is eight 8K banks, and 16K of CHR-ROM is sixteen 1K banks.
+        let mut cart = rom(206, 4, 2);
+        for (i, byte) in cart.chr.iter_mut().enumerate() {
+            *byte = (i / 1024) as u8;
+        }
+        let mut mapper = TxRom::new(cart, TxBoard::Namco108);
+
+        // R6 selects the 8K bank at $8000. Bank 13 is bank 5.
+        mapper.prg_storeb(0x8000, 6);
+        mapper.prg_storeb(0x8001, 13);
+        assert_eq!(mapper.prg_loadb(0x8000), 5);
+        assert_eq!(mapper.prg_loadb(0xe000), 7);
+
+        // R2 selects the 1K bank at $1000, and R0 the 2K bank at $0000. Bank 0x3d is bank 13.
+        mapper.prg_storeb(0x8000, 2);
+        mapper.prg_storeb(0x8001, 0x3d);
+        assert_eq!(mapper.chr_loadb(0x1000), 13);
+        mapper.prg_storeb(0x8000, 0);
+        mapper.prg_storeb(0x8001, 0x3e);
+        assert_eq!(mapper.chr_loadb(0x0000), 14);
+        assert_eq!(mapper.chr_loadb(0x0400), 15);
+    }
+
+    #[test]
+    fn namco163_peek_leaves_ram_address() {
+        let mut mapper = Namco163::new(rom(19, 2, 1));
+        // Write 0x12 and 0x34 to RAM 0 and 1 with auto-increment, then point back at 0.
+        mapper.prg_storeb(0xf800, 0x80);
+        mapper.prg_storeb(0x4800, 0x12);
+        mapper.prg_storeb(0x4800, 0x34);
+        mapper.prg_storeb(0xf800, 0x80);
+
+        assert_eq!(mapper.prg_peekb(0x4800), 0x12);
+        assert_eq!(mapper.prg_peekb(0x4800), 0x12);
+        assert_eq!(mapper.prg_loadb(0x4800), 0x12);
+        assert_eq!(mapper.prg_loadb(0x4800), 0x34);
+    }
+
+    #[test]
+    fn namco163_switches_banks_and_nametables() {
+        // 64K of PRG-ROM is eight 8K banks, and 16K of CHR-ROM is sixteen 1K banks.
+        let mut cart = rom(19, 4, 2);
+        for (i, byte) in cart.chr.iter_mut().enumerate() {
+            *byte = (i / 1024) as u8;
+        }
+        let mut mapper = Namco163::new(cart);
+        mapper.prg_storeb(0xe000, 2);
+        mapper.prg_storeb(0xe800, 0x0b);
+        mapper.prg_storeb(0xf000, 5);
+        assert_eq!(mapper.prg_loadb(0x8000), 2);
+        assert_eq!(mapper.prg_loadb(0xa000), 3);
+        assert_eq!(mapper.prg_loadb(0xc000), 5);
+        assert_eq!(mapper.prg_loadb(0xe000), 7);
+
+        mapper.prg_storeb(0x8800, 9);
+        assert_eq!(mapper.chr_loadb(0x0400), 9);
+
+        // Pages A, A, B, B of the console's RAM is horizontal mirroring.
+        for (i, &bank) in [0xe0, 0xe0, 0xe1, 0xe1].iter().enumerate() {
+            mapper.prg_storeb(0xc000 + i as u16 * 0x800, bank);
+        }
+        let mirroring = mapper.mirroring().unwrap();
+        for &addr in [0x2000, 0x2400, 0x2800, 0x2c00, 0x2c05].iter() {
+            assert_eq!(
+                mirroring.nametable_offset(addr),
+                Mirroring::Horizontal.nametable_offset(addr)
+            );
+        }
+    }
+
+    #[test]
+    fn namco163_irq_fires_when_the_counter_reaches_7fff() {
+        let mut mapper = Namco163::new(rom(19, 2, 1));
+        let irq = IrqLine::new();
+        mapper.connect_irq(irq.clone());
+        mapper.prg_storeb(0x5000, 0xf0);
+        mapper.prg_storeb(0x5800, 0xff);
+        mapper.step(0x0e);
+        assert!(!irq.asserted());
+        assert_eq!(mapper.prg_loadb(0x5000), 0xfe);
+        assert_eq!(mapper.prg_loadb(0x5800), 0xff);
+
+        // The counter stops at $7FFF.
+        mapper.step(5);
+        assert!(irq.asserted_by(IrqSource::Mapper));
+        assert_eq!(mapper.prg_loadb(0x5000), 0xff);
+
+        // Writing the counter acknowledges the IRQ.
+        mapper.prg_storeb(0x5000, 0);
+        assert!(!irq.asserted());
+    }
+
+    #[test]
+    fn namco163_plays_a_wavetable() {
+        let mut mapper = Namco163::new(rom(19, 2, 1));
+        // A four-sample wave at address 0: 0, 15, 0, 0.
+        mapper.prg_storeb(0xf800, 0x80);
+        mapper.prg_storeb(0x4800, 0xf0);
+        // Channel 8 alone, stepping one sample per update, at full volume.
+        mapper.prg_storeb(0xf800, 0x80 | 0x78);
+        for &val in [0x00, 0x00, 0x00, 0x00, 0xfd, 0x00, 0x00, 0x0f].iter() {
+            mapper.prg_storeb(0x4800, val);
+        }
+
+        // The channel is updated every 15 CPU cycles.
+        let mut samples = [0; 30];
+        assert!(mapper.expansion_audio(&mut samples));
+        assert_eq!(samples[13], 0);
+        assert_eq!(samples[14], (15 - 8) * 15 * 64);
+        assert_eq!(samples[29], -8 * 15 * 64);
+
+        mapper.prg_storeb(0xe000, 0x40);
+        assert!(!mapper.expansion_audio(&mut samples));
+    }
+
+    #[test]
+    fn mmc3_state_survives_a_savestate() {
+        let path = env::temp_dir().join("sprocketnes-mapper-state-test");
+        let mut fd = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+
+        let mut saved = TxRom::new(rom(4, 8, 8), TxBoard::Mmc3);
+        saved.prg_storeb(0x8000, 6);
+        saved.prg_storeb(0x8001, 5);
+        saved.prg_storeb(0xa000, 1);
+        saved.prg_storeb(0xa001, 0x80);
+        saved.prg_storeb(0x6000, 0x55);
+        saved.prg_storeb(0xc000, 2);
+        saved.prg_storeb(0xc001, 0);
+        saved.prg_storeb(0xe001, 0);
+        // The first rise reloads the counter with 2.
+        saved.ppu_a12(true, 341);
+        saved.ppu_a12(false, 342);
+        saved.save(&mut fd);
+
+        let mut mapper = TxRom::new(rom(4, 8, 8), TxBoard::Mmc3);
+        let irq = IrqLine::new();
+        mapper.connect_irq(irq.clone());
+        fd.seek(SeekFrom::Start(0)).unwrap();
+        mapper.load(&mut fd);
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(mapper.prg_loadb(0x8000), 5);
+        assert_eq!(mapper.mirroring(), Some(Mirroring::Horizontal));
+        assert_eq!(mapper.prg_loadb(0x6000), 0x55);
+        mapper.ppu_a12(true, 2 * 341);
+        mapper.ppu_a12(false, 2 * 341 + 1);
+        assert!(!irq.asserted());
+        mapper.ppu_a12(true, 3 * 341);
+        assert!(irq.asserted());
+    }
+
+    #[test]
+    fn mmc3_ignores_a12_rises_closer_than_the_filter() {
+        let mut mapper = TxRom::new(rom(4, 8, 8), TxBoard::Mmc3);
+        mapper.prg_storeb(0xc000, 5);
+        mapper.prg_storeb(0xc001, 0);
+        mapper.ppu_a12(true, 100);
+        assert_eq!(mapper.scanline_counter, 5);
+
+        // Low for four cycles, as between background tiles fetched from $1000
+        mapper.ppu_a12(false, 104);
+        mapper.ppu_a12(true, 108);
+        assert_eq!(mapper.scanline_counter, 5);
+
+        mapper.ppu_a12(false, 110);
+        mapper.ppu_a12(true, 110 + A12_FILTER_CYCLES);
+        assert_eq!(mapper.scanline_counter, 4);
+    }
+
+    #[test]
+    fn registry_refuses_unknown_mappers() {
+        match create_mapper(rom(255, 1, 1)) {
+            Err(MapperError::UnsupportedMapper(255)) => {}
+            _ => panic!("mapper 255 isn't built in"),
+        }
+
+        let mut registry = MapperRegistry::new();
+        registry.register(255, "Homebrew", |rom| Box::new(Nrom::new(rom)));
+        assert!(registry.supported().contains(&(255, "Homebrew")));
+        let mut mapper = registry.create(rom(255, 1, 1)).unwrap();
+        assert_eq!(mapper.prg_loadb(0xe000), 1);
+    }
+
+    // Writes a value to an MMC1 register through the serial port, a bit at a time.
+    fn mmc1_write(mapper: &mut SxRom, addr: u16, val: u8) {
+        for i in 0..5 {
+            mapper.prg_storeb(addr, val >> i);
+        }
+    }
+
+    #[test]
+    fn mmc1_switches_chr_in_4k_and_8k_banks() {
+        // 16K of CHR-ROM is four 4K banks.
+        let mut cart = rom(1, 2, 2);
+        for (i, byte) in cart.chr.iter_mut().enumerate() {
+            *byte = (i / 4096) as u8;
+        }
+        let mut mapper = SxRom::new(cart);
+
+        mmc1_write(&mut mapper, 0x8000, 0x1c);
+        mmc1_write(&mut mapper, 0xa000, 3);
+        mmc1_write(&mut mapper, 0xc000, 1);
+        assert_eq!(mapper.chr_loadb(0x0000), 3);
+        assert_eq!(mapper.chr_loadb(0x1fff), 1);
+
+        // 8K mode ignores the low bit of the first bank and the second bank entirely.
+        mmc1_write(&mut mapper, 0x8000, 0x0c);
+        assert_eq!(mapper.chr_loadb(0x0000), 2);
+        assert_eq!(mapper.chr_loadb(0x1fff), 3);
+    }
+
+    #[test]
+    fn mmc1_reports_mirroring() {
+        let mut mapper = SxRom::new(rom(1, 2, 1));
+        for &(ctrl, mirroring) in [
+            (0x0c, Mirroring::OneScreenLower),
+            (0x0d, Mirroring::OneScreenUpper),
+            (0x0e, Mirroring::Vertical),
+            (0x0f, Mirroring::Horizontal),
+        ]
+        .iter()
+        {
+            mmc1_write(&mut mapper, 0x8000, ctrl);
+            assert_eq!(mapper.mirroring(), Some(mirroring));
+        }
+    }
+
+    #[test]
+    fn mmc1_prg_ram_can_be_disabled() {
+        let mut mapper = SxRom::new(rom(1, 2, 1));
+        mapper.prg_storeb(0x6000, 0x42);
+        assert_eq!(mapper.prg_loadb(0x6000), 0x42);
+
+        mmc1_write(&mut mapper, 0xe000, 0x10);
+        assert_eq!(mapper.prg_loadb(0x6000), 0);
+        mapper.prg_storeb(0x6000, 0x24);
+
+        mmc1_write(&mut mapper, 0xe000, 0x00);
+        assert_eq!(mapper.prg_loadb(0x6000), 0x42);
+    }
+
+    #[test]
+    fn mmc3_selects_mirroring_and_protects_prg_ram() {
+        let mut mapper = TxRom::new(rom(4, 2, 1), TxBoard::Mmc3);
+        assert_eq!(mapper.mirroring(), None);
+        mapper.prg_storeb(0xa000, 0);
+        assert_eq!(mapper.mirroring(), Some(Mirroring::Vertical));
+        mapper.prg_storeb(0xa000, 1);
+        assert_eq!(mapper.mirroring(), Some(Mirroring::Horizontal));
+
+        mapper.prg_storeb(0xa001, 0x80);
+        mapper.prg_storeb(0x6000, 1);
+        assert_eq!(mapper.prg_loadb(0x6000), 1);
+
+        // Write-protected
+        mapper.prg_storeb(0xa001, 0xc0);
+        mapper.prg_storeb(0x6000, 2);
+        assert_eq!(mapper.prg_loadb(0x6000), 1);
+
+        // Disabled
+        mapper.prg_storeb(0xa001, 0x00);
+        assert_eq!(mapper.prg_loadb(0x6000), 0);
+        mapper.prg_storeb(0xa001, 0x80);
+        assert_eq!(mapper.prg_loadb(0x6000), 1);
+    }
+
+    #[test]
+    fn nrom_has_family_basic_prg_ram() {
+        let mut mapper = Nrom::new(rom(0, 1, 1));
+        mapper.prg_storeb(0x6000, 0x12);
+        mapper.prg_storeb(0x6800, 0x34);
+        assert_eq!(mapper.prg_loadb(0x6000), 0x12);
+        assert_eq!(mapper.prg_loadb(0x6800), 0x34);
+        // 4K, mirrored through $7FFF
+        assert_eq!(mapper.prg_loadb(0x7000), 0x12);
+        assert_eq!(mapper.prg_ram().unwrap()[0x800], 0x34);
+    }
+
+    #[test]
+    fn camerica_switches_prg_and_nametable() {
+        let mut mapper = create_mapper(rom(71, 8, 0)).unwrap();
+        assert_eq!(mapper.mirroring(), None);
+        mapper.prg_storeb(0xc000, 3);
+        assert_eq!(mapper.prg_loadb(0x8000), 6);
+        assert_eq!(mapper.prg_loadb(0xc000), 14);
+        // Bank 11 is bank 3.
+        mapper.prg_storeb(0xc000, 11);
+        assert_eq!(mapper.prg_loadb(0xbfff), 7);
+
+        mapper.prg_storeb(0x9000, 0x10);
+        assert_eq!(mapper.mirroring(), Some(Mirroring::OneScreenUpper));
+        mapper.prg_storeb(0x9000, 0x00);
+        assert_eq!(mapper.mirroring(), Some(Mirroring::OneScreenLower));
+    }
+
+    #[test]
+    fn mapper_34_switches_bnrom_and_nina001_banks() {
+        // BNROM, with 8K of CHR. The byte at $FFFF is 3, so writing 1 there isn't conflicted.
+        let mut mapper = create_mapper(rom(34, 8, 1)).unwrap();
+        mapper.prg_storeb(0xffff, 1);
+        assert_eq!(mapper.prg_loadb(0x8000), 4);
+        assert_eq!(mapper.prg_loadb(0xffff), 7);
+
+        // NINA-001, with 16K of CHR in four 4K banks
+        let mut cart = rom(34, 4, 2);
+        for (i, byte) in cart.chr.iter_mut().enumerate() {
+            *byte = (i / 4096) as u8;
+        }
+        let mut mapper = create_mapper(cart).unwrap();
+        mapper.prg_storeb(0x7ffd, 1);
+        mapper.prg_storeb(0x7ffe, 2);
+        mapper.prg_storeb(0x7fff, 3);
+        assert_eq!(mapper.prg_loadb(0x8000), 4);
+        assert_eq!(mapper.chr_loadb(0x0000), 2);
+        assert_eq!(mapper.chr_loadb(0x1000), 3);
+        mapper.prg_storeb(0x6000, 0x5a);
+        assert_eq!(mapper.prg_loadb(0x6000), 0x5a);
+    }
+
+    #[test]
+    fn action52_selects_chips_banks_and_modes() {
+        // Three 512K chips, and 32K of CHR in four 8K banks
+        let mut cart = rom(228, 96, 4);
+        for (i, byte) in cart.chr.iter_mut().enumerate() {
+            *byte = (i / 8192) as u8;
+        }
+        let mut mapper = create_mapper(cart).unwrap();
+
+        // Chip 3, which is the third in the dump, 16K bank 5, horizontal mirroring
+        mapper.prg_storeb(0x8000 | 0x2000 | (3 << 11) | (5 << 6) | 0x20, 2);
+        assert_eq!(mapper.prg_loadb(0x8000), 128 + 10);
+        assert_eq!(mapper.prg_loadb(0xc000), 128 + 10);
+        assert_eq!(mapper.chr_loadb(0x0000), 2);
+        assert_eq!(mapper.mirroring(), Some(Mirroring::Horizontal));
+
+        // Chip 0 in 32K mode, which ignores the low bit of the bank
+        mapper.prg_storeb(0x8000 | (5 << 6), 0);
+        assert_eq!(mapper.prg_loadb(0x8000), 8);
+        assert_eq!(mapper.prg_loadb(0xc000), 10);
+        assert_eq!(mapper.mirroring(), Some(Mirroring::Vertical));
+
+        // Chip 2 isn't fitted.
+        mapper.prg_storeb(0x8000 | (2 << 11), 0);
+        assert_eq!(mapper.prg_loadb(0x8000), 0);
+
+        // Nibbles of RAM
+        mapper.prg_storeb(0x5ff1, 0xab);
+        assert_eq!(mapper.prg_loadb(0x5ff1), 0x0b);
     }
 }
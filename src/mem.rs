@@ -5,13 +5,15 @@
 use apu::Apu;
 use input::Input;
 use mapper::Mapper;
-use ppu::Ppu;
+use ppu::{Ppu, PpuEvent};
 use util::Save;
 
 use std::cell::RefCell;
 use std::fs::File;
 use std::ops::{Deref, DerefMut};
 use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 //
 // The memory interface
@@ -23,17 +25,107 @@ pub trait Mem {
     fn storeb(&mut self, addr: u16, val: u8);
 
     fn loadw(&mut self, addr: u16) -> u16 {
-        self.loadb(addr) as u16 | (self.loadb(addr + 1) as u16) << 8
+        self.loadb(addr) as u16 | (self.loadb(addr.wrapping_add(1)) as u16) << 8
     }
 
     fn storew(&mut self, addr: u16, val: u16) {
         self.storeb(addr, (val & 0xff) as u8);
-        self.storeb(addr + 1, ((val >> 8) & 0xff) as u8);
+        self.storeb(addr.wrapping_add(1), ((val >> 8) & 0xff) as u8);
+    }
+
+    /// Reads a byte without any of the side effects a load can have, such as acknowledging a
+    /// status flag or advancing a buffer, so that debuggers and the disassembler can look at
+    /// memory without disturbing it. Devices with nothing readable return 0.
+    fn peekb(&self, _addr: u16) -> u8 {
+        0
     }
 
     /// Like loadw, but has wraparound behavior on the zero page for address 0xff.
     fn loadw_zp(&mut self, addr: u8) -> u16 {
-        self.loadb(addr as u16) as u16 | (self.loadb((addr + 1) as u16) as u16) << 8
+        self.loadb(addr as u16) as u16 | (self.loadb(addr.wrapping_add(1) as u16) as u16) << 8
+    }
+
+    /// Runs everything else on the bus up to the end of the given CPU cycle. A cycle-stepped CPU
+    /// calls this once per cycle, before that cycle's memory access. Returns true if an NMI was
+    /// raised; IRQs are on the `IrqLine` instead. Plain memory has nothing to run.
+    fn tick(&mut self, _cy: u64) -> bool {
+        false
+    }
+
+    /// Returns how many cycles devices have stolen from the CPU since this was last called, such
+    /// as for the DMC's sample fetches. The CPU stalls for that long before its next instruction.
+    fn take_stall(&mut self) -> u64 {
+        0
+    }
+}
+
+//
+// The IRQ line
+//
+
+/// The devices that can pull the CPU's IRQ line low.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum IrqSource {
+    /// A scanline or cycle counter on the cartridge
+    Mapper,
+    /// The APU's frame counter
+    FrameCounter,
+    /// The APU's DMC channel, when its sample ends
+    Dmc,
+}
+
+impl IrqSource {
+    fn bit(self) -> usize {
+        1 << self as usize
+    }
+}
+
+/// The CPU's IRQ line, shared by the CPU and everything that can raise an IRQ. Each source
+/// asserts and acknowledges its IRQ independently, and the line is held for as long as any of
+/// them asserts it, so the CPU keeps taking IRQs whenever the I flag is clear until every source
+/// has been acknowledged.
+#[derive(Clone)]
+pub struct IrqLine {
+    sources: Arc<AtomicUsize>,
+}
+
+impl IrqLine {
+    /// Creates a line that nothing is asserting.
+    pub fn new() -> IrqLine {
+        IrqLine {
+            sources: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn assert(&self, source: IrqSource) {
+        self.sources.fetch_or(source.bit(), Ordering::Relaxed);
+    }
+
+    pub fn acknowledge(&self, source: IrqSource) {
+        self.sources.fetch_and(!source.bit(), Ordering::Relaxed);
+    }
+
+    /// Whether any source is asserting the line.
+    pub fn asserted(&self) -> bool {
+        self.sources.load(Ordering::Relaxed) != 0
+    }
+
+    pub fn asserted_by(&self, source: IrqSource) -> bool {
+        (self.sources.load(Ordering::Relaxed) & source.bit()) != 0
+    }
+}
+
+/// Saves the sources asserting the line. Loading sets them on the shared line, so every device
+/// connected to it sees the restored state.
+impl Save for IrqLine {
+    fn save(&mut self, fd: &mut File) {
+        let mut sources = self.sources.load(Ordering::Relaxed) as u8;
+        sources.save(fd);
+    }
+    fn load(&mut self, fd: &mut File) {
+        let mut sources = 0u8;
+        sources.load(fd);
+        self.sources.store(sources as usize, Ordering::Relaxed);
     }
 }
 
@@ -66,6 +158,9 @@ impl Mem for Ram {
     fn storeb(&mut self, addr: u16, val: u8) {
         self[addr as usize & 0x7ff] = val
     }
+    fn peekb(&self, addr: u16) -> u8 {
+        self[addr as usize & 0x7ff]
+    }
 }
 
 impl Save for Ram {
@@ -104,6 +199,14 @@ impl MemMap {
             apu: apu,
         }
     }
+
+    /// Brings the mapper, PPU and APU from CPU cycle `from` up to cycle `to`. What the PPU did
+    /// is left in its event queue.
+    pub fn catch_up(&mut self, from: u64, to: u64) {
+        self.mapper.borrow_mut().step(to - from);
+        self.ppu.step(to);
+        self.apu.step(to);
+    }
 }
 
 impl Mem for MemMap {
@@ -116,15 +219,20 @@ impl Mem for MemMap {
             self.input.loadb(addr)
         } else if addr <= 0x4018 {
             self.apu.loadb(addr)
-        } else if addr < 0x6000 {
-            0 // FIXME: I think some mappers use regs in this area?
+        } else if addr < 0x4020 {
+            0 // Disabled APU and I/O test registers.
         } else {
+            // Cartridge space. Some mappers have registers below $6000 as well.
             let mut mapper = self.mapper.borrow_mut();
             mapper.prg_loadb(addr)
         }
     }
     fn storeb(&mut self, addr: u16, val: u8) {
         if addr < 0x2000 {
+            if self.input.has_rumble_triggers() {
+                let old_val = self.ram.loadb(addr);
+                self.input.check_rumble_triggers(addr, old_val, val);
+            }
             self.ram.storeb(addr, val)
         } else if addr < 0x4000 {
             self.ppu.storeb(addr, val)
@@ -132,13 +240,107 @@ impl Mem for MemMap {
             self.input.storeb(addr, val)
         } else if addr <= 0x4018 {
             self.apu.storeb(addr, val)
-        } else if addr < 0x6000 {
-            // Nothing. FIXME: I think some mappers use regs in this area?
+        } else if addr < 0x4020 {
+            // Nothing.
         } else {
             let mut mapper = self.mapper.borrow_mut();
             mapper.prg_storeb(addr, val)
         }
     }
+    fn peekb(&self, addr: u16) -> u8 {
+        if addr < 0x2000 {
+            self.ram.peekb(addr)
+        } else if addr < 0x4000 {
+            self.ppu.peekb(addr)
+        } else if addr == 0x4016 {
+            self.input.peekb(addr)
+        } else if addr <= 0x4018 {
+            self.apu.peekb(addr)
+        } else if addr < 0x4020 {
+            0
+        } else {
+            let mut mapper = self.mapper.borrow_mut();
+            mapper.prg_peekb(addr)
+        }
+    }
+
+    // The CPU takes the NMI itself, on this cycle; other events stay queued for the frontend.
+    fn tick(&mut self, cy: u64) -> bool {
+        self.catch_up(cy - 1, cy);
+        self.ppu.take_event(PpuEvent::VblankNmi)
+    }
+
+    fn take_stall(&mut self) -> u64 {
+        self.apu.take_stall()
+    }
 }
 
-save_struct!(MemMap { ram, ppu, apu });
+impl Save for MemMap {
+    fn save(&mut self, fd: &mut File) {
+        self.ram.save(fd);
+        self.ppu.save(fd);
+        self.apu.save(fd);
+        self.mapper.borrow_mut().save(fd);
+    }
+    fn load(&mut self, fd: &mut File) {
+        self.ram.load(fd);
+        self.ppu.load(fd);
+        self.apu.load(fd);
+        self.mapper.borrow_mut().load(fd);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::{Seek, SeekFrom};
+
+    #[test]
+    fn ram_is_mirrored_every_2k() {
+        let mut ram = Ram { val: [0; 0x800] };
+        ram.storeb(0x1803, 0x42);
+        assert_eq!(ram.loadb(0x0003), 0x42);
+        assert_eq!(ram.peekb(0x0803), 0x42);
+    }
+
+    #[test]
+    fn irq_line_is_held_until_every_source_acknowledges() {
+        let line = IrqLine::new();
+        let apu = line.clone();
+        line.assert(IrqSource::Mapper);
+        apu.assert(IrqSource::FrameCounter);
+        assert!(line.asserted_by(IrqSource::FrameCounter));
+        assert!(!line.asserted_by(IrqSource::Dmc));
+
+        line.acknowledge(IrqSource::Mapper);
+        assert!(line.asserted());
+        apu.acknowledge(IrqSource::FrameCounter);
+        assert!(!line.asserted());
+    }
+
+    #[test]
+    fn loading_an_irq_line_restores_every_clone() {
+        let path = env::temp_dir().join("sprocketnes-mem-irq-test");
+        let mut fd = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        let mut saved = IrqLine::new();
+        saved.assert(IrqSource::Dmc);
+        saved.save(&mut fd);
+
+        let mut line = IrqLine::new();
+        let apu = line.clone();
+        fd.seek(SeekFrom::Start(0)).unwrap();
+        line.load(&mut fd);
+        let _ = fs::remove_file(&path);
+        assert!(apu.asserted_by(IrqSource::Dmc));
+        assert!(!apu.asserted_by(IrqSource::Mapper));
+    }
+}
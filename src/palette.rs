@@ -0,0 +1,84 @@
+//! Custom palettes, in the `.pal` format FCEUX and Nestopia read and write: the RGB triples of
+//! the 64 colors, optionally followed by the same colors under each of the other seven
+//! combinations of the color emphasis bits.
+
+//
+// Author: Patrick Walton
+//
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// The number of colors the PPU can draw, before color emphasis.
+pub const COLORS: usize = 64;
+/// The number of combinations of the three color emphasis bits.
+pub const EMPHASES: usize = 8;
+
+#[derive(Debug)]
+pub enum PaletteError {
+    /// IO error while reading the palette
+    Io(io::Error),
+    /// The file holds neither 64 nor 512 colors
+    BadSize(usize),
+}
+
+impl From<io::Error> for PaletteError {
+    fn from(err: io::Error) -> Self {
+        PaletteError::Io(err)
+    }
+}
+
+impl fmt::Display for PaletteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            PaletteError::Io(ref err) => write!(f, "{}", err),
+            PaletteError::BadSize(size) => write!(
+                f,
+                "expected {} or {} bytes, found {}",
+                COLORS * 3,
+                COLORS * EMPHASES * 3,
+                size
+            ),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Palette {
+    /// RGB triples: 64 of them, or 512 if the file covers every emphasis combination.
+    rgb: Vec<u8>,
+}
+
+impl Palette {
+    /// Reads a palette from a `.pal` file.
+    pub fn load(path: &Path) -> Result<Palette, PaletteError> {
+        let mut bytes = vec![];
+        File::open(path)?.read_to_end(&mut bytes)?;
+        Palette::from_bytes(&bytes)
+    }
+
+    /// Takes a palette from the contents of a `.pal` file: 192 bytes, or 1536 with emphasis.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Palette, PaletteError> {
+        if bytes.len() != COLORS * 3 && bytes.len() != COLORS * EMPHASES * 3 {
+            return Err(PaletteError::BadSize(bytes.len()));
+        }
+        Ok(Palette {
+            rgb: bytes.to_vec(),
+        })
+    }
+
+    /// Returns true if the palette gives the colors under every emphasis combination, rather
+    /// than leaving the PPU to dim the 64 base colors itself.
+    pub fn has_emphasis(&self) -> bool {
+        self.rgb.len() == COLORS * EMPHASES * 3
+    }
+
+    /// Returns the red, green and blue of a color under the emphasis bits of PPUMASK, which
+    /// must be zero if the palette has no emphasis colors.
+    pub fn rgb(&self, emphasis: usize, index: usize) -> (u8, u8, u8) {
+        let offset = (emphasis * COLORS + index) * 3;
+        (self.rgb[offset], self.rgb[offset + 1], self.rgb[offset + 2])
+    }
+}
@@ -0,0 +1,387 @@
+//! Soft-patching: applies an IPS or BPS patch to a ROM in memory, leaving the file on disk alone.
+//! Both formats patch the whole iNES file, header included.
+
+//
+// Author: Patrick Walton
+//
+
+use rom::{Rom, RomError};
+use util;
+
+use std::cmp;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const BPS_MAGIC: &[u8] = b"BPS1";
+
+/// The patch file extensions that are looked for next to a ROM, in order of preference.
+const PATCH_EXTENSIONS: [&str; 2] = ["bps", "ips"];
+
+#[derive(Debug)]
+pub enum PatchError {
+    /// IO error while reading the patch
+    Io(io::Error),
+    /// Not an IPS or BPS patch
+    BadMagic,
+    /// The patch ends in the middle of a record
+    Truncated,
+    /// The patch is for a different ROM
+    WrongSource,
+    /// The patch, or the ROM it produced, doesn't match its checksum
+    BadChecksum,
+    /// A number in a BPS patch is too large to be an offset or a length
+    Overflow,
+    /// The patched ROM couldn't be loaded
+    Rom(RomError),
+}
+
+impl From<io::Error> for PatchError {
+    fn from(err: io::Error) -> Self {
+        PatchError::Io(err)
+    }
+}
+
+impl From<RomError> for PatchError {
+    fn from(err: RomError) -> Self {
+        PatchError::Rom(err)
+    }
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            PatchError::Io(ref err) => write!(f, "{}", err),
+            PatchError::BadMagic => write!(f, "not an IPS or BPS patch"),
+            PatchError::Truncated => write!(f, "the patch is truncated"),
+            PatchError::WrongSource => write!(f, "the patch is for a different ROM"),
+            PatchError::BadChecksum => write!(f, "checksum mismatch"),
+            PatchError::Overflow => write!(f, "a number in the patch is out of range"),
+            PatchError::Rom(ref err) => write!(f, "the patched ROM is invalid: {}", err),
+        }
+    }
+}
+
+/// Returns the patch sitting next to a ROM with the same name (`game.bps` or `game.ips` for
+/// `game.nes`), if there is one.
+pub fn find_patch(rom_path: &Path) -> Option<PathBuf> {
+    PATCH_EXTENSIONS
+        .iter()
+        .map(|extension| rom_path.with_extension(extension))
+        .find(|path| path.exists())
+}
+
+/// Applies the patch in the given file to a ROM.
+pub fn load(rom: &Rom, path: &Path) -> Result<Rom, PatchError> {
+    let mut patch = vec![];
+    File::open(path)?.read_to_end(&mut patch)?;
+    apply(rom, &patch)
+}
+
+/// Applies an IPS or BPS patch to a ROM, returning the patched ROM.
+pub fn apply(rom: &Rom, patch: &[u8]) -> Result<Rom, PatchError> {
+    let image = rom.to_ines();
+    let patched = if patch.starts_with(IPS_MAGIC) {
+        apply_ips(image, &patch[IPS_MAGIC.len()..])?
+    } else if patch.starts_with(BPS_MAGIC) {
+        apply_bps(&image, patch)?
+    } else {
+        return Err(PatchError::BadMagic);
+    };
+    Ok(Rom::from_bytes(&patched)?)
+}
+
+/// Splits `len` bytes off the front of `data`.
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8], PatchError> {
+    if data.len() < len {
+        return Err(PatchError::Truncated);
+    }
+    let (front, rest) = data.split_at(len);
+    *data = rest;
+    Ok(front)
+}
+
+fn read_be(data: &mut &[u8], len: usize) -> Result<usize, PatchError> {
+    let bytes = take(data, len)?;
+    Ok(bytes
+        .iter()
+        .fold(0, |value, &byte| (value << 8) | byte as usize))
+}
+
+//
+// IPS
+//
+// A list of records, each an offset and either some bytes to write there or a byte to repeat.
+// See http://old.smwiki.net/wiki/IPS_file_format
+//
+
+fn apply_ips(mut image: Vec<u8>, mut patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    loop {
+        if patch.starts_with(IPS_EOF) {
+            patch = &patch[IPS_EOF.len()..];
+            break;
+        }
+        let offset = read_be(&mut patch, 3)?;
+        let (len, byte) = match read_be(&mut patch, 2)? {
+            0 => (read_be(&mut patch, 2)?, Some(take(&mut patch, 1)?[0])),
+            len => (len, None),
+        };
+        if image.len() < offset + len {
+            image.resize(offset + len, 0);
+        }
+        match byte {
+            Some(byte) => {
+                for dest in image[offset..offset + len].iter_mut() {
+                    *dest = byte;
+                }
+            }
+            None => image[offset..offset + len].copy_from_slice(take(&mut patch, len)?),
+        }
+    }
+
+    // An extension: the file may be truncated to a length given after the EOF marker.
+    if patch.len() >= 3 {
+        let len = read_be(&mut patch, 3)?;
+        image.truncate(len);
+    }
+    Ok(image)
+}
+
+//
+// BPS
+//
+// Builds the target from a series of copies from the source, the patch or the target itself,
+// with CRC32s of all three to catch mismatches. See
+// https://www.romhacking.net/documents/746/
+//
+
+fn read_bps_number(data: &mut &[u8]) -> Result<usize, PatchError> {
+    let mut value: usize = 0;
+    let mut shift: usize = 1;
+    loop {
+        let byte = take(data, 1)?[0] as usize;
+        value = match (byte & 0x7f)
+            .checked_mul(shift)
+            .and_then(|digit| value.checked_add(digit))
+        {
+            Some(value) => value,
+            None => return Err(PatchError::Overflow),
+        };
+        if (byte & 0x80) != 0 {
+            return Ok(value);
+        }
+        shift = match shift.checked_mul(0x80) {
+            Some(shift) => shift,
+            None => return Err(PatchError::Overflow),
+        };
+        value = match value.checked_add(shift) {
+            Some(value) => value,
+            None => return Err(PatchError::Overflow),
+        };
+    }
+}
+
+/// Moves a relative offset by a signed delta encoded with the sign in the low bit.
+fn bps_seek(offset: usize, delta: usize, len: usize) -> Result<usize, PatchError> {
+    let offset = if (delta & 1) == 0 {
+        offset.checked_add(delta >> 1)
+    } else {
+        offset.checked_sub(delta >> 1)
+    };
+    let offset = match offset {
+        Some(offset) => offset,
+        None => return Err(PatchError::Truncated),
+    };
+    if offset > len {
+        return Err(PatchError::Truncated);
+    }
+    Ok(offset)
+}
+
+fn apply_bps(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.len() < BPS_MAGIC.len() + 12 {
+        return Err(PatchError::Truncated);
+    }
+    let (body, footer) = patch.split_at(patch.len() - 12);
+    let checksum = |bytes: &[u8]| {
+        bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+    };
+    if util::crc32(&patch[..patch.len() - 4]) != checksum(&footer[8..12]) {
+        return Err(PatchError::BadChecksum);
+    }
+    if util::crc32(source) != checksum(&footer[0..4]) {
+        return Err(PatchError::WrongSource);
+    }
+
+    let mut actions = &body[BPS_MAGIC.len()..];
+    let source_len = read_bps_number(&mut actions)?;
+    let target_len = read_bps_number(&mut actions)?;
+    let metadata_len = read_bps_number(&mut actions)?;
+    take(&mut actions, metadata_len)?;
+    if source_len != source.len() {
+        return Err(PatchError::WrongSource);
+    }
+
+    // The length comes from the patch, so don't trust it with the allocation.
+    let mut target = Vec::with_capacity(cmp::min(target_len, source.len() + patch.len()));
+    let mut source_offset = 0;
+    let mut target_offset = 0;
+    while !actions.is_empty() {
+        let action = read_bps_number(&mut actions)?;
+        let len = (action >> 2) + 1;
+        // Writing past the target's length could only end in a checksum mismatch.
+        if len > target_len - target.len() {
+            return Err(PatchError::BadChecksum);
+        }
+        match action & 3 {
+            // SourceRead: copy from the same offset in the source.
+            0 => {
+                let start = target.len();
+                match source.get(start..start + len) {
+                    Some(bytes) => target.extend_from_slice(bytes),
+                    None => return Err(PatchError::Truncated),
+                }
+            }
+            // TargetRead: copy from the patch.
+            1 => target.extend_from_slice(take(&mut actions, len)?),
+            // SourceCopy: copy from anywhere in the source.
+            2 => {
+                let delta = read_bps_number(&mut actions)?;
+                source_offset = bps_seek(source_offset, delta, source.len())?;
+                match source.get(source_offset..source_offset + len) {
+                    Some(bytes) => target.extend_from_slice(bytes),
+                    None => return Err(PatchError::Truncated),
+                }
+                source_offset += len;
+            }
+            // TargetCopy: copy from earlier in the target, one byte at a time, because the copy
+            // may overlap what it's writing.
+            _ => {
+                let delta = read_bps_number(&mut actions)?;
+                target_offset = bps_seek(target_offset, delta, target.len())?;
+                for _ in 0..len {
+                    match target.get(target_offset).cloned() {
+                        Some(byte) => target.push(byte),
+                        None => return Err(PatchError::Truncated),
+                    }
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+
+    if target.len() != target_len || util::crc32(&target) != checksum(&footer[4..8]) {
+        return Err(PatchError::BadChecksum);
+    }
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An NROM image with 16K of PRG-ROM and 8K of CHR-ROM, all zeros.
+    fn rom() -> Rom {
+        let mut image = b"NES\x1a\x01\x01".to_vec();
+        image.resize(16 + 0x4000 + 0x2000, 0);
+        Rom::from_bytes(&image).unwrap()
+    }
+
+    fn bps_number(mut value: usize) -> Vec<u8> {
+        let mut bytes = vec![];
+        loop {
+            let digit = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                bytes.push(digit | 0x80);
+                return bytes;
+            }
+            bytes.push(digit);
+            value -= 1;
+        }
+    }
+
+    fn push_crc(bytes: &mut Vec<u8>, crc: u32) {
+        for shift in [0, 8, 16, 24].iter() {
+            bytes.push((crc >> shift) as u8);
+        }
+    }
+
+    // A BPS patch that writes `data` over the start of the source and keeps the rest.
+    fn bps(source: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut target = source.to_vec();
+        target[..data.len()].copy_from_slice(data);
+        let mut patch = BPS_MAGIC.to_vec();
+        patch.extend(bps_number(source.len()));
+        patch.extend(bps_number(target.len()));
+        patch.extend(bps_number(0));
+        patch.extend(bps_number(((data.len() - 1) << 2) | 1));
+        patch.extend_from_slice(data);
+        // The rest is a SourceRead, which copies from the same offset as the target's length.
+        patch.extend(bps_number((source.len() - data.len() - 1) << 2));
+        push_crc(&mut patch, util::crc32(source));
+        push_crc(&mut patch, util::crc32(&target));
+        let crc = util::crc32(&patch);
+        push_crc(&mut patch, crc);
+        patch
+    }
+
+    #[test]
+    fn ips_applies_records() {
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x10, 0x00, 0x02, 0xaa, 0xbb]);
+        patch.extend_from_slice(&[0x00, 0x00, 0x20, 0x00, 0x00, 0x00, 0x04, 0xcc]);
+        patch.extend_from_slice(b"EOF");
+        let patched = apply(&rom(), &patch).unwrap();
+        assert_eq!(&patched.prg[..2], &[0xaa, 0xbb]);
+        assert_eq!(&patched.prg[0x0f..0x15], &[0, 0xcc, 0xcc, 0xcc, 0xcc, 0]);
+    }
+
+    #[test]
+    fn ips_rejects_truncated_records() {
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x10, 0x00, 0x04, 0xaa]);
+        match apply(&rom(), &patch) {
+            Err(PatchError::Truncated) => {}
+            _ => panic!("truncated IPS patch was applied"),
+        }
+    }
+
+    #[test]
+    fn bps_applies_actions() {
+        let rom = rom();
+        let patch = bps(&rom.to_ines(), b"NES\x1a\x01\x01\x01");
+        let patched = apply(&rom, &patch).unwrap();
+        assert_eq!(patched.header.flags_6, 0x01);
+        assert!(patched.prg == rom.prg);
+    }
+
+    #[test]
+    fn bps_rejects_bad_checksum() {
+        let rom = rom();
+        let mut patch = bps(&rom.to_ines(), b"NES\x1a\x01");
+        let last = patch.len() - 1;
+        patch[last] ^= 0xff;
+        match apply(&rom, &patch) {
+            Err(PatchError::BadChecksum) => {}
+            _ => panic!("BPS patch with a bad checksum was applied"),
+        }
+    }
+
+    #[test]
+    fn bps_rejects_truncated_and_overflowing_numbers() {
+        let mut data: &[u8] = &[0x00, 0x00];
+        match read_bps_number(&mut data) {
+            Err(PatchError::Truncated) => {}
+            _ => panic!("read a number past the end of the patch"),
+        }
+        let mut data: &[u8] = &[0x7f; 16];
+        match read_bps_number(&mut data) {
+            Err(PatchError::Overflow) => {}
+            _ => panic!("read a number too large for usize"),
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//! Screenshots, as 24-bit RGB `.png` files.
+
+//
+// Author: Patrick Walton
+//
+
+use ppu::PixelFormat;
+use util;
+
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+// Writes a chunk: its length, type and data, and a CRC of the type and data.
+fn write_chunk(out: &mut Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    let mut checked = kind.to_vec();
+    checked.extend_from_slice(data);
+    out.write_all(&checked)?;
+    out.write_all(&util::crc32(&checked).to_be_bytes())
+}
+
+/// Writes a `width` by `height` picture in the given format as a PNG.
+pub fn write(
+    out: &mut Write,
+    pixels: &[u8],
+    width: usize,
+    height: usize,
+    format: PixelFormat,
+) -> io::Result<()> {
+    out.write_all(&SIGNATURE)?;
+
+    let mut header = vec![];
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // 8 bits per channel, RGB, then the default compression, filtering and no interlacing.
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+    write_chunk(out, b"IHDR", &header)?;
+
+    // Each row starts with its filter type, which is always none.
+    let bytes_per_pixel = format.bytes_per_pixel();
+    let mut rows = Vec::with_capacity((width * 3 + 1) * height);
+    for row in pixels.chunks(width * bytes_per_pixel).take(height) {
+        rows.push(0);
+        for pixel in row.chunks(bytes_per_pixel) {
+            match format {
+                PixelFormat::Bgr24 => rows.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]),
+                PixelFormat::Rgba32 => rows.extend_from_slice(&pixel[..3]),
+            }
+        }
+    }
+    let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+    encoder.write_all(&rows)?;
+    write_chunk(out, b"IDAT", &encoder.finish()?)?;
+
+    write_chunk(out, b"IEND", &[])
+}
+
+/// Writes a PNG to a new file, replacing any that's there.
+pub fn save(
+    path: &Path,
+    pixels: &[u8],
+    width: usize,
+    height: usize,
+    format: PixelFormat,
+) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    write(&mut out, pixels, width, height, format)?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_is_signed_and_checksummed() {
+        let mut out = vec![];
+        let pixels = [0x00, 0x00, 0xff, 0xff, 0x00, 0x00];
+        write(&mut out, &pixels, 2, 1, PixelFormat::Bgr24).unwrap();
+        assert_eq!(&out[..8], &SIGNATURE);
+        // The IHDR chunk: its length, type, width, height, depth, color type, compression,
+        // filter and interlacing, and the CRC of all but the length.
+        assert_eq!(&out[8..12], &[0, 0, 0, 13]);
+        assert_eq!(&out[12..16], b"IHDR");
+        assert_eq!(&out[16..29], &[0, 0, 0, 2, 0, 0, 0, 1, 8, 2, 0, 0, 0]);
+        assert_eq!(&out[29..33], &[0x7b, 0x40, 0xe8, 0xdd]);
+        assert_eq!(&out[out.len() - 12..], b"\0\0\0\0IEND\xae\x42\x60\x82");
+    }
+}
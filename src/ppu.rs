@@ -2,20 +2,134 @@
 // Author: Patrick Walton
 //
 
-use mapper::{Mapper, MapperResult};
-use mem::Mem;
+use fceux::{self, FceuxSection};
+use mapper::{Mapper, Mirroring};
+use mem::{IrqLine, IrqSource, Mem};
+use palette::Palette;
+use rom::Region;
 use util::Save;
 
 use std::cell::RefCell;
+use std::cmp;
+use std::collections::VecDeque;
 use std::fs::File;
 use std::ops::{Deref, DerefMut};
 use std::rc::Rc;
 
 pub const SCREEN_WIDTH: usize = 256;
 pub const SCREEN_HEIGHT: usize = 240;
-pub const CYCLES_PER_SCANLINE: u64 = 114; // 29781 cycles per frame / 261 scanlines
-pub const VBLANK_SCANLINE: usize = 241;
-pub const LAST_SCANLINE: usize = 261;
+/// How many PPU cycles make up one scanline.
+const PPU_CYCLES_PER_SCANLINE: u64 = 341;
+/// How long a bit of the I/O latch holds a 1 once nothing drives it, in dots: about 600 ms.
+const OPEN_BUS_DECAY_DOTS: u64 = 3_200_000;
+/// The number of sprites the hardware can display on a single scanline.
+pub const SPRITES_PER_SCANLINE: usize = 8;
+
+/// The shape of a frame, and the PPU's clock, on one kind of console.
+struct Timing {
+    /// The scanline vertical blank starts on.
+    vblank_scanline: u16,
+    /// The number of scanlines in a frame. The last is the pre-render scanline, on which the PPU
+    /// fetches as if rendering.
+    scanlines: u16,
+    /// The PPU runs `dots` cycles, or dots, for every `cpu_cycles` CPU cycles.
+    dots: u64,
+    cpu_cycles: u64,
+    /// Whether the pre-render scanline of every other frame is a dot short with rendering on.
+    skips_dot: bool,
+}
+
+static NTSC_TIMING: Timing = Timing {
+    vblank_scanline: 241,
+    scanlines: 262,
+    dots: 3,
+    cpu_cycles: 1,
+    skips_dot: true,
+};
+
+/// PAL frames have 50 more scanlines of vertical blank, and the CPU is divided from a faster
+/// master clock, so that the PPU runs 3.2 dots per CPU cycle.
+static PAL_TIMING: Timing = Timing {
+    vblank_scanline: 241,
+    scanlines: 312,
+    dots: 16,
+    cpu_cycles: 5,
+    skips_dot: false,
+};
+
+/// The Dendy has PAL's 312 scanlines, but NTSC's clock ratio, and puts the extra scanlines before
+/// vertical blank so that NTSC games get their usual vblank time.
+static DENDY_TIMING: Timing = Timing {
+    vblank_scanline: 291,
+    scanlines: 312,
+    dots: 3,
+    cpu_cycles: 1,
+    skips_dot: false,
+};
+
+impl Timing {
+    fn for_region(region: Region) -> &'static Timing {
+        match region.timing() {
+            Region::Pal => &PAL_TIMING,
+            Region::Dendy => &DENDY_TIMING,
+            _ => &NTSC_TIMING,
+        }
+    }
+}
+
+/// How the pixels of the PPU's screen are laid out in memory.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PixelFormat {
+    /// Three bytes per pixel: blue, green and red
+    Bgr24,
+    /// Four bytes per pixel: red, green, blue and alpha, which is always opaque
+    Rgba32,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Bgr24 => 3,
+            PixelFormat::Rgba32 => 4,
+        }
+    }
+}
+
+/// Called at the start of each scanline with its number, from 0 to the pre-render scanline, and
+/// the PPU's memories.
+pub type ScanlineCallback = Box<FnMut(u16, &mut Vram, &mut Oam)>;
+/// Called once a frame, as vertical blank starts, with the PPU's memories.
+pub type VblankCallback = Box<FnMut(&mut Vram, &mut Oam)>;
+
+/// A picture of the PPU's memory for the debug viewers, drawn in place of the screen.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PpuView {
+    /// The four nametables at half size, with the area the scroll registers show outlined
+    Nametables,
+    /// Both pattern tables in one of the eight palettes, which is outlined among all 32 entries
+    PatternTables(u8),
+    /// The 64 sprites in OAM, in order, eight to a row
+    Sprites,
+}
+
+impl PpuView {
+    /// The view after this one, or `None` after the last.
+    pub fn next(self) -> Option<PpuView> {
+        match self {
+            PpuView::Nametables => Some(PpuView::PatternTables(0)),
+            PpuView::PatternTables(_) => Some(PpuView::Sprites),
+            PpuView::Sprites => None,
+        }
+    }
+
+    pub fn name(self) -> String {
+        match self {
+            PpuView::Nametables => "Nametables".to_string(),
+            PpuView::PatternTables(palette) => format!("Pattern tables, palette {}", palette),
+            PpuView::Sprites => "Sprites".to_string(),
+        }
+    }
+}
 
 static PALETTE: [u8; 192] = [
     124, 124, 124, 0, 0, 252, 0, 0, 188, 68, 40, 188, 148, 0, 132, 168, 0, 32, 168, 16, 0, 136, 20,
@@ -29,6 +143,61 @@ static PALETTE: [u8; 192] = [
     184, 248, 216, 0, 252, 252, 248, 216, 248, 0, 0, 0, 0, 0, 0,
 ];
 
+/// How much each color emphasis bit dims the two channels it doesn't emphasize.
+const EMPHASIS_ATTENUATION: f32 = 0.816;
+
+/// Builds the palette under each of the eight combinations of the color emphasis bits in
+/// PPUMASK, indexed by those bits, from a custom palette or the built-in one. Unless the custom
+/// palette gives them itself, the emphasized colors are the base colors dimmed; on PAL and Dendy
+/// consoles the red and green bits are swapped.
+fn emphasized_palettes(region: Region, custom: Option<&Palette>) -> [[Rgb; 64]; 8] {
+    let mut palettes = [[Rgb { r: 0, g: 0, b: 0 }; 64]; 8];
+    for (emphasis, palette) in palettes.iter_mut().enumerate() {
+        if let Some(custom) = custom.filter(|custom| custom.has_emphasis()) {
+            for (index, color) in palette.iter_mut().enumerate() {
+                let (r, g, b) = custom.rgb(emphasis, index);
+                *color = Rgb { r: r, g: g, b: b };
+            }
+            continue;
+        }
+
+        let (red, green) = match region {
+            Region::Pal | Region::Dendy => (emphasis & 2 != 0, emphasis & 1 != 0),
+            _ => (emphasis & 1 != 0, emphasis & 2 != 0),
+        };
+        let blue = emphasis & 4 != 0;
+
+        // Each channel is dimmed once for every emphasis bit that's set for another channel.
+        let emphasized = [red, green, blue];
+        let mut scale = [1.0f32; 3];
+        for (channel, factor) in scale.iter_mut().enumerate() {
+            for (other, &set) in emphasized.iter().enumerate() {
+                if set && other != channel {
+                    *factor *= EMPHASIS_ATTENUATION;
+                }
+            }
+        }
+
+        for (index, color) in palette.iter_mut().enumerate() {
+            let base = match custom {
+                Some(custom) => custom.rgb(0, index),
+                None => (
+                    PALETTE[index * 3],
+                    PALETTE[index * 3 + 1],
+                    PALETTE[index * 3 + 2],
+                ),
+            };
+            let channel = |value: u8, offset: usize| (value as f32 * scale[offset]) as u8;
+            *color = Rgb {
+                r: channel(base.0, 0),
+                g: channel(base.1, 1),
+                b: channel(base.2, 2),
+            };
+        }
+    }
+    palettes
+}
+
 //
 // Registers
 //
@@ -39,8 +208,17 @@ struct Regs {
     mask: PpuMask,     // PPUMASK: 0x2001
     status: PpuStatus, // PPUSTATUS: 0x2002
     oam_addr: u8,      // OAMADDR: 0x2003
-    scroll: PpuScroll, // PPUSCROLL: 0x2005
-    addr: PpuAddr,     // PPUADDR: 0x2006
+
+    // The internal registers behind PPUSCROLL (0x2005) and PPUADDR (0x2006), named as on the
+    // NESdev wiki. See "Scrolling" below.
+    /// The current VRAM address, which is also the scroll position while rendering.
+    v: u16,
+    /// The temporary VRAM address, which is copied into `v` to start a frame or a scanline.
+    t: u16,
+    /// The fine X scroll, in pixels.
+    x: u8,
+    /// The write toggle shared by PPUSCROLL and PPUADDR, set between the two writes of a pair.
+    w: bool,
 }
 
 save_struct!(Regs {
@@ -48,8 +226,10 @@ save_struct!(Regs {
     mask,
     status,
     oam_addr,
-    scroll,
-    addr
+    v,
+    t,
+    x,
+    w
 });
 
 //
@@ -81,19 +261,8 @@ impl DerefMut for PpuCtrl {
 }
 
 impl PpuCtrl {
-    fn x_scroll_offset(self) -> u16 {
-        if (*self & 0x01) == 0 {
-            0
-        } else {
-            256
-        }
-    }
-    fn y_scroll_offset(self) -> u16 {
-        if (*self & 0x02) == 0 {
-            0
-        } else {
-            240
-        }
+    fn nametable_select(self) -> u16 {
+        (*self & 0x03) as u16
     }
     fn vram_addr_increment(self) -> u16 {
         if (*self & 0x04) == 0 {
@@ -152,9 +321,15 @@ impl DerefMut for PpuMask {
 }
 
 impl PpuMask {
-    // 0x01: grayscale
-    // 0x02: show background on left
-    // 0x04: show sprites on left
+    fn grayscale(self) -> bool {
+        (*self & 0x01) != 0
+    }
+    fn show_background_left(self) -> bool {
+        (*self & 0x02) != 0
+    }
+    fn show_sprites_left(self) -> bool {
+        (*self & 0x04) != 0
+    }
     fn show_background(self) -> bool {
         (*self & 0x08) != 0
     }
@@ -164,6 +339,9 @@ impl PpuMask {
     // 0x20: intensify reds
     // 0x40: intensify greens
     // 0x80: intensify blues
+    fn emphasis(self) -> usize {
+        (*self >> 5) as usize
+    }
 }
 
 //
@@ -221,61 +399,79 @@ impl PpuStatus {
 }
 
 //
-// PPUSCROLL: 0x2005
-//
-
-#[derive(Copy, Clone)]
-struct PpuScroll {
-    x: u8,
-    y: u8,
-    next: PpuScrollDir,
-}
-
-save_struct!(PpuScroll { x, y, next });
-
-#[derive(Copy, Clone)]
-enum PpuScrollDir {
-    XDir,
-    YDir,
-}
-
-save_enum!(PpuScrollDir { XDir, YDir });
-
+// Scrolling
 //
-// PPUADDR: 0x2006
+// `v` and `t` are laid out as 0yyy NNYY YYYX XXXX: coarse X (the tile column) in the low five
+// bits, coarse Y (the tile row) in the next five, the nametable in the next two and fine Y (the
+// pixel row within the tile) in the top three. PPUCTRL, PPUSCROLL and PPUADDR all write into `t`,
+// and PPUADDR's second write copies `t` into `v`. While rendering, the PPU copies the horizontal
+// bits of `t` into `v` at the end of every scanline and the vertical bits during the pre-render
+// scanline, so scroll changes made mid-frame take effect on the next line.
 //
 
-#[derive(Copy, Clone)]
-struct PpuAddr {
-    val: u16,
-    next: PpuAddrByte,
-}
-
-save_struct!(PpuAddr { val, next });
-
-#[derive(Copy, Clone)]
-enum PpuAddrByte {
-    Hi,
-    Lo,
-}
-
-save_enum!(PpuAddrByte { Hi, Lo });
+const COARSE_X: u16 = 0x001f;
+const COARSE_Y: u16 = 0x03e0;
+const NAMETABLE_X: u16 = 0x0400;
+const NAMETABLE_Y: u16 = 0x0800;
+const FINE_Y: u16 = 0x7000;
 
 // PPU VRAM. This implements the same Mem trait that the CPU memory does.
 
 pub struct Vram {
     pub mapper: Rc<RefCell<Box<Mapper + Send>>>,
-    pub nametables: [u8; 0x800], // 2 nametables, 0x400 each. FIXME: Not correct for all mappers.
+    /// 2 nametables, 0x400 each, or 4 on four-screen boards.
+    pub nametables: Vec<u8>,
     pub palette: [u8; 0x20],
+    /// The mirroring wired on the board, from the ROM header, or four-screen if the mapper has
+    /// its own nametable RAM
+    mirroring: Mirroring,
 }
 
 impl Vram {
-    pub fn new(mapper: Rc<RefCell<Box<Mapper + Send>>>) -> Vram {
+    pub fn new(mapper: Rc<RefCell<Box<Mapper + Send>>>, mirroring: Mirroring) -> Vram {
+        // The extra 2K is on the cartridge, so either the header or the mapper can ask for it.
+        let mirroring = match mapper.borrow().mirroring() {
+            Some(Mirroring::FourScreen) => Mirroring::FourScreen,
+            _ => mirroring,
+        };
+        let nametables_size = if mirroring == Mirroring::FourScreen {
+            0x1000
+        } else {
+            0x800
+        };
         Vram {
             mapper: mapper,
-            nametables: [0; 0x800],
+            nametables: vec![0; nametables_size],
             palette: [0; 0x20],
+            mirroring: mirroring,
+        }
+    }
+
+    /// Translates a nametable address to an offset into `nametables`, using the mapper's
+    /// mirroring if it controls it. Four-screen boards ignore the mapper, since they have no need
+    /// to mirror anything.
+    fn nametable_offset(&self, addr: u16) -> usize {
+        if self.mirroring == Mirroring::FourScreen {
+            return self.mirroring.nametable_offset(addr);
         }
+        self.mapper
+            .borrow()
+            .mirroring()
+            .unwrap_or(self.mirroring)
+            .nametable_offset(addr)
+    }
+}
+
+/// Translates a palette address to an offset into `palette`. The first entry of each sprite
+/// palette, which is never drawn, is shared with the same entry of the background palettes:
+/// $3F10, $3F14, $3F18 and $3F1C mirror $3F00, $3F04, $3F08 and $3F0C.
+#[inline(always)]
+fn palette_offset(addr: u16) -> usize {
+    let offset = addr as usize & 0x1f;
+    if (offset & 0x13) == 0x10 {
+        offset & 0x0f
+    } else {
+        offset
     }
 }
 
@@ -288,29 +484,35 @@ impl Mem for Vram {
             mapper.chr_loadb(addr)
         } else if addr < 0x3f00 {
             // Name table area
-            self.nametables[addr as usize & 0x07ff]
+            self.nametables[self.nametable_offset(addr)]
         } else if addr < 0x4000 {
             // Palette area
-            self.palette[addr as usize & 0x1f]
+            self.palette[palette_offset(addr)]
         } else {
             panic!("invalid VRAM read")
         }
     }
+    fn peekb(&self, addr: u16) -> u8 {
+        if addr < 0x2000 {
+            let mut mapper = self.mapper.borrow_mut();
+            mapper.chr_peekb(addr)
+        } else if addr < 0x3f00 {
+            self.nametables[self.nametable_offset(addr)]
+        } else {
+            self.palette[palette_offset(addr)]
+        }
+    }
     fn storeb(&mut self, addr: u16, val: u8) {
         if addr < 0x2000 {
             let mut mapper = self.mapper.borrow_mut();
             mapper.chr_storeb(addr, val)
         } else if addr < 0x3f00 {
             // Name table area
-            let addr = addr & 0x07ff;
-            self.nametables[addr as usize] = val;
+            let offset = self.nametable_offset(addr);
+            self.nametables[offset] = val;
         } else if addr < 0x4000 {
             // Palette area
-            let mut addr = addr & 0x1f;
-            if addr == 0x10 {
-                addr = 0x00; // Mirror sprite background color into universal background color.
-            }
-            self.palette[addr as usize] = val;
+            self.palette[palette_offset(addr)] = val;
         }
     }
 }
@@ -366,44 +568,20 @@ impl Save for Oam {
 
 struct SpriteStruct {
     x: u8,
-    y: u8,
+    /// The first scanline the sprite is on, one below its Y coordinate in OAM. Sprites at $FF
+    /// start below the bottom of the screen.
+    y: u16,
     tile_index_byte: u8,
     attribute_byte: u8,
 }
 
-// Specifies the indices of the tiles that make up this sprite.
-enum SpriteTiles {
-    SpriteTiles8x8(u16),
-    SpriteTiles8x16(u16, u16),
-}
-
-use self::SpriteTiles::*;
-
 impl SpriteStruct {
-    fn tiles(&self, ppu: &Ppu) -> SpriteTiles {
-        let base = ppu.regs.ctrl.sprite_pattern_table_addr();
-        match ppu.regs.ctrl.sprite_size() {
-            SpriteSize::SpriteSize8x8 => SpriteTiles8x8(self.tile_index_byte as u16 | base),
-            SpriteSize::SpriteSize8x16 => {
-                // We ignore the base set in PPUCTRL here.
-                let mut first = (self.tile_index_byte & !1) as u16;
-                if (self.tile_index_byte & 1) != 0 {
-                    first += 0x1000;
-                }
-                SpriteTiles8x16(first, first + 1)
-            }
-        }
-    }
-
     fn palette(&self) -> u8 {
         (self.attribute_byte & 3) + 4
     }
     fn flip_horizontal(&self) -> bool {
         (self.attribute_byte & 0x40) != 0
     }
-    fn flip_vertical(&self) -> bool {
-        (self.attribute_byte & 0x80) != 0
-    }
 
     fn priority(&self) -> SpritePriority {
         if (self.attribute_byte & 0x20) == 0 {
@@ -415,6 +593,7 @@ impl SpriteStruct {
 
     // Quick test to see whether this sprite is on the given scanline.
     fn on_scanline(&self, ppu: &Ppu, y: u8) -> bool {
+        let y = y as u16;
         if y < self.y {
             return false;
         }
@@ -426,7 +605,125 @@ impl SpriteStruct {
 
     // Quick test to see whether the given point is in the bounding box of this sprite.
     fn in_bounding_box(&self, ppu: &Ppu, x: u8, y: u8) -> bool {
-        x >= self.x && x < (Wrapping(self.x) + Wrapping(8)).0 && self.on_scanline(ppu, y)
+        // Sprites at the right edge are cut off rather than wrapping around to the left.
+        x >= self.x && (x as u16) < self.x as u16 + 8 && self.on_scanline(ppu, y)
+    }
+}
+
+//
+// The dot-stepped pipeline
+//
+
+/// A sprite fetched for the scanline being drawn, with its pattern already flipped horizontally
+/// if need be.
+#[derive(Copy, Clone)]
+struct LineSprite {
+    x: u8,
+    attribute_byte: u8,
+    pattern_lo: u8,
+    pattern_hi: u8,
+    /// Whether this is sprite 0, which can set the sprite 0 hit flag.
+    zero: bool,
+}
+
+save_struct!(LineSprite {
+    x,
+    attribute_byte,
+    pattern_lo,
+    pattern_hi,
+    zero
+});
+
+/// The state of the dot-stepped renderer: where it is on the scanline, and the latches and shift
+/// registers that its memory fetches fill. See "PPU rendering" on the NESdev wiki.
+#[derive(Copy, Clone)]
+struct Pipeline {
+    /// The dot within the scanline, from 0 to 340.
+    dot: u16,
+
+    // What the current background fetch has loaded so far.
+    nametable_byte: u8,
+    attribute_bits: u8,
+    pattern_lo: u8,
+    pattern_hi: u8,
+
+    // Sixteen pixels of background: the tile being drawn in the high bytes and the next in the
+    // low bytes. The attribute bits are spread out to match.
+    shift_pattern_lo: u16,
+    shift_pattern_hi: u16,
+    shift_attribute_lo: u16,
+    shift_attribute_hi: u16,
+
+    /// The sprites on the scanline, in OAM order. Without the sprite limit, all 64 can be.
+    sprites: [LineSprite; 64],
+    sprite_count: usize,
+    /// The level of A12 for each of the eight sprite fetches the hardware makes for a scanline.
+    sprite_a12: [bool; SPRITES_PER_SCANLINE],
+}
+
+impl Save for Pipeline {
+    fn save(&mut self, fd: &mut File) {
+        self.dot.save(fd);
+        self.nametable_byte.save(fd);
+        self.attribute_bits.save(fd);
+        self.pattern_lo.save(fd);
+        self.pattern_hi.save(fd);
+        self.shift_pattern_lo.save(fd);
+        self.shift_pattern_hi.save(fd);
+        self.shift_attribute_lo.save(fd);
+        self.shift_attribute_hi.save(fd);
+        for sprite in self.sprites.iter_mut() {
+            sprite.save(fd);
+        }
+        (self.sprite_count as u8).save(fd);
+        for a12 in self.sprite_a12.iter_mut() {
+            a12.save(fd);
+        }
+    }
+    fn load(&mut self, fd: &mut File) {
+        self.dot.load(fd);
+        self.nametable_byte.load(fd);
+        self.attribute_bits.load(fd);
+        self.pattern_lo.load(fd);
+        self.pattern_hi.load(fd);
+        self.shift_pattern_lo.load(fd);
+        self.shift_pattern_hi.load(fd);
+        self.shift_attribute_lo.load(fd);
+        self.shift_attribute_hi.load(fd);
+        for sprite in self.sprites.iter_mut() {
+            sprite.load(fd);
+        }
+        let mut sprite_count = 0u8;
+        sprite_count.load(fd);
+        self.sprite_count = sprite_count as usize;
+        for a12 in self.sprite_a12.iter_mut() {
+            a12.load(fd);
+        }
+    }
+}
+
+impl Pipeline {
+    fn new() -> Pipeline {
+        Pipeline {
+            dot: 0,
+            nametable_byte: 0,
+            attribute_bits: 0,
+            pattern_lo: 0,
+            pattern_hi: 0,
+            shift_pattern_lo: 0,
+            shift_pattern_hi: 0,
+            shift_attribute_lo: 0,
+            shift_attribute_hi: 0,
+            sprites: [LineSprite {
+                x: 0,
+                attribute_byte: 0,
+                pattern_lo: 0,
+                pattern_hi: 0,
+                zero: false,
+            }; 64],
+            sprite_count: 0,
+            sprite_a12: [false; SPRITES_PER_SCANLINE],
+        }
     }
 }
 
@@ -437,38 +734,85 @@ pub struct Ppu {
     vram: Vram,
     oam: Oam,
 
-    pub screen: Box<[u8; 184320]>, // 256 * 240 * 3
+    /// The most recently drawn frame, 256 * 240 pixels in `pixel_format`.
+    pub screen: Box<[u8]>,
+    pixel_format: PixelFormat,
     scanline: u16,
     ppudata_buffer: u8,
 
-    // NB: These two cannot always be computed from PPUCTRL and PPUSCROLL, because PPUADDR *also*
-    // updates the scroll position. This is important to emulate.
-    scroll_x: u16,
-    scroll_y: u16,
-
-    cy: u64,
+    /// If false, all sprites on a scanline are drawn instead of only the first eight. The sprite
+    /// overflow flag is set as usual either way.
+    sprite_limit: bool,
+    /// If true, the scanline renderer doesn't draw, and only works out whether sprite 0 hits the
+    /// background. Frames take much less time, and the screen keeps the last one drawn.
+    skip_drawing: bool,
+    /// The console whose timing is followed: NTSC, PAL or Dendy.
+    region: Region,
+    timing: &'static Timing,
+    /// The palette for each setting of the color emphasis bits.
+    palettes: [[Rgb; 64]; 8],
+
+    /// If true, the PPU runs a dot at a time through `pipeline` instead of a scanline at a time.
+    dot_stepped: bool,
+    /// The PPU cycles run so far. When drawing a scanline at a time, this is where the current
+    /// scanline starts.
+    dots: u64,
+    /// With rendering on, the pre-render scanline of every other frame is a dot short.
+    odd_frame: bool,
+    pipeline: Pipeline,
+
+    // Catching up within a scanline. The scanline renderer draws the part of the current
+    // scanline that the CPU has run past whenever a register is written, so that writes in the
+    // middle of a scanline, or in horizontal blank, take effect on the right pixel.
+    /// The PPU cycle that the PPU has been asked to run to.
+    caught_up: u64,
+    /// How many dots of the current scanline have been drawn and acted on.
+    line_progress: u16,
+    /// The pixel of the current scanline at which `v` was last loaded through PPUADDR. Pixels
+    /// from here on are drawn from that address.
+    scroll_origin: u16,
+
+    /// The I/O latch: the last value driven onto the PPU's data bus by the CPU or a register.
+    open_bus_latch: u8,
+    /// The dot at which each bit of the latch was last driven. Bits left alone long enough decay
+    /// to 0.
+    open_bus_driven: [u64; 8],
+
+    /// What has happened since the events were last taken, oldest first.
+    events: VecDeque<PpuEvent>,
+    /// The CPU's IRQ line, watched for the mapper raising its IRQ.
+    irq: IrqLine,
+    /// Whether the mapper was asserting its IRQ at the end of the last scanline.
+    mapper_irq: bool,
+
+    // Hooks for scripts, overlays and mappers that follow the raster.
+    scanline_callback: Option<ScanlineCallback>,
+    vblank_callback: Option<VblankCallback>,
 }
 
 impl Mem for Ppu {
     // Performs a load of the PPU register at the given CPU address.
+    // Write-only registers, and the bits of PPUSTATUS and palette bytes that aren't driven, read
+    // back from the I/O latch.
     fn loadb(&mut self, addr: u16) -> u8 {
-        debug_assert!(addr >= 0x2000 && addr < 0x4000, "invalid PPU register");
-        match addr & 7 {
-            0 => *self.regs.ctrl,
-            1 => *self.regs.mask,
-            2 => self.read_ppustatus(),
-            3 => 0, // OAMADDR is read-only
-            4 => panic!("OAM read unimplemented"),
-            5 => 0, // PPUSCROLL is read-only
-            6 => 0, // PPUADDR is read-only
-            7 => self.read_ppudata(),
-            _ => panic!("can't happen"),
-        }
+        debug_assert!((0x2000..0x4000).contains(&addr), "invalid PPU register");
+        let (val, driven) = match addr & 7 {
+            2 => (self.read_ppustatus(), 0xe0),
+            4 => (self.read_oamdata(), 0xff),
+            7 if (self.regs.v & 0x3fff) >= 0x3f00 => (self.read_ppudata(), 0x3f),
+            7 => (self.read_ppudata(), 0xff),
+            _ => (0, 0),
+        };
+        self.drive_open_bus(val, driven)
     }
 
     // Performs a store to the PPU register at the given CPU address.
     fn storeb(&mut self, addr: u16, val: u8) {
-        debug_assert!(addr >= 0x2000 && addr < 0x4000, "invalid PPU register");
+        debug_assert!((0x2000..0x4000).contains(&addr), "invalid PPU register");
+        if !self.dot_stepped {
+            self.catch_up_scanline();
+        }
+        self.drive_open_bus(val, 0xff);
         match addr & 7 {
             0 => self.update_ppuctrl(val),
             1 => self.regs.mask = PpuMask { val: val },
@@ -481,31 +825,98 @@ impl Mem for Ppu {
             _ => panic!("can't happen"),
         }
     }
+
+    // Returns what a load would, without resetting the address latch or advancing PPUADDR.
+    fn peekb(&self, addr: u16) -> u8 {
+        let open_bus = self.open_bus();
+        match addr & 7 {
+            2 => (*self.regs.status & 0xe0) | (open_bus & 0x1f),
+            4 => self.read_oamdata(),
+            7 if (self.regs.v & 0x3fff) >= 0x3f00 => {
+                (self.vram.peekb(self.regs.v & 0x3fff) & 0x3f) | (open_bus & 0xc0)
+            }
+            7 => self.ppudata_buffer,
+            _ => open_bus,
+        }
+    }
 }
 
-#[derive(PartialEq, Eq)]
-pub struct StepResult {
-    pub new_frame: bool,    // We wrapped around to the next scanline.
-    pub vblank_nmi: bool,   // We entered VBLANK and must generate an NMI.
-    pub scanline_irq: bool, // The mapper wants to execute a scanline IRQ.
+/// Something the PPU did that the rest of the console has to act on. Events queue up as the PPU
+/// runs, in the order they happened, until they're taken with `pop_event` or `take_event`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PpuEvent {
+    /// Vertical blank started with NMIs enabled, so the CPU must take an NMI.
+    VblankNmi,
+    /// The mapper raised an IRQ while the PPU drew the given scanline. The mapper asserts the IRQ
+    /// line itself; this is for whatever wants to know which scanline it fired on.
+    ScanlineIrq { line: u16 },
+    /// The PPU wrapped around to the first scanline: the frame in `screen` is complete.
+    FrameComplete,
 }
 
-#[derive(Copy, Clone)]
+impl Save for VecDeque<PpuEvent> {
+    fn save(&mut self, fd: &mut File) {
+        let mut count = self.len() as u16;
+        count.save(fd);
+        for event in self.iter() {
+            let (mut kind, mut line) = match *event {
+                PpuEvent::VblankNmi => (0u8, 0u16),
+                PpuEvent::ScanlineIrq { line } => (1, line),
+                PpuEvent::FrameComplete => (2, 0),
+            };
+            kind.save(fd);
+            line.save(fd);
+        }
+    }
+    fn load(&mut self, fd: &mut File) {
+        let mut count = 0u16;
+        count.load(fd);
+        self.clear();
+        for _ in 0..count {
+            let (mut kind, mut line) = (0u8, 0u16);
+            kind.load(fd);
+            line.load(fd);
+            self.push_back(match kind {
+                0 => PpuEvent::VblankNmi,
+                1 => PpuEvent::ScanlineIrq { line: line },
+                _ => PpuEvent::FrameComplete,
+            });
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
 struct Rgb {
     r: u8,
     g: u8,
     b: u8,
 }
 
-enum PatternPixelKind {
-    Background,
-    Sprite,
-}
-
-struct NametableAddr {
-    base: u16,
-    x_index: u8,
-    y_index: u8,
+/// The color the debug viewers outline things in.
+const VIEW_OUTLINE: Rgb = Rgb {
+    r: 0xff,
+    g: 0xff,
+    b: 0xff,
+};
+
+/// Writes a pixel of a 256 * 240 screen in the given format.
+#[inline(always)]
+fn put_pixel(pixels: &mut [u8], format: PixelFormat, x: usize, y: usize, color: Rgb) {
+    match format {
+        PixelFormat::Bgr24 => {
+            let offset = (y * SCREEN_WIDTH + x) * 3;
+            pixels[offset] = color.b;
+            pixels[offset + 1] = color.g;
+            pixels[offset + 2] = color.r;
+        }
+        PixelFormat::Rgba32 => {
+            let offset = (y * SCREEN_WIDTH + x) * 4;
+            pixels[offset] = color.r;
+            pixels[offset + 1] = color.g;
+            pixels[offset + 2] = color.b;
+            pixels[offset + 3] = 0xff;
+        }
+    }
 }
 
 struct SpriteColor {
@@ -521,6 +932,35 @@ enum SpritePriority {
 use self::SpritePriority::*;
 use std::num::Wrapping;
 
+/// Picks the color of a pixel from the backdrop, the background and the sprite there.
+#[inline(always)]
+fn combine(backdrop: Rgb, background: Option<Rgb>, sprite: Option<SpriteColor>) -> Rgb {
+    match (background, sprite) {
+        (None, None) => backdrop,
+        (Some(color), None) => color,
+        (
+            Some(color),
+            Some(SpriteColor {
+                priority: BelowBg, ..
+            }),
+        ) => color,
+        (
+            None,
+            Some(SpriteColor {
+                priority: BelowBg,
+                color,
+            }),
+        ) => color,
+        (
+            _,
+            Some(SpriteColor {
+                priority: AboveBg,
+                color,
+            }),
+        ) => color,
+    }
+}
+
 impl Save for Ppu {
     fn save(&mut self, fd: &mut File) {
         self.regs.save(fd);
@@ -528,9 +968,19 @@ impl Save for Ppu {
         self.oam.save(fd);
         self.scanline.save(fd);
         self.ppudata_buffer.save(fd);
-        self.scroll_x.save(fd);
-        self.scroll_y.save(fd);
-        self.cy.save(fd);
+        self.sprite_limit.save(fd);
+        self.dots.save(fd);
+        self.odd_frame.save(fd);
+        self.pipeline.save(fd);
+        self.caught_up.save(fd);
+        self.line_progress.save(fd);
+        self.scroll_origin.save(fd);
+        self.open_bus_latch.save(fd);
+        for driven in self.open_bus_driven.iter_mut() {
+            driven.save(fd);
+        }
+        self.events.save(fd);
+        self.mapper_irq.save(fd);
     }
     fn load(&mut self, fd: &mut File) {
         self.regs.load(fd);
@@ -538,265 +988,658 @@ impl Save for Ppu {
         self.oam.load(fd);
         self.scanline.load(fd);
         self.ppudata_buffer.load(fd);
-        self.scroll_x.load(fd);
-        self.scroll_y.load(fd);
-        self.cy.load(fd);
+        self.sprite_limit.load(fd);
+        self.dots.load(fd);
+        self.odd_frame.load(fd);
+        self.pipeline.load(fd);
+        self.caught_up.load(fd);
+        self.line_progress.load(fd);
+        self.scroll_origin.load(fd);
+        self.open_bus_latch.load(fd);
+        for driven in self.open_bus_driven.iter_mut() {
+            driven.load(fd);
+        }
+        self.events.load(fd);
+        self.mapper_irq.load(fd);
     }
 }
 
 impl Ppu {
-    pub fn new(vram: Vram, oam: Oam) -> Ppu {
+    pub fn new(vram: Vram, oam: Oam, region: Region, pixel_format: PixelFormat) -> Ppu {
+        let screen_size = SCREEN_WIDTH * SCREEN_HEIGHT * pixel_format.bytes_per_pixel();
         Ppu {
             regs: Regs {
                 ctrl: PpuCtrl { val: 0 },
                 mask: PpuMask { val: 0 },
                 status: PpuStatus { val: 0 },
                 oam_addr: 0,
-                scroll: PpuScroll {
-                    x: 0,
-                    y: 0,
-                    next: PpuScrollDir::XDir,
-                },
-                addr: PpuAddr {
-                    val: 0,
-                    next: PpuAddrByte::Hi,
-                },
+                v: 0,
+                t: 0,
+                x: 0,
+                w: false,
             },
             vram: vram,
             oam: oam,
 
-            screen: Box::new([0; 184320]),
+            screen: vec![0; screen_size].into_boxed_slice(),
+            pixel_format: pixel_format,
             scanline: 0,
             ppudata_buffer: 0,
 
-            scroll_x: 0,
-            scroll_y: 0,
-
-            cy: 0,
+            sprite_limit: true,
+            skip_drawing: false,
+            region: region.timing(),
+            timing: Timing::for_region(region),
+            palettes: emphasized_palettes(region.timing(), None),
+
+            dot_stepped: false,
+            dots: 0,
+            odd_frame: false,
+            pipeline: Pipeline::new(),
+
+            caught_up: 0,
+            line_progress: 0,
+            scroll_origin: 0,
+            open_bus_latch: 0,
+            open_bus_driven: [0; 8],
+            events: VecDeque::new(),
+            irq: IrqLine::new(),
+            mapper_irq: false,
+            scanline_callback: None,
+            vblank_callback: None,
         }
     }
 
-    //
-    // Color utilities
-    //
-
-    #[inline(always)]
-    fn get_color(&self, palette_index: u8) -> Rgb {
-        Rgb {
-            r: PALETTE[palette_index as usize * 3 + 2],
-            g: PALETTE[palette_index as usize * 3 + 1],
-            b: PALETTE[palette_index as usize * 3 + 0],
-        }
+    /// Returns the layout of the pixels in `screen`.
+    pub fn pixel_format(&self) -> PixelFormat {
+        self.pixel_format
     }
 
-    //
-    // Register manipulation
-    //
+    /// Returns the console whose timing the PPU follows.
+    pub fn region(&self) -> Region {
+        self.region
+    }
 
-    fn update_ppuctrl(&mut self, val: u8) {
-        self.regs.ctrl = PpuCtrl { val: val };
+    /// Draws with the colors of a custom palette, or with the built-in one if `None`.
+    pub fn set_palette(&mut self, palette: Option<&Palette>) {
+        self.palettes = emphasized_palettes(self.region, palette);
+    }
 
-        self.scroll_x = (self.scroll_x & 0xff) | self.regs.ctrl.x_scroll_offset();
-        self.scroll_y = (self.scroll_y & 0xff) | self.regs.ctrl.y_scroll_offset();
+    /// Returns true if only the first eight sprites on each scanline are drawn, as on hardware.
+    pub fn sprite_limit(&self) -> bool {
+        self.sprite_limit
     }
 
-    fn update_ppuscroll(&mut self, val: u8) {
-        match self.regs.scroll.next {
-            PpuScrollDir::XDir => {
-                self.scroll_x = (self.scroll_x & 0xff00) | (val as u16);
+    /// Enables or disables the eight-sprites-per-scanline limit. Disabling it removes flicker in
+    /// games that multiplex sprites, at the cost of accuracy.
+    pub fn set_sprite_limit(&mut self, enabled: bool) {
+        self.sprite_limit = enabled;
+    }
 
-                self.regs.scroll.x = val;
-                self.regs.scroll.next = PpuScrollDir::YDir;
-            }
-            PpuScrollDir::YDir => {
-                self.scroll_y = (self.scroll_y & 0xff00) | (val as u16);
+    /// Stops or restarts drawing frames, for fast-forwarding. Timing, NMIs, the status flags and
+    /// the mapper's view of the fetches go on as usual. The dot-stepped pipeline always draws.
+    pub fn set_skip_drawing(&mut self, skip: bool) {
+        self.skip_drawing = skip;
+    }
 
-                self.regs.scroll.y = val;
-                self.regs.scroll.next = PpuScrollDir::XDir;
-            }
-        }
+    /// Returns true if the PPU runs a dot at a time.
+    pub fn dot_stepped(&self) -> bool {
+        self.dot_stepped
     }
 
-    fn write_oamdata(&mut self, val: u8) {
-        self.oam.storeb(self.regs.oam_addr as u16, val);
-        self.regs.oam_addr = (Wrapping(self.regs.oam_addr) + Wrapping(1)).0;
+    /// Switches between drawing a scanline at a time and running the real rendering pipeline a
+    /// dot at a time. Dot-stepping is slower, but shows changes to the registers in the middle of
+    /// a scanline where they happen. Switch before the console starts running.
+    pub fn set_dot_stepped(&mut self, enabled: bool) {
+        self.dot_stepped = enabled;
+        self.pipeline = Pipeline::new();
+        self.line_progress = 0;
+        self.scroll_origin = 0;
     }
 
-    fn update_ppuaddr(&mut self, val: u8) {
-        match self.regs.addr.next {
-            PpuAddrByte::Hi => {
-                self.regs.addr.val = (self.regs.addr.val & 0x00ff) | ((val as u16) << 8);
-                self.regs.addr.next = PpuAddrByte::Lo;
-            }
-            PpuAddrByte::Lo => {
-                self.regs.addr.val = (self.regs.addr.val & 0xff00) | (val as u16);
-                self.regs.addr.next = PpuAddrByte::Hi;
+    /// Gives the PPU the CPU's IRQ line, so it can report the scanlines the mapper raises IRQs on.
+    pub fn connect_irq(&mut self, irq: IrqLine) {
+        self.irq = irq;
+    }
 
-                // Adjust the scroll registers.
-                // TODO: This is pretty much a hack. The right way is to precisely emulate the PPU
-                // internal registers.
-                // TODO: Y scrolling.
-                let addr = self.regs.addr.val & 0x07ff;
-                let xscroll_base = if addr < 0x400 { 0 } else { 256 };
-                self.scroll_x = (self.scroll_x & 0xff) | xscroll_base;
-            }
+    /// Queues up a `ScanlineIrq` if the mapper started asserting its IRQ during the given
+    /// scanline, including when it counted the scanline at its end.
+    fn note_scanline_irq(&mut self, line: u16) {
+        let asserted = self.irq.asserted_by(IrqSource::Mapper);
+        if asserted && !self.mapper_irq {
+            self.events.push_back(PpuEvent::ScanlineIrq { line: line });
         }
+        self.mapper_irq = asserted;
     }
 
-    fn read_ppustatus(&mut self) -> u8 {
-        // Reset latch.
-        self.regs.scroll.next = PpuScrollDir::XDir;
-        self.regs.addr.next = PpuAddrByte::Hi;
-
-        *self.regs.status
+    /// Takes the oldest event that hasn't been taken yet.
+    pub fn pop_event(&mut self) -> Option<PpuEvent> {
+        self.events.pop_front()
     }
 
-    fn write_ppudata(&mut self, val: u8) {
-        self.vram.storeb(self.regs.addr.val, val);
-        self.regs.addr.val += self.regs.ctrl.vram_addr_increment();
+    /// Takes every queued event of one kind, leaving the others. Returns true if there were any.
+    pub fn take_event(&mut self, event: PpuEvent) -> bool {
+        let count = self.events.len();
+        self.events.retain(|&queued| queued != event);
+        self.events.len() != count
     }
 
-    fn read_ppudata(&mut self) -> u8 {
-        let addr = self.regs.addr.val;
-        let val = self.vram.loadb(addr);
-        self.regs.addr.val += self.regs.ctrl.vram_addr_increment();
+    /// Sets the function called at the start of every scanline, or removes it if `None`. Like
+    /// the mapper's scanline counter, it runs when the PPU reaches the scanline, which when
+    /// drawing a scanline at a time may be a little after the CPU has.
+    pub fn set_scanline_callback(&mut self, callback: Option<ScanlineCallback>) {
+        self.scanline_callback = callback;
+    }
 
-        // Emulate the PPU buffering quirk.
-        if addr < 0x3f00 {
-            let buffered_val = self.ppudata_buffer;
-            self.ppudata_buffer = val;
-            buffered_val
-        } else {
-            val
-        }
+    /// Sets the function called as vertical blank starts, or removes it if `None`.
+    pub fn set_vblank_callback(&mut self, callback: Option<VblankCallback>) {
+        self.vblank_callback = callback;
     }
 
     //
-    // Background rendering helpers
+    // FCEUX savestates
     //
 
-    fn nametable_addr(&mut self, mut x_index: u16, mut y_index: u16) -> NametableAddr {
-        x_index %= 64;
-        y_index %= 60;
-
-        let nametable_base = match (x_index >= 32, y_index >= 30) {
-            (false, false) => 0x2000,
-            (true, false) => 0x2400,
-            (false, true) => 0x2800,
-            (true, true) => 0x2c00,
+    /// Restores the registers, memories and scroll position from an FCEUX PPU section. Returns a
+    /// description of each entry that was missing.
+    pub fn import_fceux(&mut self, section: &FceuxSection) -> Vec<String> {
+        let mut warnings = vec![];
+        let mut require = |name: &str, found: bool| {
+            if !found {
+                warnings.push(format!("PPU entry {} is missing", name));
+            }
         };
 
-        NametableAddr {
-            base: nametable_base,
-            x_index: (x_index % 32) as u8,
-            y_index: (y_index % 30) as u8,
+        require(
+            "NTAR",
+            section.copy_to("NTAR", &mut self.vram.nametables[..0x800]),
+        );
+        require("PRAM", section.copy_to("PRAM", &mut self.vram.palette));
+        require("SPRA", section.copy_to("SPRA", &mut self.oam.oam));
+
+        match section.get_exact("PPUR", 4) {
+            Some(regs) => {
+                self.regs.ctrl = PpuCtrl { val: regs[0] };
+                self.regs.mask = PpuMask { val: regs[1] };
+                self.regs.status = PpuStatus { val: regs[2] };
+                self.regs.oam_addr = regs[3];
+            }
+            None => require("PPUR", false),
         }
-    }
 
-    #[inline(always)]
-    fn make_sprite_info(&mut self, index: u16) -> SpriteStruct {
-        SpriteStruct {
-            y: self.oam.loadb(index * 4 + 0) + 1,
-            tile_index_byte: self.oam.loadb(index * 4 + 1),
-            attribute_byte: self.oam.loadb(index * 4 + 2),
-            x: self.oam.loadb(index * 4 + 3),
+        if let Some(val) = section.get_u8("VBUF") {
+            self.ppudata_buffer = val;
         }
-    }
-
-    #[inline(always)]
-    fn each_sprite<F>(&mut self, mut f: F)
-    where
-        F: FnMut(&mut Ppu, &SpriteStruct, u8) -> bool,
-    {
-        for i in 0..64 {
-            let sprite = self.make_sprite_info(i as u16);
-            if !f(self, &sprite, i as u8) {
-                return;
+        // FCEUX keeps the same internal registers.
+        if let Some(val) = section.get_u16("RADD") {
+            self.regs.v = val & 0x7fff;
+        }
+        if let Some(vtoggle) = section.get_u8("VTGL") {
+            self.regs.w = vtoggle != 0;
+        }
+        match (section.get_u16("TADD"), section.get_u8("XOFF")) {
+            (Some(t), Some(x)) => {
+                self.regs.t = t & 0x7fff;
+                self.regs.x = x & 7;
             }
+            _ => require("TADD", false),
         }
+
+        warnings
+    }
+
+    /// Returns the registers, memories and scroll position as an FCEUX PPU section.
+    pub fn export_fceux(&self) -> FceuxSection {
+        let mut section = FceuxSection::new(fceux::SECTION_PPU);
+        section.put("NTAR", &self.vram.nametables[..0x800]);
+        section.put("PRAM", &self.vram.palette);
+        section.put("SPRA", &self.oam.oam);
+        section.put(
+            "PPUR",
+            &[
+                *self.regs.ctrl,
+                *self.regs.mask,
+                *self.regs.status,
+                self.regs.oam_addr,
+            ],
+        );
+        section.put("XOFF", &[self.regs.x]);
+        section.put("VTGL", &[self.regs.w as u8]);
+        section.put_u16("RADD", self.regs.v);
+        section.put_u16("TADD", self.regs.t);
+        section.put("VBUF", &[self.ppudata_buffer]);
+        section
     }
 
     //
-    // Rendering
+    // Debug viewers
     //
 
-    #[inline(always)]
-    fn putpixel(&mut self, x: usize, y: usize, color: Rgb) {
-        self.screen[(y * SCREEN_WIDTH + x) * 3 + 0] = color.r;
-        self.screen[(y * SCREEN_WIDTH + x) * 3 + 1] = color.g;
-        self.screen[(y * SCREEN_WIDTH + x) * 3 + 2] = color.b;
-    }
-
-    // Returns the color (pre-palette lookup) of pixel (x,y) within the given tile.
-    #[inline(always)]
-    fn get_pattern_pixel(&mut self, kind: PatternPixelKind, tile: u16, x: u8, y: u8) -> u8 {
-        // Compute the pattern offset.
-        let mut pattern_offset = (tile << 4) + (y as u16);
-        match kind {
-            PatternPixelKind::Background => {
-                pattern_offset += self.regs.ctrl.background_pattern_table_addr()
-            }
-            PatternPixelKind::Sprite => {
-                pattern_offset += self.regs.ctrl.sprite_pattern_table_addr()
+    /// Draws a view of the PPU's memory into a new 256 * 240 screen in `pixel_format`. Nothing is
+    /// read through the mapper in a way it can see, so drawing doesn't disturb the console.
+    pub fn draw_view(&self, view: PpuView) -> Box<[u8]> {
+        let mut pixels = vec![0; self.screen.len()].into_boxed_slice();
+        let black = Rgb { r: 0, g: 0, b: 0 };
+        for y in 0..SCREEN_HEIGHT {
+            for x in 0..SCREEN_WIDTH {
+                put_pixel(&mut pixels, self.pixel_format, x, y, black);
             }
         }
+        match view {
+            PpuView::Nametables => self.draw_nametables(&mut pixels),
+            PpuView::PatternTables(palette) => self.draw_pattern_tables(&mut pixels, palette),
+            PpuView::Sprites => self.draw_sprites(&mut pixels),
+        }
+        pixels
+    }
 
-        // Determine the color of this pixel.
-        let plane0 = self.vram.loadb(pattern_offset);
-        let plane1 = self.vram.loadb(pattern_offset + 8);
-        let bit0 = (plane0 >> ((7 - ((x % 8) as u8)) as usize)) & 1;
-        let bit1 = (plane1 >> ((7 - ((x % 8) as u8)) as usize)) & 1;
-        (bit1 << 1) | bit0
+    // Returns the color of pixel value `value`, from 0 to 3, in one of the eight palettes.
+    // Emphasis and grayscale are left out, so that the viewers show the palette RAM as it is.
+    fn view_color(&self, palette: u8, value: u8) -> Rgb {
+        let entry = if value == 0 { 0 } else { palette * 4 + value };
+        self.palettes[0][(self.vram.peekb(0x3f00 + entry as u16) & 0x3f) as usize]
     }
 
-    // Returns true if the background was opaque here, false otherwise.
-    #[inline(always)]
-    fn get_background_pixel(&mut self, x: u8) -> Option<Rgb> {
-        // Adjust X and Y to account for scrolling.
-        let x = x as u16 + self.scroll_x;
-        let y = self.scanline as u16 + self.scroll_y;
-
-        // Compute the nametable address, tile index, and pixel offset within that tile.
-        let NametableAddr {
-            base,
-            x_index,
-            y_index,
-        } = self.nametable_addr(x / 8, y / 8);
-        let (xsub, ysub) = ((x % 8) as u8, (y % 8) as u8);
-
-        // Compute the nametable address and load the tile number from the nametable.
-        let tile = self
-            .vram
-            .loadb(base + 32 * (y_index as u16) + (x_index as u16));
+    // Returns the value, from 0 to 3, of one pixel of a pattern without telling the mapper.
+    fn peek_pattern_pixel(&self, pattern_offset: u16, x: u8) -> u8 {
+        let plane0 = self.vram.peekb(pattern_offset);
+        let plane1 = self.vram.peekb(pattern_offset + 8);
+        let bit = 7 - (x % 8);
+        (((plane1 >> bit) & 1) << 1) | ((plane0 >> bit) & 1)
+    }
 
-        // Fetch the pattern color.
-        let pattern_color =
-            self.get_pattern_pixel(PatternPixelKind::Background, tile as u16, xsub, ysub);
-        if pattern_color == 0 {
-            return None; // Transparent.
+    // Each pixel of the half-size view is the top left pixel of a 2x2 block of the 512x480
+    // picture the four nametables make.
+    fn draw_nametables(&self, pixels: &mut [u8]) {
+        let pattern_base = self.regs.ctrl.background_pattern_table_addr();
+        for view_y in 0..SCREEN_HEIGHT {
+            for view_x in 0..SCREEN_WIDTH {
+                let (map_x, map_y) = (view_x * 2, view_y * 2);
+                let nametable = (map_y / SCREEN_HEIGHT) * 2 + map_x / SCREEN_WIDTH;
+                let base = 0x2000 + nametable as u16 * 0x400;
+                let (x, y) = (map_x % SCREEN_WIDTH, map_y % SCREEN_HEIGHT);
+
+                let tile = self.vram.peekb(base + (y / 8 * 32 + x / 8) as u16);
+                let attribute = self.vram.peekb(base + 0x3c0 + (y / 32 * 8 + x / 32) as u16);
+                let palette = (attribute >> ((y / 16 % 2) * 4 + (x / 16 % 2) * 2)) & 3;
+                let pattern_offset = pattern_base + tile as u16 * 16 + (y % 8) as u16;
+                let value = self.peek_pattern_pixel(pattern_offset, x as u8);
+                let color = self.view_color(palette, value);
+                put_pixel(pixels, self.pixel_format, view_x, view_y, color);
+            }
         }
 
-        // Now load the attribute bits from the attribute table.
-        let group = y_index / 4 * 8 + x_index / 4;
-        let attr_byte = self.vram.loadb(base + 0x3c0 + (group as u16));
-        let (left, top) = (x_index % 4 < 2, y_index % 4 < 2);
-        let attr_table_color = match (left, top) {
-            (true, true) => attr_byte & 0x3,
-            (false, true) => (attr_byte >> 2) & 0x3,
-            (true, false) => (attr_byte >> 4) & 0x3,
-            (false, false) => (attr_byte >> 6) & 0x3,
-        };
-
-        // Determine the final color and fetch the palette from VRAM.
-        let tile_color = (attr_table_color << 2) | pattern_color;
-        let palette_index = self.vram.loadb(0x3f00 + (tile_color as u16)) & 0x3f;
-        return Some(self.get_color(palette_index));
+        // The area the next frame starts at, from the temporary address and fine X.
+        let t = self.regs.t;
+        let scroll_x = ((t >> 10) & 1) * 256 + (t & 0x1f) * 8 + self.regs.x as u16;
+        let scroll_y = ((t >> 11) & 1) * 240 + ((t >> 5) & 0x1f) * 8 + ((t >> 12) & 7);
+        let (left, top) = (scroll_x as usize / 2, scroll_y as usize / 2);
+        for i in 0..SCREEN_WIDTH / 2 {
+            for &y in [top, top + SCREEN_HEIGHT / 2 - 1].iter() {
+                let (x, y) = ((left + i) % SCREEN_WIDTH, y % SCREEN_HEIGHT);
+                put_pixel(pixels, self.pixel_format, x, y, VIEW_OUTLINE);
+            }
+        }
+        for i in 0..SCREEN_HEIGHT / 2 {
+            for &x in [left, left + SCREEN_WIDTH / 2 - 1].iter() {
+                let (x, y) = (x % SCREEN_WIDTH, (top + i) % SCREEN_HEIGHT);
+                put_pixel(pixels, self.pixel_format, x, y, VIEW_OUTLINE);
+            }
+        }
     }
 
-    fn get_sprite_pixel(
-        &mut self,
-        visible_sprites: &[Option<u8>; 8],
-        x: u8,
+    // The two pattern tables sit side by side across the top, and the background and sprite
+    // palettes in two rows below them.
+    fn draw_pattern_tables(&self, pixels: &mut [u8], palette: u8) {
+        for y in 0..128 {
+            for x in 0..SCREEN_WIDTH {
+                let table = (x / 128) as u16 * 0x1000;
+                let tile = (y / 8 * 16 + x % 128 / 8) as u16;
+                let value = self.peek_pattern_pixel(table + tile * 16 + (y % 8) as u16, x as u8);
+                put_pixel(
+                    pixels,
+                    self.pixel_format,
+                    x,
+                    y,
+                    self.view_color(palette, value),
+                );
+            }
+        }
+
+        for entry in 0..32 {
+            let color = self.palettes[0][(self.vram.peekb(0x3f00 + entry) & 0x3f) as usize];
+            let (left, top) = ((entry % 16) as usize * 16, 144 + (entry / 16) as usize * 16);
+            for y in top..top + 16 {
+                for x in left..left + 16 {
+                    put_pixel(pixels, self.pixel_format, x, y, color);
+                }
+            }
+        }
+
+        // Outline the four entries of the selected palette.
+        let (left, top) = (
+            (palette % 4) as usize * 64,
+            144 + (palette / 4) as usize * 16,
+        );
+        for i in 0..64 {
+            put_pixel(pixels, self.pixel_format, left + i, top, VIEW_OUTLINE);
+            put_pixel(pixels, self.pixel_format, left + i, top + 15, VIEW_OUTLINE);
+        }
+        for i in 0..16 {
+            put_pixel(pixels, self.pixel_format, left, top + i, VIEW_OUTLINE);
+            put_pixel(pixels, self.pixel_format, left + 63, top + i, VIEW_OUTLINE);
+        }
+    }
+
+    // Each sprite is drawn in a 32x30 cell, flipped as it would be on screen: 8x8 sprites at
+    // twice their size, and 8x16 sprites, which wouldn't fit doubled, at their own size.
+    fn draw_sprites(&self, pixels: &mut [u8]) {
+        let (width, height, scale) = match self.regs.ctrl.sprite_size() {
+            SpriteSize::SpriteSize8x8 => (8, 8, 2),
+            SpriteSize::SpriteSize8x16 => (8, 16, 1),
+        };
+        for index in 0..64 {
+            let tile_index_byte = self.oam.oam[index * 4 + 1];
+            let attribute_byte = self.oam.oam[index * 4 + 2];
+            let flip_horizontal = (attribute_byte & 0x40) != 0;
+            let palette = (attribute_byte & 3) + 4;
+
+            let left = index % 8 * 32 + (32 - width * scale) / 2;
+            let top = index / 8 * 30 + (30 - height * scale) / 2;
+            for row in 0..height {
+                let pattern_offset =
+                    self.sprite_pattern_addr(tile_index_byte, attribute_byte, row as u16);
+                for column in 0..width {
+                    let x = if flip_horizontal { 7 - column } else { column };
+                    let value = self.peek_pattern_pixel(pattern_offset, x as u8);
+                    let color = self.view_color(palette, value);
+                    for dy in 0..scale {
+                        for dx in 0..scale {
+                            let (x, y) = (left + column * scale + dx, top + row * scale + dy);
+                            put_pixel(pixels, self.pixel_format, x, y, color);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    //
+    // Color utilities
+    //
+
+    #[inline(always)]
+    fn get_color(&self, palette_index: u8) -> Rgb {
+        // Grayscale mode keeps only the brightness: the gray in the first column of each row.
+        let palette_index = if self.regs.mask.grayscale() {
+            palette_index & 0x30
+        } else {
+            palette_index
+        };
+        self.palettes[self.regs.mask.emphasis()][palette_index as usize]
+    }
+
+    //
+    // Open bus
+    //
+
+    /// The dot the CPU has reached, as far as the PPU knows.
+    fn now(&self) -> u64 {
+        self.dots.max(self.caught_up)
+    }
+
+    /// Returns the I/O latch, less the bits that have decayed.
+    fn open_bus(&self) -> u8 {
+        let now = self.now();
+        let mut val = self.open_bus_latch;
+        for (bit, &driven) in self.open_bus_driven.iter().enumerate() {
+            if now.saturating_sub(driven) >= OPEN_BUS_DECAY_DOTS {
+                val &= !(1 << bit);
+            }
+        }
+        val
+    }
+
+    /// Puts the bits of `val` in `driven` on the bus, refreshing the latch, and returns what a
+    /// read sees: those bits, and the rest from the latch.
+    fn drive_open_bus(&mut self, val: u8, driven: u8) -> u8 {
+        let now = self.now();
+        let val = (val & driven) | (self.open_bus() & !driven);
+        for (bit, time) in self.open_bus_driven.iter_mut().enumerate() {
+            if driven & (1 << bit) != 0 {
+                *time = now;
+            }
+        }
+        self.open_bus_latch = val;
+        val
+    }
+
+    //
+    // Register manipulation
+    //
+
+    fn update_ppuctrl(&mut self, val: u8) {
+        self.regs.ctrl = PpuCtrl { val: val };
+        let nametable = self.regs.ctrl.nametable_select() << 10;
+        self.regs.t = (self.regs.t & !(NAMETABLE_X | NAMETABLE_Y)) | nametable;
+    }
+
+    fn update_ppuscroll(&mut self, val: u8) {
+        if !self.regs.w {
+            self.regs.t = (self.regs.t & !COARSE_X) | (val >> 3) as u16;
+            self.regs.x = val & 7;
+        } else {
+            let (coarse_y, fine_y) = ((val >> 3) as u16, (val & 7) as u16);
+            self.regs.t = (self.regs.t & !(COARSE_Y | FINE_Y)) | coarse_y << 5 | fine_y << 12;
+        }
+        self.regs.w = !self.regs.w;
+    }
+
+    // Reads leave OAMADDR where it is.
+    fn read_oamdata(&self) -> u8 {
+        let addr = self.regs.oam_addr;
+        let val = self.oam.oam[addr as usize];
+        // Bits 2-4 of the attribute byte don't exist, and read back as 0.
+        if (addr & 3) == 2 {
+            val & 0xe3
+        } else {
+            val
+        }
+    }
+
+    fn write_oamdata(&mut self, val: u8) {
+        self.oam.storeb(self.regs.oam_addr as u16, val);
+        self.regs.oam_addr = (Wrapping(self.regs.oam_addr) + Wrapping(1)).0;
+    }
+
+    fn update_ppuaddr(&mut self, val: u8) {
+        if !self.regs.w {
+            // The first write also clears the top bit of fine Y.
+            self.regs.t = (self.regs.t & 0x00ff) | ((val & 0x3f) as u16) << 8;
+        } else {
+            self.regs.t = (self.regs.t & 0xff00) | val as u16;
+            self.regs.v = self.regs.t;
+            if self.line_progress < SCREEN_WIDTH as u16 {
+                self.scroll_origin = self.line_progress;
+            }
+        }
+        self.regs.w = !self.regs.w;
+    }
+
+    fn read_ppustatus(&mut self) -> u8 {
+        // Reset latch.
+        self.regs.w = false;
+
+        *self.regs.status
+    }
+
+    fn write_ppudata(&mut self, val: u8) {
+        self.vram.storeb(self.regs.v & 0x3fff, val);
+        self.increment_vram_addr();
+    }
+
+    fn read_ppudata(&mut self) -> u8 {
+        let addr = self.regs.v & 0x3fff;
+        let val = self.vram.loadb(addr);
+        self.increment_vram_addr();
+
+        // Emulate the PPU buffering quirk. Palette reads skip the buffer, but still fill it, with
+        // the byte of the nametable mirrored underneath the palette.
+        if addr < 0x3f00 {
+            let buffered_val = self.ppudata_buffer;
+            self.ppudata_buffer = val;
+            buffered_val
+        } else {
+            self.ppudata_buffer = self.vram.loadb(addr - 0x1000);
+            val
+        }
+    }
+
+    fn increment_vram_addr(&mut self) {
+        let increment = self.regs.ctrl.vram_addr_increment();
+        self.regs.v = self.regs.v.wrapping_add(increment) & 0x7fff;
+    }
+
+    //
+    // Scrolling
+    //
+
+    /// Moves `v` down a pixel row, as the PPU does at the end of each rendered scanline. Coarse Y
+    /// wraps from row 29 to the next nametable down; rows 30 and 31, which are attributes, wrap
+    /// to 0 without switching nametables.
+    fn increment_y(&mut self) {
+        let v = self.regs.v;
+        if (v & FINE_Y) != FINE_Y {
+            self.regs.v = v + 0x1000;
+            return;
+        }
+        let v = v & !FINE_Y;
+        self.regs.v = match (v & COARSE_Y) >> 5 {
+            29 => (v & !COARSE_Y) ^ NAMETABLE_Y,
+            31 => v & !COARSE_Y,
+            _ => v + 0x20,
+        };
+    }
+
+    fn copy_horizontal_scroll(&mut self) {
+        let bits = COARSE_X | NAMETABLE_X;
+        self.regs.v = (self.regs.v & !bits) | (self.regs.t & bits);
+    }
+
+    fn copy_vertical_scroll(&mut self) {
+        let bits = COARSE_Y | NAMETABLE_Y | FINE_Y;
+        self.regs.v = (self.regs.v & !bits) | (self.regs.t & bits);
+    }
+
+    //
+    // Background rendering helpers
+    //
+
+    #[inline(always)]
+    fn make_sprite_info(&mut self, index: u16) -> SpriteStruct {
+        SpriteStruct {
+            y: self.oam.loadb(index * 4) as u16 + 1,
+            tile_index_byte: self.oam.loadb(index * 4 + 1),
+            attribute_byte: self.oam.loadb(index * 4 + 2),
+            x: self.oam.loadb(index * 4 + 3),
+        }
+    }
+
+    #[inline(always)]
+    fn each_sprite<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&mut Ppu, &SpriteStruct, u8) -> bool,
+    {
+        for i in 0..64 {
+            let sprite = self.make_sprite_info(i as u16);
+            if !f(self, &sprite, i as u8) {
+                return;
+            }
+        }
+    }
+
+    //
+    // Rendering
+    //
+
+    #[inline(always)]
+    fn putpixel(&mut self, x: usize, y: usize, color: Rgb) {
+        put_pixel(&mut self.screen, self.pixel_format, x, y, color);
+    }
+
+    // Returns the color (pre-palette lookup) of pixel x within the pattern row at the given
+    // address.
+    #[inline(always)]
+    fn get_pattern_pixel(&mut self, pattern_offset: u16, x: u8) -> u8 {
+        let plane0 = self.vram.loadb(pattern_offset);
+        let plane1 = self.vram.loadb(pattern_offset + 8);
+        let bit0 = (plane0 >> (7 - (x % 8)) as usize) & 1;
+        let bit1 = (plane1 >> (7 - (x % 8)) as usize) & 1;
+        (bit1 << 1) | bit0
+    }
+
+    /// Returns the address of the given row of a sprite's pattern, which for 8x16 sprites may be
+    /// in the second tile. `row` counts from the top of the sprite as it's drawn.
+    fn sprite_pattern_addr(&self, tile_index_byte: u8, attribute_byte: u8, row: u16) -> u16 {
+        let flip_vertical = (attribute_byte & 0x80) != 0;
+        match self.regs.ctrl.sprite_size() {
+            SpriteSize::SpriteSize8x8 => {
+                let row = if flip_vertical { 7 - row } else { row };
+                let base = self.regs.ctrl.sprite_pattern_table_addr();
+                base + tile_index_byte as u16 * 16 + row
+            }
+            SpriteSize::SpriteSize8x16 => {
+                // Bit 0 of the tile index picks the pattern table, and flipping swaps the tiles.
+                let row = if flip_vertical { 15 - row } else { row };
+                let base = (tile_index_byte as u16 & 1) * 0x1000;
+                let tile = (tile_index_byte & !1) as u16 + row / 8;
+                base + tile * 16 + row % 8
+            }
+        }
+    }
+
+    // Returns the background color at the given pixel of the current scanline, or None if the
+    // background is transparent there.
+    #[inline(always)]
+    fn get_background_pixel(&mut self, x: u8) -> Option<Rgb> {
+        // The scanline starts at the position in `v`, offset by fine X, and crosses into the
+        // next nametable to the right if it runs off the edge of this one.
+        let v = self.regs.v;
+        let x = (v & COARSE_X) * 8 + self.regs.x as u16 + x as u16 - self.scroll_origin;
+        let nametable = (v & (NAMETABLE_X | NAMETABLE_Y)) ^ ((x & 0x100) << 2);
+        let (coarse_x, coarse_y) = ((x >> 3) & 0x1f, (v & COARSE_Y) >> 5);
+        let (xsub, ysub) = ((x % 8) as u8, ((v & FINE_Y) >> 12) as u8);
+
+        // Load the tile number from the nametable.
+        let tile = self
+            .vram
+            .loadb(0x2000 | nametable | coarse_y << 5 | coarse_x);
+
+        // Fetch the pattern color.
+        let pattern_addr =
+            self.regs.ctrl.background_pattern_table_addr() + (tile as u16) * 16 + ysub as u16;
+        let pattern_color = self.get_pattern_pixel(pattern_addr, xsub);
+        if pattern_color == 0 {
+            return None; // Transparent.
+        }
+
+        // Now load the attribute bits from the attribute table. Each byte covers 4x4 tiles, two
+        // bits for each 2x2 quarter.
+        let attr_addr = 0x23c0 | nametable | (coarse_y >> 2) << 3 | coarse_x >> 2;
+        let attr_byte = self.vram.loadb(attr_addr);
+        let shift = (coarse_y & 2) << 1 | (coarse_x & 2);
+        let attr_table_color = (attr_byte >> shift) & 0x3;
+
+        // Determine the final color and fetch the palette from VRAM.
+        let tile_color = (attr_table_color << 2) | pattern_color;
+        let palette_index = self.vram.loadb(0x3f00 + (tile_color as u16)) & 0x3f;
+        Some(self.get_color(palette_index))
+    }
+
+    fn get_sprite_pixel(
+        &mut self,
+        visible_sprites: &[Option<u8>; 64],
+        x: u8,
         background_opaque: bool,
     ) -> Option<SpriteColor> {
         for &visible_sprite_opt in visible_sprites.iter() {
@@ -806,31 +1649,23 @@ impl Ppu {
                     let sprite = self.make_sprite_info(index as u16);
 
                     // Don't need to consider this sprite if we aren't in its bounding box.
-                    if !sprite.in_bounding_box(self, x as u8, self.scanline as u8) {
+                    if !sprite.in_bounding_box(self, x, self.scanline as u8) {
                         continue;
                     }
 
-                    let pattern_color;
-                    match sprite.tiles(self) {
-                        // TODO: 8x16 rendering
-                        SpriteTiles8x8(tile) | SpriteTiles8x16(tile, _) => {
-                            let mut x = x - sprite.x;
-                            if sprite.flip_horizontal() {
-                                x = 7 - x;
-                            }
-
-                            let mut y = self.scanline as u8 - sprite.y;
-                            if sprite.flip_vertical() {
-                                y = 7 - y;
-                            }
-
-                            debug_assert!(x < 8, "sprite X miscalculation");
-                            debug_assert!(y < 8, "sprite Y miscalculation");
-
-                            pattern_color =
-                                self.get_pattern_pixel(PatternPixelKind::Sprite, tile, x, y);
-                        }
+                    let mut column = x - sprite.x;
+                    if sprite.flip_horizontal() {
+                        column = 7 - column;
                     }
+                    let row = self.scanline - sprite.y;
+                    debug_assert!(column < 8, "sprite X miscalculation");
+
+                    let pattern_addr = self.sprite_pattern_addr(
+                        sprite.tile_index_byte,
+                        sprite.attribute_byte,
+                        row,
+                    );
+                    let pattern_color = self.get_pattern_pixel(pattern_addr, column);
 
                     // If the pattern color was zero, this part of the sprite is transparent.
                     if pattern_color == 0 {
@@ -839,7 +1674,7 @@ impl Ppu {
 
                     // OK, so we know this pixel is opaque. Now if this is the first sprite and the
                     // background was not transparent, set sprite 0 hit.
-                    if index == 0 && background_opaque {
+                    if index == 0 && background_opaque && self.sprite_zero_hit_possible(x) {
                         self.regs.status.set_sprite_zero_hit(true);
                     }
 
@@ -855,130 +1690,1026 @@ impl Ppu {
                 }
             }
         }
-        return None;
+        None
     }
 
-    fn compute_visible_sprites(&mut self) -> [Option<u8>; 8] {
+    // Sprite 0 hit never happens on the last pixel of the scanline, nor on the leftmost eight
+    // while either the background or the sprites are masked there.
+    fn sprite_zero_hit_possible(&self, x: u8) -> bool {
+        let mask = self.regs.mask;
+        x != 255 && (x >= 8 || (mask.show_background_left() && mask.show_sprites_left()))
+    }
+
+    fn compute_visible_sprites(&mut self) -> [Option<u8>; 64] {
         let mut count = 0;
-        let mut result = [None; 8];
+        let mut result = [None; 64];
         self.each_sprite(|this, sprite, index| {
-            if sprite.on_scanline(this, this.scanline as u8) {
-                if count < 8 {
-                    result[count] = Some(index);
-                    count += 1;
-                    true
-                } else {
+            if !sprite.on_scanline(this, this.scanline as u8) {
+                return true;
+            }
+            if count >= SPRITES_PER_SCANLINE && this.sprite_limit {
+                return false;
+            }
+            result[count] = Some(index);
+            count += 1;
+            if count == SPRITES_PER_SCANLINE {
+                let (scanline, next) = (this.scanline, index as usize + 1);
+                if this.sprite_overflow(scanline, next) {
                     this.regs.status.set_sprite_overflow(true);
-                    false
                 }
-            } else {
-                true
             }
+            true
         });
         result
     }
 
-    fn render_scanline(&mut self) {
-        // TODO: Scrolling, mirroring
+    // Once the PPU has found eight sprites on a scanline, it goes on looking for a ninth to set
+    // the sprite overflow flag. But it steps through the bytes of each OAM entry along with the
+    // entries, so after the first it compares tile numbers, attributes and X coordinates against
+    // the scanline instead of Y coordinates: it misses some overflows and reports some that aren't
+    // there. `next` is the index of the sprite after the eighth.
+    fn sprite_overflow(&self, scanline: u16, next: usize) -> bool {
+        let height = match self.regs.ctrl.sprite_size() {
+            SpriteSize::SpriteSize8x8 => 8,
+            SpriteSize::SpriteSize8x16 => 16,
+        };
+        let mut byte = 0;
+        for index in next..64 {
+            // Sprites are drawn a line below their Y coordinate.
+            let y = self.oam.oam[index * 4 + byte] as u16;
+            if scanline.wrapping_sub(y + 1) < height {
+                return true;
+            }
+            byte = (byte + 1) % 4;
+        }
+        false
+    }
+
+    // Draws pixels `start` up to `end` of the current scanline.
+    fn render_pixels(&mut self, start: u16, end: u16) {
         let visible_sprites = self.compute_visible_sprites();
+        if self.skip_drawing {
+            self.check_sprite_zero_hit(&visible_sprites, start, end);
+            return;
+        }
 
         let backdrop_color_index = self.vram.loadb(0x3f00) & 0x3f;
         let backdrop_color = self.get_color(backdrop_color_index);
 
-        for x in 0..SCREEN_WIDTH {
+        for x in start as usize..end as usize {
             // FIXME: For performance, we shouldn't be recomputing the tile for every pixel.
             let mut background_color = None;
-            if self.regs.mask.show_background() {
+            if self.show_background_at(x as u16) {
                 background_color = self.get_background_pixel(x as u8);
             }
 
             let mut sprite_color = None;
-            if self.regs.mask.show_sprites() {
+            if self.show_sprites_at(x as u16) {
                 sprite_color =
                     self.get_sprite_pixel(&visible_sprites, x as u8, background_color.is_some());
             }
 
-            // Combine colors using priority.
-            let color = match (background_color, sprite_color) {
-                (None, None) => backdrop_color,
-                (Some(color), None) => color,
-                (
-                    Some(color),
-                    Some(SpriteColor {
-                        priority: BelowBg, ..
-                    }),
-                ) => color,
-                (
-                    None,
-                    Some(SpriteColor {
-                        priority: BelowBg,
-                        color,
-                    }),
-                ) => color,
-                (
-                    _,
-                    Some(SpriteColor {
-                        priority: AboveBg,
-                        color,
-                    }),
-                ) => color,
-            };
-
+            let color = combine(backdrop_color, background_color, sprite_color);
             let scanline = self.scanline;
             self.putpixel(x, scanline as usize, color);
         }
     }
 
-    fn start_vblank(&mut self, result: &mut StepResult) {
-        self.regs.status.set_in_vblank(true);
+    // Sets the sprite 0 hit flag if sprite 0 hits the background between pixels `start` and
+    // `end`, without drawing anything. Games wait on the flag, so it must be set even in frames
+    // that aren't drawn.
+    fn check_sprite_zero_hit(&mut self, visible_sprites: &[Option<u8>; 64], start: u16, end: u16) {
+        // Sprite 0 comes first in the list if it's on this scanline at all.
+        if visible_sprites[0] != Some(0) {
+            return;
+        }
+        let mut sprite_zero = [None; 64];
+        sprite_zero[0] = Some(0);
+        let sprite_x = self.make_sprite_info(0).x as u16;
+        for x in cmp::max(start, sprite_x)..cmp::min(end, sprite_x + 8) {
+            if self.show_background_at(x)
+                && self.show_sprites_at(x)
+                && self.get_background_pixel(x as u8).is_some()
+            {
+                self.get_sprite_pixel(&sprite_zero, x as u8, true);
+            }
+        }
+    }
 
-        // FIXME: Is this correct? Or does it happen on the *next* frame?
-        self.regs.status.set_sprite_zero_hit(false);
+    // The background and sprites can each be hidden in the leftmost eight pixels, which games
+    // use to cover up the tiles scrolling in at the edge.
+    fn show_background_at(&self, x: u16) -> bool {
+        let mask = self.regs.mask;
+        mask.show_background() && (x >= 8 || mask.show_background_left())
+    }
+    fn show_sprites_at(&self, x: u16) -> bool {
+        let mask = self.regs.mask;
+        mask.show_sprites() && (x >= 8 || mask.show_sprites_left())
+    }
+
+    fn rendering_enabled(&self) -> bool {
+        self.regs.mask.show_background() || self.regs.mask.show_sprites()
+    }
+
+    // Tells the mapper the level of A12 during each fetch the PPU makes on this scanline.
+    // Scanlines are drawn in one go, so this replays the hardware's fetch order rather than the
+    // order in which `render_scanline` happens to read memory. Only A12 is reported, which
+    // depends on the pattern tables in use and not on the tiles themselves.
+    fn report_a12(&mut self) {
+        // Up to eight sprites are fetched for the line. Unused slots fetch tile $FF.
+        let mut tiles = [0xff; SPRITES_PER_SCANLINE];
+        if self.scanline < (SCREEN_HEIGHT as u16) {
+            let mut count = 0;
+            self.each_sprite(|this, sprite, _| {
+                if sprite.on_scanline(this, this.scanline as u8) {
+                    tiles[count] = sprite.tile_index_byte;
+                    count += 1;
+                }
+                count < SPRITES_PER_SCANLINE
+            });
+        }
+
+        // 32 background tiles, then the sprites, then the first two tiles of the next line.
+        let ctrl = self.regs.ctrl;
+        let mut groups = [false; 42];
+        for (i, a12) in groups.iter_mut().enumerate() {
+            *a12 = match (i, ctrl.sprite_size()) {
+                (0...31, _) | (40...41, _) => ctrl.background_pattern_table_addr() != 0,
+                (_, SpriteSize::SpriteSize8x8) => ctrl.sprite_pattern_table_addr() != 0,
+                (_, SpriteSize::SpriteSize8x16) => (tiles[i - 32] & 1) != 0,
+            };
+        }
+
+        // Each fetch group is a nametable byte, an attribute byte (or a second nametable byte,
+        // for sprites), and the two pattern planes, two PPU cycles apart.
+        let start = self.dots;
+        let mut mapper = self.vram.mapper.borrow_mut();
+        for (i, &pattern_a12) in groups.iter().enumerate() {
+            let group_start = start + 1 + 8 * i as u64;
+            for (offset, &a12) in [false, false, pattern_a12, pattern_a12].iter().enumerate() {
+                mapper.ppu_a12(a12, group_start + 2 * offset as u64);
+            }
+        }
+        // The two nametable fetches at the end of the line.
+        mapper.ppu_a12(false, start + PPU_CYCLES_PER_SCANLINE - 4);
+        mapper.ppu_a12(false, start + PPU_CYCLES_PER_SCANLINE - 2);
+    }
+
+    /// The scanline before the first visible one, which is the last of the frame.
+    fn pre_render_scanline(&self) -> u16 {
+        self.timing.scanlines - 1
+    }
+
+    /// The number of dots the PPU has run by the given CPU cycle.
+    fn dots_at(&self, cpu_cycle: u64) -> u64 {
+        cpu_cycle * self.timing.dots / self.timing.cpu_cycles
+    }
+
+    fn start_vblank(&mut self) {
+        self.regs.status.set_in_vblank(true);
 
         if self.regs.ctrl.vblank_nmi() {
-            result.vblank_nmi = true;
+            self.events.push_back(PpuEvent::VblankNmi);
+        }
+        if let Some(ref mut callback) = self.vblank_callback {
+            callback(&mut self.vram, &mut self.oam);
         }
     }
 
-    #[inline(never)]
-    pub fn step(&mut self, run_to_cycle: u64) -> StepResult {
-        let mut result = StepResult {
-            new_frame: false,
-            vblank_nmi: false,
-            scanline_irq: false,
+    fn start_scanline(&mut self) {
+        if let Some(ref mut callback) = self.scanline_callback {
+            callback(self.scanline, &mut self.vram, &mut self.oam);
+        }
+    }
+
+    //
+    // Dot-stepped rendering
+    //
+
+    /// Loads a byte for rendering, telling the mapper the level of A12 as it goes out.
+    fn fetch(&mut self, addr: u16) -> u8 {
+        let a12 = (addr & 0x1000) != 0;
+        self.vram.mapper.borrow_mut().ppu_a12(a12, self.dots);
+        self.vram.loadb(addr)
+    }
+
+    fn increment_x(&mut self) {
+        let v = self.regs.v;
+        self.regs.v = if (v & COARSE_X) == COARSE_X {
+            (v & !COARSE_X) ^ NAMETABLE_X
+        } else {
+            v + 1
         };
+    }
+
+    /// Moves the fetched tile into the low bytes of the shift registers.
+    fn reload_shifters(&mut self) {
+        let pipeline = &mut self.pipeline;
+        pipeline.shift_pattern_lo =
+            (pipeline.shift_pattern_lo & 0xff00) | pipeline.pattern_lo as u16;
+        pipeline.shift_pattern_hi =
+            (pipeline.shift_pattern_hi & 0xff00) | pipeline.pattern_hi as u16;
+        let (lo, hi) = (pipeline.attribute_bits & 1, pipeline.attribute_bits >> 1);
+        pipeline.shift_attribute_lo = (pipeline.shift_attribute_lo & 0xff00) | (lo as u16 * 0xff);
+        pipeline.shift_attribute_hi = (pipeline.shift_attribute_hi & 0xff00) | (hi as u16 * 0xff);
+    }
+
+    fn shift(&mut self) {
+        let pipeline = &mut self.pipeline;
+        pipeline.shift_pattern_lo <<= 1;
+        pipeline.shift_pattern_hi <<= 1;
+        pipeline.shift_attribute_lo <<= 1;
+        pipeline.shift_attribute_hi <<= 1;
+    }
+
+    /// Runs one step of the background fetches, which take eight dots per tile: the nametable
+    /// byte, the attribute byte and the two pattern planes, two dots each.
+    fn fetch_background(&mut self, dot: u16) {
+        let v = self.regs.v;
+        match (dot - 1) % 8 {
+            0 => {
+                self.pipeline.nametable_byte = self.fetch(0x2000 | (v & 0x0fff));
+            }
+            2 => {
+                let addr = 0x23c0 | (v & 0x0c00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07);
+                let shift = ((v >> 4) & 4) | (v & 2);
+                self.pipeline.attribute_bits = (self.fetch(addr) >> shift) & 3;
+            }
+            4 | 6 => {
+                let tile = self.pipeline.nametable_byte as u16;
+                let fine_y = (v & FINE_Y) >> 12;
+                let addr = self.regs.ctrl.background_pattern_table_addr() + tile * 16 + fine_y;
+                if (dot - 1) % 8 == 4 {
+                    self.pipeline.pattern_lo = self.fetch(addr);
+                } else {
+                    self.pipeline.pattern_hi = self.fetch(addr + 8);
+                }
+            }
+            7 => self.increment_x(),
+            _ => {}
+        }
+    }
+
+    /// Finds the sprites on the next scanline and loads their patterns. The hardware does this
+    /// over the dots of the scanline being drawn and the ones after it; only the times of the
+    /// pattern fetches, which the mapper can see, are kept.
+    fn evaluate_sprites(&mut self, next_scanline: u16) {
+        let height = match self.regs.ctrl.sprite_size() {
+            SpriteSize::SpriteSize8x8 => 8,
+            SpriteSize::SpriteSize8x16 => 16,
+        };
+
+        let mut count = 0;
+        for index in 0..64 {
+            // Sprites are drawn a line below their Y coordinate.
+            let base = index * 4;
+            let y = self.oam.oam[base] as u16;
+            let row = next_scanline.wrapping_sub(y + 1);
+            if row >= height {
+                continue;
+            }
+            if count >= SPRITES_PER_SCANLINE && self.sprite_limit {
+                break;
+            }
+            if count == SPRITES_PER_SCANLINE - 1 && self.sprite_overflow(next_scanline, index + 1) {
+                self.regs.status.set_sprite_overflow(true);
+            }
+
+            let (tile_index_byte, attribute_byte) =
+                (self.oam.oam[base + 1], self.oam.oam[base + 2]);
+            let addr = self.sprite_pattern_addr(tile_index_byte, attribute_byte, row);
+            let (mut pattern_lo, mut pattern_hi) =
+                (self.vram.loadb(addr), self.vram.loadb(addr + 8));
+            if (attribute_byte & 0x40) != 0 {
+                pattern_lo = pattern_lo.reverse_bits();
+                pattern_hi = pattern_hi.reverse_bits();
+            }
+            self.pipeline.sprites[count] = LineSprite {
+                x: self.oam.oam[base + 3],
+                attribute_byte: attribute_byte,
+                pattern_lo: pattern_lo,
+                pattern_hi: pattern_hi,
+                zero: index == 0,
+            };
+            if count < SPRITES_PER_SCANLINE {
+                self.pipeline.sprite_a12[count] = (addr & 0x1000) != 0;
+            }
+            count += 1;
+        }
+        self.pipeline.sprite_count = count;
+
+        // Unused slots fetch tile $FF.
+        let empty_a12 = (self.sprite_pattern_addr(0xff, 0, 0) & 0x1000) != 0;
+        for a12 in self.pipeline.sprite_a12[count.min(SPRITES_PER_SCANLINE)..].iter_mut() {
+            *a12 = empty_a12;
+        }
+    }
+
+    /// Draws the pixel at `x` on the current scanline from the shift registers and the sprites.
+    fn output_dot(&mut self, x: u16) {
+        let mut background_color = None;
+        if self.show_background_at(x) {
+            let bit = 15 - self.regs.x as u16;
+            let pipeline = &self.pipeline;
+            let pattern_color = ((pipeline.shift_pattern_hi >> bit) & 1) << 1
+                | (pipeline.shift_pattern_lo >> bit) & 1;
+            if pattern_color != 0 {
+                let attr_table_color = ((pipeline.shift_attribute_hi >> bit) & 1) << 1
+                    | (pipeline.shift_attribute_lo >> bit) & 1;
+                let tile_color = (attr_table_color << 2) as u8 | pattern_color as u8;
+                let palette_index = self.vram.loadb(0x3f00 + (tile_color as u16)) & 0x3f;
+                background_color = Some(self.get_color(palette_index));
+            }
+        }
+
+        let mut sprite_color = None;
+        if self.show_sprites_at(x) {
+            for i in 0..self.pipeline.sprite_count {
+                let sprite = self.pipeline.sprites[i];
+                let offset = x.wrapping_sub(sprite.x as u16);
+                if offset >= 8 {
+                    continue;
+                }
+                let bit = 7 - offset;
+                let pattern_color =
+                    ((sprite.pattern_hi >> bit) & 1) << 1 | (sprite.pattern_lo >> bit) & 1;
+                if pattern_color == 0 {
+                    continue;
+                }
+
+                if sprite.zero
+                    && background_color.is_some()
+                    && self.sprite_zero_hit_possible(x as u8)
+                {
+                    self.regs.status.set_sprite_zero_hit(true);
+                }
+
+                let tile_color = ((sprite.attribute_byte & 3) + 4) << 2 | pattern_color;
+                let palette_index = self.vram.loadb(0x3f00 + (tile_color as u16)) & 0x3f;
+                let priority = if (sprite.attribute_byte & 0x20) == 0 {
+                    AboveBg
+                } else {
+                    BelowBg
+                };
+                sprite_color = Some(SpriteColor {
+                    priority: priority,
+                    color: self.get_color(palette_index),
+                });
+                break;
+            }
+        }
+
+        let backdrop_color_index = self.vram.loadb(0x3f00) & 0x3f;
+        let backdrop_color = self.get_color(backdrop_color_index);
+        let color = combine(backdrop_color, background_color, sprite_color);
+        let scanline = self.scanline as usize;
+        self.putpixel(x as usize, scanline, color);
+    }
+
+    /// Runs the fetches the PPU makes on a visible or pre-render scanline at the given dot.
+    fn fetch_dot(&mut self, dot: u16, pre_render: bool) {
+        match dot {
+            1...256 | 321...336 => {
+                if dot != 1 && dot != 321 {
+                    self.shift();
+                }
+                if (dot - 1) % 8 == 0 && dot != 1 && dot != 321 {
+                    self.reload_shifters();
+                }
+                self.fetch_background(dot);
+                if dot == 256 {
+                    self.increment_y();
+                }
+            }
+            257 => {
+                self.shift();
+                self.reload_shifters();
+                self.copy_horizontal_scroll();
+                let next_scanline = if pre_render { 0 } else { self.scanline + 1 };
+                self.evaluate_sprites(next_scanline);
+            }
+            337 => {
+                self.shift();
+                self.reload_shifters();
+                // The first of two nametable fetches whose results go unused.
+                let addr = 0x2000 | (self.regs.v & 0x0fff);
+                self.fetch(addr);
+            }
+            339 => {
+                let addr = 0x2000 | (self.regs.v & 0x0fff);
+                self.fetch(addr);
+            }
+            _ => {}
+        }
+
+        // The sprite pattern fetches, in the last two dots of each eight from 257.
+        if (257..=320).contains(&dot) && (dot - 257) % 8 >= 4 && (dot - 257) % 2 == 0 {
+            let a12 = self.pipeline.sprite_a12[(dot as usize - 257) / 8];
+            self.vram.mapper.borrow_mut().ppu_a12(a12, self.dots);
+        }
+
+        if pre_render && (280..=304).contains(&dot) {
+            self.copy_vertical_scroll();
+        }
+    }
+
+    /// Runs the PPU for one dot.
+    fn step_dot(&mut self) {
+        let (scanline, dot) = (self.scanline, self.pipeline.dot);
+        let visible = scanline < (SCREEN_HEIGHT as u16);
+        let pre_render = scanline == self.pre_render_scanline();
+
+        if scanline == self.timing.vblank_scanline && dot == 1 {
+            self.start_vblank();
+        } else if pre_render && dot == 1 {
+            self.regs.status.set_in_vblank(false);
+            self.regs.status.set_sprite_zero_hit(false);
+            self.regs.status.set_sprite_overflow(false);
+        }
+
+        let rendering = self.rendering_enabled();
+        if rendering && (visible || pre_render) {
+            self.fetch_dot(dot, pre_render);
+        }
+        if visible && (1..=256).contains(&dot) {
+            self.output_dot(dot - 1);
+        }
+
+        self.pipeline.dot += 1;
+        if pre_render && dot == 339 && rendering && self.odd_frame && self.timing.skips_dot {
+            self.pipeline.dot += 1;
+        }
+        if self.pipeline.dot as u64 == PPU_CYCLES_PER_SCANLINE {
+            self.pipeline.dot = 0;
+            self.scanline += 1;
+            self.vram.mapper.borrow_mut().next_scanline();
+            let line = self.scanline - 1;
+            self.note_scanline_irq(line);
+            if self.scanline == self.timing.scanlines {
+                self.events.push_back(PpuEvent::FrameComplete);
+                self.scanline = 0;
+                self.odd_frame = !self.odd_frame;
+            }
+            self.start_scanline();
+        }
+    }
+
+    fn step_dots(&mut self, run_to_cycle: u64) {
+        while self.dots < self.dots_at(run_to_cycle) {
+            self.step_dot();
+            self.dots += 1;
+        }
+    }
+
+    //
+    // Scanline-at-a-time rendering
+    //
+
+    /// Does the work of the current scanline up to the given dot: drawing its pixels, and the
+    /// scroll updates at the end of it. Anything already done is skipped.
+    fn run_scanline_to(&mut self, dot: u16) {
+        let from = self.line_progress;
+        if dot <= from {
+            return;
+        }
+        self.line_progress = dot;
+
+        // Pixel x comes out on dot x + 1.
+        let visible = self.scanline < (SCREEN_HEIGHT as u16);
+        let width = SCREEN_WIDTH as u16;
+        if visible && from < width {
+            self.render_pixels(from, dot.min(width));
+        }
+
+        let pre_render = self.scanline == self.pre_render_scanline();
+        if (visible || pre_render) && self.rendering_enabled() {
+            let passes = |event: u16| from < event && dot >= event;
+            if passes(256) {
+                self.increment_y();
+            }
+            if passes(257) {
+                self.copy_horizontal_scroll();
+            }
+            // On the pre-render line, go back to the top.
+            if pre_render && passes(304) {
+                self.copy_vertical_scroll();
+            }
+        }
+    }
+
+    /// Brings the current scanline up to where the CPU is, before a register write changes how
+    /// the rest of it is drawn.
+    fn catch_up_scanline(&mut self) {
+        if self.caught_up > self.dots {
+            let dot = (self.caught_up - self.dots).min(PPU_CYCLES_PER_SCANLINE - 1);
+            self.run_scanline_to(dot as u16);
+        }
+    }
+
+    /// Runs the PPU up to the given CPU cycle, queueing up events for what happened.
+    #[inline(never)]
+    pub fn step(&mut self, run_to_cycle: u64) {
+        if self.dot_stepped {
+            return self.step_dots(run_to_cycle);
+        }
+
+        self.caught_up = self.dots_at(run_to_cycle);
         loop {
-            let next_scanline_cycle: u64 = self.cy + CYCLES_PER_SCANLINE;
-            if next_scanline_cycle > run_to_cycle {
+            // With rendering on, the pre-render scanline of every other frame skips its last dot.
+            let mut scanline_dots = PPU_CYCLES_PER_SCANLINE;
+            if self.scanline == self.pre_render_scanline()
+                && self.odd_frame
+                && self.timing.skips_dot
+                && self.rendering_enabled()
+            {
+                scanline_dots -= 1;
+            }
+            if self.dots + scanline_dots > self.caught_up {
                 break;
             }
 
-            if self.scanline < (SCREEN_HEIGHT as u16) {
-                self.render_scanline();
+            self.run_scanline_to(scanline_dots as u16);
+            let fetching = self.scanline < (SCREEN_HEIGHT as u16)
+                || self.scanline == self.pre_render_scanline();
+            if fetching && self.rendering_enabled() {
+                self.report_a12();
             }
+            self.line_progress = 0;
+            self.scroll_origin = 0;
 
             self.scanline += 1;
+            self.vram.mapper.borrow_mut().next_scanline();
+            let line = self.scanline - 1;
+            self.note_scanline_irq(line);
+
+            if self.scanline == self.timing.vblank_scanline {
+                self.start_vblank();
+            } else if self.scanline == self.pre_render_scanline() {
+                // The flags are cleared at the start of the pre-render line, as when dot-stepped.
+                self.regs.status.set_in_vblank(false);
+                self.regs.status.set_sprite_zero_hit(false);
+                self.regs.status.set_sprite_overflow(false);
+            } else if self.scanline == self.timing.scanlines {
+                self.events.push_back(PpuEvent::FrameComplete);
+                self.scanline = 0;
+                self.odd_frame = !self.odd_frame;
+            }
+            self.start_scanline();
+
+            self.dots += scanline_dots;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mapper::{self, Nrom};
+    use rom::Rom;
+
+    use std::env;
+    use std::fs::{self, File};
+
+    // A PPU on an NTSC console with an NROM cartridge whose CHR-ROM is blank.
+    fn ppu() -> Ppu {
+        ppu_with_chr(&[0; 0x2000])
+    }
+
+    // A PPU on an NTSC console with an NROM cartridge with the given 8K of CHR-ROM.
+    fn ppu_with_chr(chr: &[u8]) -> Ppu {
+        ppu_in_region(chr, Region::Ntsc)
+    }
+
+    fn ppu_in_region(chr: &[u8], region: Region) -> Ppu {
+        let mut image = b"NES\x1a\x01\x01".to_vec();
+        image.resize(16 + 0x4000, 0);
+        image.extend_from_slice(chr);
+        let rom = Box::new(Rom::from_bytes(&image).unwrap());
+        let mapper: Box<Mapper + Send> = Box::new(Nrom::new(rom));
+        let vram = Vram::new(Rc::new(RefCell::new(mapper)), Mirroring::Horizontal);
+        Ppu::new(vram, Oam::new(), region, PixelFormat::Bgr24)
+    }
+
+    // Writes bytes to VRAM from `addr` on through PPUADDR and PPUDATA.
+    fn write_vram(ppu: &mut Ppu, addr: u16, vals: &[u8]) {
+        ppu.storeb(0x2006, (addr >> 8) as u8);
+        ppu.storeb(0x2006, addr as u8);
+        for &val in vals {
+            ppu.storeb(0x2007, val);
+        }
+    }
+
+    // The color of the pixel at (x, y) of the last frame drawn.
+    fn pixel(ppu: &Ppu, x: usize, y: usize) -> Rgb {
+        let offset = (y * SCREEN_WIDTH + x) * 3;
+        let bgr = &ppu.screen[offset..offset + 3];
+        Rgb {
+            r: bgr[2],
+            g: bgr[1],
+            b: bgr[0],
+        }
+    }
+
+    #[test]
+    fn four_screen_header_gives_four_nametables() {
+        // NROM with the four-screen bit set in flags 6
+        let mut image = b"NES\x1a\x01\x01\x08".to_vec();
+        image.resize(16 + 0x4000 + 0x2000, 0);
+        let rom = Box::new(Rom::from_bytes(&image).unwrap());
+        let mirroring = rom.header.mirroring();
+        assert_eq!(mirroring, Mirroring::FourScreen);
+        let mapper: Box<Mapper + Send> = Box::new(Nrom::new(rom));
+        let mut vram = Vram::new(Rc::new(RefCell::new(mapper)), mirroring);
+        assert_eq!(vram.nametables.len(), 0x1000);
+
+        for (i, &addr) in [0x2000, 0x2400, 0x2800, 0x2c00].iter().enumerate() {
+            vram.storeb(addr + 5, i as u8 + 1);
+        }
+        for (i, &addr) in [0x2000, 0x2400, 0x2800, 0x2c00].iter().enumerate() {
+            assert_eq!(vram.loadb(addr + 5), i as u8 + 1);
+            assert_eq!(vram.loadb(addr + 0x1000 + 5), i as u8 + 1);
+        }
+    }
+
+    // The first CPU cycle on which the PPU is on the given NTSC scanline.
+    fn scanline_cycle(scanline: u64) -> u64 {
+        (scanline * PPU_CYCLES_PER_SCANLINE + 2) / 3
+    }
+
+    #[test]
+    fn status_flags_clear_at_start_of_pre_render_line() {
+        for &dot_stepped in [false, true].iter() {
+            let mut ppu = ppu();
+            ppu.set_dot_stepped(dot_stepped);
+            ppu.step(scanline_cycle(260));
+            assert!(*ppu.regs.status & 0x80 != 0);
+            ppu.regs.status.set_sprite_zero_hit(true);
+            ppu.regs.status.set_sprite_overflow(true);
+
+            ppu.step(scanline_cycle(261) + 1);
+            assert_eq!(*ppu.regs.status & 0xe0, 0);
+        }
+    }
+
+    #[test]
+    fn dot_stepped_savestate_restores_pipeline() {
+        let chr: Vec<u8> = (0..0x2000).map(|i| (i * 37) as u8).collect();
+        let mut ppu = ppu_with_chr(&chr);
+        ppu.set_dot_stepped(true);
+        for &(addr, val) in [
+            (0x2006, 0x3f),
+            (0x2006, 0x00),
+            (0x2007, 0x0f),
+            (0x2007, 0x16),
+        ]
+        .iter()
+        {
+            ppu.storeb(addr, val);
+        }
+        for &val in [0x2a, 0x12].iter() {
+            ppu.storeb(0x2007, val);
+        }
+        ppu.storeb(0x2001, 0x0a);
+
+        // Stop in the middle of scanline 100, with tiles in the shift registers.
+        ppu.step(scanline_cycle(100) + 50);
+        let path = env::temp_dir().join("sprocketnes-ppu-savestate-test");
+        ppu.save(&mut File::create(&path).unwrap());
+        let mut restored = ppu_with_chr(&chr);
+        restored.set_dot_stepped(true);
+        restored.load(&mut File::open(&path).unwrap());
+        let _ = fs::remove_file(&path);
+
+        ppu.step(scanline_cycle(240));
+        restored.step(scanline_cycle(240));
+        // Compare from a little past where the state was saved, around dot 150.
+        let start = (100 * SCREEN_WIDTH + 160) * PixelFormat::Bgr24.bytes_per_pixel();
+        assert!(ppu.screen[start..] == restored.screen[start..]);
+    }
+
+    #[test]
+    fn mapper_irq_is_reported_with_its_scanline() {
+        for &dot_stepped in [false, true].iter() {
+            let mut ppu = ppu();
+            ppu.set_dot_stepped(dot_stepped);
+            let irq = IrqLine::new();
+            ppu.connect_irq(irq.clone());
+
+            ppu.step(scanline_cycle(42) + 20);
+            irq.assert(IrqSource::Mapper);
+            ppu.step(scanline_cycle(45));
+            assert_eq!(ppu.pop_event(), Some(PpuEvent::ScanlineIrq { line: 42 }));
+            assert_eq!(ppu.pop_event(), None);
+
+            // The event is queued only when the IRQ is raised, not for as long as it's held.
+            irq.acknowledge(IrqSource::Mapper);
+            ppu.step(scanline_cycle(46) + 20);
+            irq.assert(IrqSource::Mapper);
+            ppu.step(scanline_cycle(48));
+            assert_eq!(ppu.pop_event(), Some(PpuEvent::ScanlineIrq { line: 46 }));
+            assert_eq!(ppu.pop_event(), None);
+        }
+    }
+
+    #[test]
+    fn savestate_keeps_queued_events() {
+        let mut saved = ppu();
+        saved.events.push_back(PpuEvent::ScanlineIrq { line: 17 });
+        saved.events.push_back(PpuEvent::VblankNmi);
+        let path = env::temp_dir().join("sprocketnes-ppu-events-test");
+        saved.save(&mut File::create(&path).unwrap());
+        let mut restored = ppu();
+        restored.load(&mut File::open(&path).unwrap());
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(
+            restored.pop_event(),
+            Some(PpuEvent::ScanlineIrq { line: 17 })
+        );
+        assert_eq!(restored.pop_event(), Some(PpuEvent::VblankNmi));
+        assert_eq!(restored.pop_event(), None);
+    }
 
+    #[test]
+    fn mmc3_irq_fires_on_the_scanline_it_counts_down_to() {
+        for &dot_stepped in [false, true].iter() {
+            let mut image = b"NES\x1a\x02\x01\x40".to_vec();
+            image.resize(16 + 0x8000 + 0x2000, 0);
+            let rom = Box::new(Rom::from_bytes(&image).unwrap());
+            let mut mapper = mapper::create_mapper(rom).unwrap();
+            let irq = IrqLine::new();
+            mapper.connect_irq(irq.clone());
+            let vram = Vram::new(Rc::new(RefCell::new(mapper)), Mirroring::Horizontal);
+            let mut ppu = Ppu::new(vram, Oam::new(), Region::Ntsc, PixelFormat::Bgr24);
+            ppu.set_dot_stepped(dot_stepped);
+            ppu.connect_irq(irq.clone());
+
+            // Background at $0000 and sprites at $1000, so A12 rises once a line, at the sprite
+            // fetches. The first rise loads the counter and the next 20 count it down.
+            ppu.storeb(0x2000, 0x08);
+            ppu.storeb(0x2001, 0x18);
             {
-                let mut mapper = self.vram.mapper.borrow_mut();
-                if mapper.next_scanline() == MapperResult::Irq {
-                    result.scanline_irq = true
+                let mut mapper = ppu.vram.mapper.borrow_mut();
+                mapper.prg_storeb(0xc000, 20);
+                mapper.prg_storeb(0xc001, 0);
+                mapper.prg_storeb(0xe001, 0);
+            }
+
+            ppu.step(scanline_cycle(20) + 80);
+            assert!(!irq.asserted());
+            ppu.step(scanline_cycle(22));
+            assert!(irq.asserted());
+            assert_eq!(ppu.pop_event(), Some(PpuEvent::ScanlineIrq { line: 20 }));
+        }
+    }
+
+    #[test]
+    fn sprite_overflow_scan_compares_the_wrong_bytes() {
+        for &dot_stepped in [false, true].iter() {
+            // Eight sprites on scanlines 51-58, and the next two off screen. The second of those
+            // has a tile number of 50, which the scan takes for a Y coordinate.
+            let mut found = ppu();
+            found.set_dot_stepped(dot_stepped);
+            for byte in found.oam.oam.iter_mut() {
+                *byte = 0xff;
+            }
+            for sprite in 0..8 {
+                found.oam.oam[sprite * 4] = 50;
+            }
+            found.oam.oam[9 * 4 + 1] = 50;
+            found.storeb(0x2001, 0x18);
+            found.step(scanline_cycle(60));
+            assert!(*found.regs.status & 0x20 != 0);
+
+            // A real ninth sprite is missed when the scan has moved on from the Y coordinates.
+            let mut missed = ppu();
+            missed.set_dot_stepped(dot_stepped);
+            for byte in missed.oam.oam.iter_mut() {
+                *byte = 0xff;
+            }
+            for sprite in (0..8).chain(9..10) {
+                missed.oam.oam[sprite * 4] = 50;
+            }
+            missed.storeb(0x2001, 0x18);
+            missed.step(scanline_cycle(60));
+            assert!(*missed.regs.status & 0x20 == 0);
+        }
+    }
+
+    #[test]
+    fn oamdata_reads_attribute_bits_2_to_4_as_0() {
+        let mut ppu = ppu();
+        ppu.storeb(0x2003, 1);
+        for &val in [0xff, 0xff, 0xff].iter() {
+            ppu.storeb(0x2004, val);
+        }
+        ppu.storeb(0x2003, 1);
+        assert_eq!(ppu.loadb(0x2004), 0xff);
+        ppu.storeb(0x2003, 2);
+        assert_eq!(ppu.loadb(0x2004), 0xe3);
+        ppu.storeb(0x2003, 3);
+        assert_eq!(ppu.loadb(0x2004), 0xff);
+    }
+
+    #[test]
+    fn palette_reads_fill_the_buffer_from_the_nametable_underneath() {
+        let mut ppu = ppu();
+        write_vram(&mut ppu, 0x2f05, &[0x77]);
+        write_vram(&mut ppu, 0x3f05, &[0x12]);
+
+        // The palette byte comes back straight away, with no dummy read.
+        write_vram(&mut ppu, 0x3f05, &[]);
+        assert_eq!(ppu.loadb(0x2007), 0x12);
+        write_vram(&mut ppu, 0x2000, &[]);
+        assert_eq!(ppu.loadb(0x2007), 0x77);
+    }
+
+    #[test]
+    fn palette_mirrors_sprite_backdrops_onto_the_background() {
+        let mut ppu = ppu();
+        write_vram(&mut ppu, 0x3f10, &[0x21]);
+        write_vram(&mut ppu, 0x3f14, &[0x22]);
+        write_vram(&mut ppu, 0x3f11, &[0x23]);
+        for &(addr, val) in [
+            (0x3f00, 0x21),
+            (0x3f04, 0x22),
+            (0x3f01, 0x00),
+            (0x3f31, 0x23),
+        ]
+        .iter()
+        {
+            write_vram(&mut ppu, addr, &[]);
+            assert_eq!(ppu.loadb(0x2007), val);
+        }
+    }
+
+    // The lengths in dots of the frames after the first, while the PPU runs `frames` of them.
+    fn frame_lengths(ppu: &mut Ppu, frames: usize) -> Vec<u64> {
+        let mut starts = vec![];
+        let mut cycle = 0;
+        while starts.len() <= frames {
+            cycle += 1;
+            ppu.step(cycle);
+            while let Some(event) = ppu.pop_event() {
+                if event == PpuEvent::FrameComplete {
+                    // The dots run so far on the first scanline
+                    let dot = if ppu.dot_stepped {
+                        ppu.pipeline.dot as u64
+                    } else {
+                        0
+                    };
+                    starts.push(ppu.dots - dot);
                 }
             }
+        }
+        starts.windows(2).map(|pair| pair[1] - pair[0]).collect()
+    }
 
-            if self.scanline == (VBLANK_SCANLINE as u16) {
-                self.start_vblank(&mut result);
-            } else if self.scanline == (LAST_SCANLINE as u16) {
-                result.new_frame = true;
-                self.scanline = 0;
-                self.regs.status.set_in_vblank(false);
+    #[test]
+    fn odd_frames_are_a_dot_shorter_with_rendering_on() {
+        for &dot_stepped in [false, true].iter() {
+            let mut on = ppu();
+            on.set_dot_stepped(dot_stepped);
+            on.storeb(0x2001, 0x08);
+            assert_eq!(frame_lengths(&mut on, 3), [89341, 89342, 89341]);
+
+            let mut off = ppu();
+            off.set_dot_stepped(dot_stepped);
+            assert_eq!(frame_lengths(&mut off, 2), [89342, 89342]);
+        }
+    }
+
+    #[test]
+    fn pal_and_dendy_frames_have_312_lines_and_no_short_frames() {
+        for &region in [Region::Pal, Region::Dendy].iter() {
+            let mut ppu = ppu_in_region(&[0; 0x2000], region);
+            ppu.storeb(0x2001, 0x08);
+            assert_eq!(frame_lengths(&mut ppu, 2), [312 * 341, 312 * 341]);
+        }
+
+        // Dendy vertical blank starts 50 lines late, on the same clock as NTSC.
+        let mut ppu = ppu_in_region(&[0; 0x2000], Region::Dendy);
+        ppu.step(scanline_cycle(290));
+        assert!(*ppu.regs.status & 0x80 == 0);
+        ppu.step(scanline_cycle(292));
+        assert!(*ppu.regs.status & 0x80 != 0);
+    }
+
+    #[test]
+    fn sprites_8x16_take_their_pattern_table_from_the_tile_number() {
+        let mut ppu = ppu();
+        ppu.storeb(0x2000, 0x20);
+        assert_eq!(ppu.sprite_pattern_addr(0x03, 0x00, 0), 0x1020);
+        assert_eq!(ppu.sprite_pattern_addr(0x03, 0x00, 9), 0x1031);
+        assert_eq!(ppu.sprite_pattern_addr(0x02, 0x00, 0), 0x0020);
+        // Flipped, the bottom tile comes first.
+        assert_eq!(ppu.sprite_pattern_addr(0x03, 0x80, 0), 0x1037);
+        assert_eq!(ppu.sprite_pattern_addr(0x03, 0x80, 15), 0x1020);
+    }
+
+    #[test]
+    fn grayscale_and_emphasis_change_the_colors() {
+        let mut ppu = ppu();
+        let plain = ppu.get_color(0x16);
+        ppu.storeb(0x2001, 0x01);
+        assert_eq!(ppu.get_color(0x16), ppu.get_color(0x10));
+        ppu.storeb(0x2001, 0x20);
+        let emphasized = ppu.get_color(0x16);
+        assert!(emphasized != plain);
+        assert_eq!(emphasized, ppu.palettes[1][0x16]);
+    }
+
+    // A PPU with a solid background, in palette color 1, and a solid tile 0 for sprites.
+    fn solid_ppu() -> Ppu {
+        let mut chr = [0; 0x2000];
+        for byte in chr[0..8].iter_mut() {
+            *byte = 0xff;
+        }
+        let mut ppu = ppu_with_chr(&chr);
+        write_vram(&mut ppu, 0x3f00, &[0x0f, 0x30]);
+        write_vram(&mut ppu, 0x2000, &[]);
+        for byte in ppu.oam.oam.iter_mut() {
+            *byte = 0xff;
+        }
+        ppu
+    }
+
+    #[test]
+    fn left_column_of_background_can_be_hidden() {
+        for &dot_stepped in [false, true].iter() {
+            for &(mask, left_shown) in [(0x0a, true), (0x08, false)].iter() {
+                let mut ppu = solid_ppu();
+                ppu.set_dot_stepped(dot_stepped);
+                ppu.storeb(0x2001, mask);
+                ppu.step(scanline_cycle(20));
+                let backdrop = ppu.get_color(0x0f);
+                let background = ppu.get_color(0x30);
+                assert_eq!(pixel(&ppu, 10, 10), background);
+                let left = if left_shown { background } else { backdrop };
+                assert_eq!(pixel(&ppu, 3, 10), left);
             }
+        }
+    }
 
-            self.cy += CYCLES_PER_SCANLINE;
+    #[test]
+    fn sprite_zero_hits_only_where_both_layers_show() {
+        for &dot_stepped in [false, true].iter() {
+            for &(mask, x, hit) in [
+                (0x1e, 0, true),
+                (0x18, 0, false),
+                (0x18, 1, true),
+                (0x1e, 255, false),
+                (0x08, 20, false),
+            ]
+            .iter()
+            {
+                let mut ppu = solid_ppu();
+                ppu.set_dot_stepped(dot_stepped);
+                ppu.oam.oam[0..4].copy_from_slice(&[9, 0, 0, x]);
+                ppu.storeb(0x2001, mask);
+                ppu.step(scanline_cycle(20));
+                assert_eq!(
+                    *ppu.regs.status & 0x40 != 0,
+                    hit,
+                    "mask {:x}, x {}",
+                    mask,
+                    x
+                );
+            }
+        }
+    }
 
-            debug_assert!(self.cy % CYCLES_PER_SCANLINE == 0, "at even scanline cycle");
+    #[test]
+    fn writes_mid_scanline_take_effect_from_the_next_pixel() {
+        for &dot_stepped in [false, true].iter() {
+            // The background is transparent, so the backdrop shows everywhere.
+            let mut ppu = ppu();
+            ppu.set_dot_stepped(dot_stepped);
+            write_vram(&mut ppu, 0x3f00, &[0x01]);
+            ppu.storeb(0x2001, 0x0a);
+
+            // Dot 100 of line 10
+            ppu.step(scanline_cycle(10) + 33);
+            write_vram(&mut ppu, 0x3f00, &[0x21]);
+            ppu.step(scanline_cycle(20));
+
+            let (before, after) = (ppu.get_color(0x01), ppu.get_color(0x21));
+            assert_eq!(pixel(&ppu, 50, 10), before);
+            assert_eq!(pixel(&ppu, 150, 10), after);
+            assert_eq!(pixel(&ppu, 50, 9), before);
+            assert_eq!(pixel(&ppu, 50, 11), after);
         }
+    }
+
+    #[test]
+    fn open_bus_bits_decay_unless_refreshed() {
+        let mut ppu = ppu();
+        ppu.storeb(0x2000, 0x1f);
+        assert_eq!(ppu.loadb(0x2005), 0x1f);
+        // PPUSTATUS drives only its top three bits.
+        assert_eq!(ppu.loadb(0x2002) & 0x1f, 0x1f);
 
-        return result;
+        ppu.step(OPEN_BUS_DECAY_DOTS / 3 + 1);
+        assert_eq!(ppu.loadb(0x2005), 0);
     }
 }
@@ -1,42 +1,176 @@
-//! Contains iNES ROM loading code.
+//! Contains iNES and UNIF ROM loading code. Either can also be loaded from a zip or gzip archive.
+//! The headers of iNES images are checked against the ROM database in `romdb`.
 
 //
 // Author: Patrick Walton
 //
 
+use mapper::Mirroring;
+use romdb;
 use util;
+use zip;
+
+use flate2::read::GzDecoder;
 
 use std::fmt;
+use std::fs::File;
 use std::io::{self, Read};
+use std::path::Path;
 use std::vec::Vec;
 
 #[derive(Debug)]
-pub enum RomLoadError {
+pub enum RomError {
     /// IO error while reading the ROM image
-    IoError(io::Error),
-    /// The ROM image has an invalid format
-    FormatError,
+    Io(io::Error),
+    /// The file is neither an iNES nor a UNIF image
+    BadMagic,
+    /// The image ends before all of the data its header promises
+    Truncated,
+    /// The image is for hardware other than a plain NES or Famicom
+    UnsupportedVariant(&'static str),
+    /// The UNIF image is for a board with the given name, which has no known mapper number
+    UnknownBoard(String),
+    /// The UNIF image has a PRG or CHR chunk whose ID doesn't end in a hex digit
+    BadChunk(String),
+    /// The zip archive contains no file with a ROM image's extension
+    NoRomInArchive,
+    /// The file starts like a zip archive but has no central directory, or a corrupt one
+    BadArchive,
 }
 
-impl From<io::Error> for RomLoadError {
+impl From<io::Error> for RomError {
     fn from(err: io::Error) -> Self {
-        RomLoadError::IoError(err)
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof => RomError::Truncated,
+            _ => RomError::Io(err),
+        }
+    }
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            RomError::Io(ref err) => write!(f, "{}", err),
+            RomError::BadMagic => write!(f, "not an iNES or UNIF ROM image"),
+            RomError::Truncated => write!(f, "the ROM image is truncated"),
+            RomError::UnsupportedVariant(variant) => write!(f, "{} aren't supported", variant),
+            RomError::UnknownBoard(ref board) => write!(f, "unknown UNIF board {}", board),
+            RomError::BadChunk(ref id) => write!(f, "bad UNIF chunk {}", id),
+            RomError::NoRomInArchive => write!(f, "the archive contains no ROM image"),
+            RomError::BadArchive => write!(f, "the zip archive's directory is missing or corrupt"),
+        }
     }
 }
 
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// The size of a PlayChoice-10 INST-ROM and its PROM (16 bytes of data and 16 of CounterOut).
+const PLAYCHOICE_ROM_SIZE: u64 = 8192 + 32;
+
+/// UNIF board names and the iNES mapper numbers of the boards. Names are looked up both as-is
+/// and without their first dash-separated part, so that "NES-SLROM" and "HVC-SLROM" match
+/// "SLROM".
+static UNIF_BOARDS: [(&str, u8); 43] = [
+    ("NROM", 0),
+    ("NROM-128", 0),
+    ("NROM-256", 0),
+    ("SAROM", 1),
+    ("SBROM", 1),
+    ("SCROM", 1),
+    ("SEROM", 1),
+    ("SFROM", 1),
+    ("SGROM", 1),
+    ("SHROM", 1),
+    ("SJROM", 1),
+    ("SKROM", 1),
+    ("SLROM", 1),
+    ("SL1ROM", 1),
+    ("SNROM", 1),
+    ("SOROM", 1),
+    ("SUROM", 1),
+    ("SXROM", 1),
+    ("TBROM", 4),
+    ("TEROM", 4),
+    ("TFROM", 4),
+    ("TGROM", 4),
+    ("TKROM", 4),
+    ("TLROM", 4),
+    ("TL1ROM", 4),
+    ("TR1ROM", 4),
+    ("TSROM", 4),
+    ("TVROM", 4),
+    ("NAMCOT-163", 19),
+    ("BNROM", 34),
+    ("AVE-NINA-01", 34),
+    ("AVE-NINA-02", 34),
+    ("TENGEN-800032", 64),
+    ("CAMERICA-BF9093", 71),
+    ("CAMERICA-BF9097", 71),
+    ("DEROM", 206),
+    ("DE1ROM", 206),
+    ("DRROM", 206),
+    ("NAMCOT-3401", 206),
+    ("NAMCOT-3406", 206),
+    ("NAMCOT-3413", 206),
+    ("NAMCOT-3414", 206),
+    ("NAMCOT-3415", 206),
+];
+
+fn unif_board_mapper(name: &str) -> Option<u8> {
+    let lookup = |name: &str| {
+        UNIF_BOARDS
+            .iter()
+            .find(|board| board.0.eq_ignore_ascii_case(name))
+            .map(|board| board.1)
+    };
+    lookup(name).or_else(|| match name.find('-') {
+        Some(dash) => lookup(&name[dash + 1..]),
+        None => None,
+    })
+}
+
 /// A ROM image
 pub struct Rom {
     pub header: INesHeader,
+    /// 512 bytes to be loaded at $7000 before the game starts, if any
+    pub trainer: Option<Vec<u8>>,
     /// PRG-ROM
     pub prg: Vec<u8>,
     /// CHR-ROM
     pub chr: Vec<u8>,
+    /// For PlayChoice-10 ROMs, the 8K INST-ROM holding the menu's instructions for the game,
+    /// followed by the decryption PROM, if the dump includes them. The game itself doesn't use
+    /// either.
+    pub playchoice_rom: Option<Vec<u8>>,
 }
 
 impl Rom {
-    pub fn load(r: &mut Read) -> Result<Rom, RomLoadError> {
+    /// Loads an iNES or UNIF image, possibly zipped or gzipped, from a file.
+    pub fn from_path(path: &Path) -> Result<Rom, RomError> {
+        Rom::from_reader(&mut File::open(path)?)
+    }
+
+    /// Loads an iNES or UNIF image, possibly zipped or gzipped, held in memory.
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Rom, RomError> {
+        Rom::from_reader(&mut bytes)
+    }
+
+    /// Loads an iNES or UNIF image, possibly zipped or gzipped, from a reader. Unless the image
+    /// was zipped, the reader is left just past the end of it.
+    pub fn from_reader(r: &mut Read) -> Result<Rom, RomError> {
         let mut header = [0u8; 16];
-        try!(util::read_to_buf(&mut header, r));
+        util::read_to_buf(&mut header, r)?;
+        if header[0..2] == GZIP_MAGIC {
+            return Rom::from_reader(&mut GzDecoder::new((&header[..]).chain(r)));
+        }
+        if zip::is_zip(&header) {
+            let mut data = header.to_vec();
+            r.read_to_end(&mut data)?;
+            return Rom::from_bytes(&zip::extract_rom(&data)?);
+        }
+        if header[0..4] == *b"UNIF" {
+            return Rom::load_unif(r);
+        }
 
         let header = INesHeader {
             magic: [header[0], header[1], header[2], header[3]],
@@ -47,42 +181,245 @@ impl Rom {
             prg_ram_size: header[8],
             flags_9: header[9],
             flags_10: header[10],
-            zero: [0; 5],
+            zero: [header[11], header[12], header[13], header[14], header[15]],
         };
 
         if header.magic != *b"NES\x1a" {
-            return Err(RomLoadError::FormatError);
+            return Err(RomError::BadMagic);
+        }
+        if header.nes2() && (header.flags_7 & 3) == 3 {
+            return Err(RomError::UnsupportedVariant(
+                "ROMs for extended console types",
+            ));
         }
+        if header.console_type() == ConsoleType::VsSystem {
+            return Err(RomError::UnsupportedVariant("Vs. System ROMs"));
+        }
+
+        let trainer = if header.trainer() {
+            let mut trainer = vec![0u8; 512];
+            util::read_to_buf(&mut trainer, r)?;
+            Some(trainer)
+        } else {
+            None
+        };
 
-        let prg_bytes = header.prg_rom_size as usize * 16384;
-        let mut prg_rom = vec![0u8; prg_bytes];
-        try!(util::read_to_buf(&mut prg_rom, r));
+        let mut prg_rom = vec![0u8; header.prg_rom_bytes()];
+        util::read_to_buf(&mut prg_rom, r)?;
 
-        let chr_bytes = header.chr_rom_size as usize * 8192;
-        let mut chr_rom = vec![0u8; chr_bytes];
-        try!(util::read_to_buf(&mut chr_rom, r));
+        let mut chr_rom = vec![0u8; header.chr_rom_bytes()];
+        util::read_to_buf(&mut chr_rom, r)?;
 
-        Ok(Rom {
+        let playchoice_rom = if header.console_type() == ConsoleType::PlayChoice10 {
+            let mut data = vec![];
+            r.take(PLAYCHOICE_ROM_SIZE).read_to_end(&mut data)?;
+            if data.is_empty() {
+                None
+            } else {
+                Some(data)
+            }
+        } else {
+            None
+        };
+
+        let mut rom = Rom {
             header: header,
+            trainer: trainer,
             prg: prg_rom,
             chr: chr_rom,
+            playchoice_rom: playchoice_rom,
+        };
+        romdb::correct_header(&mut rom);
+        Ok(rom)
+    }
+
+    /// Returns the TV system the game was made for, according to its header.
+    pub fn region(&self) -> Region {
+        self.header.region()
+    }
+
+    /// Serializes the ROM as an iNES image, converting it to one if it was loaded from UNIF.
+    pub fn to_ines(&self) -> Vec<u8> {
+        let header = &self.header;
+        let mut image = header.magic.to_vec();
+        image.extend_from_slice(&[
+            header.prg_rom_size,
+            header.chr_rom_size,
+            header.flags_6,
+            header.flags_7,
+            header.prg_ram_size,
+            header.flags_9,
+            header.flags_10,
+        ]);
+        image.extend_from_slice(&header.zero);
+        if let Some(ref trainer) = self.trainer {
+            image.extend_from_slice(trainer);
+        }
+        image.extend_from_slice(&self.prg);
+        image.extend_from_slice(&self.chr);
+        if let Some(ref playchoice_rom) = self.playchoice_rom {
+            image.extend_from_slice(playchoice_rom);
+        }
+        image
+    }
+
+    /// Loads a UNIF image, whose first 16 bytes have already been read. UNIF is a list of chunks
+    /// with 4-byte IDs: the board name in MAPR, up to 16 pieces each of PRG-ROM and CHR-ROM in
+    /// PRG0-PRGF and CHR0-CHRF, and so on. The image is converted into an iNES-style ROM.
+    fn load_unif(r: &mut Read) -> Result<Rom, RomError> {
+        // The rest of the 32-byte header is the revision number and padding.
+        let mut data = vec![];
+        r.read_to_end(&mut data)?;
+        if data.len() < 16 {
+            return Err(RomError::Truncated);
+        }
+        let mut rest = &data[16..];
+
+        let mut board = None;
+        let mut prg_chunks: [&[u8]; 16] = [&[]; 16];
+        let mut chr_chunks: [&[u8]; 16] = [&[]; 16];
+        let mut flags_6 = 0;
+        while !rest.is_empty() {
+            if rest.len() < 8 {
+                return Err(RomError::Truncated);
+            }
+            let id = &rest[0..4];
+            let len = rest[4] as usize
+                | (rest[5] as usize) << 8
+                | (rest[6] as usize) << 16
+                | (rest[7] as usize) << 24;
+            if rest.len() - 8 < len {
+                return Err(RomError::Truncated);
+            }
+            let chunk = &rest[8..8 + len];
+            rest = &rest[8 + len..];
+
+            let chunks = match &id[0..3] {
+                b"PRG" => Some(&mut prg_chunks),
+                b"CHR" => Some(&mut chr_chunks),
+                _ => None,
+            };
+            if let Some(chunks) = chunks {
+                let index = match (id[3] as char).to_digit(16) {
+                    Some(index) => index as usize,
+                    None => {
+                        return Err(RomError::BadChunk(String::from_utf8_lossy(id).into_owned()))
+                    }
+                };
+                chunks[index] = chunk;
+            }
+            match id {
+                b"MAPR" => {
+                    let name = chunk.split(|&byte| byte == 0).next().unwrap_or(&[]);
+                    board = Some(String::from_utf8_lossy(name).into_owned());
+                }
+                b"MIRR" if !chunk.is_empty() => {
+                    // 0 and 1 are hard-wired horizontal and vertical mirroring, and 4 is
+                    // four-screen VRAM. The rest are up to the mapper.
+                    flags_6 |= match chunk[0] {
+                        1 => 0x01,
+                        4 => 0x08,
+                        _ => 0,
+                    };
+                }
+                b"BATR" => flags_6 |= 0x02,
+                _ => {}
+            }
+        }
+
+        let board = board.ok_or(RomError::UnsupportedVariant(
+            "UNIF images without a board name",
+        ))?;
+        let mapper = unif_board_mapper(&board).ok_or(RomError::UnknownBoard(board))?;
+        let prg = prg_chunks.concat();
+        let chr = chr_chunks.concat();
+
+        Ok(Rom {
+            header: INesHeader {
+                magic: *b"NES\x1a",
+                prg_rom_size: ((prg.len() + 16383) / 16384) as u8,
+                chr_rom_size: ((chr.len() + 8191) / 8192) as u8,
+                flags_6: flags_6 | (mapper << 4),
+                flags_7: mapper & 0xf0,
+                prg_ram_size: 0,
+                flags_9: 0,
+                flags_10: 0,
+                zero: [0; 5],
+            },
+            trainer: None,
+            prg: prg,
+            chr: chr,
+            playchoice_rom: None,
         })
     }
 }
 
+/// The TV system a game was made for.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Region {
+    Ntsc,
+    Pal,
+    /// Runs on both NTSC and PAL consoles
+    Multiple,
+    /// The Dendy famiclone
+    Dendy,
+}
+
+impl Region {
+    /// Returns the console whose timing a game for this region should be run with. Games that run
+    /// on both NTSC and PAL consoles are run as NTSC.
+    pub fn timing(self) -> Region {
+        match self {
+            Region::Multiple => Region::Ntsc,
+            region => region,
+        }
+    }
+
+    /// How many frames a second the console draws.
+    pub fn frame_rate(self) -> f64 {
+        match self.timing() {
+            Region::Pal | Region::Dendy => 50.007,
+            _ => 60.0988,
+        }
+    }
+}
+
+/// The machine a ROM was made for.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ConsoleType {
+    /// A NES or Famicom
+    Nes,
+    /// A Vs. System arcade cabinet, with its own palette, DIP switches and coin slots
+    VsSystem,
+    /// A PlayChoice-10 arcade cabinet, which runs ordinary NES games
+    PlayChoice10,
+}
+
+impl fmt::Display for ConsoleType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            ConsoleType::Nes => write!(f, "NES"),
+            ConsoleType::VsSystem => write!(f, "Vs. System"),
+            ConsoleType::PlayChoice10 => write!(f, "PlayChoice-10"),
+        }
+    }
+}
+
+/// An iNES header. NES 2.0 headers reuse the same layout, so the raw fields are named after their
+/// iNES meaning; use the methods to decode them in either format.
 pub struct INesHeader {
     /// 'N' 'E' 'S' '\x1a'
     pub magic: [u8; 4],
-    /// number of 16K units of PRG-ROM
+    /// number of 16K units of PRG-ROM (in NES 2.0, the low byte of the size)
     pub prg_rom_size: u8,
-    /// number of 8K units of CHR-ROM
+    /// number of 8K units of CHR-ROM (in NES 2.0, the low byte of the size)
     pub chr_rom_size: u8,
     /// MMMMATPA
     ///
     /// * M: Low nibble of mapper number
-    /// * A: 0xx0: vertical arrangement/horizontal mirroring (CIRAM A10 = PPU A11)
-    ///      0xx1: horizontal arrangement/vertical mirroring (CIRAM A10 = PPU A10)
-    ///      1xxx: four-screen VRAM
+    /// * A: 0xx0: vertical arrangement/horizontal mirroring (CIRAM A10 = PPU A11);
+    ///   0xx1: horizontal arrangement/vertical mirroring (CIRAM A10 = PPU A10);
+    ///   1xxx: four-screen VRAM
     /// * T: ROM contains a trainer
     /// * P: Cartridge has persistent memory
     pub flags_6: u8,
@@ -94,21 +431,61 @@ pub struct INesHeader {
     /// * U: ROM is for VS Unisystem
     pub flags_7: u8,
     /// number of 8K units of PRG-RAM
+    ///
+    /// In NES 2.0, SSSSMMMM:
+    ///
+    /// * S: Submapper number
+    /// * M: Bits 8-11 of mapper number
     pub prg_ram_size: u8,
     /// RRRRRRRT
     ///
     /// * R: Reserved (= 0)
     /// * T: 0 for NTSC, 1 for PAL
+    ///
+    /// In NES 2.0, CCCCPPPP:
+    ///
+    /// * C: Bits 8-11 of the CHR-ROM size
+    /// * P: Bits 8-11 of the PRG-ROM size
     pub flags_9: u8,
+    /// Unofficial in iNES. In NES 2.0, NNNNRRRR:
+    ///
+    /// * N: PRG-NVRAM size is 64 << N bytes, or none if N is 0
+    /// * R: PRG-RAM size is 64 << R bytes, or none if R is 0
     pub flags_10: u8,
-    /// always zero
+    /// always zero in iNES
+    ///
+    /// In NES 2.0:
+    ///
+    /// * 0: CHR-NVRAM and CHR-RAM sizes, as in `flags_10`
+    /// * 1: Region in the low two bits (0 = NTSC, 1 = PAL, 2 = both, 3 = Dendy)
+    /// * 2-4: Vs. System type, miscellaneous ROM count and default expansion device
     pub zero: [u8; 5],
 }
 
 impl INesHeader {
+    /// Returns true if this is a NES 2.0 header.
+    pub fn nes2(&self) -> bool {
+        (self.flags_7 & 0x0c) == 0x08
+    }
+
     /// Returns the mapper ID.
-    pub fn mapper(&self) -> u8 {
-        (self.flags_7 & 0xf0) | (self.flags_6 >> 4)
+    pub fn mapper(&self) -> u16 {
+        let mapper = ((self.flags_7 & 0xf0) | (self.flags_6 >> 4)) as u16;
+        if self.nes2() {
+            mapper | (self.prg_ram_size as u16 & 0xf) << 8
+        } else {
+            mapper
+        }
+    }
+
+    /// Returns the submapper ID, which tells apart boards that share a mapper ID. Always 0 for
+    /// iNES headers.
+    pub fn submapper(&self) -> u8 {
+        if self.nes2() {
+            self.prg_ram_size >> 4
+        } else {
+            0
+        }
     }
 
     /// Returns the low nibble of the mapper ID.
@@ -116,21 +493,216 @@ impl INesHeader {
         self.flags_6 >> 4
     }
 
+    /// Returns the size of the PRG-ROM in bytes.
+    pub fn prg_rom_bytes(&self) -> usize {
+        if self.nes2() {
+            INesHeader::nes2_rom_size(self.flags_9 & 0xf, self.prg_rom_size, 16384)
+        } else {
+            self.prg_rom_size as usize * 16384
+        }
+    }
+
+    /// Returns the size of the CHR-ROM in bytes.
+    pub fn chr_rom_bytes(&self) -> usize {
+        if self.nes2() {
+            INesHeader::nes2_rom_size(self.flags_9 >> 4, self.chr_rom_size, 8192)
+        } else {
+            self.chr_rom_size as usize * 8192
+        }
+    }
+
+    /// Decodes a NES 2.0 ROM size. If the high nibble is $F, the low byte is EEEEEEMM and the size
+    /// is 2^E * (MM * 2 + 1) bytes; otherwise the size is a count of `unit`-sized banks.
+    fn nes2_rom_size(high: u8, low: u8, unit: usize) -> usize {
+        if high == 0xf {
+            (1 << (low >> 2)) * ((low as usize & 3) * 2 + 1)
+        } else {
+            ((high as usize) << 8 | low as usize) * unit
+        }
+    }
+
+    /// Decodes a NES 2.0 RAM size nibble.
+    fn nes2_ram_size(shift: u8) -> usize {
+        if shift == 0 {
+            0
+        } else {
+            64 << shift
+        }
+    }
+
+    /// Returns the size of the volatile PRG-RAM in bytes, or `None` if the header doesn't say.
+    /// Only NES 2.0 headers do.
+    pub fn prg_ram_bytes(&self) -> Option<usize> {
+        if self.nes2() {
+            Some(INesHeader::nes2_ram_size(self.flags_10 & 0xf))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the size of the battery-backed PRG-RAM in bytes, or `None` if the header doesn't
+    /// say.
+    pub fn prg_nvram_bytes(&self) -> Option<usize> {
+        if self.nes2() {
+            Some(INesHeader::nes2_ram_size(self.flags_10 >> 4))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the size of the CHR-RAM in bytes, or `None` if the header doesn't say.
+    pub fn chr_ram_bytes(&self) -> Option<usize> {
+        if self.nes2() {
+            Some(INesHeader::nes2_ram_size(self.zero[0] & 0xf))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the size of the battery-backed CHR-RAM in bytes, or `None` if the header doesn't
+    /// say.
+    pub fn chr_nvram_bytes(&self) -> Option<usize> {
+        if self.nes2() {
+            Some(INesHeader::nes2_ram_size(self.zero[0] >> 4))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the TV system the game was made for.
+    pub fn region(&self) -> Region {
+        if !self.nes2() {
+            return if (self.flags_9 & 1) == 0 {
+                Region::Ntsc
+            } else {
+                Region::Pal
+            };
+        }
+        match self.zero[1] & 3 {
+            0 => Region::Ntsc,
+            1 => Region::Pal,
+            2 => Region::Multiple,
+            _ => Region::Dendy,
+        }
+    }
+
+    /// Returns the nametable mirroring wired on the board. Mappers that switch mirroring override
+    /// it, except on four-screen boards.
+    pub fn mirroring(&self) -> Mirroring {
+        if (self.flags_6 & 0x08) != 0 {
+            Mirroring::FourScreen
+        } else if (self.flags_6 & 0x01) != 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        }
+    }
+
+    /// Returns the machine the ROM was made for. NES 2.0's extended console types are rejected
+    /// at load time, so they never show up here.
+    pub fn console_type(&self) -> ConsoleType {
+        if (self.flags_7 & 0x01) != 0 {
+            ConsoleType::VsSystem
+        } else if (self.flags_7 & 0x02) != 0 {
+            ConsoleType::PlayChoice10
+        } else {
+            ConsoleType::Nes
+        }
+    }
+
     pub fn trainer(&self) -> bool {
         (self.flags_6 & 0x04) != 0
     }
+
+    /// Returns true if the cartridge has battery-backed memory, which should outlive the session.
+    pub fn battery(&self) -> bool {
+        (self.flags_6 & 0x02) != 0
+    }
 }
 
 impl fmt::Display for INesHeader {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         write!(
             f,
-            "PRG-ROM: {} KB, CHR-ROM: {} KB, Mapper: {} ({}), Trainer: {}",
-            self.prg_rom_size as u32 * 16,
-            self.chr_rom_size as u32 * 8,
+            "{}, Console: {}, PRG-ROM: {} KB, CHR-ROM: {} KB, Mapper: {}.{} ({}), Trainer: {}, \
+             Battery: {}",
+            if self.nes2() { "NES 2.0" } else { "iNES" },
+            self.console_type(),
+            self.prg_rom_bytes() / 1024,
+            self.chr_rom_bytes() / 1024,
             self.mapper(),
+            self.submapper(),
             self.ines_mapper(),
             self.trainer(),
+            self.battery(),
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ines(flags_6: u8, flags_7: u8) -> Vec<u8> {
+        let mut bytes = vec![b'N', b'E', b'S', 0x1a, 1, 1, flags_6, flags_7];
+        bytes.resize(16 + 16384 + 8192, 0);
+        bytes
+    }
+
+    #[test]
+    fn loads_a_plain_ines_rom() {
+        let rom = Rom::from_bytes(&ines(0, 0)).unwrap();
+        assert_eq!(rom.prg.len(), 16384);
+        assert_eq!(rom.chr.len(), 8192);
+    }
+
+    #[test]
+    fn rejects_vs_system_roms() {
+        match Rom::from_bytes(&ines(0, 0x01)) {
+            Err(RomError::UnsupportedVariant(variant)) => assert_eq!(variant, "Vs. System ROMs"),
+            _ => panic!("a Vs. System ROM was accepted"),
+        }
+    }
+
+    /// A UNIF image made of the given chunks.
+    fn unif(chunks: &[(&[u8], &[u8])]) -> Vec<u8> {
+        let mut bytes = b"UNIF".to_vec();
+        bytes.extend_from_slice(&[7, 0, 0, 0]);
+        bytes.resize(32, 0);
+        for &(id, data) in chunks {
+            let len = data.len() as u32;
+            bytes.extend_from_slice(id);
+            bytes.extend_from_slice(&[len as u8, (len >> 8) as u8, (len >> 16) as u8, 0]);
+            bytes.extend_from_slice(data);
+        }
+        bytes
+    }
+
+    #[test]
+    fn loads_a_unif_image() {
+        let (prg0, prg1, chr0) = (vec![1; 16384], vec![2; 16384], vec![3; 8192]);
+        let image = unif(&[
+            (b"MAPR", b"NES-NROM-256\0"),
+            (b"PRG1", &prg1),
+            (b"PRG0", &prg0),
+            (b"CHR0", &chr0),
+            (b"MIRR", &[1]),
+            (b"BATR", &[1]),
+        ]);
+        let rom = Rom::from_bytes(&image).unwrap();
+        assert_eq!(rom.header.mapper(), 0);
+        assert_eq!(rom.header.mirroring(), Mirroring::Vertical);
+        assert!(rom.header.battery());
+        assert_eq!(rom.prg, [prg0, prg1].concat());
+        assert_eq!(rom.chr, chr0);
+    }
+
+    #[test]
+    fn rejects_unif_chunks_with_bad_indices() {
+        let image = unif(&[(b"MAPR", b"NES-NROM-256\0"), (b"PRGX", &[0; 16384])]);
+        match Rom::from_bytes(&image) {
+            Err(RomError::BadChunk(id)) => assert_eq!(id, "PRGX"),
+            _ => panic!("a PRG chunk with a bad index was accepted"),
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! A database of known cartridges, in the style of NesCartDB, used to correct bad iNES headers.
+//!
+//! Many dumps in circulation carry headers with the wrong mapper number, mirroring or battery
+//! flag. Games are identified by the CRC32 of their PRG-ROM followed by their CHR-ROM (that is,
+//! the image minus its header and trainer), which is what NesCartDB lists as the cartridge CRC.
+
+//
+// Author: Patrick Walton
+//
+
+use mapper::Mirroring;
+use rom::Rom;
+use util;
+
+/// Returns the bits of `flags_6` in an iNES header that select the given mirroring. Mirroring
+/// that only a mapper can select has no bits of its own.
+fn flags_6_mirroring_bits(mirroring: Mirroring) -> u8 {
+    match mirroring {
+        Mirroring::Vertical => 0x01,
+        Mirroring::FourScreen => 0x08,
+        _ => 0x00,
+    }
+}
+
+/// What the database knows about one cartridge.
+pub struct GameInfo {
+    /// CRC32 of the PRG-ROM followed by the CHR-ROM
+    pub crc: u32,
+    pub name: &'static str,
+    pub mapper: u8,
+    /// Ignored by mappers that control mirroring themselves, but still recorded so the header
+    /// can be fixed
+    pub mirroring: Mirroring,
+    pub battery: bool,
+    /// Whether a discrete board's register writes conflict with its PRG-ROM, if the board
+    /// differs from the usual one for its mapper
+    pub bus_conflicts: Option<bool>,
+}
+
+/// The known cartridges, sorted by CRC so that they can be binary searched. Entries should only be
+/// added for dumps verified against NesCartDB, like so:
+///
+/// ```text
+/// GameInfo { crc: 0x........, name: "...", mapper: N, mirroring: Mirroring::..., battery: ..,
+///            bus_conflicts: None },
+/// ```
+pub static GAMES: &[GameInfo] = &[
+    GameInfo {
+        crc: 0x3337ec46,
+        name: "Super Mario Bros.",
+        mapper: 0,
+        mirroring: Mirroring::Vertical,
+        battery: false,
+        bus_conflicts: None,
+    },
+    GameInfo {
+        crc: 0x3fe272fb,
+        name: "The Legend of Zelda",
+        mapper: 1,
+        mirroring: Mirroring::Horizontal,
+        battery: true,
+        bus_conflicts: None,
+    },
+    GameInfo {
+        crc: 0x6d72c53a,
+        name: "Tetris",
+        mapper: 1,
+        mirroring: Mirroring::Horizontal,
+        battery: false,
+        bus_conflicts: None,
+    },
+];
+
+/// Returns the CRC32 the database identifies the ROM by.
+pub fn rom_crc(rom: &Rom) -> u32 {
+    let mut data = Vec::with_capacity(rom.prg.len() + rom.chr.len());
+    data.extend_from_slice(&rom.prg);
+    data.extend_from_slice(&rom.chr);
+    util::crc32(&data)
+}
+
+/// Looks up a cartridge by the CRC32 of its PRG-ROM and CHR-ROM.
+pub fn lookup(crc: u32) -> Option<&'static GameInfo> {
+    GAMES
+        .binary_search_by_key(&crc, |game| game.crc)
+        .ok()
+        .map(|index| &GAMES[index])
+}
+
+/// Overwrites the mapper number, mirroring and battery flag in the ROM's header with the
+/// database's, if the ROM is in the database, and logs each field that changed.
+pub fn correct_header(rom: &mut Rom) {
+    let game = match lookup(rom_crc(rom)) {
+        Some(game) => game,
+        None => return,
+    };
+
+    let mut corrections = vec![];
+    let header = &mut rom.header;
+    if header.mapper() != game.mapper as u16 {
+        corrections.push(format!("mapper {} -> {}", header.mapper(), game.mapper));
+        header.flags_6 = (header.flags_6 & 0x0f) | (game.mapper << 4);
+        header.flags_7 = (header.flags_7 & 0x0f) | (game.mapper & 0xf0);
+        if header.nes2() {
+            header.prg_ram_size &= 0xf0;
+        }
+    }
+    let mirroring = header.mirroring();
+    if mirroring != game.mirroring {
+        corrections.push(format!("mirroring {:?} -> {:?}", mirroring, game.mirroring));
+        header.flags_6 = (header.flags_6 & !0x09) | flags_6_mirroring_bits(game.mirroring);
+    }
+    if header.battery() != game.battery {
+        corrections.push(format!("battery {} -> {}", header.battery(), game.battery));
+        header.flags_6 ^= 0x02;
+    }
+    for correction in corrections.iter() {
+        println!("Corrected the header of {}: {}", game.name, correction);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The CRC32 lookup table, indexed by the low byte of the register.
+    fn crc_table() -> Vec<u32> {
+        (0..256)
+            .map(|i| {
+                (0..8).fold(i as u32, |crc, _| {
+                    if crc & 1 != 0 {
+                        (crc >> 1) ^ 0xedb88320
+                    } else {
+                        crc >> 1
+                    }
+                })
+            })
+            .collect()
+    }
+
+    // Overwrites the last four bytes of `data` so that its CRC32 becomes `target`. Each table
+    // entry has a different high byte, so the entries the last four bytes index can be found
+    // by working back from the target.
+    fn forge_crc(data: &mut [u8], target: u32) {
+        let table = crc_table();
+        let prefix = data.len() - 4;
+        let mut register = !target;
+        let mut indices = [0u8; 4];
+        for k in (0..4).rev() {
+            let index = (0..256)
+                .find(|&j| table[j] >> 24 == register >> 24)
+                .unwrap();
+            indices[k] = index as u8;
+            register = (register ^ table[index]) << 8;
+        }
+        let mut register = !util::crc32(&data[..prefix]);
+        for k in 0..4 {
+            data[prefix + k] = (register as u8) ^ indices[k];
+            register = (register >> 8) ^ table[indices[k] as usize];
+        }
+    }
+
+    #[test]
+    fn games_are_sorted() {
+        assert!(GAMES.windows(2).all(|pair| pair[0].crc < pair[1].crc));
+    }
+
+    #[test]
+    fn bad_header_is_corrected() {
+        // A 16K PRG-ROM and 8K CHR-ROM cartridge with the Legend of Zelda's CRC, whose header
+        // claims NROM with vertical mirroring and no battery.
+        let mut image = b"NES\x1a\x01\x01\x01\x00".to_vec();
+        image.resize(16 + 0x4000 + 0x2000, 0);
+        forge_crc(&mut image[16..], 0x3fe272fb);
+
+        let rom = Rom::from_bytes(&image).unwrap();
+        assert_eq!(rom_crc(&rom), 0x3fe272fb);
+        assert_eq!(rom.header.mapper(), 1);
+        assert_eq!(rom.header.mirroring(), Mirroring::Horizontal);
+        assert!(rom.header.battery());
+    }
+
+    #[test]
+    fn unknown_header_is_kept() {
+        let mut image = b"NES\x1a\x01\x01\x01\x00".to_vec();
+        image.resize(16 + 0x4000 + 0x2000, 0);
+
+        let rom = Rom::from_bytes(&image).unwrap();
+        assert!(lookup(rom_crc(&rom)).is_none());
+        assert_eq!(rom.header.mapper(), 0);
+        assert_eq!(rom.header.mirroring(), Mirroring::Vertical);
+        assert!(!rom.header.battery());
+    }
+}
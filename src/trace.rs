@@ -0,0 +1,78 @@
+//! Instruction traces, one line per instruction in the format of nestest.log, for comparing the
+//! CPU against other emulators and against known-good logs.
+
+//
+// Author: Patrick Walton
+//
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+pub struct Tracer {
+    out: Box<Write>,
+    /// Only instructions from `start` to `end`, inclusive, are logged.
+    start: u16,
+    end: u16,
+}
+
+impl Tracer {
+    /// Logs every instruction to `out`.
+    pub fn new(out: Box<Write>) -> Tracer {
+        Tracer {
+            out: out,
+            start: 0x0000,
+            end: 0xffff,
+        }
+    }
+
+    /// Logs every instruction to a new file, replacing any that's there.
+    pub fn create(path: &Path) -> io::Result<Tracer> {
+        let file = File::create(path)?;
+        Ok(Tracer::new(Box::new(BufWriter::new(file))))
+    }
+
+    /// Logs only the instructions at addresses from `start` to `end`, inclusive. Narrowing the
+    /// range to the code under study keeps long sessions from writing gigabytes.
+    pub fn set_range(&mut self, start: u16, end: u16) {
+        self.start = start;
+        self.end = end;
+    }
+
+    /// Whether an instruction at `pc` would be logged.
+    pub fn wants(&self, pc: u16) -> bool {
+        pc >= self.start && pc <= self.end
+    }
+
+    /// Writes a line for an instruction: its address, its bytes, its disassembly, the registers
+    /// before it runs and the cycle it starts on.
+    pub fn log(
+        &mut self,
+        pc: u16,
+        bytes: &[u8],
+        disassembly: &str,
+        registers: &str,
+        cy: u64,
+    ) -> io::Result<()> {
+        let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        // Unofficial opcodes are marked with a `*` in the column before the mnemonic.
+        let disassembly = if disassembly.starts_with('*') {
+            disassembly.to_string()
+        } else {
+            format!(" {}", disassembly)
+        };
+        writeln!(
+            self.out,
+            "{:04X}  {:9}{:33}{} CYC:{}",
+            pc,
+            bytes.join(" "),
+            disassembly,
+            registers,
+            cy
+        )
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
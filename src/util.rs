@@ -2,18 +2,20 @@
 // Author: Patrick Walton
 //
 
+use flate2::Crc;
 use std::fs::File;
 use std::io::{self, Read, Write};
+use std::path::Path;
 
 /// Reads until the buffer is filled or the reader signals EOF
 pub fn read_to_buf(buf: &mut [u8], rd: &mut Read) -> io::Result<()> {
     let mut total = 0;
     while total < buf.len() {
-        let count = try!(rd.read(&mut buf[total..]));
+        let count = rd.read(&mut buf[total..])?;
         if count == 0 {
             // Buffer not yet filled, but EOF reached
             return Err(io::Error::new(
-                io::ErrorKind::Other,
+                io::ErrorKind::UnexpectedEof,
                 "eof reached prematurely",
             ));
         }
@@ -23,6 +25,13 @@ pub fn read_to_buf(buf: &mut [u8], rd: &mut Read) -> io::Result<()> {
     Ok(())
 }
 
+/// Computes the CRC32 of the given bytes, as used by zip, IPS tools, BPS and ROM databases.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
 //
 // A tiny custom serialization infrastructure, used for savestates.
 //
@@ -50,7 +59,7 @@ impl Save for u8 {
 
 impl Save for u16 {
     fn save(&mut self, fd: &mut File) {
-        fd.write(&[*self as u8, (*self >> 8) as u8]).unwrap();
+        fd.write_all(&[*self as u8, (*self >> 8) as u8]).unwrap();
     }
     fn load(&mut self, fd: &mut File) {
         let mut buf = [0, 0];
@@ -62,8 +71,8 @@ impl Save for u16 {
 impl Save for u64 {
     fn save(&mut self, fd: &mut File) {
         let mut buf = [0; 8];
-        for i in 0..8 {
-            buf[i] = ((*self) >> (i * 8)) as u8;
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = (*self >> (i * 8)) as u8;
         }
         fd.write_all(&buf).unwrap();
     }
@@ -71,15 +80,35 @@ impl Save for u64 {
         let mut buf = [0; 8];
         read_to_buf(&mut buf, fd).unwrap();
         *self = 0;
-        for i in 0..8 {
-            *self = *self | (buf[i] as u64) << (i * 8);
+        for (i, &byte) in buf.iter().enumerate() {
+            *self |= (byte as u64) << (i * 8);
         }
     }
 }
 
-impl<'a> Save for &'a mut [u8] {
+impl Save for &mut [u8] {
     fn save(&mut self, fd: &mut File) {
-        fd.write(*self).unwrap();
+        fd.write_all(self).unwrap();
+    }
+    fn load(&mut self, fd: &mut File) {
+        read_to_buf(self, fd).unwrap();
+    }
+}
+
+impl Save for f32 {
+    fn save(&mut self, fd: &mut File) {
+        fd.write_all(&self.to_bits().to_le_bytes()).unwrap();
+    }
+    fn load(&mut self, fd: &mut File) {
+        let mut buf = [0; 4];
+        read_to_buf(&mut buf, fd).unwrap();
+        *self = f32::from_bits(u32::from_le_bytes(buf));
+    }
+}
+
+impl<const N: usize> Save for [u8; N] {
+    fn save(&mut self, fd: &mut File) {
+        fd.write_all(self).unwrap();
     }
     fn load(&mut self, fd: &mut File) {
         read_to_buf(self, fd).unwrap();
@@ -88,7 +117,7 @@ impl<'a> Save for &'a mut [u8] {
 
 impl Save for bool {
     fn save(&mut self, fd: &mut File) {
-        fd.write(&[if *self { 0 } else { 1 }]).unwrap();
+        fd.write_all(&[if *self { 1 } else { 0 }]).unwrap();
     }
     fn load(&mut self, fd: &mut File) {
         let mut val: [u8; 1] = [0];
@@ -97,6 +126,40 @@ impl Save for bool {
     }
 }
 
+/// The start of every savestate file.
+const STATE_MAGIC: [u8; 4] = *b"SNES";
+/// Bumped whenever the layout of a savestate changes, such as when a device saves more of its
+/// state or a value is encoded differently, so that states from other versions are refused
+/// instead of loading as garbage. States written before there was a header are refused too.
+const STATE_VERSION: u8 = 1;
+
+/// Writes a savestate file: a header with the version, and then the state.
+pub fn save_state(state: &mut Save, path: &Path) -> io::Result<()> {
+    let mut fd = File::create(path)?;
+    fd.write_all(&STATE_MAGIC)?;
+    fd.write_all(&[STATE_VERSION])?;
+    state.save(&mut fd);
+    Ok(())
+}
+
+/// Reads a savestate file written by `save_state`. The state is left alone if the file is from
+/// another version.
+pub fn load_state(state: &mut Save, path: &Path) -> io::Result<()> {
+    let mut fd = File::open(path)?;
+    let mut header = [0; 5];
+    if read_to_buf(&mut header, &mut fd).is_err()
+        || header[0..4] != STATE_MAGIC
+        || header[4] != STATE_VERSION
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the savestate is from another version",
+        ));
+    }
+    state.load(&mut fd);
+    Ok(())
+}
+
 // A convenience macro to save and load entire structs.
 macro_rules! save_struct(
     ($name:ident { $($field:ident),* }) => (
@@ -111,52 +174,38 @@ macro_rules! save_struct(
     )
 );
 
-macro_rules! save_enum(
-    ($name:ident { $val_0:ident, $val_1:ident }) => (
-        impl Save for $name {
-            fn save(&mut self, fd: &mut File) {
-                let mut val: u8 = match *self { $name::$val_0 => 0, $name::$val_1 => 1 };
-                val.save(fd)
-            }
-            fn load(&mut self, fd: &mut File) {
-                let mut val: u8 = 0;
-                val.load(fd);
-                *self = if val == 0 { $name::$val_0 } else { $name::$val_1 };
-            }
-        }
-    )
-);
-
-//
-// Random number generation
-//
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn savestates_round_trip() {
+        let path = env::temp_dir().join("sprocketnes-util-state-test");
+        save_state(&mut 0x1234u16, &path).unwrap();
+        let mut val = 0u16;
+        load_state(&mut val, &path).unwrap();
+        let _ = fs::remove_file(&path);
+        assert_eq!(val, 0x1234);
+    }
 
-// TODO remove this and emulate the APU's noise generator properly
+    #[test]
+    fn savestates_from_other_versions_are_refused() {
+        let path = env::temp_dir().join("sprocketnes-util-old-state-test");
+        let mut val = 0u16;
 
-#[derive(Copy, Clone)]
-pub struct Xorshift {
-    pub x: u32,
-    pub y: u32,
-    pub z: u32,
-    pub w: u32,
-}
+        // From before the header
+        fs::write(&path, [0x34, 0x12]).unwrap();
+        assert!(load_state(&mut val, &path).is_err());
 
-impl Xorshift {
-    pub fn new() -> Xorshift {
-        Xorshift {
-            x: 123456789,
-            y: 362436069,
-            z: 521288629,
-            w: 88675123,
-        }
-    }
+        let mut old = STATE_MAGIC.to_vec();
+        old.extend_from_slice(&[STATE_VERSION - 1, 0x34, 0x12]);
+        fs::write(&path, &old).unwrap();
+        assert!(load_state(&mut val, &path).is_err());
+        let _ = fs::remove_file(&path);
 
-    pub fn next(&mut self) -> u32 {
-        let t = self.x ^ (self.x << 11);
-        self.x = self.y;
-        self.y = self.z;
-        self.z = self.w;
-        self.w = self.w ^ (self.w >> 19) ^ (t ^ (t >> 8));
-        self.w
+        assert_eq!(val, 0);
     }
 }
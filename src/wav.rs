@@ -0,0 +1,149 @@
+//! Recordings of the audio output, as 16-bit mono PCM `.wav` files.
+
+//
+// Author: Patrick Walton
+//
+
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Where a recording is written: a file, or a `Cursor` for tests.
+pub trait Output: Write + Seek {}
+
+impl<T: Write + Seek> Output for T {}
+
+/// The size of the RIFF header, the format chunk and the data chunk's header.
+const HEADER_LEN: u32 = 44;
+
+/// The most bytes of samples there can be, with the RIFF chunk's size still fitting in 32 bits.
+/// That's about 13 and a half hours at 44.1 kHz.
+const MAX_DATA_LEN: u32 = u32::MAX - (HEADER_LEN - 8);
+
+pub struct WavWriter {
+    out: Box<Output>,
+    /// How many bytes of samples have been written.
+    data_len: u32,
+}
+
+impl WavWriter {
+    /// Starts a recording at the given sample rate, writing the header straight away. The sizes
+    /// in it are filled in by `finish`.
+    pub fn new(mut out: Box<Output>, sample_rate: u32) -> io::Result<WavWriter> {
+        out.write_all(b"RIFF")?;
+        out.write_all(&(HEADER_LEN - 8).to_le_bytes())?;
+        out.write_all(b"WAVEfmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        out.write_all(&1u16.to_le_bytes())?; // PCM
+        out.write_all(&1u16.to_le_bytes())?; // Mono
+        out.write_all(&sample_rate.to_le_bytes())?;
+        out.write_all(&(sample_rate * 2).to_le_bytes())?; // Bytes per second
+        out.write_all(&2u16.to_le_bytes())?; // Bytes per sample
+        out.write_all(&16u16.to_le_bytes())?; // Bits per sample
+        out.write_all(b"data")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        Ok(WavWriter {
+            out: out,
+            data_len: 0,
+        })
+    }
+
+    /// Starts a recording in a new file, replacing any that's there.
+    pub fn create(path: &Path, sample_rate: u32) -> io::Result<WavWriter> {
+        let file = File::create(path)?;
+        WavWriter::new(Box::new(BufWriter::new(file)), sample_rate)
+    }
+
+    /// Appends samples to the recording. Fails, writing none of them, if they'd make the
+    /// recording too long for a WAV file, after which it can still be finished.
+    pub fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+        let len = samples.len() as u64 * 2;
+        if self.data_len as u64 + len > MAX_DATA_LEN as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "the recording is too long for a WAV file",
+            ));
+        }
+        for &sample in samples.iter() {
+            self.out.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_len += len as u32;
+        Ok(())
+    }
+
+    /// Fills in the sizes in the header. Until this is called, players see an empty recording.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out
+            .write_all(&(HEADER_LEN - 8 + self.data_len).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(HEADER_LEN as u64 - 4))?;
+        self.out.write_all(&self.data_len.to_le_bytes())?;
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::io::Cursor;
+    use std::rc::Rc;
+
+    // A `Cursor` that can still be looked at after the writer that owns it is finished.
+    #[derive(Clone)]
+    struct Shared(Rc<RefCell<Cursor<Vec<u8>>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for Shared {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.0.borrow_mut().seek(pos)
+        }
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        let mut le = [0; 4];
+        le.copy_from_slice(&bytes[offset..offset + 4]);
+        u32::from_le_bytes(le)
+    }
+
+    #[test]
+    fn finish_fills_in_sizes() {
+        let out = Shared(Rc::new(RefCell::new(Cursor::new(vec![]))));
+        let mut writer = WavWriter::new(Box::new(out.clone()), 44100).unwrap();
+        writer.write(&[1, -1, 2]).unwrap();
+        writer.write(&[0x1234]).unwrap();
+        writer.finish().unwrap();
+
+        let bytes = out.0.borrow().get_ref().clone();
+        assert_eq!(bytes.len(), 44 + 8);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32_at(&bytes, 4), 36 + 8);
+        assert_eq!(u32_at(&bytes, 24), 44100);
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(u32_at(&bytes, 40), 8);
+        assert_eq!(&bytes[50..52], &[0x34, 0x12]);
+    }
+
+    #[test]
+    fn overlong_recordings_are_refused() {
+        let out = Shared(Rc::new(RefCell::new(Cursor::new(vec![]))));
+        let mut writer = WavWriter::new(Box::new(out.clone()), 44100).unwrap();
+        writer.data_len = MAX_DATA_LEN - 2;
+        writer.write(&[7]).unwrap();
+        assert!(writer.write(&[7]).is_err());
+        writer.finish().unwrap();
+
+        let bytes = out.0.borrow().get_ref().clone();
+        assert_eq!(u32_at(&bytes, 4), u32::MAX);
+        assert_eq!(u32_at(&bytes, 40), MAX_DATA_LEN);
+    }
+}
@@ -0,0 +1,207 @@
+//! Just enough of the zip format to pull a ROM image out of an archive.
+
+//
+// Author: Patrick Walton
+//
+
+use rom::RomError;
+
+use flate2::read::DeflateDecoder;
+use std::cmp;
+use std::io::Read;
+
+const END_OF_CENTRAL_DIRECTORY: [u8; 4] = *b"PK\x05\x06";
+const CENTRAL_DIRECTORY_ENTRY: [u8; 4] = *b"PK\x01\x02";
+const LOCAL_FILE_HEADER: [u8; 4] = *b"PK\x03\x04";
+
+/// The file name extensions of ROM images.
+const ROM_EXTENSIONS: [&str; 3] = [".nes", ".unf", ".unif"];
+
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, RomError> {
+    match data.get(offset..offset + 2) {
+        Some(bytes) => Ok(bytes[0] as u16 | (bytes[1] as u16) << 8),
+        None => Err(RomError::Truncated),
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, RomError> {
+    let low = read_u16(data, offset)? as u32;
+    let high = read_u16(data, offset + 2)? as u32;
+    Ok(low | high << 16)
+}
+
+fn is_rom_name(name: &[u8]) -> bool {
+    let name = String::from_utf8_lossy(name).to_lowercase();
+    ROM_EXTENSIONS
+        .iter()
+        .any(|extension| name.ends_with(extension))
+}
+
+/// Returns true if `data` starts like a zip archive.
+pub fn is_zip(data: &[u8]) -> bool {
+    data.starts_with(&LOCAL_FILE_HEADER)
+}
+
+/// Extracts the first ROM image in a zip archive. The central directory at the end of the
+/// archive is used to find it, because the sizes in the local headers may be missing.
+pub fn extract_rom(data: &[u8]) -> Result<Vec<u8>, RomError> {
+    // The end of central directory record is last, followed only by a comment.
+    let end = match data
+        .windows(4)
+        .rposition(|window| window == END_OF_CENTRAL_DIRECTORY)
+    {
+        Some(end) => end,
+        None => return Err(RomError::BadArchive),
+    };
+    let entry_count = read_u16(data, end + 10)?;
+    let mut entry = read_u32(data, end + 16)? as usize;
+
+    for _ in 0..entry_count {
+        if data.get(entry..entry + 4) != Some(&CENTRAL_DIRECTORY_ENTRY[..]) {
+            return Err(RomError::BadArchive);
+        }
+        let method = read_u16(data, entry + 10)?;
+        let compressed_len = read_u32(data, entry + 20)? as usize;
+        let len = read_u32(data, entry + 24)? as usize;
+        let name_len = read_u16(data, entry + 28)? as usize;
+        let extra_len = read_u16(data, entry + 30)? as usize;
+        let comment_len = read_u16(data, entry + 32)? as usize;
+        let header = read_u32(data, entry + 42)? as usize;
+        let name = match data.get(entry + 46..entry + 46 + name_len) {
+            Some(name) => name,
+            None => return Err(RomError::Truncated),
+        };
+        entry += 46 + name_len + extra_len + comment_len;
+        if !is_rom_name(name) {
+            continue;
+        }
+
+        // The local header's name and extra field lengths can differ from the central one's.
+        let start = header
+            + 30
+            + read_u16(data, header + 26)? as usize
+            + read_u16(data, header + 28)? as usize;
+        let compressed = match data.get(start..start + compressed_len) {
+            Some(compressed) => compressed,
+            None => return Err(RomError::Truncated),
+        };
+        return match method {
+            STORED => Ok(compressed.to_vec()),
+            DEFLATED => {
+                // The length comes from the archive, and deflate can't expand data by more
+                // than about 1032 times, so don't trust it any further than that.
+                let mut rom = Vec::with_capacity(cmp::min(len, compressed.len() * 1032));
+                DeflateDecoder::new(compressed).read_to_end(&mut rom)?;
+                Ok(rom)
+            }
+            _ => Err(RomError::UnsupportedVariant(
+                "zip entries compressed with methods other than deflate",
+            )),
+        };
+    }
+    Err(RomError::NoRomInArchive)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use flate2::write::DeflateEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn push_u16(bytes: &mut Vec<u8>, value: u16) {
+        bytes.extend_from_slice(&[value as u8, (value >> 8) as u8]);
+    }
+
+    fn push_u32(bytes: &mut Vec<u8>, value: u32) {
+        push_u16(bytes, value as u16);
+        push_u16(bytes, (value >> 16) as u16);
+    }
+
+    // An archive holding one file, with the given method and already compressed contents.
+    fn archive(name: &str, method: u16, contents: &[u8], len: usize) -> Vec<u8> {
+        let mut data = LOCAL_FILE_HEADER.to_vec();
+        data.resize(26, 0);
+        push_u16(&mut data, name.len() as u16);
+        push_u16(&mut data, 0);
+        data.extend_from_slice(name.as_bytes());
+        data.extend_from_slice(contents);
+
+        let directory = data.len();
+        data.extend_from_slice(&CENTRAL_DIRECTORY_ENTRY);
+        data.resize(directory + 10, 0);
+        push_u16(&mut data, method);
+        data.resize(directory + 20, 0);
+        push_u32(&mut data, contents.len() as u32);
+        push_u32(&mut data, len as u32);
+        push_u16(&mut data, name.len() as u16);
+        data.resize(directory + 42, 0);
+        push_u32(&mut data, 0);
+        data.extend_from_slice(name.as_bytes());
+
+        let end = data.len();
+        data.extend_from_slice(&END_OF_CENTRAL_DIRECTORY);
+        data.resize(end + 10, 0);
+        push_u16(&mut data, 1);
+        push_u32(&mut data, (end - directory) as u32);
+        push_u32(&mut data, directory as u32);
+        push_u16(&mut data, 0);
+        data
+    }
+
+    fn image() -> Vec<u8> {
+        (0..0x1000).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn stored_rom_is_extracted() {
+        let data = archive("Game.NES", STORED, &image(), 0x1000);
+        assert!(is_zip(&data));
+        assert_eq!(extract_rom(&data).unwrap(), image());
+    }
+
+    #[test]
+    fn deflated_rom_is_extracted() {
+        let mut encoder = DeflateEncoder::new(vec![], Compression::default());
+        encoder.write_all(&image()).unwrap();
+        let data = archive("game.nes", DEFLATED, &encoder.finish().unwrap(), 0x1000);
+        assert_eq!(extract_rom(&data).unwrap(), image());
+    }
+
+    #[test]
+    fn corrupt_archives_are_rejected() {
+        match extract_rom(&archive("readme.txt", STORED, b"hello", 5)) {
+            Err(RomError::NoRomInArchive) => {}
+            _ => panic!("found a ROM in an archive without one"),
+        }
+
+        let data = archive("game.nes", STORED, &image(), 0x1000);
+        match extract_rom(&data[..data.len() - 22]) {
+            Err(RomError::BadArchive) => {}
+            _ => panic!("extracted a ROM from an archive without a central directory"),
+        }
+
+        // Point the directory past the end of the file.
+        let mut bad_offset = data.clone();
+        let offset = bad_offset.len() - 6;
+        bad_offset[offset + 3] = 0x7f;
+        match extract_rom(&bad_offset) {
+            Err(RomError::BadArchive) => {}
+            _ => panic!("followed a corrupt central directory offset"),
+        }
+
+        // Cut the deflate stream short, and claim a huge uncompressed length.
+        let mut encoder = DeflateEncoder::new(vec![], Compression::default());
+        encoder.write_all(&image()).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let short = &compressed[..compressed.len() / 2];
+        match extract_rom(&archive("game.nes", DEFLATED, short, 0xffff_ffff)) {
+            Err(RomError::Truncated) => {}
+            _ => panic!("extracted a ROM from a truncated deflate stream"),
+        }
+    }
+}
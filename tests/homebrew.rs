@@ -0,0 +1,157 @@
+//! Boots a tiny generated homebrew ROM end to end, so that the whole pipeline (ROM loading, mapper,
+//! CPU, PPU and APU) is exercised without needing any ROM files.
+//!
+//! The program waits for the PPU to warm up, loads a palette, fills the first nametable with a
+//! pattern of the four tiles in CHR-ROM, starts a square wave on pulse 1, and then enables
+//! rendering and NMIs. Each NMI scrolls the screen one pixel to the right and changes the pitch.
+
+//
+// Author: Patrick Walton
+//
+
+extern crate nes;
+
+use nes::rom::Rom;
+use nes::Headless;
+
+const FRAMES: usize = 60;
+
+/// The FNV-1a hash of the screen after `FRAMES` frames.
+const SCREEN_HASH: u64 = 0x576ac9066d67e845;
+/// The FNV-1a hash of every sample mixed during `FRAMES` frames.
+const AUDIO_HASH: u64 = 0x4f8646abffc74968;
+/// How many samples the APU mixes during `FRAMES` frames.
+const AUDIO_SAMPLES: usize = 39690;
+
+/// The program, assembled at $C000. 16K of PRG-ROM is mirrored at $8000 and $C000.
+#[rustfmt::skip]
+const PROGRAM: [u8; 0x80] = [
+    // reset:
+    0x78,                   // C000: SEI
+    0xd8,                   // C001: CLD
+    0xa2, 0xff,             // C002: LDX #$FF
+    0x9a,                   // C004: TXS
+    0x2c, 0x02, 0x20,       // C005: BIT $2002
+    0x10, 0xfb,             // C008: BPL $C005
+    0x2c, 0x02, 0x20,       // C00A: BIT $2002
+    0x10, 0xfb,             // C00D: BPL $C00A
+
+    // Load the background palette.
+    0xa9, 0x3f,             // C00F: LDA #$3F
+    0x8d, 0x06, 0x20,       // C011: STA $2006
+    0xa9, 0x00,             // C014: LDA #$00
+    0x8d, 0x06, 0x20,       // C016: STA $2006
+    0xa2, 0x00,             // C019: LDX #0
+    0xbd, 0x7c, 0xc0,       // C01B: LDA palette,X
+    0x8d, 0x07, 0x20,       // C01E: STA $2007
+    0xe8,                   // C021: INX
+    0xe0, 0x04,             // C022: CPX #4
+    0xd0, 0xf5,             // C024: BNE $C01B
+
+    // Fill the first nametable (and its attributes) with tiles 0, 1, 2, 3, 0, 1...
+    0xa9, 0x20,             // C026: LDA #$20
+    0x8d, 0x06, 0x20,       // C028: STA $2006
+    0xa9, 0x00,             // C02B: LDA #$00
+    0x8d, 0x06, 0x20,       // C02D: STA $2006
+    0xa0, 0x04,             // C030: LDY #4
+    0xa2, 0x00,             // C032: LDX #0
+    0x8a,                   // C034: TXA
+    0x29, 0x03,             // C035: AND #3
+    0x8d, 0x07, 0x20,       // C037: STA $2007
+    0xe8,                   // C03A: INX
+    0xd0, 0xf7,             // C03B: BNE $C034
+    0x88,                   // C03D: DEY
+    0xd0, 0xf4,             // C03E: BNE $C034
+
+    // Reset the scroll position.
+    0xa9, 0x00,             // C040: LDA #0
+    0x8d, 0x05, 0x20,       // C042: STA $2005
+    0x8d, 0x05, 0x20,       // C045: STA $2005
+
+    // Play a square wave on pulse 1: 50% duty, constant volume 15.
+    0xa9, 0x01,             // C048: LDA #$01
+    0x8d, 0x15, 0x40,       // C04A: STA $4015
+    0xa9, 0xbf,             // C04D: LDA #$BF
+    0x8d, 0x00, 0x40,       // C04F: STA $4000
+    0xa9, 0xfd,             // C052: LDA #$FD
+    0x8d, 0x02, 0x40,       // C054: STA $4002
+    0xa9, 0x00,             // C057: LDA #$00
+    0x8d, 0x03, 0x40,       // C059: STA $4003
+
+    // Enable NMIs and the background, then spin.
+    0xa9, 0x80,             // C05C: LDA #$80
+    0x8d, 0x00, 0x20,       // C05E: STA $2000
+    0xa9, 0x0a,             // C061: LDA #$0A
+    0x8d, 0x01, 0x20,       // C063: STA $2001
+    0x4c, 0x66, 0xc0,       // C066: JMP $C066
+
+    // nmi: Scroll right by one pixel and change the pitch.
+    0xe6, 0x00,             // C069: INC $00
+    0xa5, 0x00,             // C06B: LDA $00
+    0x8d, 0x05, 0x20,       // C06D: STA $2005
+    0xa9, 0x00,             // C070: LDA #0
+    0x8d, 0x05, 0x20,       // C072: STA $2005
+    0xa5, 0x00,             // C075: LDA $00
+    0x8d, 0x02, 0x40,       // C077: STA $4002
+    0x40,                   // C07A: RTI
+
+    // irq:
+    0x40,                   // C07B: RTI
+
+    // palette:
+    0x0f, 0x16, 0x2a, 0x12, // C07C
+];
+
+/// Builds an iNES image: mapper 0 with vertical mirroring, 16K of PRG-ROM and 8K of CHR-ROM.
+fn homebrew_rom() -> Vec<u8> {
+    let mut image = b"NES\x1a".to_vec();
+    image.extend_from_slice(&[1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+    let mut prg = vec![0; 0x4000];
+    prg[..PROGRAM.len()].copy_from_slice(&PROGRAM);
+    // The NMI, reset and IRQ vectors.
+    prg[0x3ffa..].copy_from_slice(&[0x69, 0xc0, 0x00, 0xc0, 0x7b, 0xc0]);
+    image.extend_from_slice(&prg);
+
+    // Tile n is drawn in color n, with a diagonal stripe so that scrolling shows.
+    let mut chr = vec![0; 0x2000];
+    for tile in 0..4 {
+        for row in 0..8 {
+            let stripe = 0x80 >> row;
+            let low = if (tile & 1) != 0 { 0xff } else { stripe };
+            let high = if (tile & 2) != 0 { 0xff } else { 0 };
+            chr[tile * 16 + row] = low;
+            chr[tile * 16 + 8 + row] = high;
+        }
+    }
+    image.extend_from_slice(&chr);
+    image
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for &byte in bytes.iter() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[test]
+fn homebrew_rom_boots() {
+    let rom = Rom::from_bytes(&homebrew_rom()).unwrap();
+    let mut console = Headless::new(rom).unwrap();
+
+    let mut samples = vec![];
+    for _ in 0..FRAMES {
+        console.run_frame(&mut samples);
+    }
+
+    let sample_bytes: Vec<u8> = samples
+        .iter()
+        .flat_map(|&sample| vec![sample as u8, (sample >> 8) as u8])
+        .collect();
+    assert_eq!(fnv1a(console.screen()), SCREEN_HASH);
+    assert_eq!(samples.len(), AUDIO_SAMPLES);
+    assert_eq!(fnv1a(&sample_bytes), AUDIO_HASH);
+}
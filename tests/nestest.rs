@@ -0,0 +1,114 @@
+//! Runs nestest in its automated mode, which starts at $C000 without a reset and works through
+//! every official and unofficial opcode, and compares the CPU's trace with nestest.log, reporting
+//! the first line where they differ.
+//!
+//! Neither file ships with sprocketnes. Put `nestest.nes` and `nestest.log` (the log with total
+//! cycle counts, as linked from the nesdev wiki) in `tests/roms`, or point `NESTEST_DIR` at the
+//! directory holding them, then run `cargo test -- --ignored`. The test is ignored by default
+//! because of this, and fails if it's run without them.
+
+//
+// Author: Patrick Walton
+//
+
+extern crate nes;
+
+use nes::mem::Mem;
+use nes::rom::Rom;
+use nes::trace::Tracer;
+use nes::Headless;
+
+use std::env;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+
+/// Where nestest's automated mode starts.
+const START_PC: u16 = 0xc000;
+/// nestest.log counts the 7 cycles of the reset sequence, which starting at $C000 skips.
+const RESET_CYCLES: u64 = 7;
+/// The length of the registers field, `A:00 X:00 Y:00 P:24 SP:FD`.
+const REGISTERS_LEN: usize = 25;
+
+/// The parts of a trace line that are compared: the disassembly and the PPU position are left
+/// out, since they're formatted differently from emulator to emulator.
+#[derive(PartialEq, Debug)]
+struct State {
+    pc: String,
+    registers: String,
+    cycles: u64,
+}
+
+fn parse(line: &str, cycle_offset: u64) -> Option<State> {
+    let registers = match line.find("A:") {
+        Some(start) if line.len() >= start + REGISTERS_LEN => &line[start..start + REGISTERS_LEN],
+        _ => return None,
+    };
+    let cycles = match line.rfind("CYC:") {
+        Some(start) => match line[start + 4..].trim().parse::<u64>() {
+            Ok(cycles) => cycles,
+            Err(_) => return None,
+        },
+        None => return None,
+    };
+    Some(State {
+        pc: line[..4].to_string(),
+        registers: registers.to_string(),
+        cycles: cycles + cycle_offset,
+    })
+}
+
+fn read_lines(path: &PathBuf) -> Vec<String> {
+    let file = File::open(path).unwrap();
+    BufReader::new(file)
+        .lines()
+        .map(|line| line.unwrap())
+        .collect()
+}
+
+#[test]
+#[ignore = "needs nestest.nes and nestest.log in tests/roms or NESTEST_DIR"]
+fn nestest_matches_reference_log() {
+    let dir = match env::var("NESTEST_DIR") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/roms"),
+    };
+    let (rom_path, log_path) = (dir.join("nestest.nes"), dir.join("nestest.log"));
+    assert!(
+        rom_path.exists() && log_path.exists(),
+        "no nestest.nes and nestest.log in {}",
+        dir.display()
+    );
+
+    let expected = read_lines(&log_path);
+    let trace_path = env::temp_dir().join("sprocketnes-nestest.log");
+    let rom = Rom::from_path(&rom_path).unwrap();
+    let mut console = Headless::starting_at(rom, START_PC).unwrap();
+    console
+        .cpu
+        .set_tracer(Some(Tracer::create(&trace_path).unwrap()));
+    for _ in 0..expected.len() {
+        console.step();
+    }
+    console.cpu.set_tracer(None).unwrap().flush().unwrap();
+
+    let actual = read_lines(&trace_path);
+    for (index, expected_line) in expected.iter().enumerate() {
+        let actual_line = actual.get(index).map(|line| &**line).unwrap_or("");
+        let matches = match parse(expected_line, 0) {
+            Some(state) => parse(actual_line, RESET_CYCLES) == Some(state),
+            None => continue,
+        };
+        assert!(
+            matches,
+            "nestest.log line {} differs:\nexpected: {}\n  traced: {}",
+            index + 1,
+            expected_line,
+            actual_line
+        );
+    }
+
+    // nestest leaves the number of the first failing official and unofficial test in $02 and $03.
+    let results = (console.cpu.mem.loadb(0x02), console.cpu.mem.loadb(0x03));
+    assert_eq!(results, (0, 0), "nestest reported failures");
+}